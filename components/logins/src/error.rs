@@ -90,6 +90,9 @@ pub enum ErrorKind {
     #[fail(display = "Error executing SQL: {}", _0)]
     SqlError(#[fail(cause)] rusqlite::Error),
 
+    #[fail(display = "Error upgrading the database: {}", _0)]
+    MigrationError(#[fail(cause)] sql_support::MigrationError),
+
     #[fail(display = "Error parsing URL: {}", _0)]
     UrlParseError(#[fail(cause)] url::ParseError),
 }
@@ -117,6 +120,7 @@ impl_from_error! {
     (JsonError, serde_json::Error),
     (UrlParseError, url::ParseError),
     (SqlError, rusqlite::Error),
+    (MigrationError, sql_support::MigrationError),
     (InvalidLogin, InvalidLogin)
 }
 
@@ -91,7 +91,8 @@
 use crate::db;
 use crate::error::*;
 use lazy_static::lazy_static;
-use sql_support::ConnExt;
+use rusqlite::Connection;
+use sql_support::{init_schema, ConnExt, DowngradePolicy, Schema};
 
 /// Note that firefox-ios is currently on version 3. Version 4 is this version,
 /// which adds a metadata table and changes timestamps to be in milliseconds
@@ -168,8 +169,6 @@ lazy_static! {
         )",
         common_sql = COMMON_SQL
     );
-    static ref SET_VERSION_SQL: String =
-        format!("PRAGMA user_version = {version}", version = VERSION);
 }
 
 const CREATE_META_TABLE_SQL: &'static str = "
@@ -208,9 +207,18 @@ const UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL: &'static str = "
 pub(crate) static LAST_SYNC_META_KEY: &'static str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &'static str = "global_state";
 
-pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
-    let user_version = db.query_one::<i64>("PRAGMA user_version")?;
-    if user_version == 0 {
+struct LoginsSchema;
+
+impl Schema for LoginsSchema {
+    type Error = Error;
+    const NAME: &'static str = "logins";
+    const VERSION: i64 = VERSION;
+    // Everything here is user data, so there's nothing to rebuild, but we'd
+    // rather keep working with a newer database than lock users out of their
+    // logins.
+    const DOWNGRADE_POLICY: DowngradePolicy = DowngradePolicy::Ignore;
+
+    fn create(&self, db: &Connection) -> Result<()> {
         // This logic is largely taken from firefox-ios. AFAICT at some point
         // they went from having schema versions tracked using a table named
         // `tableList` to using `PRAGMA user_version`. This leads to the
@@ -218,13 +226,13 @@ pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
         //
         // - If `tableList` exists, we're hopelessly far in the past, drop any
         //   tables we have (to ensure we avoid name collisions/stale data) and
-        //   recreate. (This is captured by the `upgrade` case where from == 0)
+        //   recreate.
         //
         // - If `tableList` doesn't exist and `PRAGMA user_version` is 0, it's
         //   the first time through, just create the new tables.
         //
         // - Otherwise, it's a normal schema upgrade from an earlier
-        //   `PRAGMA user_version`.
+        //   `PRAGMA user_version`, which `upgrade_from` handles.
         let table_list_exists = db.query_one::<i64>(
             "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'tableList'",
         )? != 0;
@@ -232,56 +240,42 @@ pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
         if table_list_exists {
             drop(db)?;
         }
-        return create(db);
+        create(db)
     }
-    if user_version != VERSION {
-        if user_version < VERSION {
-            upgrade(db, user_version)?;
-        } else {
-            log::warn!(
-                "Loaded future schema version {} (we only understand version {}). \
-                 Optimisitically ",
-                user_version,
-                VERSION
-            )
-        }
+
+    fn upgrade_from(&self, db: &Connection, version: i64) -> Result<()> {
+        upgrade_from(db, version)
     }
-    Ok(())
+}
+
+pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
+    init_schema(db, &LoginsSchema)
 }
 
 // https://github.com/mozilla-mobile/firefox-ios/blob/master/Storage/SQL/LoginsSchema.swift#L100
-fn upgrade(db: &db::LoginDb, from: i64) -> Result<()> {
-    log::debug!("Upgrading schema from {} to {}", from, VERSION);
-    if from == VERSION {
-        return Ok(());
-    }
-    assert_ne!(
-        from, 0,
-        "Upgrading from user_version = 0 should already be handled (in `init`)"
-    );
-    if from < 3 {
+fn upgrade_from(db: &Connection, version: i64) -> Result<()> {
+    match version {
+        1 => {}
         // These indices were added in v3 (apparently)
-        db.execute_all(&[
+        2 => db.execute_all(&[
             CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
             CREATE_DELETED_HOSTNAME_INDEX_SQL,
-        ])?;
-    }
-    if from < 4 {
+        ])?,
         // This is the update from the firefox-ios schema to our schema.
         // The `loginsSyncMeta` table was added in v4, and we moved
         // from using microseconds to milliseconds for `timeCreated`,
         // `timeLastUsed`, and `timePasswordChanged`.
-        db.execute_all(&[
+        3 => db.execute_all(&[
             CREATE_META_TABLE_SQL,
             UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
             UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
-            &*SET_VERSION_SQL,
-        ])?;
+        ])?,
+        _ => unreachable!("no upgrade from version {}", version),
     }
     Ok(())
 }
 
-pub(crate) fn create(db: &db::LoginDb) -> Result<()> {
+pub(crate) fn create(db: &Connection) -> Result<()> {
     log::debug!("Creating schema");
     db.execute_all(&[
         &*CREATE_LOCAL_TABLE_SQL,
@@ -289,18 +283,56 @@ pub(crate) fn create(db: &db::LoginDb) -> Result<()> {
         CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
         CREATE_DELETED_HOSTNAME_INDEX_SQL,
         CREATE_META_TABLE_SQL,
-        &*SET_VERSION_SQL,
     ])?;
     Ok(())
 }
 
-pub(crate) fn drop(db: &db::LoginDb) -> Result<()> {
+pub(crate) fn drop(db: &Connection) -> Result<()> {
     log::debug!("Dropping schema");
     db.execute_all(&[
         "DROP TABLE IF EXISTS loginsM",
         "DROP TABLE IF EXISTS loginsL",
         "DROP TABLE IF EXISTS loginsSyncMeta",
-        "PRAGMA user_version = 0",
     ])?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginDb;
+
+    #[test]
+    fn test_upgrade_from_v3() {
+        // firefox-ios's last schema, with microsecond timestamps and no
+        // metadata table.
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_all(&[
+            &*CREATE_LOCAL_TABLE_SQL,
+            &*CREATE_MIRROR_TABLE_SQL,
+            CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
+            CREATE_DELETED_HOSTNAME_INDEX_SQL,
+            "INSERT INTO loginsL (guid, hostname, password, timeCreated,
+                                  timeLastUsed, timePasswordChanged)
+             VALUES ('loginAAAAAAA', 'https://example.com', 'hunter2',
+                     1000000, 2000000, 3000000)",
+            "PRAGMA user_version = 3",
+        ])
+        .unwrap();
+
+        let db = LoginDb::with_connection(conn, None).unwrap();
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), VERSION);
+        assert_eq!(
+            db.query_one::<i64>(
+                "SELECT timeCreated + timeLastUsed + timePasswordChanged FROM loginsL"
+            )
+            .unwrap(),
+            6000
+        );
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsSyncMeta")
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -5,6 +5,7 @@
 package org.mozilla.places
 
 import android.util.Log
+import com.sun.jna.Callback
 import com.sun.jna.Library
 import com.sun.jna.Native
 import com.sun.jna.Pointer
@@ -53,20 +54,70 @@ internal interface LibPlacesFFI : Library {
             out_err: RustError.ByReference
     )
 
+    /** Returns a handle which you need to free with places_interrupt_handle_destroy */
+    fun places_new_interrupt_handle(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    ): RawPlacesInterruptHandle?
+
+    /**
+     * Interrupts whatever is running on the handle's connection, including writes. Safe to call
+     * from any thread.
+     */
+    fun places_interrupt(
+            handle: RawPlacesInterruptHandle,
+            out_err: RustError.ByReference
+    )
+
     /** Returns JSON string, which you need to free with places_destroy_string */
     fun places_query_autocomplete(
             conn: RawPlacesConnection,
             search: String,
             limit: Int,
+            user_context_id: Int,
+            ignore_diacritics: Byte,
             out_err: RustError.ByReference
     ): Pointer?
 
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_autofill(
+            conn: RawPlacesConnection,
+            search: String,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_register_open_page(
+            conn: RawPlacesConnection,
+            url: String,
+            user_context_id: Int,
+            out_err: RustError.ByReference
+    )
+
+    fun places_unregister_open_page(
+            conn: RawPlacesConnection,
+            url: String,
+            user_context_id: Int,
+            out_err: RustError.ByReference
+    )
+
+    fun places_unregister_all_open_pages(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    )
+
     fun places_get_visited(
             conn: RawPlacesConnection,
             urls_json: String,
             out_err: RustError.ByReference
     ): Pointer?
 
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_visit_page(
+            conn: RawPlacesConnection,
+            query_json: String,
+            out_err: RustError.ByReference
+    ): Pointer?
+
     fun places_get_visited_urls_in_range(
             conn: RawPlacesConnection,
             start: Long,
@@ -75,6 +126,136 @@ internal interface LibPlacesFFI : Library {
             out_err: RustError.ByReference
     ): Pointer?
 
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_visit_chain(
+            conn: RawPlacesConnection,
+            visit_id: Long,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_top_sites(
+            conn: RawPlacesConnection,
+            limit: Int,
+            dedupe_by_origin: Byte,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_block_top_site(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    )
+
+    fun places_unblock_top_site(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    )
+
+    fun places_unblock_all_top_sites(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    )
+
+    fun places_delete_visits_between(
+            conn: RawPlacesConnection,
+            start: Long,
+            end: Long,
+            out_err: RustError.ByReference
+    )
+
+    fun places_delete_visit(
+            conn: RawPlacesConnection,
+            visit_id: Long,
+            out_err: RustError.ByReference
+    )
+
+    fun places_delete_host(
+            conn: RawPlacesConnection,
+            host: String,
+            out_err: RustError.ByReference
+    )
+
+    fun places_delete_origin(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    )
+
+    fun places_delete_everything(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    )
+
+    /** Returns 1 if expiration finished, 0 if it should be called again */
+    fun places_expire_history(
+            conn: RawPlacesConnection,
+            max_pages: Int,
+            max_visits: Int,
+            time_limit_ms: Int,
+            out_err: RustError.ByReference
+    ): Byte
+
+    /** Returns 1 if there are no stale frecencies left, 0 if it should be called again */
+    fun places_update_stale_frecencies(
+            conn: RawPlacesConnection,
+            max_places: Int,
+            out_err: RustError.ByReference
+    ): Byte
+
+    /** Returns JSON string, which you need to free with places_destroy_string */
+    fun places_get_frecency_settings(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_set_frecency_settings(
+            conn: RawPlacesConnection,
+            frecency_settings: String,
+            out_err: RustError.ByReference
+    )
+
+    fun places_decay_adaptive_history(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    )
+
+    fun places_clear_adaptive_history_for_url(
+            conn: RawPlacesConnection,
+            url: String,
+            out_err: RustError.ByReference
+    )
+
+    fun places_enable_search_index(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    )
+
+    fun places_disable_search_index(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    )
+
+    fun places_rebuild_search_index(
+            conn: RawPlacesConnection,
+            out_err: RustError.ByReference
+    )
+
+    /** Returns an id for places_unregister_history_observer */
+    fun places_register_history_observer(
+            conn: RawPlacesConnection,
+            callback: RawHistoryObserver,
+            out_err: RustError.ByReference
+    ): Long
+
+    /** Returns 1 if the observer was unregistered, 0 if there's no observer with the id */
+    fun places_unregister_history_observer(
+            conn: RawPlacesConnection,
+            id: Long,
+            out_err: RustError.ByReference
+    ): Byte
+
     fun sync15_history_sync(
             conn: RawPlacesConnection,
             key_id: String,
@@ -89,6 +270,15 @@ internal interface LibPlacesFFI : Library {
 
     /** Destroy connection created using `places_connection_new` */
     fun places_connection_destroy(obj: RawPlacesConnection)
+
+    /** Destroy handle created using `places_new_interrupt_handle` */
+    fun places_interrupt_handle_destroy(obj: RawPlacesInterruptHandle)
 }
 
 class RawPlacesConnection : PointerType()
+class RawPlacesInterruptHandle : PointerType()
+
+/** Called with a JSON array of history events. */
+internal interface RawHistoryObserver : Callback {
+    fun invoke(json: String)
+}
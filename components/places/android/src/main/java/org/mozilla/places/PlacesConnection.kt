@@ -8,7 +8,6 @@ import com.sun.jna.Pointer
 import org.json.JSONArray
 import org.json.JSONException
 import org.json.JSONObject
-import java.io.File
 
 /**
  * An implementation of a [PlacesAPI] backed by a Rust Places library.
@@ -19,27 +18,27 @@ import java.io.File
  */
 class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI, AutoCloseable {
     private var db: RawPlacesConnection?
+    // `interrupt` is called while another thread is using the connection, so it can't take the
+    // connection's lock. This lock only makes sure that the handle isn't destroyed while it's used.
+    @Volatile
+    private var interruptHandle: RawPlacesInterruptHandle?
+    private val interruptLock = Any()
+    // JNA only holds weak references to callbacks, so we keep the ones we've registered alive
+    // until they're unregistered.
+    private val historyObservers: MutableMap<Long, RawHistoryObserver> = mutableMapOf()
+    // Rust calls the raw observers while it's still using the connection, on the thread that
+    // changed history. They queue the events here, and `rustCall` delivers them once it's released
+    // the connection, so that observers can use it.
+    private val pendingHistoryEvents = object : ThreadLocal<MutableList<PendingHistoryEvents>>() {
+        override fun initialValue(): MutableList<PendingHistoryEvents> = mutableListOf()
+    }
 
     init {
-        try {
-            db = rustCall { error ->
-                LibPlacesFFI.INSTANCE.places_connection_new(path, encryption_key, error)
-            }
-        } catch (e: InternalPanic) {
-
-            // Places Rust library does not yet support schema migrations; as a very temporary quick
-            // fix to avoid crashes of our upstream consumers, let's delete the database file
-            // entirely and try again.
-            // FIXME https://github.com/mozilla/application-services/issues/438
-            if (e.message != "sorry, no upgrades yet - delete your db!") {
-                throw e
-            }
-
-            File(path).delete()
-
-            db = rustCall { error ->
-                LibPlacesFFI.INSTANCE.places_connection_new(path, encryption_key, error)
-            }
+        db = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_connection_new(path, encryption_key, error)
+        }
+        interruptHandle = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_new_interrupt_handle(this.db!!, error)
         }
     }
 
@@ -50,6 +49,25 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
         if (db != null) {
             LibPlacesFFI.INSTANCE.places_connection_destroy(db)
         }
+        synchronized(interruptLock) {
+            val interruptHandle = this.interruptHandle
+            this.interruptHandle = null
+            if (interruptHandle != null) {
+                LibPlacesFFI.INSTANCE.places_interrupt_handle_destroy(interruptHandle)
+            }
+        }
+        historyObservers.clear()
+    }
+
+    override fun interrupt() {
+        synchronized(interruptLock) {
+            val interruptHandle = this.interruptHandle ?: return
+            val e = RustError.ByReference()
+            LibPlacesFFI.INSTANCE.places_interrupt(interruptHandle, e)
+            if (e.isFailure()) {
+                throw e.intoException()
+            }
+        }
     }
 
     override fun noteObservation(data: VisitObservation) {
@@ -59,13 +77,50 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
         }
     }
 
-    override fun queryAutocomplete(query: String, limit: Int): List<SearchResult> {
+    override fun queryAutocomplete(
+        query: String,
+        limit: Int,
+        userContextId: Int?,
+        ignoreDiacritics: Boolean
+    ): List<SearchResult> {
         val json = rustCallForString { error ->
-            LibPlacesFFI.INSTANCE.places_query_autocomplete(this.db!!, query, limit, error)
+            val ignoreDiacriticsArg: Byte = if (ignoreDiacritics) { 1 } else { 0 }
+            LibPlacesFFI.INSTANCE.places_query_autocomplete(
+                    this.db!!, query, limit, userContextId ?: -1, ignoreDiacriticsArg, error)
         }
         return SearchResult.fromJSONArray(json)
     }
 
+    override fun autofill(query: String): AutofillResult? {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_autofill(this.db!!, query, error)
+        }
+        if (json == "null") {
+            return null
+        }
+        return AutofillResult.fromJSON(JSONObject(json))
+    }
+
+    override fun registerOpenPage(url: String, userContextId: Int) {
+        require(userContextId >= 0) { "userContextId must not be negative" }
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_register_open_page(this.db!!, url, userContextId, error)
+        }
+    }
+
+    override fun unregisterOpenPage(url: String, userContextId: Int) {
+        require(userContextId >= 0) { "userContextId must not be negative" }
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_unregister_open_page(this.db!!, url, userContextId, error)
+        }
+    }
+
+    override fun unregisterAllOpenPages() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_unregister_all_open_pages(this.db!!, error)
+        }
+    }
+
     override fun getVisited(urls: List<String>): List<Boolean> {
         val urlsToJson = JSONArray()
         for (url in urls) {
@@ -97,6 +152,168 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
         return result
     }
 
+    override fun getVisitPage(query: VisitQuery): VisitPage {
+        val queryJson = query.toJSON().toString()
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_get_visit_page(this.db!!, queryJson, error)
+        }
+        return VisitPage.fromJSON(JSONObject(json))
+    }
+
+    override fun getVisitChain(visitId: Long): List<VisitInfo> {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_get_visit_chain(this.db!!, visitId, error)
+        }
+        return VisitInfo.fromJSONArray(json)
+    }
+
+    override fun getTopSites(limit: Int, dedupeByOrigin: Boolean): List<TopSite> {
+        val json = rustCallForString { error ->
+            val dedupeArg: Byte = if (dedupeByOrigin) { 1 } else { 0 }
+            LibPlacesFFI.INSTANCE.places_get_top_sites(this.db!!, limit, dedupeArg, error)
+        }
+        return TopSite.fromJSONArray(json)
+    }
+
+    override fun blockTopSite(url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_block_top_site(this.db!!, url, error)
+        }
+    }
+
+    override fun unblockTopSite(url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_unblock_top_site(this.db!!, url, error)
+        }
+    }
+
+    override fun unblockAllTopSites() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_unblock_all_top_sites(this.db!!, error)
+        }
+    }
+
+    override fun deleteVisitsBetween(start: Long, end: Long) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_delete_visits_between(this.db!!, start, end, error)
+        }
+    }
+
+    override fun deleteVisit(visitId: Long) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_delete_visit(this.db!!, visitId, error)
+        }
+    }
+
+    override fun deleteHost(host: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_delete_host(this.db!!, host, error)
+        }
+    }
+
+    override fun deleteOrigin(url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_delete_origin(this.db!!, url, error)
+        }
+    }
+
+    override fun deleteEverything() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_delete_everything(this.db!!, error)
+        }
+    }
+
+    override fun expireHistory(maxPages: Int, maxVisits: Int, timeLimitMs: Int): Boolean {
+        require(maxPages >= 0) { "maxPages must not be negative" }
+        require(maxVisits >= 0) { "maxVisits must not be negative" }
+        require(timeLimitMs >= 0) { "timeLimitMs must not be negative" }
+        val finished = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_expire_history(
+                    this.db!!, maxPages, maxVisits, timeLimitMs, error)
+        }
+        return finished.toInt() != 0
+    }
+
+    override fun updateStaleFrecencies(maxPlaces: Int): Boolean {
+        val finished = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_update_stale_frecencies(this.db!!, maxPlaces, error)
+        }
+        return finished.toInt() != 0
+    }
+
+    override fun getFrecencySettings(): FrecencySettings {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_get_frecency_settings(this.db!!, error)
+        }
+        return FrecencySettings.fromJSON(JSONObject(json))
+    }
+
+    override fun setFrecencySettings(settings: FrecencySettings) {
+        val json = settings.toJSON().toString()
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_set_frecency_settings(this.db!!, json, error)
+        }
+    }
+
+    override fun decayAdaptiveHistory() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_decay_adaptive_history(this.db!!, error)
+        }
+    }
+
+    override fun clearAdaptiveHistoryForUrl(url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_clear_adaptive_history_for_url(this.db!!, url, error)
+        }
+    }
+
+    override fun enableSearchIndex() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_enable_search_index(this.db!!, error)
+        }
+    }
+
+    override fun disableSearchIndex() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_disable_search_index(this.db!!, error)
+        }
+    }
+
+    override fun rebuildSearchIndex() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_rebuild_search_index(this.db!!, error)
+        }
+    }
+
+    override fun registerHistoryObserver(observer: HistoryObserver): Long {
+        val callback = object : RawHistoryObserver {
+            override fun invoke(json: String) {
+                pendingHistoryEvents.get().add(
+                        PendingHistoryEvents(observer, HistoryEvent.fromJSONArray(json)))
+            }
+        }
+        return rustCall { error ->
+            val id = LibPlacesFFI.INSTANCE.places_register_history_observer(
+                    this.db!!, callback, error)
+            if (!error.isFailure()) {
+                historyObservers[id] = callback
+            }
+            id
+        }
+    }
+
+    override fun unregisterHistoryObserver(id: Long): Boolean {
+        val unregistered = rustCall { error ->
+            val result = LibPlacesFFI.INSTANCE.places_unregister_history_observer(
+                    this.db!!, id, error)
+            if (!error.isFailure()) {
+                historyObservers.remove(id)
+            }
+            result
+        }
+        return unregistered.toInt() != 0
+    }
+
     override fun sync(syncInfo: SyncAuthInfo) {
         rustCall { error ->
             LibPlacesFFI.INSTANCE.sync15_history_sync(
@@ -111,14 +328,32 @@ class PlacesConnection(path: String, encryption_key: String? = null) : PlacesAPI
     }
 
     private inline fun <U> rustCall(callback: (RustError.ByReference) -> U): U {
-        synchronized(this) {
-            val e = RustError.ByReference()
-            val ret: U = callback(e)
-            if (e.isFailure()) {
-                throw e.intoException()
-            } else {
-                return ret
+        try {
+            synchronized(this) {
+                val e = RustError.ByReference()
+                val ret: U = callback(e)
+                if (e.isFailure()) {
+                    throw e.intoException()
+                } else {
+                    return ret
+                }
             }
+        } finally {
+            deliverHistoryEvents()
+        }
+    }
+
+    private fun deliverHistoryEvents() {
+        val pending = pendingHistoryEvents.get()
+        if (pending.isEmpty()) {
+            return
+        }
+        // Observers can change history themselves, which queues more events, so we take these
+        // first.
+        val batches = pending.toList()
+        pending.clear()
+        for (batch in batches) {
+            batch.observer.onHistoryChanged(batch.events)
         }
     }
 
@@ -160,9 +395,63 @@ interface PlacesAPI {
      *
      * @param query a string to match results against.
      * @param limit a maximum number of results to retrieve.
+     * @param userContextId only suggest switching to tabs open in this user context. If null,
+     *  tabs in every context are suggested. See [registerOpenPage].
+     * @param ignoreDiacritics whether to ignore accents and other diacritics, so "cafe" matches
+     *  "Café".
      * @return a list of [SearchResult] matching the [query], in arbitrary order.
      */
-    fun queryAutocomplete(query: String, limit: Int): List<SearchResult>
+    fun queryAutocomplete(
+        query: String,
+        limit: Int,
+        userContextId: Int? = null,
+        ignoreDiacritics: Boolean = false
+    ): List<SearchResult>
+
+    /**
+     * Finds the text to autofill in the URL bar as the user types. Origins are autofilled for
+     * queries that look like the start of a host, and URLs are autofilled up to the next slash
+     * for queries with a path. Queries with spaces are never autofilled.
+     *
+     * @param query the text in the URL bar.
+     * @return the [AutofillResult], or null if there's nothing to autofill.
+     */
+    fun autofill(query: String): AutofillResult?
+
+    /**
+     * Notes that a tab was opened with a URL, so [queryAutocomplete] can suggest switching to it.
+     * Call this once for each tab. Open tabs are forgotten when the connection is closed.
+     *
+     * @param url the URL loaded in the tab.
+     * @param userContextId the user context (container) of the tab.
+     * @throws IllegalArgumentException if [userContextId] is negative.
+     */
+    fun registerOpenPage(url: String, userContextId: Int = 0)
+
+    /**
+     * Notes that a tab registered with [registerOpenPage] was closed, or navigated to another URL.
+     *
+     * @param url the URL that was loaded in the tab.
+     * @param userContextId the user context (container) of the tab.
+     * @throws IllegalArgumentException if [userContextId] is negative.
+     */
+    fun unregisterOpenPage(url: String, userContextId: Int = 0)
+
+    /**
+     * Forgets every tab registered with [registerOpenPage], in all user contexts. This is useful
+     * if the app loses track of its tabs, and wants to register them all again.
+     */
+    fun unregisterAllOpenPages()
+
+    /**
+     * Cancels a [queryAutocomplete] call that's running on another thread, which then throws
+     * [OperationInterrupted]. This is useful for canceling stale queries as the user types.
+     * Calling this when no query is running does nothing.
+     *
+     * Note that this cancels whatever is running on the connection, not just queries: a write,
+     * like [noteObservation] or [sync], is also canceled and rolled back.
+     */
+    fun interrupt()
 
     /**
      * Maps a list of page URLs to a list of booleans indicating if each URL was visited.
@@ -182,6 +471,191 @@ interface PlacesAPI {
      */
     fun getVisitedUrlsInRange(start: Long, end: Long = Long.MAX_VALUE, includeRemote: Boolean = true): List<String>
 
+    /**
+     * Returns a page of visits, most recent first, along with the pages they're for. Use
+     * [VisitPage.nextCursor] as the cursor of the next query to get the next page.
+     *
+     * @param query the cursor, limit and filters. See [VisitQuery].
+     */
+    fun getVisitPage(query: VisitQuery): VisitPage
+
+    /**
+     * Returns the chain of visits that led to a visit, for showing how the user got to a page.
+     * Each visit links to the visit of its referrer, or for redirects, the redirect source.
+     *
+     * @param visitId the [VisitInfo.visitId] of the last visit in the chain.
+     * @return the visits in the chain, oldest first, ending with the visit itself.
+     */
+    fun getVisitChain(visitId: Long): List<VisitInfo>
+
+    /**
+     * Returns the most frecent pages the user has visited, for showing on the new tab page.
+     * Hidden pages, pages that redirected, pages that failed to load, and pages removed with
+     * [blockTopSite] are skipped.
+     *
+     * @param limit the most pages to return.
+     * @param dedupeByOrigin whether to return only the most frecent page for each origin.
+     * @return a list of [TopSite], most frecent first.
+     */
+    fun getTopSites(limit: Int, dedupeByOrigin: Boolean = true): List<TopSite>
+
+    /**
+     * Removes a URL from the pages returned by [getTopSites]. The URL stays blocked until it's
+     * unblocked, even if its history is deleted.
+     */
+    fun blockTopSite(url: String)
+
+    /**
+     * Lets a URL removed with [blockTopSite] be returned by [getTopSites] again.
+     */
+    fun unblockTopSite(url: String)
+
+    /**
+     * Unblocks every URL removed with [blockTopSite].
+     */
+    fun unblockAllTopSites()
+
+    /**
+     * Deletes all visits in a given time range. Pages which are left without any visits are
+     * removed, unless they are bookmarked.
+     *
+     * @param start beginning of the range, unix timestamp in milliseconds.
+     * @param end end of the range, inclusive, unix timestamp in milliseconds.
+     */
+    fun deleteVisitsBetween(start: Long, end: Long = Long.MAX_VALUE)
+
+    /**
+     * Deletes a single visit. If it was the page's last visit, the page is removed too,
+     * unless it is bookmarked. Deleting a visit that doesn't exist does nothing.
+     *
+     * @param visitId the [VisitInfo.visitId] of the visit to delete.
+     */
+    fun deleteVisit(visitId: Long)
+
+    /**
+     * Deletes the history for every page on a host, regardless of scheme or port.
+     *
+     * @param host the host to forget, eg "www.example.com".
+     */
+    fun deleteHost(host: String)
+
+    /**
+     * Deletes the history for every page with the same origin (scheme, host and port) as a URL.
+     *
+     * @param url any URL in the origin to forget.
+     */
+    fun deleteOrigin(url: String)
+
+    /**
+     * Deletes all history. If history is synced, it is also deleted from other devices.
+     */
+    fun deleteEverything()
+
+    /**
+     * Removes old history so the database doesn't grow without limit. The least frecent pages
+     * and the oldest visits are removed first. Bookmarked pages are never removed, and expired
+     * history is not removed from other devices. Adaptive history is decayed, as if by
+     * [decayAdaptiveHistory], and the inputs that haven't been used in a long time are removed.
+     *
+     * This does a limited amount of work, so it's suitable for calling while the device is idle.
+     *
+     * @param maxPages the most pages to keep, not counting bookmarked pages.
+     * @param maxVisits the most visits to keep.
+     * @param timeLimitMs roughly how long to spend expiring, in milliseconds.
+     * @return true if expiration finished, false if it ran out of time and should be called again.
+     * @throws IllegalArgumentException if any of the arguments are negative.
+     */
+    fun expireHistory(maxPages: Int = 20000, maxVisits: Int = 100000, timeLimitMs: Int = 100): Boolean
+
+    /**
+     * Recalculates the frecency of pages whose frecency is out of date, either because their
+     * visits were changed by a sync, or because their visits have aged. Until this is called,
+     * those pages may be ranked incorrectly in [queryAutocomplete] results.
+     *
+     * Nothing else recalculates out of date frecencies, so apps need to call this periodically,
+     * like while the device is idle, and after syncing. It does a limited amount of work, so it may
+     * need to be called more than once.
+     *
+     * @param maxPlaces the most pages to update.
+     * @return true if everything is up to date, false if this should be called again.
+     */
+    fun updateStaleFrecencies(maxPlaces: Int = 500): Boolean
+
+    /**
+     * Returns the settings used to rank pages by frecency.
+     */
+    fun getFrecencySettings(): FrecencySettings
+
+    /**
+     * Changes the settings used to rank pages by frecency. If they're different from the current
+     * settings, the frecency of every page is recalculated before this returns, which can take a
+     * while for large histories. Other connections that are already open keep using the old
+     * settings until they're reopened.
+     *
+     * @param settings the new settings.
+     * @throws InvalidFrecencySettings if `numVisits` isn't positive, or the bucket cutoffs aren't
+     *  increasing. The current settings are kept.
+     */
+    fun setFrecencySettings(settings: FrecencySettings)
+
+    /**
+     * Decays the weight of the results the user picked for past [queryAutocomplete] queries, so
+     * that old picks don't outrank recent ones forever. Call this periodically, like once a day
+     * while the device is idle; calling it more often is harmless.
+     */
+    fun decayAdaptiveHistory()
+
+    /**
+     * Forgets which queries the user picked a URL for, so it's no longer ranked higher in
+     * [queryAutocomplete] results for those queries.
+     *
+     * @param url the URL to forget.
+     */
+    fun clearAdaptiveHistoryForUrl(url: String)
+
+    /**
+     * Creates an index of the URL, title and tags of each page, which makes [queryAutocomplete]
+     * faster for big histories. The index is kept up to date as pages change, so this only needs
+     * to be called once. It does nothing if the index already exists.
+     *
+     * Other connections that are already open don't use or update the index until they're
+     * reopened, so this should be called before opening them. Likewise for [disableSearchIndex].
+     *
+     * This fails if the SQLite library wasn't built with FTS5.
+     */
+    fun enableSearchIndex()
+
+    /**
+     * Removes the index created by [enableSearchIndex].
+     */
+    fun disableSearchIndex()
+
+    /**
+     * Rebuilds the index created by [enableSearchIndex] from scratch, in case it's out of date.
+     * This can take a while for big histories.
+     */
+    fun rebuildSearchIndex()
+
+    /**
+     * Registers an observer that's told about changes to history, like visits being added, or
+     * pages being removed by a sync, [deleteEverything] or [expireHistory]. The observer is called
+     * once for each batch of changes, after they're saved.
+     *
+     * The observer is called on the thread that changed history, just before the call that changed
+     * it returns. The connection isn't in use by then, so the observer can use it.
+     *
+     * @return an id to pass to [unregisterHistoryObserver].
+     */
+    fun registerHistoryObserver(observer: HistoryObserver): Long
+
+    /**
+     * Unregisters an observer registered with [registerHistoryObserver].
+     *
+     * @param id the id returned by [registerHistoryObserver].
+     * @return false if there's no observer with this id.
+     */
+    fun unregisterHistoryObserver(id: Long): Boolean
+
     /**
      * Syncs the history store.
      *
@@ -190,15 +664,32 @@ interface PlacesAPI {
      * using a PlacesAPI at a time, it is recommended, but not enforced, that
      * you use a separate PlacesAPI instance purely for syncing.
      *
+     * The frecency of pages whose visits were changed by the sync isn't recalculated until
+     * [updateStaleFrecencies] is called.
+     *
      */
     fun sync(syncInfo: SyncAuthInfo)
 }
 
+/**
+ * Receives changes to history. See [PlacesAPI.registerHistoryObserver].
+ */
+interface HistoryObserver {
+    /**
+     * Called with the changes made by a single transaction, in the order they were made.
+     */
+    fun onHistoryChanged(events: List<HistoryEvent>)
+}
+
+private class PendingHistoryEvents(val observer: HistoryObserver, val events: List<HistoryEvent>)
+
 open class PlacesException(msg: String): Exception(msg)
 open class InternalPanic(msg: String): PlacesException(msg)
 open class UrlParseFailed(msg: String): PlacesException(msg)
 open class InvalidPlaceInfo(msg: String): PlacesException(msg)
 open class PlacesConnectionBusy(msg: String): PlacesException(msg)
+open class OperationInterrupted(msg: String): PlacesException(msg)
+open class InvalidFrecencySettings(msg: String): PlacesException(msg)
 
 @SuppressWarnings("MagicNumber")
 enum class VisitType(val type: Int) {
@@ -220,6 +711,17 @@ enum class VisitType(val type: Int) {
     RELOAD(9)
 }
 
+enum class DocumentType(val type: Int) {
+    REGULAR(0),
+    MEDIA(1);
+
+    companion object {
+        fun fromType(type: Int): DocumentType {
+            return values().firstOrNull { it.type == type } ?: REGULAR
+        }
+    }
+}
+
 /**
  * Encapsulates either information about a visit to a page, or meta information about the page,
  * or both. Use [VisitType.UPDATE_PLACE] to differentiate an update from a visit.
@@ -234,7 +736,12 @@ data class VisitObservation(
     /** Milliseconds */
     val at: Long? = null,
     val referrer: String? = null,
-    val isRemote: Boolean? = null
+    val isRemote: Boolean? = null,
+    val description: String? = null,
+    val previewImageUrl: String? = null,
+    val documentType: DocumentType? = null,
+    /** Milliseconds the user looked at the page, added to its total view time. */
+    val viewTime: Long? = null
 ) {
     fun toJSON(): JSONObject {
         val o = JSONObject()
@@ -250,6 +757,10 @@ data class VisitObservation(
         this.at?.let { o.put("at", it) }
         this.referrer?.let { o.put("referrer", it) }
         this.isRemote?.let { o.put("is_remote", it) }
+        this.description?.let { o.put("description", it) }
+        this.previewImageUrl?.let { o.put("preview_image_url", it) }
+        this.documentType?.let { o.put("document_type", it.type) }
+        this.viewTime?.let { o.put("view_time", it) }
         return o
     }
 }
@@ -291,3 +802,371 @@ data class SearchResult(
         }
     }
 }
+
+data class AutofillResult(
+    val searchString: String,
+    /** The text to show in the URL bar: the query as typed, followed by the autofilled text. */
+    val completion: String,
+    val url: String,
+    /** The start of the autofilled text in [completion], which should be selected. */
+    val selectionStart: Int,
+    /** The end of the autofilled text in [completion]. */
+    val selectionEnd: Int
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): AutofillResult {
+            return AutofillResult(
+                searchString = jsonObject.getString("search_string"),
+                completion = jsonObject.getString("completion"),
+                url = jsonObject.getString("url"),
+                selectionStart = jsonObject.getInt("selection_start"),
+                selectionEnd = jsonObject.getInt("selection_end")
+            )
+        }
+    }
+}
+
+data class TopSite(
+    val url: String,
+    val title: String?,
+    val frecency: Long,
+    val previewImageUrl: String? = null,
+    val description: String? = null,
+    val documentType: DocumentType = DocumentType.REGULAR,
+    /** Milliseconds */
+    val totalViewTime: Long = 0
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): TopSite {
+            fun stringOrNull(key: String): String? {
+                return if (jsonObject.isNull(key)) null else jsonObject.getString(key)
+            }
+
+            return TopSite(
+                url = jsonObject.getString("url"),
+                title = stringOrNull("title"),
+                frecency = jsonObject.getLong("frecency"),
+                previewImageUrl = stringOrNull("preview_image_url"),
+                description = stringOrNull("description"),
+                documentType = DocumentType.fromType(jsonObject.getInt("document_type")),
+                totalViewTime = jsonObject.getLong("total_view_time")
+            )
+        }
+
+        fun fromJSONArray(jsonArrayText: String): List<TopSite> {
+            val result: MutableList<TopSite> = mutableListOf()
+            val array = JSONArray(jsonArrayText)
+            for (index in 0 until array.length()) {
+                result.add(fromJSON(array.getJSONObject(index)))
+            }
+            return result
+        }
+    }
+}
+
+/**
+ * The weights and bonuses used to rank pages by frecency. These mirror desktop's
+ * `places.frecency.*` preferences, and default to the same values.
+ */
+data class FrecencySettings(
+    val numVisits: Int = 10,
+    val firstBucketCutoffDays: Int = 4,
+    val secondBucketCutoffDays: Int = 14,
+    val thirdBucketCutoffDays: Int = 31,
+    val fourthBucketCutoffDays: Int = 90,
+    val firstBucketWeight: Int = 100,
+    val secondBucketWeight: Int = 70,
+    val thirdBucketWeight: Int = 50,
+    val fourthBucketWeight: Int = 30,
+    val defaultBucketWeight: Int = 10,
+    val embedVisitBonus: Int = 0,
+    val framedLinkVisitBonus: Int = 0,
+    val linkVisitBonus: Int = 100,
+    val typedVisitBonus: Int = 2000,
+    val bookmarkVisitBonus: Int = 75,
+    val downloadVisitBonus: Int = 0,
+    val permanentRedirectVisitBonus: Int = 0,
+    val temporaryRedirectVisitBonus: Int = 0,
+    val redirectSourceVisitBonus: Int = 25,
+    val defaultVisitBonus: Int = 0,
+    val unvisitedBookmarkBonus: Int = 140,
+    val unvisitedTypedBonus: Int = 200,
+    val reloadVisitBonus: Int = 0
+) {
+    fun toJSON(): JSONObject {
+        val o = JSONObject()
+        o.put("num_visits", this.numVisits)
+        o.put("first_bucket_cutoff_days", this.firstBucketCutoffDays)
+        o.put("second_bucket_cutoff_days", this.secondBucketCutoffDays)
+        o.put("third_bucket_cutoff_days", this.thirdBucketCutoffDays)
+        o.put("fourth_bucket_cutoff_days", this.fourthBucketCutoffDays)
+        o.put("first_bucket_weight", this.firstBucketWeight)
+        o.put("second_bucket_weight", this.secondBucketWeight)
+        o.put("third_bucket_weight", this.thirdBucketWeight)
+        o.put("fourth_bucket_weight", this.fourthBucketWeight)
+        o.put("default_bucket_weight", this.defaultBucketWeight)
+        o.put("embed_visit_bonus", this.embedVisitBonus)
+        o.put("framed_link_visit_bonus", this.framedLinkVisitBonus)
+        o.put("link_visit_bonus", this.linkVisitBonus)
+        o.put("typed_visit_bonus", this.typedVisitBonus)
+        o.put("bookmark_visit_bonus", this.bookmarkVisitBonus)
+        o.put("download_visit_bonus", this.downloadVisitBonus)
+        o.put("permanent_redirect_visit_bonus", this.permanentRedirectVisitBonus)
+        o.put("temporary_redirect_visit_bonus", this.temporaryRedirectVisitBonus)
+        o.put("redirect_source_visit_bonus", this.redirectSourceVisitBonus)
+        o.put("default_visit_bonus", this.defaultVisitBonus)
+        o.put("unvisited_bookmark_bonus", this.unvisitedBookmarkBonus)
+        o.put("unvisited_typed_bonus", this.unvisitedTypedBonus)
+        o.put("reload_visit_bonus", this.reloadVisitBonus)
+        return o
+    }
+
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): FrecencySettings {
+            return FrecencySettings(
+                numVisits = jsonObject.getInt("num_visits"),
+                firstBucketCutoffDays = jsonObject.getInt("first_bucket_cutoff_days"),
+                secondBucketCutoffDays = jsonObject.getInt("second_bucket_cutoff_days"),
+                thirdBucketCutoffDays = jsonObject.getInt("third_bucket_cutoff_days"),
+                fourthBucketCutoffDays = jsonObject.getInt("fourth_bucket_cutoff_days"),
+                firstBucketWeight = jsonObject.getInt("first_bucket_weight"),
+                secondBucketWeight = jsonObject.getInt("second_bucket_weight"),
+                thirdBucketWeight = jsonObject.getInt("third_bucket_weight"),
+                fourthBucketWeight = jsonObject.getInt("fourth_bucket_weight"),
+                defaultBucketWeight = jsonObject.getInt("default_bucket_weight"),
+                embedVisitBonus = jsonObject.getInt("embed_visit_bonus"),
+                framedLinkVisitBonus = jsonObject.getInt("framed_link_visit_bonus"),
+                linkVisitBonus = jsonObject.getInt("link_visit_bonus"),
+                typedVisitBonus = jsonObject.getInt("typed_visit_bonus"),
+                bookmarkVisitBonus = jsonObject.getInt("bookmark_visit_bonus"),
+                downloadVisitBonus = jsonObject.getInt("download_visit_bonus"),
+                permanentRedirectVisitBonus = jsonObject.getInt("permanent_redirect_visit_bonus"),
+                temporaryRedirectVisitBonus = jsonObject.getInt("temporary_redirect_visit_bonus"),
+                redirectSourceVisitBonus = jsonObject.getInt("redirect_source_visit_bonus"),
+                defaultVisitBonus = jsonObject.getInt("default_visit_bonus"),
+                unvisitedBookmarkBonus = jsonObject.getInt("unvisited_bookmark_bonus"),
+                unvisitedTypedBonus = jsonObject.getInt("unvisited_typed_bonus"),
+                reloadVisitBonus = jsonObject.getInt("reload_visit_bonus")
+            )
+        }
+    }
+}
+
+/**
+ * Where a page of visits returned by [PlacesAPI.getVisitPage] starts.
+ */
+sealed class VisitCursor {
+    /**
+     * Start with the visits listed after a visit, identified by its time in milliseconds and its
+     * [VisitInfo.visitId]. A page never repeats or skips visits from the page before it, even if
+     * history changed in between.
+     */
+    data class Before(val time: Long, val visitId: Long) : VisitCursor()
+
+    /**
+     * Skip a number of visits. If history changed since the page before it, a page can repeat
+     * or skip visits.
+     */
+    data class Offset(val count: Int) : VisitCursor()
+
+    fun toJSON(): JSONObject {
+        val o = JSONObject()
+        when (this) {
+            is Before -> o.put("before", JSONObject()
+                    .put("visit_date", this.time)
+                    .put("visit_id", this.visitId))
+            is Offset -> o.put("offset", this.count)
+        }
+        return o
+    }
+
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): VisitCursor {
+            return if (jsonObject.has("before")) {
+                val before = jsonObject.getJSONObject("before")
+                Before(before.getLong("visit_date"), before.getLong("visit_id"))
+            } else {
+                Offset(jsonObject.getInt("offset"))
+            }
+        }
+    }
+}
+
+/**
+ * Which visits [PlacesAPI.getVisitPage] returns.
+ */
+data class VisitQuery(
+    /** The most visits to return. */
+    val limit: Int,
+    /** Where to start, or null to start with the most recent visit. */
+    val cursor: VisitCursor? = null,
+    /** Only return visits of these types, or visits of every type if null. */
+    val visitTypes: List<VisitType>? = null,
+    /** Only return visits to pages whose URL or title contains this text, ignoring case. */
+    val search: String? = null,
+    /** Only return visits to pages on this host, eg "www.example.com". */
+    val host: String? = null,
+    /** Whether to return visits from other devices. */
+    val includeRemote: Boolean = true,
+    /** Whether to hide visits that redirected, so each redirect chain is returned once. */
+    val collapseRedirects: Boolean = true
+) {
+    fun toJSON(): JSONObject {
+        val o = JSONObject()
+        o.put("limit", this.limit)
+        this.cursor?.let { o.put("cursor", it.toJSON()) }
+        this.visitTypes?.let {
+            val types = JSONArray()
+            for (visitType in it) {
+                types.put(visitType.type)
+            }
+            o.put("visit_types", types)
+        }
+        this.search?.let { o.put("search", it) }
+        this.host?.let { o.put("host", it) }
+        o.put("include_remote", this.includeRemote)
+        o.put("collapse_redirects", this.collapseRedirects)
+        return o
+    }
+}
+
+data class VisitInfo(
+    val visitId: Long,
+    val url: String,
+    val title: String?,
+    /** Milliseconds */
+    val visitTime: Long,
+    val visitType: VisitType,
+    /** Whether the visit happened on another device, and was synced to this one. */
+    val isRemote: Boolean,
+    val previewImageUrl: String? = null,
+    val description: String? = null,
+    val documentType: DocumentType = DocumentType.REGULAR,
+    /** Milliseconds */
+    val totalViewTime: Long = 0
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): VisitInfo {
+            fun stringOrNull(key: String): String? {
+                return if (jsonObject.isNull(key)) null else jsonObject.getString(key)
+            }
+
+            val visitType = jsonObject.getInt("visit_type")
+            return VisitInfo(
+                visitId = jsonObject.getLong("visit_id"),
+                url = jsonObject.getString("url"),
+                title = stringOrNull("title"),
+                visitTime = jsonObject.getLong("visit_date"),
+                visitType = VisitType.values().first { it.type == visitType },
+                isRemote = jsonObject.getBoolean("is_remote"),
+                previewImageUrl = stringOrNull("preview_image_url"),
+                description = stringOrNull("description"),
+                documentType = DocumentType.fromType(jsonObject.getInt("document_type")),
+                totalViewTime = jsonObject.getLong("total_view_time")
+            )
+        }
+
+        fun fromJSONArray(jsonArrayText: String): List<VisitInfo> {
+            val result: MutableList<VisitInfo> = mutableListOf()
+            val array = JSONArray(jsonArrayText)
+            for (index in 0 until array.length()) {
+                result.add(fromJSON(array.getJSONObject(index)))
+            }
+            return result
+        }
+    }
+}
+
+data class VisitPage(
+    val visits: List<VisitInfo>,
+    /** The cursor for the next page, or null if this is the last page. */
+    val nextCursor: VisitCursor?
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): VisitPage {
+            val visits = mutableListOf<VisitInfo>()
+            val array = jsonObject.getJSONArray("visits")
+            for (index in 0 until array.length()) {
+                visits.add(VisitInfo.fromJSON(array.getJSONObject(index)))
+            }
+            val nextCursor = if (jsonObject.isNull("next_cursor")) {
+                null
+            } else {
+                VisitCursor.fromJSON(jsonObject.getJSONObject("next_cursor"))
+            }
+            return VisitPage(visits, nextCursor)
+        }
+    }
+}
+
+/**
+ * A change to history, reported to a [HistoryObserver].
+ */
+sealed class HistoryEvent {
+    /** A page was added, either for a new visit, or because it was synced or imported. */
+    data class PageAdded(val guid: String, val url: String, val title: String?) : HistoryEvent()
+
+    data class VisitAdded(
+        val guid: String,
+        val url: String,
+        /** Milliseconds */
+        val visitTime: Long,
+        val visitType: VisitType,
+        /** Whether the visit happened on another device, and was synced to this one. */
+        val isRemote: Boolean
+    ) : HistoryEvent()
+
+    data class TitleChanged(val guid: String, val url: String, val title: String?) : HistoryEvent()
+
+    /** A page was removed, along with all of its visits. */
+    data class PageRemoved(val guid: String, val url: String) : HistoryEvent()
+
+    /**
+     * All history was deleted with [PlacesAPI.deleteEverything]. This replaces the events for the
+     * pages and visits that were removed.
+     */
+    object HistoryCleared : HistoryEvent()
+
+    data class FrecencyChanged(val guid: String, val url: String, val frecency: Long) : HistoryEvent()
+
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): HistoryEvent {
+            fun stringOrNull(key: String): String? {
+                return if (jsonObject.isNull(key)) null else jsonObject.getString(key)
+            }
+
+            val type = jsonObject.getString("type")
+            if (type == "history_cleared") {
+                return HistoryCleared
+            }
+            val guid = jsonObject.getString("guid")
+            val url = jsonObject.getString("url")
+            return when (type) {
+                "page_added" -> PageAdded(guid, url, stringOrNull("title"))
+                "visit_added" -> {
+                    val visitType = jsonObject.getInt("visit_type")
+                    VisitAdded(
+                        guid = guid,
+                        url = url,
+                        visitTime = jsonObject.getLong("visit_date"),
+                        visitType = VisitType.values().first { it.type == visitType },
+                        isRemote = jsonObject.getBoolean("is_remote")
+                    )
+                }
+                "title_changed" -> TitleChanged(guid, url, stringOrNull("title"))
+                "page_removed" -> PageRemoved(guid, url)
+                "frecency_changed" -> FrecencyChanged(guid, url, jsonObject.getLong("frecency"))
+                else -> throw JSONException("Unknown history event type: $type")
+            }
+        }
+
+        fun fromJSONArray(jsonArrayText: String): List<HistoryEvent> {
+            val result: MutableList<HistoryEvent> = mutableListOf()
+            val array = JSONArray(jsonArrayText)
+            for (index in 0 until array.length()) {
+                result.add(fromJSON(array.getJSONObject(index)))
+            }
+            return result
+        }
+    }
+}
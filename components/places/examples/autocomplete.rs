@@ -117,7 +117,7 @@ impl LegacyPlace {
             }],
         }
     }
-    pub fn insert(self, conn: &rusqlite::Connection, options: &ImportPlacesOptions) -> Result<()> {
+    pub fn insert(self, conn: &places::PlacesDb, options: &ImportPlacesOptions) -> Result<()> {
         let url = Url::parse(&self.url)?;
         for v in self.visits {
             let obs = VisitObservation::new(url.clone())
@@ -195,7 +195,7 @@ fn import_places(
     };
     let mut place_counter = 0;
 
-    let tx = new.db.transaction()?;
+    let tx = new.unchecked_transaction()?;
 
     print!(
         "Processing {} / {} places (approx.)",
@@ -221,12 +221,12 @@ fn import_places(
         );
         let _ = std::io::stdout().flush();
         if current_place.id != -1 {
-            current_place.insert(tx.conn(), &options)?;
+            current_place.insert(new, &options)?;
         }
         current_place = LegacyPlace::from_row(&row);
     }
     if current_place.id != -1 {
-        current_place.insert(tx.conn(), &options)?;
+        current_place.insert(new, &options)?;
     }
     println!("Finished processing records");
     println!("Committing....");
@@ -253,7 +253,7 @@ mod autocomplete {
     use std::thread;
     use std::time::{Duration, Instant};
 
-    use places::api::matcher::{search_frecent, SearchParams, SearchResult};
+    use places::api::matcher::{search_frecent, MatchFolding, SearchParams, SearchResult};
 
     #[derive(Debug, Clone)]
     struct ConnectionArgs {
@@ -543,6 +543,8 @@ mod autocomplete {
                             autocompleter.query(SearchParams {
                                 search_string: query_str.clone(),
                                 limit: 10,
+                                user_context_id: None,
+                                match_folding: MatchFolding::Case,
                             })?;
                         }
                     }
@@ -625,6 +627,8 @@ mod autocomplete {
                         autocompleter.query(SearchParams {
                             search_string: query_str.clone(),
                             limit: 10,
+                            user_context_id: None,
+                            match_folding: MatchFolding::Case,
                         })?;
                     } else {
                         pending_change = true;
@@ -653,6 +657,8 @@ mod autocomplete {
                     autocompleter.query(SearchParams {
                         search_string: query_str.clone(),
                         limit: 10,
+                        user_context_id: None,
+                        match_folding: MatchFolding::Case,
                     })?;
                 }
             }
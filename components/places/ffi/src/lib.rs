@@ -7,10 +7,14 @@ use ffi_support::{
     rust_string_from_c, ExternError,
 };
 use places::history_sync::store::HistoryStore;
-use places::{storage, PlacesDb};
+use places::{storage, HistoryObserver, PlacesDb, PlacesInterruptHandle};
+use std::ffi::CString;
 use std::os::raw::c_char;
 
-use places::api::matcher::{search_frecent, SearchParams};
+use places::api::matcher::{
+    autofill, clear_adaptive_history_for_url, decay_adaptive_history, search_frecent, MatchFolding,
+    SearchParams,
+};
 
 // indirection to help `?` figure out the target error type
 fn parse_url(url: &str) -> sync15::Result<url::Url> {
@@ -64,13 +68,42 @@ pub unsafe extern "C" fn places_note_observation(
     })
 }
 
+/// Get a handle which can be used to interrupt `places_query_autocomplete` calls on `conn` from
+/// another thread. Returned handle must be freed with `places_interrupt_handle_destroy`.
+#[no_mangle]
+pub extern "C" fn places_new_interrupt_handle(
+    conn: &PlacesDb,
+    error: &mut ExternError,
+) -> *mut PlacesInterruptHandle {
+    log::trace!("places_new_interrupt_handle");
+    call_with_result(error, || -> places::Result<_> {
+        Ok(conn.new_interrupt_handle())
+    })
+}
+
+/// Interrupt the call that's running on the handle's connection, if any. The interrupted call
+/// fails with the `DATABASE_INTERRUPTED` error code. This isn't limited to queries: writes are
+/// interrupted and rolled back, too. Safe to call from any thread.
+#[no_mangle]
+pub extern "C" fn places_interrupt(handle: &PlacesInterruptHandle, error: &mut ExternError) {
+    log::trace!("places_interrupt");
+    call_with_result(error, || -> places::Result<_> {
+        handle.interrupt();
+        Ok(())
+    })
+}
+
 /// Execute a query, returning a `Vec<SearchResult>` as a JSON string. Returned string must be freed
-/// using `places_destroy_string`. Returns null and logs on errors (for now).
+/// using `places_destroy_string`. Returns null and logs on errors (for now). The query can be
+/// canceled using `places_interrupt`. Only tabs open in `user_context_id` are suggested, or tabs
+/// in any context if it's negative. If `ignore_diacritics` is nonzero, "cafe" matches "Café".
 #[no_mangle]
 pub unsafe extern "C" fn places_query_autocomplete(
     conn: &PlacesDb,
     search: *const c_char,
     limit: u32,
+    user_context_id: i32,
+    ignore_diacritics: u8,
     error: &mut ExternError,
 ) -> *mut c_char {
     log::trace!("places_query_autocomplete");
@@ -80,11 +113,83 @@ pub unsafe extern "C" fn places_query_autocomplete(
             SearchParams {
                 search_string: ffi_support::rust_string_from_c(search),
                 limit,
+                user_context_id: if user_context_id < 0 {
+                    None
+                } else {
+                    Some(user_context_id as u32)
+                },
+                match_folding: if ignore_diacritics != 0 {
+                    MatchFolding::CaseAndDiacritics
+                } else {
+                    MatchFolding::Case
+                },
             },
         )
     })
 }
 
+/// Find the text to autofill in the URL bar for `search`, returning an `Option<AutofillResult>`
+/// as a JSON string, which is `null` if there's nothing to autofill. Returned string must be
+/// freed using `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_autofill(
+    conn: &PlacesDb,
+    search: *const c_char,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::trace!("places_autofill");
+    call_with_result(error, || -> places::Result<String> {
+        let result = autofill(conn, rust_str_from_c(search))?;
+        Ok(serde_json::to_string(&result)?)
+    })
+}
+
+/// Note that a tab was opened with `url` in the given user context, so autocomplete can suggest
+/// switching to it.
+#[no_mangle]
+pub unsafe extern "C" fn places_register_open_page(
+    conn: &PlacesDb,
+    url: *const c_char,
+    user_context_id: u32,
+    error: &mut ExternError,
+) {
+    log::trace!("places_register_open_page");
+    call_with_result(error, || -> places::Result<()> {
+        storage::open_tabs::register_open_page(
+            conn,
+            &parse_url(rust_str_from_c(url))?,
+            user_context_id,
+        )
+    })
+}
+
+/// Note that a tab with `url` in the given user context was closed or navigated away.
+#[no_mangle]
+pub unsafe extern "C" fn places_unregister_open_page(
+    conn: &PlacesDb,
+    url: *const c_char,
+    user_context_id: u32,
+    error: &mut ExternError,
+) {
+    log::trace!("places_unregister_open_page");
+    call_with_result(error, || -> places::Result<()> {
+        storage::open_tabs::unregister_open_page(
+            conn,
+            &parse_url(rust_str_from_c(url))?,
+            user_context_id,
+        )
+    })
+}
+
+/// Forget every open tab, in all user contexts.
+#[no_mangle]
+pub extern "C" fn places_unregister_all_open_pages(conn: &PlacesDb, error: &mut ExternError) {
+    log::trace!("places_unregister_all_open_pages");
+    call_with_result(error, || {
+        storage::open_tabs::unregister_all_open_pages(conn)
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn places_get_visited(
     conn: &PlacesDb,
@@ -128,6 +233,293 @@ pub extern "C" fn places_get_visited_urls_in_range(
     })
 }
 
+/// Returns a page of visits, most recent first, as a JSON string. `query_json` is a JSON
+/// `VisitQuery`, with the cursor, limit and filters. Returned string must be freed using
+/// `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_visit_page(
+    conn: &PlacesDb,
+    query_json: *const c_char,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::trace!("places_get_visit_page");
+    call_with_result(error, || -> places::Result<String> {
+        let query: storage::visits::VisitQuery = serde_json::from_str(rust_str_from_c(query_json))?;
+        let page = storage::visits::get_visit_page(conn, &query)?;
+        Ok(serde_json::to_string(&page)?)
+    })
+}
+
+/// Returns the chain of visits that led to the visit with `visit_id`, oldest first, as a JSON
+/// array. Returned string must be freed using `places_destroy_string`.
+#[no_mangle]
+pub extern "C" fn places_get_visit_chain(
+    conn: &PlacesDb,
+    visit_id: i64,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::trace!("places_get_visit_chain");
+    call_with_result(error, || -> places::Result<String> {
+        let chain = storage::visits::get_visit_chain(conn, places::RowId(visit_id))?;
+        Ok(serde_json::to_string(&chain)?)
+    })
+}
+
+/// Returns the most frecent pages for the new tab page, as a JSON array. If `dedupe_by_origin`
+/// is nonzero, only the most frecent page for each origin is returned. Returned string must be
+/// freed using `places_destroy_string`.
+#[no_mangle]
+pub extern "C" fn places_get_top_sites(
+    conn: &PlacesDb,
+    limit: u32,
+    dedupe_by_origin: u8,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::trace!("places_get_top_sites");
+    call_with_result(error, || -> places::Result<String> {
+        let top_sites = storage::top_sites::get_top_sites(conn, limit, dedupe_by_origin != 0)?;
+        Ok(serde_json::to_string(&top_sites)?)
+    })
+}
+
+/// Removes `url` from the top sites, until it's unblocked.
+#[no_mangle]
+pub unsafe extern "C" fn places_block_top_site(
+    conn: &PlacesDb,
+    url: *const c_char,
+    error: &mut ExternError,
+) {
+    log::trace!("places_block_top_site");
+    call_with_result(error, || -> places::Result<()> {
+        storage::top_sites::block_top_site(conn, &parse_url(rust_str_from_c(url))?)
+    })
+}
+
+/// Lets a URL blocked with `places_block_top_site` show up in the top sites again.
+#[no_mangle]
+pub unsafe extern "C" fn places_unblock_top_site(
+    conn: &PlacesDb,
+    url: *const c_char,
+    error: &mut ExternError,
+) {
+    log::trace!("places_unblock_top_site");
+    call_with_result(error, || -> places::Result<()> {
+        storage::top_sites::unblock_top_site(conn, &parse_url(rust_str_from_c(url))?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_unblock_all_top_sites(conn: &PlacesDb, error: &mut ExternError) {
+    log::trace!("places_unblock_all_top_sites");
+    call_with_result(error, || storage::top_sites::unblock_all_top_sites(conn))
+}
+
+#[no_mangle]
+pub extern "C" fn places_delete_visits_between(
+    conn: &PlacesDb,
+    start: i64,
+    end: i64,
+    error: &mut ExternError,
+) {
+    log::trace!("places_delete_visits_between");
+    call_with_result(error, || {
+        storage::delete_visits_between(
+            conn,
+            places::Timestamp(start.max(0) as u64),
+            places::Timestamp(end.max(0) as u64),
+        )
+    })
+}
+
+/// Deletes a single visit, like one from `places_get_visit_page`.
+#[no_mangle]
+pub extern "C" fn places_delete_visit(conn: &PlacesDb, visit_id: i64, error: &mut ExternError) {
+    log::trace!("places_delete_visit");
+    call_with_result(error, || {
+        storage::delete_visit(conn, places::RowId(visit_id))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn places_delete_host(
+    conn: &PlacesDb,
+    host: *const c_char,
+    error: &mut ExternError,
+) {
+    log::trace!("places_delete_host");
+    call_with_result(error, || storage::delete_host(conn, rust_str_from_c(host)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn places_delete_origin(
+    conn: &PlacesDb,
+    url: *const c_char,
+    error: &mut ExternError,
+) {
+    log::trace!("places_delete_origin");
+    call_with_result(error, || -> places::Result<()> {
+        storage::delete_origin(conn, &parse_url(rust_str_from_c(url))?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_delete_everything(conn: &PlacesDb, error: &mut ExternError) {
+    log::trace!("places_delete_everything");
+    call_with_result(error, || storage::delete_everything(conn))
+}
+
+/// Expires old history until there are at most `max_pages` pages and
+/// `max_visits` visits, or until `time_limit_ms` has passed. Returns 1 if
+/// expiration finished, or 0 if it ran out of time and should be called
+/// again later.
+#[no_mangle]
+pub extern "C" fn places_expire_history(
+    conn: &PlacesDb,
+    max_pages: u32,
+    max_visits: u32,
+    time_limit_ms: u32,
+    error: &mut ExternError,
+) -> u8 {
+    log::trace!("places_expire_history");
+    call_with_result(error, || -> places::Result<u8> {
+        let settings = storage::expiration::ExpirationSettings {
+            max_pages,
+            max_visits,
+            ..Default::default()
+        };
+        let stats = storage::expiration::expire_history(
+            conn,
+            &settings,
+            std::time::Duration::from_millis(time_limit_ms.into()),
+        )?;
+        Ok(stats.finished as u8)
+    })
+}
+
+/// Recalculates up to `max_places` stale frecencies. Returns 1 if there are
+/// none left, or 0 if it should be called again.
+#[no_mangle]
+pub extern "C" fn places_update_stale_frecencies(
+    conn: &PlacesDb,
+    max_places: u32,
+    error: &mut ExternError,
+) -> u8 {
+    log::trace!("places_update_stale_frecencies");
+    call_with_result(error, || -> places::Result<u8> {
+        Ok(storage::update_stale_frecencies(conn, max_places)? as u8)
+    })
+}
+
+/// Returns the `FrecencySettings` used to calculate frecencies, as a JSON string. Returned string
+/// must be freed using `places_destroy_string`.
+#[no_mangle]
+pub extern "C" fn places_get_frecency_settings(
+    conn: &PlacesDb,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::trace!("places_get_frecency_settings");
+    call_with_result(error, || -> places::Result<String> {
+        Ok(serde_json::to_string(&storage::get_frecency_settings(
+            conn,
+        )?)?)
+    })
+}
+
+/// Changes the settings used to calculate frecencies to `frecency_settings`, a JSON
+/// `FrecencySettings` object. Fails with `INVALID_FRECENCY_SETTINGS` if they're invalid. If they
+/// changed, every frecency is recalculated before this returns.
+#[no_mangle]
+pub unsafe extern "C" fn places_set_frecency_settings(
+    conn: &PlacesDb,
+    frecency_settings: *const c_char,
+    error: &mut ExternError,
+) {
+    log::trace!("places_set_frecency_settings");
+    call_with_result(error, || -> places::Result<()> {
+        let json = ffi_support::rust_str_from_c(frecency_settings);
+        storage::set_frecency_settings(conn, &serde_json::from_str(json)?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_decay_adaptive_history(conn: &PlacesDb, error: &mut ExternError) {
+    log::trace!("places_decay_adaptive_history");
+    call_with_result(error, || decay_adaptive_history(conn))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn places_clear_adaptive_history_for_url(
+    conn: &PlacesDb,
+    url: *const c_char,
+    error: &mut ExternError,
+) {
+    log::trace!("places_clear_adaptive_history_for_url");
+    call_with_result(error, || -> places::Result<()> {
+        clear_adaptive_history_for_url(conn, &parse_url(rust_str_from_c(url))?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_enable_search_index(conn: &PlacesDb, error: &mut ExternError) {
+    log::trace!("places_enable_search_index");
+    call_with_result(error, || storage::search_index::enable_search_index(conn))
+}
+
+#[no_mangle]
+pub extern "C" fn places_disable_search_index(conn: &PlacesDb, error: &mut ExternError) {
+    log::trace!("places_disable_search_index");
+    call_with_result(error, || storage::search_index::disable_search_index(conn))
+}
+
+#[no_mangle]
+pub extern "C" fn places_rebuild_search_index(conn: &PlacesDb, error: &mut ExternError) {
+    log::trace!("places_rebuild_search_index");
+    call_with_result(error, || storage::search_index::rebuild_search_index(conn))
+}
+
+/// Registers a callback that's called with the changes to history made by each transaction, as a
+/// JSON array of `HistoryEvent`s. Returns an id for `places_unregister_history_observer`.
+///
+/// The callback is called on the thread that changed history, while it's still using `conn`, so
+/// it must not use `conn`. Callers that need to should queue the events, and handle them once the
+/// call that changed history returns.
+#[no_mangle]
+pub extern "C" fn places_register_history_observer(
+    conn: &mut PlacesDb,
+    callback: extern "C" fn(json: *const c_char),
+    error: &mut ExternError,
+) -> u64 {
+    log::trace!("places_register_history_observer");
+    call_with_result(error, || -> places::Result<u64> {
+        conn.register_history_observer(HistoryObserver::new(move |events| {
+            let json = match serde_json::to_string(events) {
+                Ok(json) => json,
+                Err(e) => {
+                    log::error!("Failed to serialize history events: {}", e);
+                    return;
+                }
+            };
+            // It's impossible for JSON to have embedded null bytes.
+            let s = CString::new(json).unwrap();
+            callback(s.as_ptr());
+        }))
+    })
+}
+
+/// Unregisters a callback registered with `places_register_history_observer`. Returns 0 if there's
+/// no callback with this id.
+#[no_mangle]
+pub extern "C" fn places_unregister_history_observer(
+    conn: &mut PlacesDb,
+    id: u64,
+    error: &mut ExternError,
+) -> u8 {
+    log::trace!("places_unregister_history_observer");
+    call_with_result(error, || -> places::Result<u8> {
+        Ok(conn.unregister_history_observer(id)? as u8)
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sync15_history_sync(
     conn: &PlacesDb,
@@ -155,3 +547,4 @@ pub unsafe extern "C" fn sync15_history_sync(
 
 define_string_destructor!(places_destroy_string);
 define_box_destructor!(PlacesDb, places_connection_destroy);
+define_box_destructor!(PlacesInterruptHandle, places_interrupt_handle_destroy);
@@ -0,0 +1,13 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// This module can become, roughly: PlacesUtils.bookmarks
+
+pub use crate::storage::bookmarks::{
+    delete_bookmark, fetch_bookmark, fetch_tree, insert_bookmark, reorder_bookmarks,
+    update_bookmark, BookmarkNode, BookmarkPosition, BookmarkRootGuid, BookmarkUpdateInfo,
+    InsertableBookmark, InsertableFolder, InsertableItem, InsertableSeparator,
+    USER_CONTENT_ROOTS,
+};
+pub use crate::types::BookmarkType;
@@ -6,13 +6,102 @@ use super::apply_observation;
 use crate::db::PlacesDb;
 use crate::error::*;
 use crate::observation::VisitObservation;
+use crate::storage::{get_meta, put_meta};
 use crate::types::*;
+use bitflags::bitflags;
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::HashMap;
 use url::Url;
 
 // This module can become, roughly: PlacesUtils.history()
 
-pub fn can_add_url(_url: &Url) -> Result<bool> {
-    Ok(true)
+/// The longest URL we store, in bytes. This is the same as desktop's
+/// default for `browser.history.maxUrlLength`.
+pub const DEFAULT_MAX_URL_LENGTH: usize = 65536;
+
+/// The schemes we never store in history. These are the same as desktop's
+/// nsNavHistory::CanAddURI.
+pub const DEFAULT_BLOCKED_SCHEMES: &[&str] = &[
+    "about",
+    "blob",
+    "chrome",
+    "data",
+    "imap",
+    "javascript",
+    "mailbox",
+    "moz-anno",
+    "moz-extension",
+    "news",
+    "resource",
+    "view-source",
+    "wyciwyg",
+];
+
+// The moz_meta key we store the filter under, if it's been changed.
+const URL_FILTER_META_KEY: &str = "history_url_filter";
+
+/// Decides which URLs can be stored in, and synced from, history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UrlFilter {
+    pub blocked_schemes: Vec<String>,
+    pub max_url_length: usize,
+}
+
+impl Default for UrlFilter {
+    fn default() -> Self {
+        UrlFilter {
+            blocked_schemes: DEFAULT_BLOCKED_SCHEMES
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            max_url_length: DEFAULT_MAX_URL_LENGTH,
+        }
+    }
+}
+
+impl UrlFilter {
+    pub fn can_add_url(&self, url: &Url) -> bool {
+        if url.as_str().len() > self.max_url_length {
+            return false;
+        }
+        match url.scheme() {
+            // The common cases, which we never block.
+            "http" | "https" => true,
+            scheme => !self.blocked_schemes.iter().any(|s| s == scheme),
+        }
+    }
+}
+
+/// Returns the current URL filter.
+pub fn get_url_filter(db: &impl ConnExt) -> Result<UrlFilter> {
+    Ok(match get_meta::<String>(db, URL_FILTER_META_KEY)? {
+        Some(json) => serde_json::from_str(&json)?,
+        None => UrlFilter::default(),
+    })
+}
+
+/// Changes the URL filter. This doesn't remove anything which is already in
+/// history, but future visits, and incoming and outgoing synced records, are
+/// checked against it. Connections cache the filter, so other connections
+/// which are already open keep using the old filter until they're reopened.
+pub fn set_url_filter(db: &PlacesDb, filter: &UrlFilter) -> Result<()> {
+    put_meta(db, URL_FILTER_META_KEY, &serde_json::to_string(filter)?)?;
+    db.url_filter.replace(Some(filter.clone()));
+    db.notify_history_observers();
+    Ok(())
+}
+
+/// Returns true if `url` can be stored in history. The filter is cached on
+/// the connection, so this is cheap enough to call for every visit.
+pub fn can_add_url(db: &PlacesDb, url: &Url) -> Result<bool> {
+    if let Some(filter) = &*db.url_filter.borrow() {
+        return Ok(filter.can_add_url(url));
+    }
+    let filter = get_url_filter(db)?;
+    let can_add = filter.can_add_url(url);
+    db.url_filter.replace(Some(filter));
+    Ok(can_add)
 }
 
 // eg: PlacesUtils.history.insert({url: "http", title: ..., visits: [{date: ...}]})
@@ -46,10 +135,8 @@ pub fn insert(conn: &mut PlacesDb, place: AddablePlaceInfo) -> Result<()> {
             .with_visit_type(v.transition)
             .with_at(v.date)
             .with_title(place.title.clone())
-            .with_is_remote(!v.is_local);
-        // .with_referrer(...) ????
-
-        //if place.referrer
+            .with_is_remote(!v.is_local)
+            .with_referrer(v.referrer);
         apply_observation(conn, obs)?;
     }
     Ok(())
@@ -100,31 +187,313 @@ mod tests {
         assert_ne!(row.get::<_, i32>("frecency"), 0);
         // XXX - check more.
     }
+
+    #[test]
+    fn test_url_filter() -> Result<()> {
+        let filter = UrlFilter::default();
+        for url in &[
+            "http://example.com/",
+            "https://example.com/",
+            "ftp://example.com/",
+            "file:///etc/passwd",
+        ] {
+            assert!(
+                filter.can_add_url(&Url::parse(url)?),
+                "{} should be allowed",
+                url
+            );
+        }
+        for url in &[
+            "about:config",
+            "data:text/plain,hi",
+            "javascript:alert('hi')",
+            "blob:https://example.com/1234",
+            "view-source:https://example.com/",
+            "moz-extension://1234/page.html",
+        ] {
+            assert!(
+                !filter.can_add_url(&Url::parse(url)?),
+                "{} should be blocked",
+                url
+            );
+        }
+        let long = Url::parse(&format!(
+            "http://example.com/{}",
+            "x".repeat(DEFAULT_MAX_URL_LENGTH)
+        ))?;
+        assert!(!filter.can_add_url(&long));
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_url_filter() -> Result<()> {
+        let mut c = PlacesDb::open_in_memory(None)?;
+        assert_eq!(get_url_filter(&c)?, UrlFilter::default());
+        let filter = UrlFilter {
+            blocked_schemes: vec!["ftp".to_string()],
+            max_url_length: 100,
+        };
+        set_url_filter(&c, &filter)?;
+        assert_eq!(get_url_filter(&c)?, filter);
+
+        // Visits to blocked urls are ignored.
+        let blocked = Url::parse("ftp://example.com/")?;
+        apply_observation(
+            &mut c,
+            VisitObservation::new(blocked).with_visit_type(VisitTransition::Link),
+        )?;
+        let allowed = Url::parse("about:blank")?;
+        apply_observation(
+            &mut c,
+            VisitObservation::new(allowed).with_visit_type(VisitTransition::Link),
+        )?;
+        let urls = c
+            .prepare("SELECT url FROM moz_places")?
+            .query_map(&[], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        assert_eq!(urls, vec!["about:blank"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recently_visited() {
+        let mut recent = RecentEvents::default();
+        let url = Url::parse("http://example.com").expect("it's a valid url");
+        let now = Timestamp(RECENTLY_VISITED_MAX_AGE_MS * 10);
+        assert!(!recent.is_recently_visited(&url, now));
+        recent.add_recently_visited(&url, now);
+        assert!(recent.is_recently_visited(&url, now));
+        assert!(
+            recent.is_recently_visited(&url, Timestamp(now.0 + RECENTLY_VISITED_MAX_AGE_MS - 1))
+        );
+        assert!(!recent.is_recently_visited(&url, Timestamp(now.0 + RECENTLY_VISITED_MAX_AGE_MS)));
+
+        // The cache is bounded, and the oldest urls are forgotten first.
+        for i in 0..RECENTLY_VISITED_MAX_SIZE {
+            let other = Url::parse(&format!("http://example.com/{}", i)).unwrap();
+            recent.add_recently_visited(&other, Timestamp(now.0 + 1 + i as u64));
+        }
+        assert_eq!(recent.visited.len(), RECENTLY_VISITED_MAX_SIZE);
+        assert!(!recent.is_recently_visited(&url, now));
+    }
+
+    #[test]
+    fn test_recent_flags() {
+        let mut recent = RecentEvents::default();
+        let url = Url::parse("http://example.com").expect("it's a valid url");
+        let now = Timestamp(RECENT_EVENT_THRESHOLD_MS * 10);
+        assert_eq!(
+            recent.get_recent_flags(&url, now),
+            RecentEventFlags::empty()
+        );
+        recent.add_recent_event(&url, RecentEventFlags::TYPED, now);
+        recent.add_recent_event(&url, RecentEventFlags::BOOKMARKED, now);
+        let flags = recent.get_recent_flags(&url, now);
+        assert_eq!(
+            flags,
+            RecentEventFlags::TYPED | RecentEventFlags::BOOKMARKED
+        );
+        assert_eq!(
+            adjust_transition(VisitTransition::Link, flags),
+            VisitTransition::Typed
+        );
+        assert_eq!(
+            adjust_transition(VisitTransition::Link, RecentEventFlags::BOOKMARKED),
+            VisitTransition::Bookmark
+        );
+        assert_eq!(
+            adjust_transition(VisitTransition::Embed, RecentEventFlags::ACTIVATED),
+            VisitTransition::FramedLink
+        );
+        assert_eq!(
+            adjust_transition(VisitTransition::RedirectTemporary, flags),
+            VisitTransition::RedirectTemporary
+        );
+        let later = Timestamp(now.0 + RECENT_EVENT_THRESHOLD_MS);
+        assert_eq!(
+            recent.get_recent_flags(&url, later),
+            RecentEventFlags::empty()
+        );
+    }
+
+    fn count_visits(conn: &PlacesDb) -> u32 {
+        conn.query_row("SELECT COUNT(*) FROM moz_historyvisits", &[], |row| {
+            row.get(0)
+        })
+        .expect("should be able to count")
+    }
+
+    #[test]
+    fn test_visit_uri() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/")?;
+        visit_uri(&mut conn, &url, None, VisitTransition::Link, None, false)?;
+        assert_eq!(count_visits(&conn), 1);
+
+        // Reloading, or refreshing, shouldn't add more visits.
+        visit_uri(&mut conn, &url, None, VisitTransition::Reload, None, false)?;
+        visit_uri(
+            &mut conn,
+            &url,
+            Some(url.clone()),
+            VisitTransition::Link,
+            None,
+            false,
+        )?;
+        assert_eq!(count_visits(&conn), 1);
+
+        // But a new visit from elsewhere should.
+        let other = Url::parse("http://example.com/other")?;
+        visit_uri(
+            &mut conn,
+            &url,
+            Some(other.clone()),
+            VisitTransition::Link,
+            None,
+            false,
+        )?;
+        assert_eq!(count_visits(&conn), 2);
+
+        // Typed pages get typed visits.
+        mark_page_as_typed(&mut conn, &other);
+        visit_uri(&mut conn, &other, None, VisitTransition::Link, None, false)?;
+        let visit_type: u8 = conn.query_row(
+            "SELECT v.visit_type FROM moz_historyvisits v
+             JOIN moz_places h ON h.id = v.place_id
+             WHERE h.url = 'http://example.com/other'",
+            &[],
+            |row| row.get(0),
+        )?;
+        assert_eq!(visit_type, VisitTransition::Typed as u8);
+        Ok(())
+    }
 }
 
 /////////////////////////////////////////////
 // Stuff to reimplement nsHistory::VisitUri()
-fn is_recently_visited(_url: &Url) -> Result<bool> {
-    // History.cpp keeps an in-memory hashtable of urls visited in the last
-    // 6 minutes to avoid pages which self-refresh from getting many entries.
-    // ie, there's no DB query done here.
-    // TODO: implement this.
-    Ok(false)
+
+// History.cpp keeps an in-memory hashtable of urls visited in the last
+// 6 minutes to avoid pages which self-refresh from getting many entries.
+// ie, there's no DB query done here. These values are from History.cpp.
+const RECENTLY_VISITED_MAX_AGE_MS: u64 = 6 * 60 * 1000;
+const RECENTLY_VISITED_MAX_SIZE: usize = 64;
+
+// The other "recent" flags are just a 15-second in-memory cache, and all of
+// them rely on explicit calls to set the flag. eg:
+// nsNavHistory::MarkPageAsTyped(nsIURI *aURI) just adds to the cache.
+const RECENT_EVENT_THRESHOLD_MS: u64 = 15 * 1000;
+const RECENT_EVENTS_MAX_SIZE: usize = 128;
+
+bitflags! {
+    pub struct RecentEventFlags: u8 {
+        /// User typed in URL recently.
+        const TYPED = 1 << 0;
+        /// User tapped URL link recently.
+        const ACTIVATED = 1 << 1;
+        /// User bookmarked URL recently.
+        const BOOKMARKED = 1 << 2;
+    }
 }
 
-fn add_recently_visited(_url: &Url) -> Result<()> {
-    Ok(())
+// The in-memory state used by `visit_uri`. Each connection has its own.
+#[derive(Debug, Default)]
+pub struct RecentEvents {
+    visited: HashMap<Url, Timestamp>,
+    events: HashMap<(RecentEventFlags, Url), Timestamp>,
 }
 
-// Other "recent" flags:
-// enum RecentEventFlags {
-//    RECENT_TYPED      = 1 << 0,    // User typed in URL recently
-//    RECENT_ACTIVATED  = 1 << 1,    // User tapped URL link recently
-//    RECENT_BOOKMARKED = 1 << 2     // User bookmarked URL recently
-//  };
-// All of which are just a 15-second in-memory cache, and all of which appear
-// to rely on explicit calls to set the flag. eg:
-// nsNavHistory::MarkPageAsTyped(nsIURI *aURI) just adds to the cache.
+fn is_recent(then: Timestamp, now: Timestamp, max_age_ms: u64) -> bool {
+    now.0.saturating_sub(then.0) < max_age_ms
+}
+
+impl RecentEvents {
+    pub fn is_recently_visited(&self, url: &Url, now: Timestamp) -> bool {
+        self.visited.get(url).map_or(false, |&then| {
+            is_recent(then, now, RECENTLY_VISITED_MAX_AGE_MS)
+        })
+    }
+
+    pub fn add_recently_visited(&mut self, url: &Url, now: Timestamp) {
+        self.visited.insert(url.clone(), now);
+        if self.visited.len() > RECENTLY_VISITED_MAX_SIZE {
+            self.visited
+                .retain(|_, &mut then| is_recent(then, now, RECENTLY_VISITED_MAX_AGE_MS));
+        }
+        // If everything is recent, forget the oldest.
+        while self.visited.len() > RECENTLY_VISITED_MAX_SIZE {
+            let oldest = self
+                .visited
+                .iter()
+                .min_by_key(|(_, &then)| then)
+                .map(|(url, _)| url.clone())
+                .expect("can't be empty");
+            self.visited.remove(&oldest);
+        }
+    }
+
+    pub fn add_recent_event(&mut self, url: &Url, flag: RecentEventFlags, now: Timestamp) {
+        self.events.insert((flag, url.clone()), now);
+        if self.events.len() > RECENT_EVENTS_MAX_SIZE {
+            self.events
+                .retain(|_, &mut then| is_recent(then, now, RECENT_EVENT_THRESHOLD_MS));
+        }
+    }
+
+    pub fn get_recent_flags(&self, url: &Url, now: Timestamp) -> RecentEventFlags {
+        let mut flags = RecentEventFlags::empty();
+        for &flag in &[
+            RecentEventFlags::TYPED,
+            RecentEventFlags::ACTIVATED,
+            RecentEventFlags::BOOKMARKED,
+        ] {
+            if let Some(&then) = self.events.get(&(flag, url.clone())) {
+                if is_recent(then, now, RECENT_EVENT_THRESHOLD_MS) {
+                    flags |= flag;
+                }
+            }
+        }
+        flags
+    }
+}
+
+/// Notes that the user typed `url`, so that a visit to it in the next 15
+/// seconds is recorded as typed. The equivalent of
+/// nsNavHistory::MarkPageAsTyped.
+pub fn mark_page_as_typed(conn: &mut PlacesDb, url: &Url) {
+    conn.recent_events
+        .add_recent_event(url, RecentEventFlags::TYPED, Timestamp::now());
+}
+
+/// Notes that the user followed a link to `url`, so that a visit to it in
+/// a frame in the next 15 seconds is recorded as a framed link rather than
+/// an embed.
+pub fn mark_page_as_followed_link(conn: &mut PlacesDb, url: &Url) {
+    conn.recent_events
+        .add_recent_event(url, RecentEventFlags::ACTIVATED, Timestamp::now());
+}
+
+/// Notes that the user bookmarked `url`, so that a visit to it in the next
+/// 15 seconds is recorded as a bookmark visit.
+pub fn mark_page_as_bookmarked(conn: &mut PlacesDb, url: &Url) {
+    conn.recent_events
+        .add_recent_event(url, RecentEventFlags::BOOKMARKED, Timestamp::now());
+}
+
+// Picks the transition for a visit, using the recent flags in the same way
+// History::VisitURI does. Callers pass in the transition they think is
+// right, but they don't know about recent events.
+fn adjust_transition(transition: VisitTransition, flags: RecentEventFlags) -> VisitTransition {
+    match transition {
+        VisitTransition::Link if flags.contains(RecentEventFlags::TYPED) => VisitTransition::Typed,
+        VisitTransition::Link if flags.contains(RecentEventFlags::BOOKMARKED) => {
+            VisitTransition::Bookmark
+        }
+        VisitTransition::Embed if flags.contains(RecentEventFlags::ACTIVATED) => {
+            VisitTransition::FramedLink
+        }
+        _ => transition,
+    }
+}
 
 // Is this URL the *source* is a redirect? Note that this is different than
 // the redirect flags in the TransitionType, as that is the flag for the
@@ -153,30 +522,27 @@ pub fn visit_uri(
     is_error_page: bool,
 ) -> Result<()> {
     // Silently return if URI is something we shouldn't add to DB.
-    if !can_add_url(&url)? {
+    if !can_add_url(conn, url)? {
         return Ok(());
     };
+    let now = Timestamp::now();
     // Do not save a reloaded uri if we have visited the same URI recently.
-    // (Note that desktop implies `reload` based of the "is it the same as last
-    // and is it recent" check below) - but here we are asking for the
-    // VisitTransition to be passed in, which explicity has a value for reload.
-    // Note clear if we should try and unify these.
-    // (and note that if we can, we can drop the recently_visited cache)
-    if let Some(ref last) = last_url {
-        if url == last && is_recently_visited(url)? {
-            // it's a reload we don't want to record, although we do want to
-            // update it as being recent.
-            add_recently_visited(url)?;
-            return Ok(());
-        };
+    // Desktop implies `reload` based of the "is it the same as last and is it
+    // recent" check below, but here the caller can also tell us explicitly
+    // with the VisitTransition, so we treat both the same.
+    let is_reload =
+        transition == VisitTransition::Reload || last_url.as_ref().map_or(false, |l| l == url);
+    if is_reload && conn.recent_events.is_recently_visited(url, now) {
+        // it's a reload we don't want to record, although we do want to
+        // update it as being recent.
+        conn.recent_events.add_recently_visited(url, now);
+        return Ok(());
     }
     // So add it.
 
-    // XXX - translate the flags passed to this function, along with the
-    // RECENT_* cache above to create the correct Transition type.
-    // call get_hidden_state to see if .hidden should be set.
+    let transition = adjust_transition(transition, conn.recent_events.get_recent_flags(url, now));
 
-    // get_hidden_state...
+    // XXX - call get_hidden_state to see if .hidden should be set.
 
     // EMBED visits are session-persistent and should not go through the database.
     // They exist only to keep track of isVisited status during the session.
@@ -191,6 +557,9 @@ pub fn visit_uri(
         .with_is_redirect_source(redirect_source.map(|_r| true))
         .with_is_permanent_redirect_source(
             redirect_source.map(|r| r == RedirectSourceType::Permanent),
-        );
-    apply_observation(conn, obs)
+        )
+        .with_referrer(last_url);
+    apply_observation(conn, obs)?;
+    conn.recent_events.add_recently_visited(url, now);
+    Ok(())
 }
@@ -2,105 +2,523 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::db::PlacesDb;
-use crate::error::Result;
+use crate::db::{schema, InterruptScope, PlacesDb};
+use crate::error::{ErrorKind, Result};
+use crate::storage;
+use crate::storage::search_index::search_index_match;
+use crate::types::Timestamp;
 use serde_derive::*;
+use sql_support::ConnExt;
 use url::Url;
 
-pub use crate::match_impl::{MatchBehavior, SearchBehavior};
+pub use crate::match_impl::{MatchBehavior, MatchFolding, SearchBehavior};
 
 #[derive(Debug, Clone)]
 pub struct SearchParams {
     pub search_string: String,
     pub limit: u32,
+    /// Only suggest switching to tabs that are open in this user context. If
+    /// `None`, tabs in every context are suggested.
+    pub user_context_id: Option<u32>,
+    /// How to compare the search string with URLs, titles and tags.
+    pub match_folding: MatchFolding,
 }
 
 /// Synchronously queries all providers for autocomplete matches, then filters
-/// the matches. This isn't cancelable yet; once a search is started, it can't
-/// be interrupted, even if the user moves on (see
-/// https://github.com/mozilla/application-services/issues/265).
+/// the matches. A search can be canceled from another thread, if the user
+/// moves on, by interrupting it with a `PlacesInterruptHandle`. Canceled
+/// searches fail with `ErrorKind::InterruptedError`.
 ///
 /// A provider can be anything that returns URL suggestions: Places history
-/// and bookmarks, synced tabs, search engine suggestions, and search keywords.
+/// and bookmarks, open tabs, synced tabs, search engine suggestions, and
+/// search keywords.
 pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<SearchResult>> {
-    // TODO: Tokenize the query.
+    let scope = conn.begin_interrupt_scope();
+    let query = Query::parse(&params.search_string);
 
     // Try to find the first heuristic result. Desktop tries extensions,
     // search engine aliases, origins, URLs, search engine domains, and
     // preloaded sites, before trying to fall back to fixing up the URL,
     // and a search if all else fails. We only try origins and URLs for
-    // heuristic matches, since that's all we support.
+    // heuristic matches, since that's all we support. Like Desktop, we
+    // skip heuristic matches for restricted and multi-word queries.
+    let origin_or_url = OriginOrUrl::new(&query.search_string, conn);
+    // After the first result, try the queries for adaptive matches, open
+    // tabs that aren't in history, and suggestions for bookmarked URLs.
+    let adaptive = Adaptive::with_behavior(
+        &query.search_string,
+        conn,
+        MatchBehavior::BoundaryAnywhere,
+        query.search_behavior,
+    )
+    .with_user_context_id(params.user_context_id)
+    .with_folding(params.match_folding);
+    let open_tabs = OpenTabs::with_behavior(
+        &query.search_string,
+        conn,
+        MatchBehavior::BoundaryAnywhere,
+        query.search_behavior,
+    )
+    .with_user_context_id(params.user_context_id)
+    .with_folding(params.match_folding);
+    let suggestions = Suggestions::with_behavior(
+        &query.search_string,
+        conn,
+        MatchBehavior::BoundaryAnywhere,
+        query.search_behavior,
+    )
+    .with_user_context_id(params.user_context_id)
+    .with_folding(params.match_folding);
+    // If we don't have enough results, query adaptive matches and
+    // suggestions again, matching anywhere instead of on boundaries.
+    let adaptive_anywhere = Adaptive::with_behavior(
+        &query.search_string,
+        conn,
+        MatchBehavior::Anywhere,
+        query.search_behavior,
+    )
+    .with_user_context_id(params.user_context_id)
+    .with_folding(params.match_folding);
+    let suggestions_anywhere = Suggestions::with_behavior(
+        &query.search_string,
+        conn,
+        MatchBehavior::Anywhere,
+        query.search_behavior,
+    )
+    .with_user_context_id(params.user_context_id)
+    .with_folding(params.match_folding);
 
-    let matches = match_with_limit(
-        &[
-            // Try to match on the origin, or the full URL.
-            &OriginOrUrl::new(&params.search_string, conn),
-            // After the first result, try the queries for adaptive matches and
-            // suggestions for bookmarked URLs.
-            &Adaptive::new(&params.search_string, conn),
-            &Suggestions::new(&params.search_string, conn),
-            // If we don't have enough results, query adaptive matches and
-            // suggestions again, matching anywhere instead of on boundaries.
-            &Adaptive::with_behavior(
-                &params.search_string,
-                conn,
-                MatchBehavior::Anywhere,
-                SearchBehavior::default(),
-            ),
-            &Suggestions::with_behavior(
-                &params.search_string,
-                conn,
-                MatchBehavior::Anywhere,
-                SearchBehavior::default(),
-            ),
-        ],
-        params.limit,
-    )?;
+    let mut matchers: Vec<&dyn Matcher> = Vec::with_capacity(6);
+    if !query.restricted && query.num_tokens == 1 {
+        matchers.push(&origin_or_url);
+    }
+    matchers.push(&adaptive);
+    if query.search_behavior.contains(SearchBehavior::OPENPAGE) {
+        matchers.push(&open_tabs);
+    }
+    matchers.push(&suggestions);
+    matchers.push(&adaptive_anywhere);
+    matchers.push(&suggestions_anywhere);
+
+    let matches = match_with_limit(&matchers, params.limit, &scope)?;
 
     Ok(matches)
 }
 
-fn match_with_limit(matchers: &[&dyn Matcher], max_results: u32) -> Result<(Vec<SearchResult>)> {
+/// Maps a restrict token onto the search behavior it selects. These are the
+/// same characters that Desktop uses by default (see the
+/// `browser.urlbar.restrict.*` and `browser.urlbar.match.*` prefs).
+fn restrict_behavior(token: &str) -> Option<SearchBehavior> {
+    Some(match token {
+        "^" => SearchBehavior::HISTORY,
+        "*" => SearchBehavior::BOOKMARK,
+        "+" => SearchBehavior::TAG,
+        "%" => SearchBehavior::OPENPAGE,
+        "~" => SearchBehavior::TYPED,
+        "#" => SearchBehavior::TITLE,
+        "@" => SearchBehavior::URL,
+        _ => return None,
+    })
+}
+
+/// A tokenized autocomplete query.
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    /// The search tokens, without any restrict tokens, joined with single
+    /// spaces. `AUTOCOMPLETE_MATCH` splits this back into words, and only
+    /// matches pages that match every word.
+    search_string: String,
+
+    /// The number of search tokens in `search_string`.
+    num_tokens: usize,
+
+    /// The search behavior selected by the restrict tokens, or the default
+    /// behavior if the query doesn't have any.
+    search_behavior: SearchBehavior,
+
+    /// Indicates if the query had any restrict tokens.
+    restricted: bool,
+}
+
+impl Query {
+    fn parse(search_string: &str) -> Query {
+        let mut tokens = Vec::new();
+        let mut restrict_flags = SearchBehavior::empty();
+        let mut restricted = false;
+        for token in search_string.split_whitespace() {
+            match restrict_behavior(token) {
+                Some(behavior) => {
+                    restrict_flags |= behavior;
+                    restricted = true;
+                }
+                None => tokens.push(token),
+            }
+        }
+        // Title and URL restrict tokens only change which fields we match
+        // on, so they're combined with the default behavior. The others
+        // select the kinds of pages to match, and all must apply.
+        let fields = SearchBehavior::TITLE | SearchBehavior::URL;
+        let search_behavior = if restrict_flags.intersects(!fields) {
+            restrict_flags | SearchBehavior::RESTRICT
+        } else {
+            SearchBehavior::default() | restrict_flags
+        };
+        Query {
+            search_string: tokens.join(" "),
+            num_tokens: tokens.len(),
+            search_behavior,
+            restricted,
+        }
+    }
+}
+
+fn match_with_limit(
+    matchers: &[&dyn Matcher],
+    max_results: u32,
+    scope: &InterruptScope,
+) -> Result<(Vec<SearchResult>)> {
     let mut results = Vec::new();
     let mut rem_results = max_results;
     for m in matchers {
         if rem_results == 0 {
             break;
         }
-        let matches = m.search(rem_results)?;
+        scope.err_if_interrupted()?;
+        let matches = match m.search(rem_results) {
+            Ok(matches) => matches,
+            // SQLite reports interrupted statements as `SQLITE_INTERRUPT`
+            // errors, which we replace with our own error, so that callers
+            // don't need to dig through the SQL error to find out.
+            Err(_) if scope.was_interrupted() => {
+                return Err(ErrorKind::InterruptedError.into());
+            }
+            Err(e) => return Err(e),
+        };
         results.extend(matches);
         rem_results = rem_results.saturating_sub(results.len() as u32);
     }
     Ok(results)
 }
 
+/// How much adaptive history decays each day. Like Desktop, input history
+/// that isn't used loses about half its weight every four weeks.
+const ADAPTIVE_HISTORY_DAILY_DECAY: f64 = 0.975;
+
+/// Input history that decays below this use count is removed.
+const ADAPTIVE_HISTORY_MIN_USE_COUNT: f64 = 0.01;
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
 /// Records an accepted autocomplete match, recording the query string,
 /// and chosen URL for subsequent matches.
+///
+/// Inputs are expanded as the user types more of them: any input we already
+/// have for the URL that's a prefix of the query string, like "moz" for
+/// "mozilla", is replaced by the query string, keeping its use count. Since
+/// `Adaptive` matches inputs that start with the search string, "moz" still
+/// matches the URL.
 pub fn accept_result(conn: &PlacesDb, result: &SearchResult) -> Result<()> {
+    conn.in_transaction(|| accept_result_in_tx(conn, result))
+}
+
+fn accept_result_in_tx(conn: &PlacesDb, result: &SearchResult) -> Result<()> {
     // See `nsNavHistory::AutoCompleteFeedback`.
-    let mut stmt = conn.db.prepare(
+    let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
+        (":input_text", &result.search_string),
+        (":page_url", &result.url.as_str()),
+    ];
+    conn.execute_named_cached(
         "
         INSERT OR REPLACE INTO moz_inputhistory(place_id, input, use_count)
-        SELECT h.id, IFNULL(i.input, :input_text), IFNULL(i.use_count, 0) * .9 + 1
+        SELECT h.id, :input_text,
+               IFNULL((SELECT MAX(i.use_count) FROM moz_inputhistory i
+                       WHERE i.place_id = h.id
+                         AND substr(:input_text, 1, length(i.input)) = i.input), 0) * .9 + 1
         FROM moz_places h
-        LEFT JOIN moz_inputhistory i ON i.place_id = h.id AND i.input = :input_text
         WHERE url_hash = hash(:page_url) AND url = :page_url
     ",
+        params,
+    )?;
+    conn.execute_named_cached(
+        "
+        DELETE FROM moz_inputhistory
+        WHERE place_id = (SELECT id FROM moz_places
+                          WHERE url_hash = hash(:page_url) AND url = :page_url)
+          AND input <> :input_text
+          AND substr(:input_text, 1, length(input)) = input
+    ",
+        params,
+    )?;
+    Ok(())
+}
+
+/// Decays the use counts of adaptive history, so that URLs the user picked
+/// a long time ago don't outrank recent picks forever. This should be called
+/// periodically, like once a day while the device is idle; it applies the
+/// decay for every whole day since it was last called, so calling it more
+/// often is harmless.
+pub fn decay_adaptive_history(conn: &PlacesDb) -> Result<()> {
+    conn.in_transaction(|| decay_adaptive_history_in_tx(conn, Timestamp::now()))
+}
+
+pub(crate) fn decay_adaptive_history_in_tx(conn: &PlacesDb, now: Timestamp) -> Result<()> {
+    let last_decayed =
+        storage::get_meta::<Timestamp>(conn, schema::MOZ_META_KEY_ADAPTIVE_HISTORY_LAST_DECAYED)?;
+    let last_decayed = match last_decayed {
+        Some(last_decayed) => last_decayed,
+        None => {
+            // Nothing to decay yet, so just start counting from now.
+            storage::put_meta(
+                conn,
+                schema::MOZ_META_KEY_ADAPTIVE_HISTORY_LAST_DECAYED,
+                &now,
+            )?;
+            return Ok(());
+        }
+    };
+    let days = now.0.saturating_sub(last_decayed.0) / MS_PER_DAY;
+    if days == 0 {
+        return Ok(());
+    }
+    let factor = ADAPTIVE_HISTORY_DAILY_DECAY.powi(days.min(i32::max_value() as u64) as i32);
+    conn.execute_named_cached(
+        "UPDATE moz_inputhistory SET use_count = use_count * :factor",
+        &[(":factor", &factor)],
+    )?;
+    conn.execute_named_cached(
+        "DELETE FROM moz_inputhistory WHERE use_count < :min_use_count",
+        &[(":min_use_count", &ADAPTIVE_HISTORY_MIN_USE_COUNT)],
+    )?;
+    // Keep the rest of the day, so that the next call decays on schedule.
+    storage::put_meta(
+        conn,
+        schema::MOZ_META_KEY_ADAPTIVE_HISTORY_LAST_DECAYED,
+        &Timestamp(last_decayed.0 + days * MS_PER_DAY),
     )?;
-    let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
-        (":input_text", &result.search_string),
-        (":page_url", &result.url.as_str()),
-    ];
-    stmt.execute_named(params)?;
     Ok(())
 }
 
+/// Forgets every input the user picked `url` for, so it's no longer boosted
+/// by adaptive history.
+pub fn clear_adaptive_history_for_url(conn: &PlacesDb, url: &Url) -> Result<()> {
+    conn.execute_named_cached(
+        "DELETE FROM moz_inputhistory
+         WHERE place_id = (SELECT id FROM moz_places
+                           WHERE url_hash = hash(:page_url) AND url = :page_url)",
+        &[(":page_url", &url.as_str())],
+    )?;
+    conn.notify_history_observers();
+    Ok(())
+}
+
+/// An inline completion for the URL bar, returned by `autofill`.
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct AutofillResult {
+    /// The search string for this completion.
+    pub search_string: String,
+
+    /// The text to show in the URL bar. This is the search string, exactly
+    /// as typed, followed by the autofilled text.
+    pub completion: String,
+
+    /// The URL to open when the user confirms the completion.
+    #[serde(with = "url_serde")]
+    pub url: Url,
+
+    /// The start of the autofilled text in `completion`, in characters. The
+    /// URL bar should select from here to `selection_end`, so that typing
+    /// replaces the autofilled text.
+    pub selection_start: u32,
+
+    /// The end of the autofilled text in `completion`, in characters. This
+    /// is always the end of `completion`.
+    pub selection_end: u32,
+
+    /// Why this matched: `MatchReason::Origin` if we autofilled an origin,
+    /// or `MatchReason::Url` if we autofilled up to the next slash in a URL.
+    pub reason: MatchReason,
+}
+
+/// Returns the text to autofill in the URL bar as the user types. Like
+/// Desktop, we autofill origins for search strings that look like the start
+/// of a host, and URLs up to the next slash for search strings with a path.
+/// Only origins that are at least as frecent as the autofill threshold are
+/// autofilled. We never autofill search strings with spaces.
+///
+/// Schemes like "https://" and "www." are stripped before matching and kept
+/// in the completion. A typed scheme must match the page's scheme, and typing
+/// "www." only matches hosts that start with "www.".
+pub fn autofill(conn: &PlacesDb, search_string: &str) -> Result<Option<AutofillResult>> {
+    if search_string.is_empty() || search_string.contains(char::is_whitespace) {
+        return Ok(None);
+    }
+    let (prefix, typed_www, remainder) = split_autofill_prefix(search_string);
+    if remainder.is_empty() {
+        return Ok(None);
+    }
+    let threshold = storage::origin_frecency_threshold(conn)?;
+    let prefix = if prefix.is_empty() {
+        None
+    } else {
+        Some(prefix.to_ascii_lowercase())
+    };
+    let found = if looks_like_origin(remainder) {
+        autofill_origin(conn, prefix, typed_www, remainder, threshold)?
+    } else {
+        autofill_url(conn, prefix, typed_www, remainder, threshold)?
+    };
+    Ok(found.and_then(|(url, fixed, reason)| {
+        // `fixed` is the matched origin or URL, without its prefix or "www.",
+        // and must start with what the user typed, ignoring case.
+        let typed = fixed.get(..remainder.len())?;
+        if !typed.eq_ignore_ascii_case(remainder) {
+            return None;
+        }
+        let completion = [search_string, &fixed[remainder.len()..]].concat();
+        Some(AutofillResult {
+            search_string: search_string.into(),
+            selection_start: search_string.chars().count() as u32,
+            selection_end: completion.chars().count() as u32,
+            completion,
+            url,
+            reason,
+        })
+    }))
+}
+
+// Splits a search string into the typed scheme, like "https://", if any,
+// whether "www." was typed after the scheme, and the rest of the string.
+fn split_autofill_prefix(search_string: &str) -> (&str, bool, &str) {
+    let (prefix, remainder) = split_after_prefix(search_string);
+    // `split_after_prefix` treats everything up to the first colon as the
+    // scheme, but "localhost:8080" is a host and port, so we only strip
+    // prefixes like "http://".
+    let (prefix, remainder) = if prefix.ends_with("//") {
+        (prefix, remainder)
+    } else {
+        ("", search_string)
+    };
+    match remainder.get(..4) {
+        Some(www) if www.eq_ignore_ascii_case("www.") => (prefix, true, &remainder[4..]),
+        _ => (prefix, false, remainder),
+    }
+}
+
+// Finds the most frecent origin whose host starts with `host`, returning its
+// URL and its host without "www.", followed by a slash.
+fn autofill_origin(
+    conn: &PlacesDb,
+    prefix: Option<String>,
+    typed_www: bool,
+    host: &str,
+    threshold: f64,
+) -> Result<Option<(Url, String, MatchReason)>> {
+    let host = host.to_ascii_lowercase();
+    let origin = conn.try_query_row(
+        "SELECT prefix, host
+         FROM moz_origins
+         WHERE ((NOT :typedWww AND host BETWEEN :host AND :host || X'FFFF') OR
+                host BETWEEN 'www.' || :host AND 'www.' || :host || X'FFFF')
+           AND (:prefix IS NULL OR prefix = :prefix)
+           AND frecency >= :frecencyThreshold
+         ORDER BY frecency DESC, id DESC
+         LIMIT 1",
+        &[
+            (":host", &host),
+            (":typedWww", &typed_www),
+            (":prefix", &prefix),
+            (":frecencyThreshold", &threshold),
+        ],
+        |row| -> Result<_> {
+            Ok((
+                row.get_checked::<_, String>("prefix")?,
+                row.get_checked::<_, String>("host")?,
+            ))
+        },
+        true,
+    )?;
+    Ok(match origin {
+        Some((prefix, host)) => {
+            let url = Url::parse(&[&prefix, &host, "/"].concat())?;
+            let fixed = [strip_www(&host), "/"].concat();
+            Some((url, fixed, MatchReason::Origin))
+        }
+        None => None,
+    })
+}
+
+// Finds the most frecent URL that starts with `stripped_url`, a host followed
+// by a path, returning the URL and the URL without its prefix or "www.", up to
+// and including the next slash after `stripped_url`.
+fn autofill_url(
+    conn: &PlacesDb,
+    prefix: Option<String>,
+    typed_www: bool,
+    stripped_url: &str,
+    threshold: f64,
+) -> Result<Option<(Url, String, MatchReason)>> {
+    let (host, remainder) = split_after_host_and_port(stripped_url);
+    let host = host.to_ascii_lowercase();
+    let href = conn.try_query_row(
+        "SELECT h.url
+         FROM moz_places h
+         JOIN moz_origins o ON o.id = h.origin_id
+         WHERE ((NOT :typedWww AND o.rev_host = reverse_host(:host) AND
+                 strip_prefix_and_userinfo(h.url) BETWEEN :host || :remainder AND
+                                                          :host || :remainder || X'FFFF') OR
+                (o.rev_host = reverse_host(:host) || 'www.' AND
+                 strip_prefix_and_userinfo(h.url) BETWEEN 'www.' || :host || :remainder AND
+                                                          'www.' || :host || :remainder || X'FFFF'))
+           AND (:prefix IS NULL OR o.prefix = :prefix)
+           AND o.frecency >= :frecencyThreshold
+           AND h.frecency > 0
+           AND h.hidden = 0
+         ORDER BY h.frecency DESC, h.id DESC
+         LIMIT 1",
+        &[
+            (":host", &host),
+            (":remainder", &remainder),
+            (":typedWww", &typed_www),
+            (":prefix", &prefix),
+            (":frecencyThreshold", &threshold),
+        ],
+        |row| row.get_checked::<_, String>(0),
+        true,
+    )?;
+    let href = match href {
+        Some(href) => href,
+        None => return Ok(None),
+    };
+    let (url_prefix, _) = split_after_prefix(&href);
+    let (host_and_port, path) = split_after_host_and_port(&href);
+    let stripped = [host_and_port, path].concat();
+    let fixed = strip_www(&stripped);
+    let www = &stripped[..stripped.len() - fixed.len()];
+    let next_slash = fixed
+        .get(stripped_url.len()..)
+        .and_then(|rest| rest.find('/'));
+    let end = match next_slash {
+        Some(index) => stripped_url.len() + index + 1,
+        None => fixed.len(),
+    };
+    let fixed = &fixed[..end];
+    let url = Url::parse(&[url_prefix, www, fixed].concat())?;
+    Ok(Some((url, fixed.into(), MatchReason::Url)))
+}
+
+fn strip_www(host: &str) -> &str {
+    match host.get(..4) {
+        Some(www) if www.eq_ignore_ascii_case("www.") => &host[4..],
+        _ => host,
+    }
+}
+
 pub fn split_after_prefix(href: &str) -> (&str, &str) {
     match href.find(':') {
         None => ("", href),
         Some(index) => {
             let mut end = index + 1;
-            if href.len() >= end + 2 && &href[end..end + 2] == "//" {
+            if href[end..].starts_with("//") {
                 end += 2;
             }
             (&href[0..end], &href[end..])
@@ -112,7 +530,7 @@ pub fn split_after_host_and_port(href: &str) -> (&str, &str) {
     let (_, remainder) = split_after_prefix(href);
     let mut start = 0;
     let mut end = remainder.len();
-    for (index, c) in remainder.chars().enumerate() {
+    for (index, c) in remainder.char_indices() {
         if c == '/' || c == '?' || c == '#' {
             end = index;
             break;
@@ -142,6 +560,8 @@ pub enum MatchReason {
     Bookmark,
     // Hrm... This will probably make this all serialize weird...
     Tags(String),
+    /// The URL is open in a tab, so the app can offer to switch to it.
+    SwitchTab,
 }
 
 #[derive(Debug, Clone, Serialize, Eq, PartialEq)]
@@ -193,6 +613,10 @@ impl SearchResult {
         if bookmarked {
             reasons.push(MatchReason::Bookmark);
         }
+        let open_count = row.get_checked::<_, Option<u32>>("open_count")?;
+        if open_count.unwrap_or(0) > 0 {
+            reasons.push(MatchReason::SwitchTab);
+        }
         let url = Url::parse(&url).expect("Invalid URL in Places");
 
         Ok(Self {
@@ -219,6 +643,10 @@ impl SearchResult {
         if let Some(tags) = tags {
             reasons.push(MatchReason::Tags(tags));
         }
+        let open_count = row.get_checked::<_, Option<u32>>("open_count")?;
+        if open_count.unwrap_or(0) > 0 {
+            reasons.push(MatchReason::SwitchTab);
+        }
         let url = Url::parse(&url).expect("Invalid URL in Places");
 
         let frecency = row.get_checked::<_, i64>("frecency")?;
@@ -251,6 +679,23 @@ impl SearchResult {
         })
     }
 
+    pub fn from_open_tab_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let search_string = row.get_checked::<_, String>("searchString")?;
+        let url = row.get_checked::<_, String>("url")?;
+
+        let url = Url::parse(&url).expect("Invalid URL in open pages");
+        let title = url.as_str().to_owned();
+
+        Ok(Self {
+            search_string,
+            url,
+            title,
+            icon_url: None,
+            frecency: 0,
+            reasons: vec![MatchReason::SwitchTab],
+        })
+    }
+
     pub fn from_url_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
         let search_string = row.get_checked::<_, String>("searchString")?;
         let href = row.get_checked::<_, String>("url")?;
@@ -345,10 +790,11 @@ impl<'query, 'conn> Matcher for OriginOrUrl<'query, 'conn> {
                 LIMIT 1
             ",
             )?;
+            let threshold = storage::origin_frecency_threshold(self.conn)?;
             let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
                 (":prefix", &rusqlite::types::Null),
                 (":searchString", &self.query),
-                (":frecencyThreshold", &-1i64),
+                (":frecencyThreshold", &threshold),
             ];
             for result in stmt.query_and_then_named(params, SearchResult::from_origin_row)? {
                 results.push(result?);
@@ -398,11 +844,25 @@ impl<'query, 'conn> Matcher for OriginOrUrl<'query, 'conn> {
     }
 }
 
+// Narrows down the pages that `Adaptive` and `Suggestions` run
+// `AUTOCOMPLETE_MATCH` on to the ones the search index finds, if we have a
+// query for it. See `storage::search_index`.
+fn search_index_filter(fts_query: &Option<String>) -> &'static str {
+    if fts_query.is_some() {
+        "AND h.id IN (SELECT rowid FROM moz_places_fts
+                      WHERE moz_places_fts MATCH :ftsQuery)"
+    } else {
+        ""
+    }
+}
+
 struct Adaptive<'query, 'conn> {
     query: &'query str,
     conn: &'conn PlacesDb,
     match_behavior: MatchBehavior,
     search_behavior: SearchBehavior,
+    user_context_id: Option<u32>,
+    folding: MatchFolding,
 }
 
 impl<'query, 'conn> Adaptive<'query, 'conn> {
@@ -426,13 +886,27 @@ impl<'query, 'conn> Adaptive<'query, 'conn> {
             conn,
             match_behavior,
             search_behavior,
+            user_context_id: None,
+            folding: MatchFolding::default(),
         }
     }
+
+    pub fn with_user_context_id(mut self, user_context_id: Option<u32>) -> Self {
+        self.user_context_id = user_context_id;
+        self
+    }
+
+    pub fn with_folding(mut self, folding: MatchFolding) -> Self {
+        self.folding = folding;
+        self
+    }
 }
 
 impl<'query, 'conn> Matcher for Adaptive<'query, 'conn> {
     fn search(&self, max_results: u32) -> Result<Vec<SearchResult>> {
-        let mut stmt = self.conn.db.prepare(
+        let fts_query =
+            search_index_match(self.conn, self.query, self.match_behavior, self.folding)?;
+        let mut stmt = self.conn.db.prepare(&format!(
             "
             SELECT h.url as url,
                    h.title as title,
@@ -443,11 +917,17 @@ impl<'query, 'conn> Matcher for Adaptive<'query, 'conn> {
                           title NOT NULL
                     ORDER BY lastModified DESC
                     LIMIT 1) AS btitle,
-                   NULL AS tags,
+                   (SELECT GROUP_CONCAT(t.tag, ',')
+                    FROM moz_tags t
+                    JOIN moz_tags_relation r ON r.tag_id = t.id
+                    WHERE r.place_id = h.id) AS tags,
                    h.visit_count_local + h.visit_count_remote AS visit_count,
                    h.typed as typed,
                    h.id as id,
-                   NULL AS open_count,
+                   (SELECT SUM(t.open_count) FROM moz_openpages_temp t
+                    WHERE t.url = h.url
+                      AND (:userContextId IS NULL OR
+                           t.userContextId = :userContextId)) AS open_count,
                    h.frecency as frecency,
                    :searchString AS searchString
             FROM (
@@ -461,19 +941,27 @@ impl<'query, 'conn> Matcher for Adaptive<'query, 'conn> {
             WHERE AUTOCOMPLETE_MATCH(:searchString, h.url,
                                      IFNULL(btitle, h.title), tags,
                                      visit_count, h.typed, bookmarked,
-                                     NULL, :matchBehavior, :searchBehavior)
+                                     open_count, :matchBehavior, :searchBehavior,
+                                     :matchFolding)
+              {search_index_filter}
             ORDER BY rank DESC, h.frecency DESC
             LIMIT :maxResults
         ",
-        )?;
-        let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
+            search_index_filter = search_index_filter(&fts_query),
+        ))?;
+        let mut params: Vec<(&str, &dyn rusqlite::types::ToSql)> = vec![
             (":searchString", &self.query),
             (":matchBehavior", &self.match_behavior),
             (":searchBehavior", &self.search_behavior),
+            (":matchFolding", &self.folding),
+            (":userContextId", &self.user_context_id),
             (":maxResults", &max_results),
         ];
+        if let Some(fts_query) = &fts_query {
+            params.push((":ftsQuery", fts_query));
+        }
         let mut results = Vec::new();
-        for result in stmt.query_and_then_named(params, SearchResult::from_adaptive_row)? {
+        for result in stmt.query_and_then_named(&params, SearchResult::from_adaptive_row)? {
             results.push(result?);
         }
         Ok(results)
@@ -485,6 +973,8 @@ struct Suggestions<'query, 'conn> {
     conn: &'conn PlacesDb,
     match_behavior: MatchBehavior,
     search_behavior: SearchBehavior,
+    user_context_id: Option<u32>,
+    folding: MatchFolding,
 }
 
 impl<'query, 'conn> Suggestions<'query, 'conn> {
@@ -508,13 +998,27 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
             conn,
             match_behavior,
             search_behavior,
+            user_context_id: None,
+            folding: MatchFolding::default(),
         }
     }
+
+    pub fn with_user_context_id(mut self, user_context_id: Option<u32>) -> Self {
+        self.user_context_id = user_context_id;
+        self
+    }
+
+    pub fn with_folding(mut self, folding: MatchFolding) -> Self {
+        self.folding = folding;
+        self
+    }
 }
 
 impl<'query, 'conn> Matcher for Suggestions<'query, 'conn> {
     fn search(&self, max_results: u32) -> Result<Vec<SearchResult>> {
-        let mut stmt = self.conn.db.prepare(
+        let fts_query =
+            search_index_match(self.conn, self.query, self.match_behavior, self.folding)?;
+        let mut stmt = self.conn.db.prepare(&format!(
             "
             SELECT h.url, h.title,
                    EXISTS(SELECT 1 FROM moz_bookmarks
@@ -524,31 +1028,121 @@ impl<'query, 'conn> Matcher for Suggestions<'query, 'conn> {
                           title NOT NULL
                     ORDER BY lastModified DESC
                     LIMIT 1) AS btitle,
-                   NULL AS tags,
+                   (SELECT GROUP_CONCAT(t.tag, ',')
+                    FROM moz_tags t
+                    JOIN moz_tags_relation r ON r.tag_id = t.id
+                    WHERE r.place_id = h.id) AS tags,
                    h.visit_count_local + h.visit_count_remote AS visit_count,
                    h.typed as typed,
                    h.id as id,
-                   NULL AS open_count, h.frecency, :searchString AS searchString
+                   (SELECT SUM(t.open_count) FROM moz_openpages_temp t
+                    WHERE t.url = h.url
+                      AND (:userContextId IS NULL OR
+                           t.userContextId = :userContextId)) AS open_count,
+                   h.frecency, :searchString AS searchString
             FROM moz_places h
             WHERE h.frecency > 0
+              {search_index_filter}
               AND AUTOCOMPLETE_MATCH(:searchString, h.url,
                                      IFNULL(btitle, h.title), tags,
                                      visit_count, h.typed,
-                                     bookmarked, NULL,
-                                     :matchBehavior, :searchBehavior)
-              AND (+h.visit_count_local > 0 OR +h.visit_count_remote > 0)
+                                     bookmarked, open_count,
+                                     :matchBehavior, :searchBehavior,
+                                     :matchFolding)
+              AND (+h.visit_count_local > 0 OR +h.visit_count_remote > 0
+                   OR h.foreign_count > 0)
             ORDER BY h.frecency DESC, h.id DESC
             LIMIT :maxResults
+        ",
+            search_index_filter = search_index_filter(&fts_query),
+        ))?;
+        let mut params: Vec<(&str, &dyn rusqlite::types::ToSql)> = vec![
+            (":searchString", &self.query),
+            (":matchBehavior", &self.match_behavior),
+            (":searchBehavior", &self.search_behavior),
+            (":matchFolding", &self.folding),
+            (":userContextId", &self.user_context_id),
+            (":maxResults", &max_results),
+        ];
+        if let Some(fts_query) = &fts_query {
+            params.push((":ftsQuery", fts_query));
+        }
+        let mut results = Vec::new();
+        for result in stmt.query_and_then_named(&params, SearchResult::from_suggestion_row)? {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+}
+
+/// Matches open tabs for URLs that aren't in history or bookmarks. Open tabs
+/// for URLs that are in Places are matched by `Adaptive` and `Suggestions`.
+struct OpenTabs<'query, 'conn> {
+    query: &'query str,
+    conn: &'conn PlacesDb,
+    match_behavior: MatchBehavior,
+    search_behavior: SearchBehavior,
+    user_context_id: Option<u32>,
+    folding: MatchFolding,
+}
+
+impl<'query, 'conn> OpenTabs<'query, 'conn> {
+    pub fn with_behavior(
+        query: &'query str,
+        conn: &'conn PlacesDb,
+        match_behavior: MatchBehavior,
+        search_behavior: SearchBehavior,
+    ) -> OpenTabs<'query, 'conn> {
+        OpenTabs {
+            query,
+            conn,
+            match_behavior,
+            search_behavior,
+            user_context_id: None,
+            folding: MatchFolding::default(),
+        }
+    }
+
+    pub fn with_user_context_id(mut self, user_context_id: Option<u32>) -> Self {
+        self.user_context_id = user_context_id;
+        self
+    }
+
+    pub fn with_folding(mut self, folding: MatchFolding) -> Self {
+        self.folding = folding;
+        self
+    }
+}
+
+impl<'query, 'conn> Matcher for OpenTabs<'query, 'conn> {
+    fn search(&self, max_results: u32) -> Result<Vec<SearchResult>> {
+        let mut stmt = self.conn.db.prepare(
+            "
+            SELECT t.url AS url,
+                   :searchString AS searchString
+            FROM moz_openpages_temp t
+            LEFT JOIN moz_places h ON h.url_hash = hash(t.url) AND h.url = t.url
+            WHERE h.id IS NULL
+              AND (:userContextId IS NULL OR t.userContextId = :userContextId)
+              AND AUTOCOMPLETE_MATCH(:searchString, t.url, t.url, NULL,
+                                     0, 0, 0, t.open_count,
+                                     :matchBehavior, :searchBehavior,
+                                     :matchFolding)
+            GROUP BY t.url
+            ORDER BY MAX(t.ROWID) DESC
+            LIMIT :maxResults
         ",
         )?;
         let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
             (":searchString", &self.query),
             (":matchBehavior", &self.match_behavior),
             (":searchBehavior", &self.search_behavior),
+            (":matchFolding", &self.folding),
+            (":userContextId", &self.user_context_id),
             (":maxResults", &max_results),
         ];
         let mut results = Vec::new();
-        for result in stmt.query_and_then_named(params, SearchResult::from_suggestion_row)? {
+        for result in stmt.query_and_then_named(params, SearchResult::from_open_tab_row)? {
             results.push(result?);
         }
         Ok(results)
@@ -560,6 +1154,8 @@ mod tests {
     use super::*;
     use crate::observation::VisitObservation;
     use crate::storage::apply_observation;
+    use crate::storage::open_tabs::{register_open_page, unregister_open_page};
+    use crate::storage::tags::tag_url;
     use crate::types::{Timestamp, VisitTransition};
 
     #[test]
@@ -573,6 +1169,7 @@ mod tests {
         assert_eq!(split_after_prefix("notaspec"), ("", "notaspec"));
         assert_eq!(split_after_prefix("http:/"), ("http:", "/"));
         assert_eq!(split_after_prefix("http://"), ("http://", ""));
+        assert_eq!(split_after_prefix("foo:bé"), ("foo:", "bé"));
 
         assert_eq!(
             split_after_host_and_port("http://example.com/"),
@@ -587,6 +1184,213 @@ mod tests {
             ("example.com", "/")
         );
         assert_eq!(split_after_host_and_port("foo:example"), ("example", ""));
+        assert_eq!(
+            split_after_host_and_port("http://üser@café.example/ü"),
+            ("café.example", "/ü")
+        );
+        assert_eq!(split_after_host_and_port("café/x"), ("café", "/x"));
+    }
+
+    #[test]
+    fn parse_query() {
+        let query = Query::parse("  foo   bar ");
+        assert_eq!(query.search_string, "foo bar");
+        assert_eq!(query.num_tokens, 2);
+        assert_eq!(query.search_behavior, SearchBehavior::default());
+        assert!(!query.restricted);
+
+        // Restrict characters are only tokens when they stand alone.
+        let query = Query::parse("^foo bar*");
+        assert_eq!(query.search_string, "^foo bar*");
+        assert!(!query.restricted);
+
+        let query = Query::parse("* foo ~ bar");
+        assert_eq!(query.search_string, "foo bar");
+        assert_eq!(query.num_tokens, 2);
+        assert_eq!(
+            query.search_behavior,
+            SearchBehavior::BOOKMARK | SearchBehavior::TYPED | SearchBehavior::RESTRICT
+        );
+        assert!(query.restricted);
+
+        // Title and URL tokens narrow the fields we search, without
+        // restricting the kinds of pages.
+        let query = Query::parse("# foo");
+        assert_eq!(query.search_string, "foo");
+        assert_eq!(
+            query.search_behavior,
+            SearchBehavior::default() | SearchBehavior::TITLE
+        );
+        assert!(query.restricted);
+
+        let query = Query::parse("@ + foo");
+        assert_eq!(
+            query.search_behavior,
+            SearchBehavior::URL | SearchBehavior::TAG | SearchBehavior::RESTRICT
+        );
+
+        let query = Query::parse("^");
+        assert_eq!(query.search_string, "");
+        assert_eq!(query.num_tokens, 0);
+        assert_eq!(
+            query.search_behavior,
+            SearchBehavior::HISTORY | SearchBehavior::RESTRICT
+        );
+    }
+
+    #[test]
+    fn search_tokens() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+
+        let typed = Url::parse("http://example.com/typed").unwrap();
+        let followed = Url::parse("http://example.com/followed").unwrap();
+        for (url, transition) in &[
+            (&typed, VisitTransition::Typed),
+            (&followed, VisitTransition::Link),
+        ] {
+            let visit = VisitObservation::new((*url).clone())
+                .with_title("Example page".to_string())
+                .with_visit_type(*transition)
+                .with_at(Timestamp::now());
+            apply_observation(&mut conn, visit).expect("Should apply visit");
+        }
+
+        let search = |search_string: &str| -> Vec<Url> {
+            let mut urls = search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: search_string.into(),
+                    limit: 10,
+                    user_context_id: None,
+                    match_folding: MatchFolding::Case,
+                },
+            )
+            .expect("Should search")
+            .into_iter()
+            .map(|result| result.url)
+            .collect::<Vec<_>>();
+            urls.sort();
+            urls.dedup();
+            urls
+        };
+
+        // Every token must match.
+        assert_eq!(
+            search("page example"),
+            vec![followed.clone(), typed.clone()]
+        );
+        assert_eq!(search("example typed"), vec![typed.clone()]);
+        assert!(search("example missing").is_empty());
+
+        // Restrict tokens limit the kinds of pages we match.
+        assert_eq!(search("~ example"), vec![typed.clone()]);
+        assert_eq!(search("example ~"), vec![typed.clone()]);
+        assert!(search("* example").is_empty());
+
+        // ...Or the fields we search.
+        assert_eq!(search("# page"), vec![followed.clone(), typed.clone()]);
+        assert!(search("@ page").is_empty());
+        assert_eq!(search("@ followed"), vec![followed.clone()]);
+    }
+
+    #[test]
+    fn split_autofill() {
+        assert_eq!(split_autofill_prefix("moz"), ("", false, "moz"));
+        assert_eq!(
+            split_autofill_prefix("https://www.moz"),
+            ("https://", true, "moz")
+        );
+        assert_eq!(split_autofill_prefix("WWW.moz"), ("", true, "moz"));
+        assert_eq!(
+            split_autofill_prefix("localhost:8080"),
+            ("", false, "localhost:8080")
+        );
+        assert_eq!(split_autofill_prefix("http://"), ("http://", false, ""));
+    }
+
+    #[test]
+    fn autofill_origins_and_urls() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        for (url, visits) in &[
+            ("https://www.mozilla.org/en-US/firefox/", 2),
+            ("https://www.mozilla.org/en-US/about/", 1),
+        ] {
+            for _ in 0..*visits {
+                let visit = VisitObservation::new(Url::parse(url).unwrap())
+                    .with_visit_type(VisitTransition::Typed)
+                    .with_at(Timestamp::now());
+                apply_observation(&mut conn, visit).expect("Should apply visit");
+            }
+        }
+
+        let complete = |search_string: &str| {
+            autofill(&conn, search_string)
+                .expect("Should autofill")
+                .map(|result| {
+                    assert_eq!(result.search_string, search_string);
+                    assert_eq!(
+                        result.selection_start as usize,
+                        search_string.chars().count()
+                    );
+                    assert_eq!(
+                        result.selection_end as usize,
+                        result.completion.chars().count()
+                    );
+                    (result.completion, result.url.into_string(), result.reason)
+                })
+        };
+
+        // Origins are autofilled without "www.", unless it was typed, and
+        // keep the case and scheme that the user typed.
+        assert_eq!(
+            complete("moz"),
+            Some((
+                "mozilla.org/".into(),
+                "https://www.mozilla.org/".into(),
+                MatchReason::Origin
+            ))
+        );
+        assert_eq!(
+            complete("MOZ").map(|(completion, _, _)| completion),
+            Some("MOZilla.org/".into())
+        );
+        assert_eq!(
+            complete("www.moz").map(|(completion, _, _)| completion),
+            Some("www.mozilla.org/".into())
+        );
+        assert_eq!(
+            complete("https://moz").map(|(completion, _, _)| completion),
+            Some("https://mozilla.org/".into())
+        );
+        assert_eq!(complete("http://moz"), None);
+
+        // URLs are autofilled up to the next slash.
+        assert_eq!(
+            complete("mozilla.org/en"),
+            Some((
+                "mozilla.org/en-US/".into(),
+                "https://www.mozilla.org/en-US/".into(),
+                MatchReason::Url
+            ))
+        );
+        assert_eq!(
+            complete("mozilla.org/en-US/"),
+            Some((
+                "mozilla.org/en-US/firefox/".into(),
+                "https://www.mozilla.org/en-US/firefox/".into(),
+                MatchReason::Url
+            ))
+        );
+        assert_eq!(
+            complete("mozilla.org/en-US/a").map(|(_, url, _)| url),
+            Some("https://www.mozilla.org/en-US/about/".into())
+        );
+
+        assert_eq!(complete(""), None);
+        assert_eq!(complete("moz illa"), None);
+        assert_eq!(complete("example"), None);
+        assert_eq!(complete("mozilla.org/de"), None);
+        assert_eq!(complete("café/x"), None);
     }
 
     #[test]
@@ -606,6 +1410,8 @@ mod tests {
             SearchParams {
                 search_string: "example.com".into(),
                 limit: 10,
+                user_context_id: None,
+                match_folding: MatchFolding::Case,
             },
         )
         .expect("Should search by origin");
@@ -621,6 +1427,8 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com".into(),
                 limit: 10,
+                user_context_id: None,
+                match_folding: MatchFolding::Case,
             },
         )
         .expect("Should search by URL without path");
@@ -635,6 +1443,8 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com/1".into(),
                 limit: 10,
+                user_context_id: None,
+                match_folding: MatchFolding::Case,
             },
         )
         .expect("Should search by URL with path");
@@ -662,6 +1472,8 @@ mod tests {
             SearchParams {
                 search_string: "ample".into(),
                 limit: 10,
+                user_context_id: None,
+                match_folding: MatchFolding::Case,
             },
         )
         .expect("Should search by adaptive input history");
@@ -676,6 +1488,8 @@ mod tests {
             SearchParams {
                 search_string: "example".into(),
                 limit: 1,
+                user_context_id: None,
+                match_folding: MatchFolding::Case,
             },
         )
         .expect("Should search until reaching limit");
@@ -691,4 +1505,321 @@ mod tests {
             }]
         );
     }
+
+    #[test]
+    fn search_interrupted() {
+        // A matcher that interrupts the search once it's done.
+        struct Interrupting<'a> {
+            handle: &'a crate::db::PlacesInterruptHandle,
+        }
+        impl<'a> Matcher for Interrupting<'a> {
+            fn search(&self, _: u32) -> Result<Vec<SearchResult>> {
+                self.handle.interrupt();
+                Ok(Vec::new())
+            }
+        }
+
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let visit = VisitObservation::new(Url::parse("http://example.com/").unwrap())
+            .with_title("Example page".to_string())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp::now());
+        apply_observation(&mut conn, visit).expect("Should apply visit");
+
+        // Interrupting before a search starts doesn't cancel it.
+        let handle = conn.new_interrupt_handle();
+        handle.interrupt();
+        let results = search_frecent(
+            &conn,
+            SearchParams {
+                search_string: "example".into(),
+                limit: 10,
+                user_context_id: None,
+                match_folding: MatchFolding::Case,
+            },
+        )
+        .expect("Should search");
+        assert!(!results.is_empty());
+
+        let scope = conn.begin_interrupt_scope();
+        let err = match_with_limit(
+            &[
+                &Interrupting { handle: &handle },
+                &Suggestions::new("example", &conn),
+            ],
+            10,
+            &scope,
+        )
+        .expect_err("Should be interrupted");
+        match err.kind() {
+            ErrorKind::InterruptedError => {}
+            kind => panic!("Wrong error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn search_open_tabs() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+
+        let visited = Url::parse("http://example.com/visited").unwrap();
+        let visited_tab = Url::parse("http://example.com/visited-tab").unwrap();
+        let unvisited_tab = Url::parse("http://example.com/unvisited-tab").unwrap();
+        for url in &[&visited, &visited_tab] {
+            let visit = VisitObservation::new((*url).clone())
+                .with_title("Example page".to_string())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now());
+            apply_observation(&mut conn, visit).expect("Should apply visit");
+        }
+        register_open_page(&conn, &visited_tab, 0).expect("Should register tab");
+        register_open_page(&conn, &unvisited_tab, 1).expect("Should register tab");
+
+        let search = |search_string: &str, user_context_id: Option<u32>| -> Vec<SearchResult> {
+            let mut results = search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: search_string.into(),
+                    limit: 10,
+                    user_context_id,
+                    match_folding: MatchFolding::Case,
+                },
+            )
+            .expect("Should search");
+            results.sort_by(|a, b| a.url.cmp(&b.url));
+            results.dedup_by(|a, b| a.url == b.url);
+            results
+        };
+        let is_switch_tab =
+            |result: &SearchResult| result.reasons.iter().any(|r| *r == MatchReason::SwitchTab);
+
+        // Open tabs are suggested along with history, whether or not they're
+        // in history themselves.
+        let results = search("example", None);
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| (result.url.clone(), is_switch_tab(result)))
+                .collect::<Vec<_>>(),
+            vec![
+                (Url::parse("http://example.com/").unwrap(), false),
+                (unvisited_tab.clone(), true),
+                (visited.clone(), false),
+                (visited_tab.clone(), true),
+            ]
+        );
+
+        // `%` only matches open tabs.
+        let results = search("% example", None);
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|result| result.url)
+                .collect::<Vec<_>>(),
+            vec![unvisited_tab.clone(), visited_tab.clone()]
+        );
+
+        // Only tabs in the same user context are suggested.
+        let results = search("% example", Some(1));
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|result| result.url)
+                .collect::<Vec<_>>(),
+            vec![unvisited_tab.clone()]
+        );
+
+        unregister_open_page(&conn, &unvisited_tab, 1).expect("Should unregister tab");
+        let results = search("% example", None);
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|result| result.url)
+                .collect::<Vec<_>>(),
+            vec![visited_tab.clone()]
+        );
+    }
+
+    fn get_input_history(conn: &PlacesDb) -> Vec<(String, f64)> {
+        let mut stmt = conn
+            .prepare("SELECT input, use_count FROM moz_inputhistory ORDER BY input")
+            .expect("Should prepare");
+        stmt.query_map(&[], |row| (row.get::<_, String>(0), row.get::<_, f64>(1)))
+            .expect("Should query input history")
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .expect("Should read input history")
+    }
+
+    #[test]
+    fn adaptive_history() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("http://example.com/").unwrap();
+        let other_url = Url::parse("http://example.com/other").unwrap();
+        for url in &[&url, &other_url] {
+            let visit = VisitObservation::new((*url).clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now());
+            apply_observation(&mut conn, visit).expect("Should apply visit");
+        }
+        let accept = |search_string: &str, url: &Url| {
+            accept_result(
+                &conn,
+                &SearchResult {
+                    search_string: search_string.into(),
+                    url: url.clone(),
+                    title: String::new(),
+                    icon_url: None,
+                    frecency: -1,
+                    reasons: vec![],
+                },
+            )
+            .expect("Should accept result");
+        };
+
+        // Stored inputs that are prefixes of the new input are expanded.
+        accept("ex", &url);
+        accept("exa", &url);
+        accept("ex", &other_url);
+        let history = get_input_history(&conn);
+        assert_eq!(
+            history
+                .iter()
+                .map(|(input, _)| input.as_str())
+                .collect::<Vec<_>>(),
+            vec!["ex", "exa"]
+        );
+        assert!((history[0].1 - 1.0).abs() < 1e-9);
+        assert!((history[1].1 - 1.9).abs() < 1e-9);
+        let by_adaptive = Adaptive::new("e", &conn)
+            .search(10)
+            .expect("Should search adaptive history");
+        assert_eq!(
+            by_adaptive
+                .into_iter()
+                .map(|result| result.url)
+                .collect::<Vec<_>>(),
+            vec![url.clone(), other_url.clone()]
+        );
+
+        // Decaying the first time just starts the clock.
+        let now = Timestamp::now();
+        decay_adaptive_history_in_tx(&conn, now).expect("Should start decaying");
+        assert_eq!(get_input_history(&conn), history);
+
+        // Less than a day later, nothing changes.
+        decay_adaptive_history_in_tx(&conn, Timestamp(now.0 + MS_PER_DAY - 1))
+            .expect("Should not decay");
+        assert_eq!(get_input_history(&conn), history);
+
+        let later = Timestamp(now.0 + 2 * MS_PER_DAY);
+        decay_adaptive_history_in_tx(&conn, later).expect("Should decay");
+        let decayed = get_input_history(&conn);
+        assert!((decayed[0].1 - history[0].1 * 0.975 * 0.975).abs() < 1e-9);
+        assert!((decayed[1].1 - history[1].1 * 0.975 * 0.975).abs() < 1e-9);
+
+        // Unused entries eventually go away.
+        decay_adaptive_history_in_tx(&conn, Timestamp(later.0 + 200 * MS_PER_DAY))
+            .expect("Should decay");
+        assert_eq!(get_input_history(&conn).len(), 1);
+
+        clear_adaptive_history_for_url(&conn, &url).expect("Should clear adaptive history");
+        assert!(get_input_history(&conn).is_empty());
+
+        // Removing a page removes its input history.
+        accept("ex", &other_url);
+        assert_eq!(get_input_history(&conn).len(), 1);
+        crate::storage::delete_visits_between(&conn, Timestamp(0), Timestamp::now())
+            .expect("Should delete visits");
+        assert!(get_input_history(&conn).is_empty());
+    }
+
+    #[test]
+    fn search_tags() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+
+        let tagged = Url::parse("http://example.com/tagged").unwrap();
+        let untagged = Url::parse("http://example.com/untagged").unwrap();
+        for url in &[&tagged, &untagged] {
+            let visit = VisitObservation::new((*url).clone())
+                .with_title("Example page".to_string())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now());
+            apply_observation(&mut conn, visit).expect("Should apply visit");
+        }
+        tag_url(&conn, &tagged, "fruit").expect("Should tag URL");
+        tag_url(&conn, &tagged, "banana").expect("Should tag URL");
+
+        // The tags are searched along with the title and URL.
+        let by_tag = Suggestions::new("banana", &conn)
+            .search(10)
+            .expect("Should search by tag");
+        assert_eq!(by_tag.len(), 1);
+        assert_eq!(by_tag[0].url, tagged);
+        assert!(by_tag[0].reasons.iter().any(|reason| match reason {
+            MatchReason::Tags(tags) => tags.contains("banana") && tags.contains("fruit"),
+            _ => false,
+        }));
+
+        // Restricting to tags only returns tagged pages.
+        let restricted = Suggestions::with_behavior(
+            "example",
+            &conn,
+            MatchBehavior::BoundaryAnywhere,
+            SearchBehavior::TAG | SearchBehavior::RESTRICT,
+        )
+        .search(10)
+        .expect("Should search tagged pages");
+        assert_eq!(
+            restricted
+                .into_iter()
+                .map(|result| result.url)
+                .collect::<Vec<_>>(),
+            vec![tagged.clone()]
+        );
+
+        let unrestricted = Suggestions::new("example", &conn)
+            .search(10)
+            .expect("Should search all pages");
+        assert_eq!(unrestricted.len(), 2);
+    }
+
+    #[test]
+    fn search_with_index() {
+        use crate::storage::search_index::enable_search_index;
+
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        for (url, title) in &[
+            ("http://example.com/", "Example page"),
+            ("http://mozilla.org/", "Mozilla FireFox"),
+        ] {
+            let visit = VisitObservation::new(Url::parse(url).unwrap())
+                .with_title(title.to_string())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now());
+            apply_observation(&mut conn, visit).expect("Should apply visit");
+        }
+        enable_search_index(&conn).expect("Should enable the search index");
+
+        // Matching on boundaries uses the index...
+        let results = Suggestions::new("fox", &conn)
+            .search(10)
+            .expect("Should search with the index");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].url.as_str(), "http://mozilla.org/");
+
+        // ...but matching anywhere can't.
+        let results = Suggestions::with_behavior(
+            "ample",
+            &conn,
+            MatchBehavior::Anywhere,
+            SearchBehavior::default(),
+        )
+        .search(10)
+        .expect("Should search without the index");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].url.as_str(), "http://example.com/");
+        let results = Suggestions::new("ample", &conn)
+            .search(10)
+            .expect("Should search with the index");
+        assert!(results.is_empty());
+    }
 }
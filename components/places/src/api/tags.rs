@@ -0,0 +1,10 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// This module can become, roughly: PlacesUtils.tagging
+
+pub use crate::storage::tags::{
+    get_tags_for_url, get_urls_with_tag, remove_all_tags_from_url, remove_tag, tag_url, untag_url,
+    validate_tag, TAG_MAX_LENGTH,
+};
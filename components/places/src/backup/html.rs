@@ -0,0 +1,613 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// The Netscape bookmarks HTML format. It's not really HTML: folders are
+// `<H3>` headings followed by a `<DL>` list of their children, bookmarks are
+// `<A>` links, and separators are `<HR>`s. Tags aren't closed consistently,
+// so we don't use an HTML parser, just a tokenizer that understands enough of
+// the format to read the files browsers write.
+//
+// Like desktop, we write the menu's children at the top level, and the other
+// roots as folders with an attribute that says which root they are. The
+// format doesn't have GUIDs, so we make them from each item's parent and
+// contents when we import a file. Importing the same file twice makes the
+// same GUIDs, so it doesn't duplicate anything.
+
+use super::{fetch_bookmarked_tags, BackupSource};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::hash::hash_string;
+use crate::import::{run_import, ImportStats, SourceBookmark};
+use crate::storage::bookmarks::{fetch_tree, BookmarkNode, BookmarkRootGuid};
+use crate::types::{BookmarkType, Timestamp};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const HEADER: &str = "<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file.
+     It will be read and overwritten.
+     DO NOT EDIT! -->
+<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks Menu</H1>
+
+";
+
+// The attributes that mark a folder as one of our roots.
+const TOOLBAR_ATTRIBUTE: &str = "PERSONAL_TOOLBAR_FOLDER";
+const UNFILED_ATTRIBUTE: &str = "UNFILED_BOOKMARKS_FOLDER";
+const MOBILE_ATTRIBUTE: &str = "MOBILE_BOOKMARKS_FOLDER";
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_html(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                name if name.starts_with("#x") || name.starts_with("#X") => {
+                    u32::from_str_radix(&name[2..], 16)
+                        .ok()
+                        .and_then(std::char::from_u32)
+                }
+                name if name.starts_with('#') => {
+                    name[1..].parse().ok().and_then(std::char::from_u32)
+                }
+                _ => None,
+            };
+            c.map(|c| (c, end))
+        });
+        match entity {
+            Some((c, end)) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn write_node(
+    writer: &mut impl Write,
+    node: &BookmarkNode,
+    tags: &HashMap<String, Vec<String>>,
+    depth: usize,
+) -> Result<()> {
+    let indent = "    ".repeat(depth);
+    let dates = format!(
+        "ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\"",
+        node.date_added.0 / 1000,
+        node.last_modified.0 / 1000
+    );
+    match node.node_type {
+        BookmarkType::Bookmark => {
+            let url = match &node.url {
+                Some(url) => url,
+                None => return Ok(()),
+            };
+            let tags = match tags.get(url.as_str()) {
+                Some(tags) => format!(" TAGS=\"{}\"", escape_html(&tags.join(","))),
+                None => String::new(),
+            };
+            writeln!(
+                writer,
+                "{}<DT><A HREF=\"{}\" {}{}>{}</A>",
+                indent,
+                escape_html(url.as_str()),
+                dates,
+                tags,
+                escape_html(node.title.as_ref().map(String::as_str).unwrap_or_default())
+            )?;
+        }
+        BookmarkType::Folder => {
+            write_folder(
+                writer,
+                node,
+                "",
+                node.title.as_ref().map(String::as_str).unwrap_or_default(),
+                tags,
+                depth,
+            )?;
+        }
+        BookmarkType::Separator => {
+            writeln!(writer, "{}<HR>", indent)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_folder(
+    writer: &mut impl Write,
+    folder: &BookmarkNode,
+    attributes: &str,
+    title: &str,
+    tags: &HashMap<String, Vec<String>>,
+    depth: usize,
+) -> Result<()> {
+    let indent = "    ".repeat(depth);
+    writeln!(
+        writer,
+        "{}<DT><H3 ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\"{}>{}</H3>",
+        indent,
+        folder.date_added.0 / 1000,
+        folder.last_modified.0 / 1000,
+        attributes,
+        escape_html(title)
+    )?;
+    writeln!(writer, "{}<DL><p>", indent)?;
+    for child in &folder.children {
+        write_node(writer, child, tags, depth + 1)?;
+    }
+    writeln!(writer, "{}</DL><p>", indent)?;
+    Ok(())
+}
+
+/// Writes every bookmark to `writer` in the Netscape bookmarks HTML format.
+pub fn export_bookmarks_to_html(db: &PlacesDb, mut writer: impl Write) -> Result<()> {
+    let root = fetch_tree(db, &BookmarkRootGuid::Root.as_guid())?
+        .ok_or_else(|| InvalidPlaceInfo::NoItem(BookmarkRootGuid::Root.as_str().into()))?;
+    let tags = fetch_bookmarked_tags(db)?;
+    writer.write_all(HEADER.as_bytes())?;
+    writeln!(writer, "<DL><p>")?;
+    for folder in &root.children {
+        let (attribute, title) = match BookmarkRootGuid::well_known(&folder.guid.0) {
+            Some(BookmarkRootGuid::Menu) => {
+                for child in &folder.children {
+                    write_node(&mut writer, child, &tags, 1)?;
+                }
+                continue;
+            }
+            Some(BookmarkRootGuid::Toolbar) => (TOOLBAR_ATTRIBUTE, "Bookmarks Toolbar"),
+            Some(BookmarkRootGuid::Unfiled) => (UNFILED_ATTRIBUTE, "Other Bookmarks"),
+            Some(BookmarkRootGuid::Mobile) => (MOBILE_ATTRIBUTE, "Mobile Bookmarks"),
+            _ => continue,
+        };
+        write_folder(
+            &mut writer,
+            folder,
+            &format!(" {}=\"true\"", attribute),
+            title,
+            &tags,
+            1,
+        )?;
+    }
+    writeln!(writer, "</DL>")?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Start {
+        name: String,
+        attributes: HashMap<String, String>,
+    },
+    End(String),
+    Text(String),
+}
+
+// Parses the inside of a start tag, like `A HREF="http://example.com/"`.
+// Names are uppercased, and attributes without values are empty.
+fn parse_start_tag(tag: &str) -> Token {
+    let tag = tag.trim();
+    let name_end = tag
+        .find(|c: char| c.is_ascii_whitespace())
+        .unwrap_or_else(|| tag.len());
+    let name = tag[..name_end].trim_end_matches('/').to_ascii_uppercase();
+    let mut attributes = HashMap::new();
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_ascii_whitespace())
+            .unwrap_or_else(|| rest.len());
+        let key = rest[..key_end].to_ascii_uppercase();
+        rest = rest[key_end..].trim_start();
+        let mut value = String::new();
+        if rest.starts_with('=') {
+            rest = rest[1..].trim_start();
+            let (raw, after) = match rest.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => {
+                    let inner = &rest[1..];
+                    match inner.find(quote) {
+                        Some(end) => (&inner[..end], &inner[end + 1..]),
+                        None => (inner, ""),
+                    }
+                }
+                _ => {
+                    let end = rest
+                        .find(|c: char| c.is_ascii_whitespace())
+                        .unwrap_or_else(|| rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            value = unescape_html(raw);
+            rest = after;
+        }
+        if !key.is_empty() && key != "/" {
+            attributes.insert(key, value);
+        }
+        rest = rest.trim_start();
+    }
+    Token::Start { name, attributes }
+}
+
+fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(end) => &rest[end + 3..],
+                None => "",
+            };
+        } else if rest.starts_with('<') {
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => break,
+            };
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with('/') {
+                tokens.push(Token::End(tag[1..].trim().to_ascii_uppercase()));
+            } else if !tag.starts_with('!') {
+                tokens.push(parse_start_tag(tag));
+            }
+        } else {
+            let end = rest.find('<').unwrap_or_else(|| rest.len());
+            tokens.push(Token::Text(unescape_html(&rest[..end])));
+            rest = &rest[end..];
+        }
+    }
+    tokens
+}
+
+// Makes a GUID for an item in a bookmarks file, from its parent's GUID and
+// `key`. This isn't random, but it only needs to be unique within the file,
+// and the same each time the file is imported.
+fn make_guid(parent_guid: &str, key: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut guid = String::with_capacity(12);
+    for salt in 0..3 {
+        let hash = hash_string(&format!("{}\n{}\n{}", salt, parent_guid, key));
+        for i in 0..4 {
+            guid.push(ALPHABET[((hash >> (i * 6)) & 63) as usize] as char);
+        }
+    }
+    guid
+}
+
+// Reads the text up to the end tag `name`, starting at `tokens[index]`.
+// Returns the text, and the index of the token after the end tag.
+fn read_text(tokens: &[Token], mut index: usize, name: &str) -> (String, usize) {
+    let mut text = String::new();
+    while let Some(token) = tokens.get(index) {
+        index += 1;
+        match token {
+            Token::Text(t) => text.push_str(t),
+            Token::End(end) if end == name => break,
+            _ => {}
+        }
+    }
+    (text.trim().to_string(), index)
+}
+
+fn parse_date(attributes: &HashMap<String, String>, name: &str) -> Timestamp {
+    attributes
+        .get(name)
+        .and_then(|date| date.parse::<u64>().ok())
+        .map(|secs| Timestamp(secs.saturating_mul(1000)))
+        .unwrap_or_else(Timestamp::now)
+}
+
+fn parse_bookmarks(html: &str) -> BackupSource {
+    let mut source = BackupSource::new("html");
+    let tokens = tokenize(html);
+    let menu = BookmarkRootGuid::Menu.as_str().to_string();
+    // The folders we're in, and how many children we've seen in each.
+    let mut folders: Vec<(String, usize)> = Vec::new();
+    // The folder that the next `<DL>` lists the children of.
+    let mut next_folder: Option<String> = None;
+    let mut index = 0;
+    while let Some(token) = tokens.get(index) {
+        index += 1;
+        let (name, attributes) = match token {
+            Token::Start { name, attributes } => (name, attributes),
+            Token::End(name) => {
+                if name == "DL" {
+                    folders.pop();
+                }
+                continue;
+            }
+            Token::Text(_) => continue,
+        };
+        if name == "DL" {
+            let guid = next_folder
+                .take()
+                .or_else(|| folders.last().map(|(guid, _)| guid.clone()))
+                .unwrap_or_else(|| menu.clone());
+            folders.push((guid, 0));
+            continue;
+        }
+        if name != "H3" && name != "A" && name != "HR" {
+            continue;
+        }
+        let parent_guid = folders
+            .last()
+            .map(|(guid, _)| guid.clone())
+            .unwrap_or_else(|| menu.clone());
+        let position = match folders.last_mut() {
+            Some((_, count)) => {
+                *count += 1;
+                *count
+            }
+            None => 0,
+        };
+        let date_added = parse_date(attributes, "ADD_DATE");
+        let last_modified = attributes
+            .get("LAST_MODIFIED")
+            .map(|_| parse_date(attributes, "LAST_MODIFIED"))
+            .unwrap_or(date_added);
+        match name.as_str() {
+            "H3" => {
+                let (title, next) = read_text(&tokens, index, "H3");
+                index = next;
+                let root = if attributes.contains_key(TOOLBAR_ATTRIBUTE) {
+                    Some(BookmarkRootGuid::Toolbar)
+                } else if attributes.contains_key(UNFILED_ATTRIBUTE) {
+                    Some(BookmarkRootGuid::Unfiled)
+                } else if attributes.contains_key(MOBILE_ATTRIBUTE) {
+                    Some(BookmarkRootGuid::Mobile)
+                } else {
+                    None
+                };
+                if let Some(root) = root {
+                    next_folder = Some(root.as_str().to_string());
+                    continue;
+                }
+                // Sibling folders can have the same title, so we use the
+                // position, too.
+                let guid = make_guid(&parent_guid, &format!("folder\n{}\n{}", position, title));
+                source.bookmarks.push(SourceBookmark {
+                    guid: guid.clone(),
+                    parent_guid,
+                    kind: Some(BookmarkType::Folder),
+                    url: None,
+                    title: Some(title),
+                    date_added,
+                    last_modified,
+                });
+                next_folder = Some(guid);
+            }
+            "A" => {
+                let (title, next) = read_text(&tokens, index, "A");
+                index = next;
+                let url = match attributes.get("HREF") {
+                    Some(url) => url,
+                    None => continue,
+                };
+                if let Some(tags) = attributes.get("TAGS") {
+                    for tag in tags.split(',') {
+                        source.add_tag(url, tag);
+                    }
+                }
+                source.bookmarks.push(SourceBookmark {
+                    guid: make_guid(&parent_guid, &format!("bookmark\n{}", url)),
+                    parent_guid,
+                    kind: Some(BookmarkType::Bookmark),
+                    url: Some(url.clone()),
+                    title: if title.is_empty() { None } else { Some(title) },
+                    date_added,
+                    last_modified,
+                });
+            }
+            "HR" => {
+                source.bookmarks.push(SourceBookmark {
+                    guid: make_guid(&parent_guid, &format!("separator\n{}", position)),
+                    parent_guid,
+                    kind: Some(BookmarkType::Separator),
+                    url: None,
+                    title: None,
+                    date_added,
+                    last_modified,
+                });
+            }
+            _ => {}
+        }
+    }
+    source
+}
+
+/// Imports bookmarks from a file in the Netscape bookmarks HTML format.
+/// Bookmarks for a URL that's already in the same folder are skipped.
+pub fn import_bookmarks_from_html(db: &PlacesDb, mut reader: impl Read) -> Result<ImportStats> {
+    let mut html = String::new();
+    reader.read_to_string(&mut html)?;
+    let source = parse_bookmarks(&html);
+    run_import(db, &source, &mut |_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, InsertableBookmark, InsertableItem, InsertableSeparator,
+    };
+    use crate::storage::tags::{get_tags_for_url, tag_url};
+    use url::Url;
+
+    #[test]
+    fn test_unescape_html() {
+        assert_eq!(unescape_html("a &amp; b &lt;c&gt;"), "a & b <c>");
+        assert_eq!(unescape_html("&#39;&#x41;&quot;"), "'A\"");
+        assert_eq!(unescape_html("AT&T &unknown; &"), "AT&T &unknown; &");
+        assert_eq!(unescape_html(&escape_html("<\"'&'\">")), "<\"'&'\">");
+    }
+
+    #[test]
+    fn test_import_html() -> Result<()> {
+        let html = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+            <TITLE>Bookmarks</TITLE>
+            <H1>Bookmarks</H1>
+            <DL><p>
+                <DT><A HREF="http://example.com/" ADD_DATE="1500000000" TAGS="news,web">Example &amp; Co</A>
+                <DT><H3 ADD_DATE="1500000000">Folder</H3>
+                <DL><p>
+                    <DT><A HREF="http://mozilla.org/">Mozilla</A>
+                    <DD>A description, which we ignore
+                    <HR>
+                    <DT><A HREF="http://mozilla.org/">Mozilla again</A>
+                </DL><p>
+                <DT><H3 PERSONAL_TOOLBAR_FOLDER="true">Toolbar</H3>
+                <DL><p>
+                    <DT><A HREF=http://example.org/ ADD_DATE=1500000000>Unquoted</A>
+                </DL><p>
+            </DL>"#;
+        let conn = PlacesDb::open_in_memory(None)?;
+        let stats = import_bookmarks_from_html(&conn, html.as_bytes())?;
+        // The second bookmark for mozilla.org is a duplicate.
+        assert_eq!(stats.bookmarks, 5);
+        assert_eq!(stats.tags, 2);
+        assert!(stats.errors.is_empty());
+
+        let menu = fetch_tree(&conn, &BookmarkRootGuid::Menu.as_guid())?.expect("has a menu");
+        assert_eq!(menu.children.len(), 2);
+        assert_eq!(menu.children[0].title, Some("Example & Co".to_string()));
+        assert_eq!(menu.children[0].date_added, Timestamp(1_500_000_000_000));
+        let folder = &menu.children[1];
+        assert_eq!(folder.title, Some("Folder".to_string()));
+        assert_eq!(
+            folder
+                .children
+                .iter()
+                .map(|c| c.node_type)
+                .collect::<Vec<_>>(),
+            vec![BookmarkType::Bookmark, BookmarkType::Separator]
+        );
+        let toolbar =
+            fetch_tree(&conn, &BookmarkRootGuid::Toolbar.as_guid())?.expect("has a toolbar");
+        assert_eq!(
+            toolbar.children[0].url,
+            Some(Url::parse("http://example.org/")?)
+        );
+        assert_eq!(
+            get_tags_for_url(&conn, &Url::parse("http://example.com/")?)?,
+            vec!["news".to_string(), "web".to_string()]
+        );
+
+        // Importing again doesn't duplicate anything.
+        let stats = import_bookmarks_from_html(&conn, html.as_bytes())?;
+        assert_eq!(stats.bookmarks, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_html_same_named_folders() -> Result<()> {
+        let html = r#"<DL><p>
+                <DT><H3>Folder</H3>
+                <DL><p>
+                    <DT><A HREF="http://example.com/">Example</A>
+                </DL><p>
+                <DT><H3>Folder</H3>
+                <DL><p>
+                    <DT><A HREF="http://mozilla.org/">Mozilla</A>
+                </DL><p>
+            </DL>"#;
+        let conn = PlacesDb::open_in_memory(None)?;
+        let stats = import_bookmarks_from_html(&conn, html.as_bytes())?;
+        assert_eq!(stats.bookmarks, 4);
+        assert!(stats.errors.is_empty());
+
+        let menu = fetch_tree(&conn, &BookmarkRootGuid::Menu.as_guid())?.expect("has a menu");
+        assert_eq!(menu.children.len(), 2);
+        assert_ne!(menu.children[0].guid, menu.children[1].guid);
+        let urls = menu
+            .children
+            .iter()
+            .map(|folder| {
+                assert_eq!(folder.title, Some("Folder".to_string()));
+                folder
+                    .children
+                    .iter()
+                    .filter_map(|c| c.url.as_ref().map(|url| url.as_str()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![vec!["http://example.com/"], vec!["http://mozilla.org/"]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_html_round_trip() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/?a=1&b=2")?;
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: Some(Timestamp(1_500_000_000_000)),
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: Some("<Example>".into()),
+            }),
+        )?;
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Separator(InsertableSeparator {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+            }),
+        )?;
+        tag_url(&conn, &url, "news")?;
+        let mut html = Vec::new();
+        export_bookmarks_to_html(&conn, &mut html)?;
+        let html = String::from_utf8(html).expect("should be UTF-8");
+        assert!(html.contains("UNFILED_BOOKMARKS_FOLDER=\"true\""));
+        assert!(html.contains("&lt;Example&gt;"));
+
+        let other = PlacesDb::open_in_memory(None)?;
+        let stats = import_bookmarks_from_html(&other, html.as_bytes())?;
+        assert_eq!(stats.bookmarks, 2);
+        let unfiled =
+            fetch_tree(&other, &BookmarkRootGuid::Unfiled.as_guid())?.expect("has unfiled");
+        assert_eq!(unfiled.children[0].url, Some(url.clone()));
+        assert_eq!(unfiled.children[0].title, Some("<Example>".to_string()));
+        assert_eq!(unfiled.children[0].date_added, Timestamp(1_500_000_000_000));
+        assert_eq!(unfiled.children[1].node_type, BookmarkType::Separator);
+        assert_eq!(get_tags_for_url(&other, &url)?, vec!["news".to_string()]);
+        Ok(())
+    }
+}
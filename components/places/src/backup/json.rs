@@ -0,0 +1,370 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Desktop's JSON formats. Both store times in microseconds.
+
+use super::{fetch_bookmarked_tags, BackupSource};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::import::{
+    from_micros, run_import, ImportStats, SourceBookmark, SourcePage, SourceVisit,
+};
+use crate::storage::bookmarks::{fetch_tree, BookmarkNode, BookmarkRootGuid};
+use crate::storage::RowId;
+use crate::types::{BookmarkType, Timestamp, VisitTransition};
+use serde_derive::*;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const TYPE_BOOKMARK: &str = "text/x-moz-place";
+const TYPE_FOLDER: &str = "text/x-moz-place-container";
+const TYPE_SEPARATOR: &str = "text/x-moz-place-separator";
+
+// An item in a desktop bookmark backup. Folders contain their children, and
+// bookmarks list their tags, separated by commas.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BookmarkRecord {
+    guid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default)]
+    index: u32,
+    #[serde(default)]
+    date_added: i64,
+    #[serde(default)]
+    last_modified: i64,
+    #[serde(default)]
+    type_code: u8,
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    children: Vec<BookmarkRecord>,
+}
+
+// A page and its visits, in the same shape as a desktop history sync record.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryRecord {
+    id: String,
+    hist_uri: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    visits: Vec<VisitRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VisitRecord {
+    date: i64,
+    #[serde(rename = "type")]
+    visit_type: i64,
+    // Desktop doesn't have this, so its visits are imported as remote.
+    #[serde(default)]
+    is_local: bool,
+}
+
+fn to_micros(ts: Timestamp) -> i64 {
+    (ts.0 as i64).saturating_mul(1000)
+}
+
+// The names desktop gives its roots in backups.
+fn root_name(guid: &str) -> Option<&'static str> {
+    Some(match BookmarkRootGuid::well_known(guid)? {
+        BookmarkRootGuid::Root => "placesRoot",
+        BookmarkRootGuid::Menu => "bookmarksMenuFolder",
+        BookmarkRootGuid::Toolbar => "toolbarFolder",
+        BookmarkRootGuid::Unfiled => "unfiledBookmarksFolder",
+        BookmarkRootGuid::Mobile => "mobileFolder",
+    })
+}
+
+fn bookmark_record(node: &BookmarkNode, tags: &HashMap<String, Vec<String>>) -> BookmarkRecord {
+    BookmarkRecord {
+        guid: node.guid.0.clone(),
+        title: node.title.clone(),
+        index: node.position,
+        date_added: to_micros(node.date_added),
+        last_modified: to_micros(node.last_modified),
+        type_code: node.node_type as u8,
+        kind: match node.node_type {
+            BookmarkType::Bookmark => TYPE_BOOKMARK,
+            BookmarkType::Folder => TYPE_FOLDER,
+            BookmarkType::Separator => TYPE_SEPARATOR,
+        }
+        .to_string(),
+        root: root_name(&node.guid.0).map(String::from),
+        uri: node.url.as_ref().map(|url| url.to_string()),
+        tags: node
+            .url
+            .as_ref()
+            .and_then(|url| tags.get(url.as_str()))
+            .map(|tags| tags.join(",")),
+        children: node
+            .children
+            .iter()
+            .map(|child| bookmark_record(child, tags))
+            .collect(),
+    }
+}
+
+/// Writes every bookmark to `writer`, in the JSON format desktop uses for
+/// its bookmark backups.
+pub fn export_bookmarks_to_json(db: &PlacesDb, writer: impl Write) -> Result<()> {
+    let root = fetch_tree(db, &BookmarkRootGuid::Root.as_guid())?
+        .ok_or_else(|| InvalidPlaceInfo::NoItem(BookmarkRootGuid::Root.as_str().into()))?;
+    let tags = fetch_bookmarked_tags(db)?;
+    serde_json::to_writer(writer, &bookmark_record(&root, &tags))?;
+    Ok(())
+}
+
+fn add_bookmarks(source: &mut BackupSource, parent: &BookmarkRecord) {
+    for child in &parent.children {
+        source.bookmarks.push(SourceBookmark {
+            guid: child.guid.clone(),
+            parent_guid: parent.guid.clone(),
+            kind: BookmarkType::from_u8(child.type_code),
+            url: child.uri.clone(),
+            title: child.title.clone(),
+            date_added: from_micros(child.date_added),
+            last_modified: from_micros(child.last_modified),
+        });
+        if let (Some(url), Some(tags)) = (&child.uri, &child.tags) {
+            for tag in tags.split(',') {
+                source.add_tag(url, tag);
+            }
+        }
+        add_bookmarks(source, child);
+    }
+}
+
+/// Imports bookmarks from a desktop bookmark backup. Bookmarks we already
+/// have, with the same GUID, or for the same URL in the same folder, are
+/// skipped.
+pub fn import_bookmarks_from_json(db: &PlacesDb, reader: impl Read) -> Result<ImportStats> {
+    let root: BookmarkRecord = serde_json::from_reader(reader)?;
+    let mut source = BackupSource::new("json");
+    // Desktop backups can include the tags root, which we skip, since tags
+    // are also listed on their bookmarks.
+    for folder in &root.children {
+        if BookmarkRootGuid::well_known(&folder.guid).is_some() {
+            add_bookmarks(&mut source, folder);
+        }
+    }
+    run_import(db, &source, &mut |_| ())
+}
+
+/// Writes every page that has visits to `writer` as a JSON array, with one
+/// record per page, in the same shape as desktop's history sync records.
+/// Pages are written as they're read.
+pub fn export_history_to_json(db: &PlacesDb, mut writer: impl Write) -> Result<()> {
+    let mut pages_stmt = db.prepare(
+        "SELECT h.id, h.guid, h.url, h.title FROM moz_places h
+         WHERE EXISTS(SELECT 1 FROM moz_historyvisits v WHERE v.place_id = h.id)
+         ORDER BY h.id",
+    )?;
+    let mut visits_stmt = db.prepare(
+        "SELECT visit_date, visit_type, is_local FROM moz_historyvisits
+         WHERE place_id = :place_id
+         ORDER BY visit_date",
+    )?;
+    let mut rows = pages_stmt.query(&[])?;
+    writer.write_all(b"[")?;
+    let mut first = true;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let place_id: RowId = row.get_checked("id")?;
+        let visits = visits_stmt
+            .query_and_then_named(&[(":place_id", &place_id)], |row| -> Result<_> {
+                Ok(VisitRecord {
+                    date: to_micros(row.get_checked("visit_date")?),
+                    visit_type: row.get_checked("visit_type")?,
+                    is_local: row.get_checked("is_local")?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        if !first {
+            writer.write_all(b",")?;
+        }
+        first = false;
+        serde_json::to_writer(
+            &mut writer,
+            &HistoryRecord {
+                id: row.get_checked("guid")?,
+                hist_uri: row.get_checked("url")?,
+                title: row.get_checked("title")?,
+                visits,
+            },
+        )?;
+    }
+    writer.write_all(b"]")?;
+    Ok(())
+}
+
+/// Imports history exported by `export_history_to_json`, or in the same
+/// format. Pages we already have, with the same URL, get the visits we don't
+/// have yet.
+pub fn import_history_from_json(db: &PlacesDb, reader: impl Read) -> Result<ImportStats> {
+    let records: Vec<HistoryRecord> = serde_json::from_reader(reader)?;
+    let mut source = BackupSource::new("json");
+    source.pages = records
+        .into_iter()
+        .enumerate()
+        .map(|(index, record)| {
+            let visits = record
+                .visits
+                .iter()
+                .map(|visit| SourceVisit {
+                    date: from_micros(visit.date),
+                    visit_type: visit.visit_type,
+                    is_local: visit.is_local,
+                })
+                .collect::<Vec<_>>();
+            let typed = visits
+                .iter()
+                .filter(|visit| visit.visit_type == VisitTransition::Typed as i64)
+                .count() as u32;
+            SourcePage {
+                id: index as i64 + 1,
+                guid: record.id,
+                url: record.hist_uri,
+                title: record.title,
+                description: None,
+                preview_image_url: None,
+                hidden: false,
+                typed,
+                visits,
+            }
+        })
+        .collect();
+    run_import(db, &source, &mut |_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::apply_observation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, InsertableBookmark, InsertableFolder, InsertableItem,
+    };
+    use crate::storage::tags::{get_tags_for_url, tag_url};
+    use crate::types::SyncGuid;
+    use url::Url;
+
+    fn insert_bookmarks(conn: &PlacesDb) -> Result<()> {
+        insert_bookmark(
+            conn,
+            &InsertableItem::Folder(InsertableFolder {
+                parent_guid: BookmarkRootGuid::Toolbar.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: Some(Timestamp(1000)),
+                last_modified: Some(Timestamp(2000)),
+                guid: Some(SyncGuid("folderAAAAAA".into())),
+                title: Some("Folder".into()),
+            }),
+        )?;
+        insert_bookmark(
+            conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: SyncGuid("folderAAAAAA".into()),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some(SyncGuid("bookmarkAAAA".into())),
+                url: Url::parse("http://example.com/")?,
+                title: Some("Example".into()),
+            }),
+        )?;
+        tag_url(conn, &Url::parse("http://example.com/")?, "news")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bookmarks_round_trip() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        insert_bookmarks(&conn)?;
+        let mut json = Vec::new();
+        export_bookmarks_to_json(&conn, &mut json)?;
+
+        let value: serde_json::Value = serde_json::from_slice(&json)?;
+        assert_eq!(value["root"], "placesRoot");
+        let toolbar = &value["children"][1];
+        assert_eq!(toolbar["root"], "toolbarFolder");
+        assert_eq!(toolbar["children"][0]["type"], TYPE_FOLDER);
+        assert_eq!(toolbar["children"][0]["dateAdded"], 1_000_000);
+        let bookmark = &toolbar["children"][0]["children"][0];
+        assert_eq!(bookmark["uri"], "http://example.com/");
+        assert_eq!(bookmark["typeCode"], 1);
+        assert_eq!(bookmark["tags"], "news");
+
+        let other = PlacesDb::open_in_memory(None)?;
+        let stats = import_bookmarks_from_json(&other, json.as_slice())?;
+        assert_eq!(stats.bookmarks, 2);
+        assert_eq!(stats.tags, 1);
+        assert!(stats.errors.is_empty());
+        let tree = fetch_tree(&other, &SyncGuid("folderAAAAAA".into()))?
+            .expect("should import the folder");
+        assert_eq!(tree.date_added, Timestamp(1000));
+        assert_eq!(tree.children[0].guid, SyncGuid("bookmarkAAAA".into()));
+        assert_eq!(
+            get_tags_for_url(&other, &Url::parse("http://example.com/")?)?,
+            vec!["news".to_string()]
+        );
+
+        // Importing again doesn't duplicate anything.
+        let stats = import_bookmarks_from_json(&other, json.as_slice())?;
+        assert_eq!(stats.bookmarks, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_history_round_trip() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/")?;
+        for (at, visit_type) in &[
+            (1000, VisitTransition::Typed),
+            (2000, VisitTransition::Link),
+        ] {
+            apply_observation(
+                &mut conn,
+                VisitObservation::new(url.clone())
+                    .with_title("Example".to_string())
+                    .with_at(Timestamp(*at))
+                    .with_visit_type(*visit_type),
+            )?;
+        }
+        let mut json = Vec::new();
+        export_history_to_json(&conn, &mut json)?;
+
+        let value: serde_json::Value = serde_json::from_slice(&json)?;
+        assert_eq!(value[0]["histUri"], "http://example.com/");
+        assert_eq!(value[0]["title"], "Example");
+        assert_eq!(value[0]["visits"][0]["date"], 1_000_000);
+        assert_eq!(value[0]["visits"][0]["type"], VisitTransition::Typed as u8);
+
+        let other = PlacesDb::open_in_memory(None)?;
+        let stats = import_history_from_json(&other, json.as_slice())?;
+        assert_eq!(stats.pages, 1);
+        assert_eq!(stats.visits, 2);
+        let stats = import_history_from_json(&other, json.as_slice())?;
+        assert_eq!(stats.pages, 0);
+        assert_eq!(stats.visits, 0);
+
+        let mut exported = Vec::new();
+        export_history_to_json(&other, &mut exported)?;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&exported)?,
+            value
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Exports history and bookmarks to files, and imports them back. We support
+// two formats:
+//
+// - JSON. Bookmarks use the format of desktop's bookmark backups, so they
+//   can be restored on desktop, and history uses the format of desktop's
+//   history sync records.
+// - The Netscape bookmarks HTML format, which most browsers can import and
+//   export. This only has bookmarks.
+//
+// History exports are written as we read each page, so they don't need to
+// fit in memory. Bookmark exports read the whole tree first, and the tags of
+// every bookmarked page in one query, since both formats nest folders inside
+// their parents. Imports read the whole file, then import it like any other
+// source in `crate::import`, so they skip records we already have in the same
+// way.
+
+mod html;
+mod json;
+
+pub use self::html::{export_bookmarks_to_html, import_bookmarks_from_html};
+pub use self::json::{
+    export_bookmarks_to_json, export_history_to_json, import_bookmarks_from_json,
+    import_history_from_json,
+};
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::import::{
+    ImportPhase, ImportSource, SourceBookmark, SourceInputHistory, SourcePage, SourceTag,
+    SourceVisitLink,
+};
+use std::collections::HashMap;
+
+// Records read from a backup file. Pages and tags are identified by their
+// index in the file, starting at 1.
+struct BackupSource {
+    kind: &'static str,
+    pages: Vec<SourcePage>,
+    bookmarks: Vec<SourceBookmark>,
+    tags: Vec<SourceTag>,
+}
+
+impl BackupSource {
+    fn new(kind: &'static str) -> Self {
+        BackupSource {
+            kind,
+            pages: Vec::new(),
+            bookmarks: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    fn add_tag(&mut self, url: &str, tag: &str) {
+        let id = self.tags.len() as i64 + 1;
+        self.tags.push(SourceTag {
+            id,
+            url: url.to_string(),
+            tag: tag.to_string(),
+        });
+    }
+}
+
+// Returns the tags of every bookmarked page, keyed by URL, so that exports
+// don't need to look them up for each bookmark.
+fn fetch_bookmarked_tags(db: &PlacesDb) -> Result<HashMap<String, Vec<String>>> {
+    let mut stmt = db.prepare(
+        "SELECT h.url, t.tag
+         FROM moz_places h
+         JOIN moz_tags_relation r ON r.place_id = h.id
+         JOIN moz_tags t ON t.id = r.tag_id
+         WHERE EXISTS(SELECT 1 FROM moz_bookmarks b WHERE b.fk = h.id)
+         ORDER BY h.id, t.tag",
+    )?;
+    let mut rows = stmt.query(&[])?;
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        tags.entry(row.get_checked(0)?)
+            .or_default()
+            .push(row.get_checked(1)?);
+    }
+    Ok(tags)
+}
+
+fn records_after<T: Clone>(records: &[T], after_id: i64, limit: u32) -> Vec<T> {
+    records
+        .iter()
+        .skip(after_id.max(0) as usize)
+        .take(limit as usize)
+        .cloned()
+        .collect()
+}
+
+impl ImportSource for BackupSource {
+    fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    fn count(&self, phase: ImportPhase) -> Result<u64> {
+        Ok(match phase {
+            ImportPhase::History => self.pages.len() as u64,
+            ImportPhase::Tags => self.tags.len() as u64,
+            _ => 0,
+        })
+    }
+
+    fn fetch_pages(&self, after_id: i64, limit: u32) -> Result<Vec<SourcePage>> {
+        Ok(records_after(&self.pages, after_id, limit))
+    }
+
+    fn fetch_visit_links(&self, _after_id: i64, _limit: u32) -> Result<Vec<SourceVisitLink>> {
+        Ok(Vec::new())
+    }
+
+    fn fetch_input_history(&self, _after_id: i64, _limit: u32) -> Result<Vec<SourceInputHistory>> {
+        Ok(Vec::new())
+    }
+
+    fn fetch_bookmarks(&self) -> Result<Vec<SourceBookmark>> {
+        Ok(self.bookmarks.clone())
+    }
+
+    fn fetch_tags(&self, after_id: i64, limit: u32) -> Result<Vec<SourceTag>> {
+        Ok(records_after(&self.tags, after_id, limit))
+    }
+
+    fn is_resumable(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,233 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Stages incoming records into the mirror. Nothing here touches
+// moz_bookmarks - that happens when the merged tree is applied.
+
+use super::record::{BookmarkItemRecord, BookmarkSyncRecord};
+use super::{sync_id_to_guid, SyncedBookmarkKind};
+use crate::error::*;
+use crate::storage::bookmarks::BookmarkRootGuid;
+use crate::storage::tags::{get_or_create_tag_id, validate_tag};
+use crate::types::{SyncGuid, Timestamp};
+use crate::valid_guid::is_valid_places_guid;
+use rusqlite::Connection;
+use sql_support::ConnExt;
+use sync15::ServerTimestamp;
+use url::Url;
+
+// As with history, clamp dates to when bookmarks were invented.
+const EARLIEST_TIMESTAMP: Timestamp = Timestamp(727_747_200_000);
+
+fn clamp_date_added(date_added: Option<Timestamp>, modified: Timestamp) -> Timestamp {
+    match date_added {
+        Some(date) if date >= EARLIEST_TIMESTAMP && date <= modified => date,
+        // If we don't have a sane date, use the server modified time, which
+        // is the latest it could have been added.
+        _ => modified,
+    }
+}
+
+/// The parts of an incoming record we store in the mirror.
+struct StagedItem {
+    guid: SyncGuid,
+    parent_guid: Option<SyncGuid>,
+    kind: SyncedBookmarkKind,
+    date_added: Option<Timestamp>,
+    title: Option<String>,
+    url: Option<Url>,
+    feed_url: Option<Url>,
+    site_url: Option<Url>,
+    tags: Vec<String>,
+    children: Vec<SyncGuid>,
+}
+
+// Invalid URLs in livemarks aren't fatal - we just lose them.
+fn parse_optional_url(url: &Option<String>) -> Option<Url> {
+    url.as_ref().and_then(|u| Url::parse(u).ok())
+}
+
+// Like invalid livemark URLs, invalid tags are dropped rather than failing
+// the whole record.
+fn validate_tags(tags: &[String]) -> Vec<String> {
+    let mut valid = tags
+        .iter()
+        .filter_map(|tag| match validate_tag(tag) {
+            Ok(tag) => Some(tag.to_owned()),
+            Err(_) => {
+                log::warn!("Ignoring invalid tag {:?}", tag);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    valid.sort();
+    valid.dedup();
+    valid
+}
+
+impl StagedItem {
+    fn from_record(record: BookmarkItemRecord) -> Result<Self> {
+        let guid = sync_id_to_guid(record.id());
+        if !is_valid_places_guid(guid.as_ref()) {
+            return Err(InvalidPlaceInfo::InvalidGuid.into());
+        }
+        let parent_guid = record.parent_id().map(sync_id_to_guid);
+        let date_added = record.date_added();
+        let mut item = StagedItem {
+            guid,
+            parent_guid,
+            kind: SyncedBookmarkKind::Bookmark,
+            date_added,
+            title: None,
+            url: None,
+            feed_url: None,
+            site_url: None,
+            tags: Vec::new(),
+            children: Vec::new(),
+        };
+        match record {
+            BookmarkItemRecord::Bookmark(b) => {
+                item.title = b.title;
+                item.url = Some(Url::parse(&b.url.ok_or(InvalidPlaceInfo::NoUrl)?)?);
+                item.tags = validate_tags(&b.tags);
+            }
+            BookmarkItemRecord::Query(q) => {
+                item.kind = SyncedBookmarkKind::Query;
+                item.title = q.title;
+                item.url = Some(Url::parse(&q.url.ok_or(InvalidPlaceInfo::NoUrl)?)?);
+            }
+            BookmarkItemRecord::Folder(f) => {
+                item.kind = SyncedBookmarkKind::Folder;
+                item.title = f.title;
+                item.children = f.children.iter().map(|id| sync_id_to_guid(id)).collect();
+            }
+            BookmarkItemRecord::Livemark(l) => {
+                item.kind = SyncedBookmarkKind::Livemark;
+                item.title = l.title;
+                item.feed_url = parse_optional_url(&l.feed_url);
+                item.site_url = parse_optional_url(&l.site_url);
+            }
+            BookmarkItemRecord::Separator(_) => {
+                item.kind = SyncedBookmarkKind::Separator;
+            }
+        }
+        Ok(item)
+    }
+}
+
+// `REPLACE` gives the mirror row a new id, so we remove the tags for the old
+// one first.
+pub fn remove_mirror_tags(db: &Connection, guid: &SyncGuid) -> Result<()> {
+    db.execute_named_cached(
+        "DELETE FROM moz_bookmarks_synced_tag_relation
+         WHERE itemId = (SELECT id FROM moz_bookmarks_synced WHERE guid = :guid)",
+        &[(":guid", guid)],
+    )?;
+    Ok(())
+}
+
+fn stage_item(db: &Connection, item: &StagedItem, modified: Timestamp) -> Result<()> {
+    remove_mirror_tags(db, &item.guid)?;
+    db.execute_named_cached(
+        "REPLACE INTO moz_bookmarks_synced
+             (guid, parentGuid, serverModified, needsMerge, isDeleted, kind,
+              dateAdded, title, url, feedURL, siteURL)
+         VALUES
+             (:guid, :parentGuid, :serverModified, 1, 0, :kind,
+              :dateAdded, :title, :url, :feedURL, :siteURL)",
+        &[
+            (":guid", &item.guid),
+            (":parentGuid", &item.parent_guid),
+            (":serverModified", &modified),
+            (":kind", &item.kind),
+            (":dateAdded", &clamp_date_added(item.date_added, modified)),
+            (":title", &item.title),
+            (":url", &item.url.as_ref().map(Url::as_str)),
+            (":feedURL", &item.feed_url.as_ref().map(Url::as_str)),
+            (":siteURL", &item.site_url.as_ref().map(Url::as_str)),
+        ],
+    )?;
+    db.execute_named_cached(
+        "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+        &[(":guid", &item.guid)],
+    )?;
+    for tag in &item.tags {
+        let tag_id = get_or_create_tag_id(db, tag, modified)?;
+        db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_bookmarks_synced_tag_relation (itemId, tagId)
+             SELECT id, :tag_id FROM moz_bookmarks_synced WHERE guid = :guid",
+            &[(":tag_id", &tag_id), (":guid", &item.guid)],
+        )?;
+    }
+    for (position, child) in item.children.iter().enumerate() {
+        db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_bookmarks_synced_structure
+                 (guid, parentGuid, position)
+             VALUES (:guid, :parentGuid, :position)",
+            &[
+                (":guid", child),
+                (":parentGuid", &item.guid),
+                (":position", &(position as u32)),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn stage_tombstone(db: &Connection, guid: &SyncGuid, modified: Timestamp) -> Result<()> {
+    remove_mirror_tags(db, guid)?;
+    db.execute_named_cached(
+        "REPLACE INTO moz_bookmarks_synced
+             (guid, serverModified, needsMerge, isDeleted)
+         VALUES (:guid, :serverModified, 1, 1)",
+        &[(":guid", guid), (":serverModified", &modified)],
+    )?;
+    db.execute_named_cached(
+        "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+        &[(":guid", guid)],
+    )?;
+    Ok(())
+}
+
+/// Writes an incoming record or tombstone to the mirror. Invalid records are
+/// logged and skipped, rather than failing the sync.
+pub fn stage_incoming(
+    db: &Connection,
+    payload: sync15::Payload,
+    ts: ServerTimestamp,
+) -> Result<()> {
+    let modified = Timestamp(ts.as_millis());
+    let BookmarkSyncRecord { id, record } = match BookmarkSyncRecord::from_payload(payload) {
+        Ok(record) => record,
+        Err(e) => {
+            log::warn!("Error deserializing incoming record: {}", e);
+            return Ok(());
+        }
+    };
+    match record {
+        None => {
+            let guid = sync_id_to_guid(&id);
+            if BookmarkRootGuid::well_known(guid.as_ref()).is_some() {
+                log::warn!("Ignoring tombstone for root {:?}", guid);
+                return Ok(());
+            }
+            log::trace!("incoming: tombstone for {:?}", guid);
+            stage_tombstone(db, &guid, modified)
+        }
+        Some(record) => match StagedItem::from_record(record) {
+            Ok(item) => {
+                log::trace!("incoming: {:?} {:?}", item.kind, item.guid);
+                stage_item(db, &item, modified)
+            }
+            Err(e) => {
+                log::warn!(
+                    "incoming: record {:?} skipped because it is invalid: {}",
+                    id,
+                    e
+                );
+                Ok(())
+            }
+        },
+    }
+}
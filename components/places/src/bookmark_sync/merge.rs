@@ -0,0 +1,627 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// A structure-aware merger for the local and remote bookmark trees. This is a
+// simplified version of the algorithm desktop uses (see Dogear and
+// `SyncedBookmarksMirror.jsm`):
+//
+// * We walk both trees from the root, building a merged tree. For each item,
+//   the side which changed it wins - if both sides changed it, the newer
+//   change wins.
+// * A folder's children come from the side whose folder changed more
+//   recently, followed by any new children from the other side.
+// * An item which moved on both sides ends up in the parent chosen by the
+//   newer change.
+// * If a folder is deleted on one side, but the other side has changed
+//   descendants, those descendants are moved to the closest surviving
+//   ancestor. Unchanged descendants are deleted.
+// * New local items which match new remote items in the same folder are
+//   treated as duplicates and take the remote guid.
+// * Anything left over - items whose parents don't exist - are moved to
+//   unfiled.
+
+use super::tree::Tree;
+use crate::error::*;
+use crate::storage::bookmarks::BookmarkRootGuid;
+use crate::types::SyncGuid;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MergeState {
+    /// The local item wins, and should be uploaded.
+    Local,
+    /// The remote item wins, and should be applied locally.
+    Remote,
+    /// The item didn't change on either side.
+    Unchanged,
+}
+
+#[derive(Debug)]
+pub struct MergedNode {
+    pub guid: SyncGuid,
+    /// The guid of the local item, if there is one. This is only different
+    /// from `guid` if the local item is a duplicate of a remote one.
+    pub local_guid: Option<SyncGuid>,
+    pub merge_state: MergeState,
+    pub children: Vec<MergedNode>,
+}
+
+#[derive(Debug)]
+pub struct MergeResult {
+    pub root: MergedNode,
+    /// Items which should be deleted locally without writing tombstones.
+    pub delete_locally: HashSet<SyncGuid>,
+    /// Items we should upload tombstones for.
+    pub delete_remotely: HashSet<SyncGuid>,
+}
+
+pub struct Merger<'t> {
+    local: &'t Tree,
+    remote: &'t Tree,
+    merged: HashSet<SyncGuid>,
+    delete_locally: HashSet<SyncGuid>,
+    delete_remotely: HashSet<SyncGuid>,
+    dupes_by_local: HashMap<SyncGuid, SyncGuid>,
+    dupes_by_remote: HashMap<SyncGuid, SyncGuid>,
+}
+
+impl<'t> Merger<'t> {
+    pub fn new(local: &'t Tree, remote: &'t Tree) -> Self {
+        Merger {
+            local,
+            remote,
+            merged: HashSet::new(),
+            delete_locally: HashSet::new(),
+            delete_remotely: HashSet::new(),
+            dupes_by_local: HashMap::new(),
+            dupes_by_remote: HashMap::new(),
+        }
+    }
+
+    pub fn merge(mut self) -> Result<MergeResult> {
+        let (local, remote) = (self.local, self.remote);
+        let root_guid = BookmarkRootGuid::Root.as_guid();
+        let mut root = self.merge_node(&root_guid, Some(&root_guid), Some(&root_guid));
+
+        // Anything we haven't seen yet is an orphan - either its parent
+        // doesn't exist, or it was moved into a folder which was deleted.
+        let mut orphans = Vec::new();
+        for guid in remote.guids() {
+            if self.is_handled(guid) {
+                continue;
+            }
+            let item = remote.item(guid).expect("guids come from the tree");
+            if local.is_deleted(guid) && !item.needs_merge {
+                self.delete_remotely.insert(guid.clone());
+                continue;
+            }
+            let local_guid = local.item(guid).map(|_| guid);
+            orphans.push(self.merge_node(guid, local_guid, Some(guid)));
+        }
+        for guid in local.guids() {
+            if self.is_handled(guid) {
+                continue;
+            }
+            let item = local.item(guid).expect("guids come from the tree");
+            if remote.is_deleted(guid) && !item.needs_merge {
+                self.delete_locally.insert(guid.clone());
+                continue;
+            }
+            let remote_guid = remote.item(guid).map(|_| guid);
+            orphans.push(self.merge_node(guid, Some(guid), remote_guid));
+        }
+        if !orphans.is_empty() {
+            log::warn!("Moving {} orphaned bookmarks to unfiled", orphans.len());
+            let unfiled_guid = BookmarkRootGuid::Unfiled.as_guid();
+            // Both trees always have unfiled under the root, so this means
+            // one of them is broken.
+            let unfiled = match root
+                .children
+                .iter_mut()
+                .find(|node| node.guid == unfiled_guid)
+            {
+                Some(unfiled) => unfiled,
+                None => return Err(InvalidPlaceInfo::InvalidParent(unfiled_guid.0).into()),
+            };
+            unfiled.children.extend(orphans);
+        }
+
+        Ok(MergeResult {
+            root,
+            delete_locally: self.delete_locally,
+            delete_remotely: self.delete_remotely,
+        })
+    }
+
+    fn is_handled(&self, guid: &SyncGuid) -> bool {
+        self.merged.contains(guid)
+            || self.dupes_by_local.contains_key(guid)
+            || self.delete_locally.contains(guid)
+            || self.delete_remotely.contains(guid)
+    }
+
+    /// Returns the guid an item will have in the merged tree.
+    fn merged_guid(&self, local_guid: &SyncGuid) -> SyncGuid {
+        self.dupes_by_local
+            .get(local_guid)
+            .unwrap_or(local_guid)
+            .clone()
+    }
+
+    /// Returns true if an item which exists on both sides should go where
+    /// the local tree puts it.
+    fn local_parent_wins(&self, guid: &SyncGuid) -> bool {
+        match (self.local.item(guid), self.remote.item(guid)) {
+            (Some(local), Some(remote)) => {
+                local.needs_merge && (!remote.needs_merge || local.modified > remote.modified)
+            }
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    fn merge_node(
+        &mut self,
+        guid: &SyncGuid,
+        local_guid: Option<&SyncGuid>,
+        remote_guid: Option<&SyncGuid>,
+    ) -> MergedNode {
+        self.merged.insert(guid.clone());
+        if let Some(local_guid) = local_guid {
+            self.merged.insert(local_guid.clone());
+        }
+        let local_item = local_guid.and_then(|g| self.local.item(g));
+        let remote_item = remote_guid.and_then(|g| self.remote.item(g));
+        let merge_state = match (local_item, remote_item) {
+            // The roots themselves never change, only their children.
+            _ if BookmarkRootGuid::well_known(&guid.0).is_some() => MergeState::Unchanged,
+            (Some(local), Some(remote)) => {
+                if local.guid != remote.guid {
+                    // A dupe - the contents are the same, so take the remote
+                    // one as it's already on the server.
+                    MergeState::Remote
+                } else if !local.kind.is_compatible(remote.kind) {
+                    log::warn!(
+                        "Item {:?} is a {:?} locally and a {:?} remotely - taking remote",
+                        guid,
+                        local.kind,
+                        remote.kind
+                    );
+                    MergeState::Remote
+                } else {
+                    match (local.needs_merge, remote.needs_merge) {
+                        (true, true) if local.modified > remote.modified => MergeState::Local,
+                        (true, true) => MergeState::Remote,
+                        (true, false) => MergeState::Local,
+                        (false, true) => MergeState::Remote,
+                        (false, false) => MergeState::Unchanged,
+                    }
+                }
+            }
+            (Some(_), None) => MergeState::Local,
+            (None, Some(_)) => MergeState::Remote,
+            (None, None) => unreachable!("merging {:?}, which doesn't exist", guid),
+        };
+        let kind = match (merge_state, local_item, remote_item) {
+            (MergeState::Remote, _, Some(remote)) => remote.kind,
+            (_, Some(local), _) => local.kind,
+            (_, None, Some(remote)) => remote.kind,
+            (_, None, None) => unreachable!(),
+        };
+        let children = if kind.is_folder() {
+            self.merge_children(guid, local_guid, remote_guid)
+        } else {
+            Vec::new()
+        };
+        MergedNode {
+            guid: guid.clone(),
+            local_guid: local_guid.cloned(),
+            merge_state,
+            children,
+        }
+    }
+
+    fn merge_children(
+        &mut self,
+        guid: &SyncGuid,
+        local_guid: Option<&SyncGuid>,
+        remote_guid: Option<&SyncGuid>,
+    ) -> Vec<MergedNode> {
+        let (local, remote) = (self.local, self.remote);
+        let local_children = local_guid.map_or(&[][..], |g| local.children_of(g));
+        let remote_children = remote_guid.map_or(&[][..], |g| remote.children_of(g));
+
+        if let Some(local_guid) = local_guid {
+            for child in remote_children {
+                if local.item(child).is_some()
+                    || local.is_deleted(child)
+                    || self.dupes_by_remote.contains_key(child)
+                {
+                    continue;
+                }
+                if let Some(dupe) = self.find_local_dupe(local_guid, child) {
+                    log::debug!("Local item {:?} is a dupe of {:?}", dupe, child);
+                    self.dupes_by_local.insert(dupe.clone(), child.clone());
+                    self.dupes_by_remote.insert(child.clone(), dupe);
+                }
+            }
+        }
+
+        let remote_first = match (
+            local_guid.and_then(|g| local.item(g)),
+            remote_guid.and_then(|g| remote.item(g)),
+        ) {
+            (Some(local), Some(remote)) => {
+                remote.needs_merge && (!local.needs_merge || remote.modified >= local.modified)
+            }
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        let mut merged = Vec::with_capacity(max(local_children.len(), remote_children.len()));
+        if remote_first {
+            for child in remote_children {
+                self.merge_remote_child(guid, child, &mut merged);
+            }
+            for child in local_children {
+                self.merge_local_child(guid, child, &mut merged);
+            }
+        } else {
+            for child in local_children {
+                self.merge_local_child(guid, child, &mut merged);
+            }
+            for child in remote_children {
+                self.merge_remote_child(guid, child, &mut merged);
+            }
+        }
+        merged
+    }
+
+    fn find_local_dupe(
+        &self,
+        local_parent: &SyncGuid,
+        remote_child: &SyncGuid,
+    ) -> Option<SyncGuid> {
+        let remote_item = self.remote.item(remote_child)?;
+        let remote_content = remote_item.content.as_ref()?;
+        self.local
+            .children_of(local_parent)
+            .iter()
+            .find(|guid| {
+                if self.remote.item(guid).is_some()
+                    || self.remote.is_deleted(guid)
+                    || self.dupes_by_local.contains_key(*guid)
+                    || self.merged.contains(*guid)
+                {
+                    return false;
+                }
+                let local_item = self.local.item(guid).expect("children exist");
+                local_item.needs_merge
+                    && local_item.kind == remote_item.kind
+                    && local_item.content.as_ref() == Some(remote_content)
+            })
+            .cloned()
+    }
+
+    fn merge_remote_child(
+        &mut self,
+        parent: &SyncGuid,
+        child: &SyncGuid,
+        out: &mut Vec<MergedNode>,
+    ) {
+        let (local, remote) = (self.local, self.remote);
+        if self.merged.contains(child) {
+            return;
+        }
+        if let Some(dupe) = self.dupes_by_remote.get(child).cloned() {
+            out.push(self.merge_node(child, Some(&dupe), Some(child)));
+            return;
+        }
+        let remote_item = remote.item(child).expect("children exist");
+        if local.is_deleted(child) && !remote_item.needs_merge {
+            log::trace!("Deleting {:?} remotely", child);
+            self.delete_remotely.insert(child.clone());
+            self.relocate_remote_orphans(child, out);
+            return;
+        }
+        if local.item(child).is_none() {
+            out.push(self.merge_node(child, None, Some(child)));
+            return;
+        }
+        let local_parent = local.parent_of(child).map(|p| self.merged_guid(p));
+        if local_parent.as_ref() != Some(parent) && self.local_parent_wins(child) {
+            // It moved locally, and we'll merge it when we get to the new parent.
+            return;
+        }
+        out.push(self.merge_node(child, Some(child), Some(child)));
+    }
+
+    fn merge_local_child(
+        &mut self,
+        parent: &SyncGuid,
+        child: &SyncGuid,
+        out: &mut Vec<MergedNode>,
+    ) {
+        let (local, remote) = (self.local, self.remote);
+        if self.merged.contains(child) || self.dupes_by_local.contains_key(child) {
+            return;
+        }
+        let local_item = local.item(child).expect("children exist");
+        if remote.is_deleted(child) && !local_item.needs_merge {
+            log::trace!("Deleting {:?} locally", child);
+            self.delete_locally.insert(child.clone());
+            self.relocate_local_orphans(child, out);
+            return;
+        }
+        if remote.item(child).is_none() {
+            out.push(self.merge_node(child, Some(child), None));
+            return;
+        }
+        if let Some(remote_parent) = remote.parent_of(child) {
+            if remote_parent != parent && !self.local_parent_wins(child) {
+                // It moved remotely, and we'll merge it when we get to the new parent.
+                return;
+            }
+        }
+        out.push(self.merge_node(child, Some(child), Some(child)));
+    }
+
+    /// Called when a folder which exists locally was deleted remotely. Local
+    /// descendants which changed are moved into `out`, which is the closest
+    /// surviving ancestor; everything else is deleted.
+    fn relocate_local_orphans(&mut self, deleted: &SyncGuid, out: &mut Vec<MergedNode>) {
+        let (local, remote) = (self.local, self.remote);
+        for child in local.children_of(deleted) {
+            if self.is_handled(child) {
+                continue;
+            }
+            if let Some(remote_parent) = remote.parent_of(child) {
+                if remote_parent != deleted && !remote.is_deleted(child) {
+                    // It was moved out of the folder before it was deleted.
+                    continue;
+                }
+            }
+            let local_changed = local.item(child).map_or(false, |item| item.needs_merge);
+            let remote_item = remote.item(child);
+            if local_changed || remote_item.map_or(false, |item| item.needs_merge) {
+                log::trace!("Moving {:?} out of deleted folder {:?}", child, deleted);
+                out.push(self.merge_node(child, Some(child), remote_item.map(|_| child)));
+            } else {
+                self.delete_locally.insert(child.clone());
+                if remote_item.is_some() {
+                    self.delete_remotely.insert(child.clone());
+                }
+                self.relocate_local_orphans(child, out);
+            }
+        }
+    }
+
+    /// The opposite of `relocate_local_orphans` - called when a folder which
+    /// exists remotely was deleted locally.
+    fn relocate_remote_orphans(&mut self, deleted: &SyncGuid, out: &mut Vec<MergedNode>) {
+        let (local, remote) = (self.local, self.remote);
+        for child in remote.children_of(deleted) {
+            if self.is_handled(child) {
+                continue;
+            }
+            if let Some(local_parent) = local.parent_of(child) {
+                if local_parent != deleted && !local.is_deleted(child) {
+                    continue;
+                }
+            }
+            let remote_changed = remote.item(child).map_or(false, |item| item.needs_merge);
+            let local_item = local.item(child);
+            if remote_changed || local_item.map_or(false, |item| item.needs_merge) {
+                log::trace!("Moving {:?} out of deleted folder {:?}", child, deleted);
+                out.push(self.merge_node(child, local_item.map(|_| child), Some(child)));
+            } else {
+                self.delete_remotely.insert(child.clone());
+                if local_item.is_some() {
+                    self.delete_locally.insert(child.clone());
+                }
+                self.relocate_remote_orphans(child, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tree::{Content, Item};
+    use super::super::SyncedBookmarkKind::{self, Bookmark as B, Folder as F};
+    use super::*;
+    use crate::types::Timestamp;
+
+    fn root_item() -> Item {
+        Item::new(BookmarkRootGuid::Root.as_guid(), SyncedBookmarkKind::Folder)
+    }
+
+    /// Builds a tree with the roots, then `items` - a list of
+    /// (guid, parent, kind, modified, needs_merge).
+    fn make_tree(items: &[(&str, &str, SyncedBookmarkKind, u64, bool)]) -> Tree {
+        let mut tree = Tree::with_root(root_item());
+        let root_guid = BookmarkRootGuid::Root.as_guid();
+        for root in crate::storage::bookmarks::USER_CONTENT_ROOTS {
+            tree.insert(Item::new(root.as_guid(), SyncedBookmarkKind::Folder));
+            tree.set_parent(&root.as_guid(), &root_guid);
+        }
+        for (guid, parent, kind, modified, needs_merge) in items {
+            let mut item = Item::new(SyncGuid::from(*guid), *kind);
+            item.modified = Timestamp(*modified);
+            item.needs_merge = *needs_merge;
+            item.content = match kind {
+                SyncedBookmarkKind::Folder => Some(Content::Folder {
+                    title: guid.to_string(),
+                }),
+                _ => Some(Content::Bookmark {
+                    title: guid.to_string(),
+                    url: format!("http://example.com/{}", guid),
+                }),
+            };
+            tree.insert(item);
+            tree.set_parent(&SyncGuid::from(*guid), &SyncGuid::from(*parent));
+        }
+        tree
+    }
+
+    fn children(node: &MergedNode, guid: &str) -> Vec<String> {
+        fn find<'a>(node: &'a MergedNode, guid: &str) -> Option<&'a MergedNode> {
+            if node.guid.0 == guid {
+                return Some(node);
+            }
+            node.children
+                .iter()
+                .filter_map(|child| find(child, guid))
+                .next()
+        }
+        find(node, guid)
+            .expect("should be in the merged tree")
+            .children
+            .iter()
+            .map(|child| child.guid.0.clone())
+            .collect()
+    }
+
+    fn find_state(node: &MergedNode, guid: &str) -> Option<MergeState> {
+        if node.guid.0 == guid {
+            return Some(node.merge_state);
+        }
+        node.children
+            .iter()
+            .filter_map(|child| find_state(child, guid))
+            .next()
+    }
+
+    #[test]
+    fn test_new_items_both_sides() {
+        let local = make_tree(&[("bookmarkAAAA", "menu________", B, 10, true)]);
+        let remote = make_tree(&[("bookmarkBBBB", "menu________", B, 10, true)]);
+        let result = Merger::new(&local, &remote).merge().expect("should merge");
+        assert_eq!(
+            children(&result.root, "menu________"),
+            vec!["bookmarkAAAA", "bookmarkBBBB"]
+        );
+        assert_eq!(
+            find_state(&result.root, "bookmarkAAAA"),
+            Some(MergeState::Local)
+        );
+        assert_eq!(
+            find_state(&result.root, "bookmarkBBBB"),
+            Some(MergeState::Remote)
+        );
+        assert!(result.delete_locally.is_empty());
+        assert!(result.delete_remotely.is_empty());
+    }
+
+    #[test]
+    fn test_newer_value_wins() {
+        let local = make_tree(&[
+            ("bookmarkAAAA", "menu________", B, 10, true),
+            ("bookmarkBBBB", "menu________", B, 30, true),
+        ]);
+        let remote = make_tree(&[
+            ("bookmarkAAAA", "menu________", B, 20, true),
+            ("bookmarkBBBB", "menu________", B, 20, true),
+        ]);
+        let result = Merger::new(&local, &remote).merge().expect("should merge");
+        assert_eq!(
+            find_state(&result.root, "bookmarkAAAA"),
+            Some(MergeState::Remote)
+        );
+        assert_eq!(
+            find_state(&result.root, "bookmarkBBBB"),
+            Some(MergeState::Local)
+        );
+    }
+
+    #[test]
+    fn test_moves() {
+        // Moved locally into folderAAAAAA, and remotely into toolbar. The
+        // remote move is newer.
+        let local = make_tree(&[
+            ("folderAAAAAA", "menu________", F, 10, false),
+            ("bookmarkAAAA", "folderAAAAAA", B, 10, true),
+        ]);
+        let remote = make_tree(&[
+            ("folderAAAAAA", "menu________", F, 10, false),
+            ("bookmarkAAAA", "toolbar_____", B, 20, true),
+        ]);
+        let result = Merger::new(&local, &remote).merge().expect("should merge");
+        assert_eq!(children(&result.root, "toolbar_____"), vec!["bookmarkAAAA"]);
+        assert!(children(&result.root, "folderAAAAAA").is_empty());
+    }
+
+    #[test]
+    fn test_dupes() {
+        let local = make_tree(&[("bookmarkAAAA", "menu________", B, 10, true)]);
+        let mut remote = make_tree(&[]);
+        let mut item = Item::new(SyncGuid::from("bookmarkBBBB"), B);
+        item.needs_merge = true;
+        item.content = Some(Content::Bookmark {
+            title: "bookmarkAAAA".into(),
+            url: "http://example.com/bookmarkAAAA".into(),
+        });
+        remote.insert(item);
+        remote.set_parent(
+            &SyncGuid::from("bookmarkBBBB"),
+            &SyncGuid::from("menu________"),
+        );
+        let result = Merger::new(&local, &remote).merge().expect("should merge");
+        assert_eq!(children(&result.root, "menu________"), vec!["bookmarkBBBB"]);
+        let menu = &result.root.children[0];
+        assert_eq!(
+            menu.children[0].local_guid,
+            Some(SyncGuid::from("bookmarkAAAA"))
+        );
+    }
+
+    #[test]
+    fn test_deleted_folder_with_changed_children() {
+        // The folder was deleted remotely, but has a new local child.
+        let mut local = make_tree(&[
+            ("folderAAAAAA", "menu________", F, 10, false),
+            ("bookmarkAAAA", "folderAAAAAA", B, 10, false),
+            ("bookmarkBBBB", "folderAAAAAA", B, 10, true),
+        ]);
+        let mut remote = make_tree(&[("bookmarkAAAA", "menu________", B, 10, false)]);
+        remote.note_deleted(SyncGuid::from("folderAAAAAA"));
+        let result = Merger::new(&local, &remote).merge().expect("should merge");
+        assert_eq!(
+            children(&result.root, "menu________"),
+            vec!["bookmarkBBBB", "bookmarkAAAA"]
+        );
+        assert!(result
+            .delete_locally
+            .contains(&SyncGuid::from("folderAAAAAA")));
+
+        // And the other way around - deleted locally, with a new remote child.
+        local = make_tree(&[]);
+        local.note_deleted(SyncGuid::from("folderAAAAAA"));
+        local.note_deleted(SyncGuid::from("bookmarkAAAA"));
+        remote = make_tree(&[
+            ("folderAAAAAA", "menu________", F, 10, false),
+            ("bookmarkAAAA", "folderAAAAAA", B, 10, false),
+            ("bookmarkBBBB", "folderAAAAAA", B, 10, true),
+        ]);
+        let result = Merger::new(&local, &remote).merge().expect("should merge");
+        assert_eq!(children(&result.root, "menu________"), vec!["bookmarkBBBB"]);
+        assert!(result
+            .delete_remotely
+            .contains(&SyncGuid::from("folderAAAAAA")));
+        assert!(result
+            .delete_remotely
+            .contains(&SyncGuid::from("bookmarkAAAA")));
+    }
+
+    #[test]
+    fn test_orphans() {
+        let local = make_tree(&[]);
+        let mut remote = make_tree(&[]);
+        let mut item = Item::new(SyncGuid::from("bookmarkAAAA"), B);
+        item.needs_merge = true;
+        remote.insert(item);
+        let result = Merger::new(&local, &remote).merge().expect("should merge");
+        assert_eq!(children(&result.root, "unfiled_____"), vec!["bookmarkAAAA"]);
+    }
+}
@@ -0,0 +1,137 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::storage::bookmarks::BookmarkRootGuid;
+use crate::types::SyncGuid;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::Result as RusqliteResult;
+
+mod incoming;
+mod merge;
+pub mod record;
+pub mod store;
+mod tree;
+
+/// The kinds of items we might see in the bookmarks collection. These are the
+/// same values desktop uses for `moz_bookmarks_synced.kind`. Note that locally
+/// we only have bookmarks, folders and separators - queries are bookmarks with
+/// a `place:` URL, and livemarks are folders.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SyncedBookmarkKind {
+    Bookmark = 1,
+    Query = 2,
+    Folder = 3,
+    Livemark = 4,
+    Separator = 5,
+}
+
+impl SyncedBookmarkKind {
+    #[inline]
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(SyncedBookmarkKind::Bookmark),
+            2 => Some(SyncedBookmarkKind::Query),
+            3 => Some(SyncedBookmarkKind::Folder),
+            4 => Some(SyncedBookmarkKind::Livemark),
+            5 => Some(SyncedBookmarkKind::Separator),
+            _ => None,
+        }
+    }
+
+    /// Returns true if an item of kind `other` can be merged with an item of
+    /// this kind.
+    pub fn is_compatible(self, other: SyncedBookmarkKind) -> bool {
+        use self::SyncedBookmarkKind::*;
+        match (self, other) {
+            (Bookmark, Query) | (Query, Bookmark) => true,
+            (Folder, Livemark) | (Livemark, Folder) => true,
+            _ => self == other,
+        }
+    }
+
+    #[inline]
+    pub fn is_folder(self) -> bool {
+        self == SyncedBookmarkKind::Folder
+    }
+}
+
+impl ToSql for SyncedBookmarkKind {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for SyncedBookmarkKind {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        if v < 0 || v > i64::from(u8::max_value()) {
+            return Err(FromSqlError::OutOfRange(v));
+        }
+        SyncedBookmarkKind::from_u8(v as u8).ok_or_else(|| FromSqlError::OutOfRange(v))
+    }
+}
+
+// The roots have different ids on the server than the guids we (and desktop)
+// use locally.
+const ROOT_SYNC_IDS: &[(BookmarkRootGuid, &str)] = &[
+    (BookmarkRootGuid::Root, "places"),
+    (BookmarkRootGuid::Menu, "menu"),
+    (BookmarkRootGuid::Toolbar, "toolbar"),
+    (BookmarkRootGuid::Unfiled, "unfiled"),
+    (BookmarkRootGuid::Mobile, "mobile"),
+];
+
+/// Converts a record id from the server into a local guid.
+pub fn sync_id_to_guid(id: &str) -> SyncGuid {
+    ROOT_SYNC_IDS
+        .iter()
+        .find(|(_, sync_id)| *sync_id == id)
+        .map(|(root, _)| root.as_guid())
+        .unwrap_or_else(|| SyncGuid(id.into()))
+}
+
+/// Converts a local guid into the id used on the server.
+pub fn guid_to_sync_id(guid: &SyncGuid) -> String {
+    ROOT_SYNC_IDS
+        .iter()
+        .find(|(root, _)| root.as_str() == guid.as_ref())
+        .map(|(_, sync_id)| (*sync_id).to_string())
+        .unwrap_or_else(|| guid.0.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_ids() {
+        assert_eq!(sync_id_to_guid("menu"), BookmarkRootGuid::Menu.as_guid());
+        assert_eq!(sync_id_to_guid("places"), BookmarkRootGuid::Root.as_guid());
+        assert_eq!(
+            sync_id_to_guid("bookmarkAAAA"),
+            SyncGuid::from("bookmarkAAAA")
+        );
+        assert_eq!(
+            guid_to_sync_id(&BookmarkRootGuid::Mobile.as_guid()),
+            "mobile"
+        );
+        assert_eq!(
+            guid_to_sync_id(&SyncGuid::from("bookmarkAAAA")),
+            "bookmarkAAAA"
+        );
+    }
+
+    #[test]
+    fn test_kinds() {
+        assert!(SyncedBookmarkKind::Query.is_compatible(SyncedBookmarkKind::Bookmark));
+        assert!(SyncedBookmarkKind::Folder.is_compatible(SyncedBookmarkKind::Livemark));
+        assert!(!SyncedBookmarkKind::Folder.is_compatible(SyncedBookmarkKind::Bookmark));
+        assert_eq!(
+            SyncedBookmarkKind::from_u8(5),
+            Some(SyncedBookmarkKind::Separator)
+        );
+        assert_eq!(SyncedBookmarkKind::from_u8(0), None);
+    }
+}
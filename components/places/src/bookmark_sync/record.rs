@@ -0,0 +1,291 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// The records in the bookmarks collection. These match the records desktop
+// uploads, including the odd casing of some of the field names. Note that
+// the ids and parent ids in these records are "sync ids", so the roots use
+// their short names - see `sync_id_to_guid` and `guid_to_sync_id`.
+
+use crate::error::*;
+use crate::types::Timestamp;
+use serde_derive::*;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<Timestamp>,
+
+    #[serde(default)]
+    pub has_dupe: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(rename = "bmkUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<Timestamp>,
+
+    #[serde(default)]
+    pub has_dupe: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(rename = "bmkUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<Timestamp>,
+
+    #[serde(default)]
+    pub has_dupe: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(default)]
+    pub children: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LivemarkRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<Timestamp>,
+
+    #[serde(default)]
+    pub has_dupe: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(rename = "feedUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed_url: Option<String>,
+
+    #[serde(rename = "siteUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeparatorRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<Timestamp>,
+
+    #[serde(default)]
+    pub has_dupe: bool,
+
+    // Not used on newer clients, but can be used to detect parent-child
+    // position disagreements. Older clients use this for deduping.
+    #[serde(rename = "pos")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BookmarkItemRecord {
+    Bookmark(BookmarkRecord),
+    Query(QueryRecord),
+    Folder(FolderRecord),
+    Livemark(LivemarkRecord),
+    Separator(SeparatorRecord),
+}
+
+impl BookmarkItemRecord {
+    pub fn id(&self) -> &str {
+        match self {
+            BookmarkItemRecord::Bookmark(b) => &b.id,
+            BookmarkItemRecord::Query(q) => &q.id,
+            BookmarkItemRecord::Folder(f) => &f.id,
+            BookmarkItemRecord::Livemark(l) => &l.id,
+            BookmarkItemRecord::Separator(s) => &s.id,
+        }
+    }
+
+    pub fn parent_id(&self) -> Option<&str> {
+        match self {
+            BookmarkItemRecord::Bookmark(b) => b.parent_id.as_ref(),
+            BookmarkItemRecord::Query(q) => q.parent_id.as_ref(),
+            BookmarkItemRecord::Folder(f) => f.parent_id.as_ref(),
+            BookmarkItemRecord::Livemark(l) => l.parent_id.as_ref(),
+            BookmarkItemRecord::Separator(s) => s.parent_id.as_ref(),
+        }
+        .map(String::as_str)
+    }
+
+    pub fn date_added(&self) -> Option<Timestamp> {
+        match self {
+            BookmarkItemRecord::Bookmark(b) => b.date_added,
+            BookmarkItemRecord::Query(q) => q.date_added,
+            BookmarkItemRecord::Folder(f) => f.date_added,
+            BookmarkItemRecord::Livemark(l) => l.date_added,
+            BookmarkItemRecord::Separator(s) => s.date_added,
+        }
+    }
+}
+
+/// An incoming record or tombstone.
+#[derive(Debug)]
+pub struct BookmarkSyncRecord {
+    pub id: String,
+    pub record: Option<BookmarkItemRecord>,
+}
+
+impl BookmarkSyncRecord {
+    pub fn from_payload(payload: sync15::Payload) -> Result<Self> {
+        let id = payload.id.clone();
+        let record: Option<BookmarkItemRecord> = if payload.is_tombstone() {
+            None
+        } else {
+            let record: BookmarkItemRecord = payload.into_record()?;
+            Some(record)
+        };
+        Ok(Self { id, record })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sync15::Payload;
+
+    #[test]
+    fn test_desktop_records() -> Result<()> {
+        let payload = Payload::from_json(json!({
+            "id": "bookmarkAAAA",
+            "type": "bookmark",
+            "parentid": "menu",
+            "parentName": "Bookmarks Menu",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "A",
+            "bmkUri": "http://example.com/a",
+            "tags": ["foo"],
+            "keyword": null,
+            "hasDupe": false,
+            "loadInSidebar": false
+        }))?;
+        let record = BookmarkSyncRecord::from_payload(payload)?;
+        assert_eq!(record.id, "bookmarkAAAA");
+        match record.record {
+            Some(BookmarkItemRecord::Bookmark(b)) => {
+                assert_eq!(b.parent_id, Some("menu".to_string()));
+                assert_eq!(b.url, Some("http://example.com/a".to_string()));
+                assert_eq!(b.tags, vec!["foo".to_string()]);
+                assert_eq!(b.date_added, Some(Timestamp(1_381_542_355_843)));
+            }
+            other => panic!("unexpected record {:?}", other),
+        }
+
+        let payload = Payload::from_json(json!({
+            "id": "folderAAAAAA",
+            "type": "folder",
+            "parentid": "toolbar",
+            "title": "Folder",
+            "children": ["bookmarkAAAA", "separatorAA"]
+        }))?;
+        match BookmarkSyncRecord::from_payload(payload)?.record {
+            Some(BookmarkItemRecord::Folder(f)) => {
+                assert_eq!(f.children.len(), 2);
+            }
+            other => panic!("unexpected record {:?}", other),
+        }
+
+        let payload = Payload::from_json(json!({"id": "livemarkAAAA", "deleted": true}))?;
+        assert!(BookmarkSyncRecord::from_payload(payload)?.record.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let record = BookmarkItemRecord::Separator(SeparatorRecord {
+            id: "separatorAAA".into(),
+            parent_id: Some("unfiled".into()),
+            parent_name: None,
+            date_added: None,
+            has_dupe: false,
+            position: Some(3),
+        });
+        let payload = Payload::from_record(record.clone())?;
+        assert_eq!(payload.data["type"], "separator");
+        assert_eq!(payload.data["pos"], 3);
+        let back: BookmarkItemRecord = payload.into_record()?;
+        assert_eq!(back, record);
+        Ok(())
+    }
+}
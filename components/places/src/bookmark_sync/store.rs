@@ -0,0 +1,1092 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::incoming::{remove_mirror_tags, stage_incoming};
+use super::merge::{MergeResult, MergeState, MergedNode, Merger};
+use super::record::{
+    BookmarkItemRecord, BookmarkRecord, FolderRecord, LivemarkRecord, QueryRecord, SeparatorRecord,
+};
+use super::tree::{fetch_local_tree, fetch_remote_tree, Tree};
+use super::{guid_to_sync_id, sync_id_to_guid, SyncedBookmarkKind};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::bookmarks::{get_or_create_place_id, BookmarkRootGuid};
+use crate::storage::tags::get_tags_for_place;
+use crate::storage::{get_meta, put_meta, update_frecency, RowId};
+use crate::types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
+use rusqlite::types::{FromSql, ToSql};
+use rusqlite::{Connection, Row};
+use sql_support::ConnExt;
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::result;
+use sync15::request::CollectionRequest;
+use sync15::{
+    sync_multiple, ClientInfo, IncomingChangeset, KeyBundle, OutgoingChangeset, Payload,
+    ServerTimestamp, Store, Sync15StorageClientInit,
+};
+use url::Url;
+
+static LAST_SYNC_META_KEY: &'static str = "bookmarks_last_sync_time";
+static GLOBAL_STATE_META_KEY: &'static str = "bookmarks_global_state";
+
+pub struct BookmarksStore<'a> {
+    pub db: &'a PlacesDb,
+    pub client_info: Cell<Option<ClientInfo>>,
+}
+
+impl<'a> BookmarksStore<'a> {
+    pub fn new(db: &'a PlacesDb) -> Self {
+        Self {
+            db,
+            client_info: Cell::new(None),
+        }
+    }
+
+    fn put_meta(&self, key: &str, value: &ToSql) -> Result<()> {
+        put_meta(self, key, value)
+    }
+
+    fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
+        get_meta(self, key)
+    }
+
+    fn do_apply_incoming(&self, inbound: IncomingChangeset) -> Result<OutgoingChangeset> {
+        let timestamp = inbound.timestamp;
+        let tx = self.db.unchecked_transaction()?;
+        let num_incoming = inbound.changes.len();
+        for (payload, ts) in inbound.changes {
+            stage_incoming(self.db, payload, ts)?;
+        }
+        log::info!("incoming: staged {} records", num_incoming);
+
+        let local = fetch_local_tree(self.db)?;
+        let remote = fetch_remote_tree(self.db)?;
+        let result = Merger::new(&local, &remote).merge()?;
+        let uploads = apply_merge_result(self.db, &result, &remote)?;
+
+        let mut outgoing = OutgoingChangeset::new("bookmarks".into(), timestamp);
+        for guid in &uploads {
+            match fetch_outgoing_record(self.db, guid)? {
+                Some(record) => {
+                    log::trace!("outgoing {:?}", guid);
+                    outgoing.changes.push(Payload::from_record(record)?);
+                }
+                None => log::warn!("Can't find {:?} to upload", guid),
+            }
+        }
+        let mut stmt = self.db.prepare("SELECT guid FROM moz_bookmarks_deleted")?;
+        let tombstones = stmt
+            .query_map(&[], |row| row.get::<_, SyncGuid>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for guid in tombstones {
+            log::trace!("outgoing tombstone {:?}", guid);
+            outgoing
+                .changes
+                .push(Payload::new_tombstone(guid_to_sync_id(&guid)));
+        }
+
+        // As with history, write the timestamp now, so if we are interrupted
+        // creating outgoing changesets we don't need to re-reconcile.
+        self.put_meta(LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
+        tx.commit()?;
+        self.db.notify_history_observers();
+        log::info!("outgoing: {} records", outgoing.changes.len());
+        Ok(outgoing)
+    }
+
+    fn do_sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> Result<()> {
+        log::info!(
+            "sync completed after uploading {} records",
+            records_synced.len()
+        );
+        let tx = self.db.unchecked_transaction()?;
+        let modified = Timestamp(new_timestamp.as_millis());
+        for id in records_synced {
+            finish_uploaded_item(self.db, &sync_id_to_guid(id), modified)?;
+        }
+        self.db
+            .execute_cached("DELETE FROM temp_bookmarks_sync_uploads", &[])?;
+        // write timestamp to reflect what we just wrote.
+        self.put_meta(LAST_SYNC_META_KEY, &(new_timestamp.as_millis() as i64))?;
+        tx.commit()?;
+        self.db.notify_history_observers();
+        Ok(())
+    }
+
+    fn do_reset(&self) -> Result<()> {
+        log::info!("Resetting bookmarks store");
+        let tx = self.db.unchecked_transaction()?;
+        reset_storage(self.db)?;
+        self.put_meta(LAST_SYNC_META_KEY, &0)?;
+        tx.commit()?;
+        self.db.notify_history_observers();
+        Ok(())
+    }
+
+    fn set_global_state(&self, global_state: Option<String>) -> Result<()> {
+        let to_write = match global_state {
+            Some(ref s) => s,
+            None => "",
+        };
+        self.put_meta(GLOBAL_STATE_META_KEY, &to_write)
+    }
+
+    fn get_global_state(&self) -> Result<Option<String>> {
+        self.get_meta::<String>(GLOBAL_STATE_META_KEY)
+    }
+
+    /// A convenience wrapper around sync_multiple.
+    pub fn sync(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+    ) -> Result<()> {
+        let global_state: Cell<Option<String>> = Cell::new(self.get_global_state()?);
+        let result = sync_multiple(
+            &[self],
+            &global_state,
+            &self.client_info,
+            storage_init,
+            root_sync_key,
+        );
+        self.set_global_state(global_state.replace(None))?;
+        let failures = result?;
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            assert_eq!(failures.len(), 1);
+            let (name, err) = failures.into_iter().next().unwrap();
+            assert_eq!(name, "bookmarks");
+            Err(err.into())
+        }
+    }
+}
+
+impl<'a> ConnExt for BookmarksStore<'a> {
+    #[inline]
+    fn conn(&self) -> &Connection {
+        &self.db
+    }
+}
+
+impl<'a> Deref for BookmarksStore<'a> {
+    type Target = Connection;
+    #[inline]
+    fn deref(&self) -> &Connection {
+        &self.db
+    }
+}
+
+impl<'a> Store for BookmarksStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        "bookmarks"
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        Ok(self.do_apply_incoming(inbound)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> result::Result<(), failure::Error> {
+        Ok(self.do_sync_finished(new_timestamp, records_synced)?)
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        let since = self
+            .get_meta::<i64>(LAST_SYNC_META_KEY)?
+            .map(|millis| ServerTimestamp(millis as f64 / 1000.0))
+            .unwrap_or_default();
+        // Unlike history, we don't limit the number of records - we need all
+        // of them to build a complete tree.
+        Ok(CollectionRequest::new("bookmarks").full().newer_than(since))
+    }
+
+    fn reset(&self) -> result::Result<(), failure::Error> {
+        self.do_reset()?;
+        Ok(())
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        log::warn!("not implemented");
+        Ok(())
+    }
+}
+
+/// Applies the merged tree to moz_bookmarks, and returns the guids of the
+/// items which need to be uploaded.
+fn apply_merge_result(db: &PlacesDb, result: &MergeResult, remote: &Tree) -> Result<Vec<SyncGuid>> {
+    db.execute_all(&[
+        "CREATE TEMP TABLE IF NOT EXISTS temp_bookmarks_sync_uploads
+            (guid TEXT PRIMARY KEY,
+             changeDelta INTEGER NOT NULL)",
+        "DELETE FROM temp_bookmarks_sync_uploads",
+    ])?;
+    let mut applier = Applier {
+        db,
+        remote,
+        uploads: Vec::new(),
+        place_ids: HashSet::new(),
+    };
+    applier.apply_node(&result.root, None, 0)?;
+
+    let now = Timestamp::now();
+    for guid in &result.delete_locally {
+        log::trace!("Deleting {:?} locally", guid);
+        if let Some(place_id) = db.try_query_row(
+            "SELECT fk FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", guid)],
+            |row| row.get_checked::<_, Option<RowId>>(0),
+            true,
+        )? {
+            applier.place_ids.extend(place_id);
+        }
+        db.execute_named_cached(
+            "DELETE FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", guid)],
+        )?;
+    }
+    for guid in &result.delete_remotely {
+        db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_bookmarks_deleted (guid, dateRemoved)
+             VALUES (:guid, :now)",
+            &[(":guid", guid), (":now", &now)],
+        )?;
+    }
+    for place_id in &applier.place_ids {
+        update_frecency(db, *place_id, None)?;
+    }
+
+    // Everything in the mirror has now been merged.
+    db.execute_all(&[
+        "DELETE FROM moz_bookmarks_synced_structure
+         WHERE guid IN (SELECT guid FROM moz_bookmarks_synced WHERE isDeleted)",
+        "DELETE FROM moz_bookmarks_synced WHERE isDeleted",
+        "UPDATE moz_bookmarks_synced SET needsMerge = 0",
+    ])?;
+    Ok(applier.uploads)
+}
+
+struct Applier<'a> {
+    db: &'a Connection,
+    remote: &'a Tree,
+    uploads: Vec<SyncGuid>,
+    place_ids: HashSet<RowId>,
+}
+
+impl<'a> Applier<'a> {
+    fn apply_node(
+        &mut self,
+        node: &MergedNode,
+        parent_guid: Option<&SyncGuid>,
+        position: u32,
+    ) -> Result<()> {
+        // The roots never move, and the merger keeps the user content roots
+        // under the root, so anything else means the merged tree is broken.
+        if let Some(root) = BookmarkRootGuid::well_known(node.guid.as_ref()) {
+            let root_guid = BookmarkRootGuid::Root.as_guid();
+            let expected_parent = if root == BookmarkRootGuid::Root {
+                None
+            } else {
+                Some(&root_guid)
+            };
+            if parent_guid != expected_parent {
+                return Err(InvalidPlaceInfo::CannotUpdateRoot(node.guid.0.clone()).into());
+            }
+        }
+        if let Some(ref local_guid) = node.local_guid {
+            if *local_guid != node.guid {
+                log::trace!("Changing guid of dupe {:?} to {:?}", local_guid, node.guid);
+                self.db.execute_named_cached(
+                    "UPDATE moz_bookmarks SET guid = :new_guid WHERE guid = :old_guid",
+                    &[(":new_guid", &node.guid), (":old_guid", local_guid)],
+                )?;
+            }
+        }
+        match (node.merge_state, parent_guid) {
+            (MergeState::Remote, Some(parent_guid)) => {
+                self.apply_remote_item(&node.guid, parent_guid, position)?
+            }
+            (_, Some(parent_guid)) => {
+                self.db.execute_named_cached(
+                    "UPDATE moz_bookmarks
+                     SET parent = (SELECT id FROM moz_bookmarks WHERE guid = :parent_guid),
+                         position = :position
+                     WHERE guid = :guid",
+                    &[
+                        (":parent_guid", parent_guid),
+                        (":position", &position),
+                        (":guid", &node.guid),
+                    ],
+                )?;
+            }
+            // The root never moves.
+            (_, None) => {}
+        }
+        // In case it was deleted locally, but revived by the merge.
+        self.db.execute_named_cached(
+            "DELETE FROM moz_bookmarks_deleted WHERE guid = :guid",
+            &[(":guid", &node.guid)],
+        )?;
+
+        if self.needs_upload(node, parent_guid) {
+            self.db.execute_named_cached(
+                "UPDATE moz_bookmarks
+                 SET syncChangeCounter = MAX(syncChangeCounter, 1)
+                 WHERE guid = :guid",
+                &[(":guid", &node.guid)],
+            )?;
+            self.db.execute_named_cached(
+                "INSERT OR REPLACE INTO temp_bookmarks_sync_uploads (guid, changeDelta)
+                 SELECT guid, syncChangeCounter FROM moz_bookmarks WHERE guid = :guid",
+                &[(":guid", &node.guid)],
+            )?;
+            self.uploads.push(node.guid.clone());
+        } else {
+            self.db.execute_named_cached(
+                "UPDATE moz_bookmarks
+                 SET syncChangeCounter = 0,
+                     syncStatus = :status
+                 WHERE guid = :guid",
+                &[(":guid", &node.guid), (":status", &SyncStatus::Normal)],
+            )?;
+        }
+
+        for (position, child) in node.children.iter().enumerate() {
+            self.apply_node(child, Some(&node.guid), position as u32)?;
+        }
+        Ok(())
+    }
+
+    fn needs_upload(&self, node: &MergedNode, parent_guid: Option<&SyncGuid>) -> bool {
+        if BookmarkRootGuid::well_known(node.guid.as_ref()) == Some(BookmarkRootGuid::Root) {
+            // The root itself is never synced.
+            return false;
+        }
+        if node.merge_state == MergeState::Local || self.remote.item(&node.guid).is_none() {
+            return true;
+        }
+        if self.remote.parent_of(&node.guid) != parent_guid {
+            return true;
+        }
+        let remote_children = self.remote.children_of(&node.guid);
+        remote_children.len() != node.children.len()
+            || remote_children
+                .iter()
+                .zip(node.children.iter())
+                .any(|(remote_child, merged_child)| *remote_child != merged_child.guid)
+    }
+
+    fn apply_remote_item(
+        &mut self,
+        guid: &SyncGuid,
+        parent_guid: &SyncGuid,
+        position: u32,
+    ) -> Result<()> {
+        log::trace!("Applying remote {:?}", guid);
+        let remote = self.db.query_row_and_then_named(
+            "SELECT kind, title, url, dateAdded, serverModified
+             FROM moz_bookmarks_synced
+             WHERE guid = :guid",
+            &[(":guid", guid)],
+            RemoteItem::from_row,
+            true,
+        )?;
+        let (bookmark_type, place_id) = match remote.kind {
+            SyncedBookmarkKind::Bookmark | SyncedBookmarkKind::Query => {
+                let url = remote
+                    .url
+                    .as_ref()
+                    .expect("we don't stage bookmarks without urls");
+                let place_id = get_or_create_place_id(self.db, url)?;
+                self.place_ids.insert(place_id);
+                (BookmarkType::Bookmark, Some(place_id))
+            }
+            SyncedBookmarkKind::Folder | SyncedBookmarkKind::Livemark => {
+                (BookmarkType::Folder, None)
+            }
+            SyncedBookmarkKind::Separator => (BookmarkType::Separator, None),
+        };
+        let old_place_id = self.db.try_query_row(
+            "SELECT fk FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", guid)],
+            |row| row.get_checked::<_, Option<RowId>>(0),
+            true,
+        )?;
+        let last_modified = remote.server_modified.max(remote.date_added);
+        let params: &[(&str, &ToSql)] = &[
+            (":guid", guid),
+            (":fk", &place_id),
+            (":type", &bookmark_type),
+            (":parent_guid", parent_guid),
+            (":position", &position),
+            (":title", &remote.title),
+            (":dateAdded", &remote.date_added),
+            (":lastModified", &last_modified),
+            (":status", &SyncStatus::Normal),
+        ];
+        match old_place_id {
+            Some(old_place_id) => {
+                self.place_ids.extend(old_place_id);
+                self.db.execute_named_cached(
+                    "UPDATE moz_bookmarks
+                     SET fk = :fk,
+                         type = :type,
+                         parent = (SELECT id FROM moz_bookmarks WHERE guid = :parent_guid),
+                         position = :position,
+                         title = :title,
+                         dateAdded = :dateAdded,
+                         lastModified = :lastModified,
+                         syncStatus = :status,
+                         syncChangeCounter = 0
+                     WHERE guid = :guid",
+                    params,
+                )?;
+            }
+            None => {
+                self.db.execute_named_cached(
+                    "INSERT INTO moz_bookmarks
+                         (fk, type, parent, position, title, dateAdded, lastModified,
+                          guid, syncStatus, syncChangeCounter)
+                     VALUES
+                         (:fk, :type,
+                          (SELECT id FROM moz_bookmarks WHERE guid = :parent_guid),
+                          :position, :title, :dateAdded, :lastModified,
+                          :guid, :status, 0)",
+                    params,
+                )?;
+            }
+        }
+        if remote.kind == SyncedBookmarkKind::Bookmark {
+            if let Some(place_id) = place_id {
+                self.apply_remote_tags(guid, place_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Tags belong to the URL, so the tags on the remote bookmark replace any
+    // tags the URL has locally.
+    fn apply_remote_tags(&self, guid: &SyncGuid, place_id: RowId) -> Result<()> {
+        self.db.execute_named_cached(
+            "DELETE FROM moz_tags_relation WHERE place_id = :place_id",
+            &[(":place_id", &place_id)],
+        )?;
+        self.db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_tags_relation (tag_id, place_id)
+             SELECT r.tagId, :place_id
+             FROM moz_bookmarks_synced_tag_relation r
+             JOIN moz_bookmarks_synced s ON s.id = r.itemId
+             WHERE s.guid = :guid",
+            &[(":place_id", &place_id), (":guid", guid)],
+        )?;
+        Ok(())
+    }
+}
+
+struct RemoteItem {
+    kind: SyncedBookmarkKind,
+    title: Option<String>,
+    url: Option<Url>,
+    date_added: Timestamp,
+    server_modified: Timestamp,
+}
+
+impl RemoteItem {
+    fn from_row(row: &Row) -> Result<Self> {
+        let url = match row.get_checked::<_, Option<String>>("url")? {
+            Some(s) => Some(Url::parse(&s)?),
+            None => None,
+        };
+        Ok(RemoteItem {
+            kind: row.get_checked("kind")?,
+            title: row.get_checked("title")?,
+            url,
+            date_added: row.get_checked("dateAdded")?,
+            server_modified: row.get_checked("serverModified")?,
+        })
+    }
+}
+
+struct OutgoingItem {
+    row_id: RowId,
+    place_id: Option<RowId>,
+    bookmark_type: BookmarkType,
+    parent_guid: Option<SyncGuid>,
+    parent_title: Option<String>,
+    position: u32,
+    title: Option<String>,
+    url: Option<String>,
+    date_added: Timestamp,
+    mirror_kind: Option<SyncedBookmarkKind>,
+    feed_url: Option<String>,
+    site_url: Option<String>,
+}
+
+impl OutgoingItem {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(OutgoingItem {
+            row_id: row.get_checked("id")?,
+            place_id: row.get_checked("placeId")?,
+            bookmark_type: row.get_checked("type")?,
+            parent_guid: row.get_checked("parentGuid")?,
+            parent_title: row.get_checked("parentTitle")?,
+            position: row.get_checked("position")?,
+            title: row.get_checked("title")?,
+            url: row.get_checked("url")?,
+            date_added: row.get_checked("dateAdded")?,
+            mirror_kind: row.get_checked("mirrorKind")?,
+            feed_url: row.get_checked("feedURL")?,
+            site_url: row.get_checked("siteURL")?,
+        })
+    }
+}
+
+fn fetch_outgoing_record(db: &Connection, guid: &SyncGuid) -> Result<Option<BookmarkItemRecord>> {
+    let item = match db.try_query_row(
+        "SELECT b.id, b.fk AS placeId, b.type, p.guid AS parentGuid, p.title AS parentTitle,
+                b.position, b.title, h.url, b.dateAdded,
+                s.kind AS mirrorKind, s.feedURL, s.siteURL
+         FROM moz_bookmarks b
+         LEFT JOIN moz_bookmarks p ON p.id = b.parent
+         LEFT JOIN moz_places h ON h.id = b.fk
+         LEFT JOIN moz_bookmarks_synced s ON s.guid = b.guid
+         WHERE b.guid = :guid",
+        &[(":guid", guid)],
+        OutgoingItem::from_row,
+        true,
+    )? {
+        Some(item) => item,
+        None => return Ok(None),
+    };
+    let id = guid_to_sync_id(guid);
+    let parent_id = item.parent_guid.as_ref().map(guid_to_sync_id);
+    let date_added = Some(item.date_added);
+    Ok(Some(match item.bookmark_type {
+        BookmarkType::Bookmark => {
+            let is_query = item
+                .url
+                .as_ref()
+                .map_or(false, |url| url.starts_with("place:"));
+            if is_query {
+                BookmarkItemRecord::Query(QueryRecord {
+                    id,
+                    parent_id,
+                    parent_name: item.parent_title,
+                    date_added,
+                    has_dupe: true,
+                    title: item.title,
+                    url: item.url,
+                    folder_name: None,
+                    query_id: None,
+                })
+            } else {
+                let tags = match item.place_id {
+                    Some(place_id) => get_tags_for_place(db, place_id)?,
+                    None => Vec::new(),
+                };
+                BookmarkItemRecord::Bookmark(BookmarkRecord {
+                    id,
+                    parent_id,
+                    parent_name: item.parent_title,
+                    date_added,
+                    has_dupe: true,
+                    title: item.title,
+                    url: item.url,
+                    keyword: None,
+                    tags,
+                })
+            }
+        }
+        BookmarkType::Folder if item.mirror_kind == Some(SyncedBookmarkKind::Livemark) => {
+            BookmarkItemRecord::Livemark(LivemarkRecord {
+                id,
+                parent_id,
+                parent_name: item.parent_title,
+                date_added,
+                has_dupe: true,
+                title: item.title,
+                feed_url: item.feed_url,
+                site_url: item.site_url,
+            })
+        }
+        BookmarkType::Folder => {
+            let mut stmt = db.prepare_cached(
+                "SELECT guid FROM moz_bookmarks
+                 WHERE parent = :parent_id
+                 ORDER BY position",
+            )?;
+            let children = stmt
+                .query_map_named(&[(":parent_id", &item.row_id)], |row| {
+                    guid_to_sync_id(&row.get::<_, SyncGuid>(0))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            BookmarkItemRecord::Folder(FolderRecord {
+                id,
+                parent_id,
+                parent_name: item.parent_title,
+                date_added,
+                has_dupe: true,
+                title: item.title,
+                children,
+            })
+        }
+        BookmarkType::Separator => BookmarkItemRecord::Separator(SeparatorRecord {
+            id,
+            parent_id,
+            parent_name: item.parent_title,
+            date_added,
+            has_dupe: true,
+            position: Some(i64::from(item.position)),
+        }),
+    }))
+}
+
+/// Called for each item the server accepted. Resets the change counter, and
+/// updates the mirror to match what we uploaded.
+fn finish_uploaded_item(db: &Connection, guid: &SyncGuid, modified: Timestamp) -> Result<()> {
+    let is_tombstone = db.query_row_named(
+        "SELECT EXISTS(SELECT 1 FROM moz_bookmarks_deleted WHERE guid = :guid)",
+        &[(":guid", guid)],
+        |row| row.get::<_, bool>(0),
+    )?;
+    if is_tombstone {
+        db.execute_named_cached(
+            "DELETE FROM moz_bookmarks_deleted WHERE guid = :guid",
+            &[(":guid", guid)],
+        )?;
+        remove_mirror_tags(db, guid)?;
+        db.execute_named_cached(
+            "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+            &[(":guid", guid)],
+        )?;
+        db.execute_named_cached(
+            "DELETE FROM moz_bookmarks_synced WHERE guid = :guid",
+            &[(":guid", guid)],
+        )?;
+        return Ok(());
+    }
+
+    // If the item changed again while we were uploading, the counter will
+    // still be non-zero after this, and we'll upload it next time.
+    db.execute_named_cached(
+        &format!(
+            "UPDATE moz_bookmarks
+             SET syncChangeCounter = MAX(syncChangeCounter -
+                     IFNULL((SELECT changeDelta FROM temp_bookmarks_sync_uploads
+                             WHERE guid = :guid), 0), 0),
+                 syncStatus = {status}
+             WHERE guid = :guid",
+            status = SyncStatus::Normal as u8
+        ),
+        &[(":guid", guid)],
+    )?;
+    remove_mirror_tags(db, guid)?;
+    db.execute_named_cached(
+        &format!(
+            "REPLACE INTO moz_bookmarks_synced
+                 (guid, parentGuid, serverModified, needsMerge, isDeleted, kind,
+                  dateAdded, title, url, feedURL, siteURL)
+             SELECT b.guid, p.guid, :modified, 0, 0,
+                    CASE b.type
+                    WHEN {bookmark} THEN (
+                        CASE WHEN h.url LIKE 'place:%' THEN {query} ELSE {kind_bookmark} END
+                    )
+                    WHEN {folder} THEN (
+                        CASE WHEN s.kind = {livemark} THEN {livemark} ELSE {kind_folder} END
+                    )
+                    ELSE {separator} END,
+                    b.dateAdded, b.title, h.url, s.feedURL, s.siteURL
+             FROM moz_bookmarks b
+             LEFT JOIN moz_bookmarks p ON p.id = b.parent
+             LEFT JOIN moz_places h ON h.id = b.fk
+             LEFT JOIN moz_bookmarks_synced s ON s.guid = b.guid
+             WHERE b.guid = :guid",
+            bookmark = BookmarkType::Bookmark as u8,
+            folder = BookmarkType::Folder as u8,
+            query = SyncedBookmarkKind::Query as u8,
+            kind_bookmark = SyncedBookmarkKind::Bookmark as u8,
+            livemark = SyncedBookmarkKind::Livemark as u8,
+            kind_folder = SyncedBookmarkKind::Folder as u8,
+            separator = SyncedBookmarkKind::Separator as u8,
+        ),
+        &[(":guid", guid), (":modified", &modified)],
+    )?;
+    db.execute_named_cached(
+        "INSERT INTO moz_bookmarks_synced_tag_relation (itemId, tagId)
+         SELECT s.id, r.tag_id
+         FROM moz_bookmarks_synced s
+         JOIN moz_bookmarks b ON b.guid = s.guid
+         JOIN moz_tags_relation r ON r.place_id = b.fk
+         WHERE s.guid = :guid",
+        &[(":guid", guid)],
+    )?;
+    db.execute_named_cached(
+        "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+        &[(":guid", guid)],
+    )?;
+    db.execute_named_cached(
+        "INSERT INTO moz_bookmarks_synced_structure (guid, parentGuid, position)
+         SELECT c.guid, b.guid, c.position
+         FROM moz_bookmarks c
+         JOIN moz_bookmarks b ON b.id = c.parent
+         WHERE b.guid = :guid",
+        &[(":guid", guid)],
+    )?;
+    Ok(())
+}
+
+/// Forgets everything we know about the server, so that the next sync is
+/// treated as a first sync.
+fn reset_storage(db: &Connection) -> Result<()> {
+    db.execute_all(&[
+        &format!(
+            "UPDATE moz_bookmarks
+             SET syncChangeCounter = 1,
+                 syncStatus = {}",
+            SyncStatus::New as u8
+        ),
+        "DELETE FROM moz_bookmarks_deleted",
+        "DELETE FROM moz_bookmarks_synced_tag_relation",
+        "DELETE FROM moz_bookmarks_synced_structure",
+        "DELETE FROM moz_bookmarks_synced",
+    ])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::bookmarks::*;
+    use crate::api::tags::{get_tags_for_url, tag_url, untag_url};
+    use crate::db::PlacesDb;
+    use serde_json::{json, Value};
+
+    fn incoming(records: Vec<Value>) -> IncomingChangeset {
+        let now = ServerTimestamp(Timestamp::now().0 as f64 / 1000.0);
+        let mut changeset = IncomingChangeset::new("bookmarks".into(), now);
+        changeset.changes = records
+            .into_iter()
+            .map(|json| (Payload::from_json(json).expect("valid payload"), now))
+            .collect();
+        changeset
+    }
+
+    fn outgoing_ids(outgoing: &OutgoingChangeset) -> Vec<String> {
+        let mut ids = outgoing
+            .changes
+            .iter()
+            .map(|payload| payload.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    fn sync_all(store: &BookmarksStore, records: Vec<Value>) -> Result<Vec<String>> {
+        let outgoing = store.do_apply_incoming(incoming(records))?;
+        let ids = outgoing_ids(&outgoing);
+        store.do_sync_finished(ServerTimestamp(Timestamp::now().0 as f64 / 1000.0), &ids)?;
+        Ok(ids)
+    }
+
+    #[test]
+    fn test_incoming_tree() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let store = BookmarksStore::new(&conn);
+        let outgoing = store.do_apply_incoming(incoming(vec![
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "title": "Bookmarks Menu",
+                "children": ["folderAAAAAA", "bookmarkBBBB"],
+            }),
+            json!({
+                "id": "folderAAAAAA",
+                "type": "folder",
+                "parentid": "menu",
+                "title": "A folder",
+                "children": ["separatorCCC"],
+            }),
+            json!({
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "menu",
+                "title": "B",
+                "bmkUri": "http://example.com/b",
+            }),
+            json!({
+                "id": "separatorCCC",
+                "type": "separator",
+                "parentid": "folderAAAAAA",
+            }),
+            json!({
+                "id": "livemarkDDDD",
+                "type": "livemark",
+                "parentid": "toolbar",
+                "title": "D",
+                "feedUri": "http://example.com/feed",
+            }),
+        ]))?;
+
+        let menu = fetch_tree(&conn, &BookmarkRootGuid::Menu.as_guid())?.expect("has menu");
+        assert_eq!(menu.children.len(), 2);
+        assert_eq!(menu.children[0].guid, SyncGuid::from("folderAAAAAA"));
+        assert_eq!(
+            menu.children[0].children[0].node_type,
+            BookmarkType::Separator
+        );
+        assert_eq!(menu.children[1].guid, SyncGuid::from("bookmarkBBBB"));
+        assert_eq!(
+            menu.children[1].url,
+            Some(Url::parse("http://example.com/b").unwrap())
+        );
+        // Livemarks are stored locally as folders.
+        let toolbar =
+            fetch_tree(&conn, &BookmarkRootGuid::Toolbar.as_guid())?.expect("has toolbar");
+        assert_eq!(toolbar.children[0].node_type, BookmarkType::Folder);
+        // Everything came from the server, so there's nothing to upload.
+        assert!(outgoing.changes.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_incoming_moved_roots() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let store = BookmarksStore::new(&conn);
+        store.do_apply_incoming(incoming(vec![
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "title": "Bookmarks Menu",
+                "children": ["unfiled", "places", "bookmarkAAAA"],
+            }),
+            json!({
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "menu",
+                "title": "Other Bookmarks",
+                "children": [],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "menu",
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+            }),
+            // An orphan, which the merger moves to unfiled.
+            json!({
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "folderCCCCCC",
+                "title": "B",
+                "bmkUri": "http://example.com/b",
+            }),
+        ]))?;
+
+        // The roots stay where they are.
+        let root = fetch_tree(&conn, &BookmarkRootGuid::Root.as_guid())?.expect("has root");
+        assert_eq!(
+            root.children
+                .iter()
+                .map(|child| child.guid.clone())
+                .collect::<Vec<_>>(),
+            USER_CONTENT_ROOTS
+                .iter()
+                .map(|root| root.as_guid())
+                .collect::<Vec<_>>()
+        );
+        let menu = fetch_tree(&conn, &BookmarkRootGuid::Menu.as_guid())?.expect("has menu");
+        assert_eq!(menu.children.len(), 1);
+        assert_eq!(menu.children[0].guid, SyncGuid::from("bookmarkAAAA"));
+        let unfiled =
+            fetch_tree(&conn, &BookmarkRootGuid::Unfiled.as_guid())?.expect("has unfiled");
+        assert_eq!(unfiled.children.len(), 1);
+        assert_eq!(unfiled.children[0].guid, SyncGuid::from("bookmarkBBBB"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_changes() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let store = BookmarksStore::new(&conn);
+        let guid = insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some(SyncGuid::from("bookmarkAAAA")),
+                url: Url::parse("http://example.com/a").unwrap(),
+                title: Some("A".into()),
+            }),
+        )?;
+        assert_eq!(sync_all(&store, vec![])?, vec!["bookmarkAAAA", "unfiled"]);
+        // Nothing changed, so nothing to upload.
+        assert!(sync_all(&store, vec![])?.is_empty());
+
+        // A local deletion uploads a tombstone, and the new parent.
+        delete_bookmark(&conn, &guid)?;
+        let outgoing = store.do_apply_incoming(incoming(vec![]))?;
+        assert_eq!(outgoing_ids(&outgoing), vec!["bookmarkAAAA", "unfiled"]);
+        let tombstone = outgoing
+            .changes
+            .iter()
+            .find(|payload| payload.id == "bookmarkAAAA")
+            .unwrap();
+        assert!(tombstone.is_tombstone());
+        Ok(())
+    }
+
+    #[test]
+    fn test_incoming_tombstone() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let store = BookmarksStore::new(&conn);
+        sync_all(
+            &store,
+            vec![
+                json!({
+                    "id": "unfiled",
+                    "type": "folder",
+                    "parentid": "places",
+                    "children": ["bookmarkAAAA"],
+                }),
+                json!({
+                    "id": "bookmarkAAAA",
+                    "type": "bookmark",
+                    "parentid": "unfiled",
+                    "bmkUri": "http://example.com/a",
+                }),
+            ],
+        )?;
+        assert!(fetch_bookmark(&conn, &SyncGuid::from("bookmarkAAAA"))?.is_some());
+
+        let uploaded = sync_all(&store, vec![json!({"id": "bookmarkAAAA", "deleted": true})])?;
+        assert!(fetch_bookmark(&conn, &SyncGuid::from("bookmarkAAAA"))?.is_none());
+        // We shouldn't write a local tombstone for a remote deletion.
+        assert!(!uploaded.contains(&"bookmarkAAAA".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dupe_new_local() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let store = BookmarksStore::new(&conn);
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Menu.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some(SyncGuid::from("bookmarkAAAA")),
+                url: Url::parse("http://example.com/a").unwrap(),
+                title: Some("A".into()),
+            }),
+        )?;
+        let uploaded = sync_all(
+            &store,
+            vec![
+                json!({
+                    "id": "menu",
+                    "type": "folder",
+                    "parentid": "places",
+                    "children": ["bookmarkBBBB"],
+                }),
+                json!({
+                    "id": "bookmarkBBBB",
+                    "type": "bookmark",
+                    "parentid": "menu",
+                    "title": "A",
+                    "bmkUri": "http://example.com/a",
+                }),
+            ],
+        )?;
+        assert!(fetch_bookmark(&conn, &SyncGuid::from("bookmarkAAAA"))?.is_none());
+        assert!(fetch_bookmark(&conn, &SyncGuid::from("bookmarkBBBB"))?.is_some());
+        assert!(!uploaded.contains(&"bookmarkAAAA".to_string()));
+        assert!(!uploaded.contains(&"menu".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let store = BookmarksStore::new(&conn);
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Separator(InsertableSeparator {
+                parent_guid: BookmarkRootGuid::Mobile.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some(SyncGuid::from("separatorAAA")),
+            }),
+        )?;
+        assert_eq!(sync_all(&store, vec![])?, vec!["mobile", "separatorAAA"]);
+        store.do_reset()?;
+        // After a reset, everything is uploaded again.
+        assert_eq!(sync_all(&store, vec![])?, vec!["mobile", "separatorAAA"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tags() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let store = BookmarksStore::new(&conn);
+        sync_all(
+            &store,
+            vec![
+                json!({
+                    "id": "toolbar",
+                    "type": "folder",
+                    "parentid": "places",
+                    "children": ["bookmarkAAAA"],
+                }),
+                json!({
+                    "id": "bookmarkAAAA",
+                    "type": "bookmark",
+                    "parentid": "toolbar",
+                    "bmkUri": "http://example.com/a",
+                    "tags": ["foo", "bar", " foo ", ""],
+                }),
+            ],
+        )?;
+        let url = Url::parse("http://example.com/a")?;
+        assert_eq!(get_tags_for_url(&conn, &url)?, vec!["bar", "foo"]);
+
+        // Changing the tags locally uploads the bookmark.
+        untag_url(&conn, &url, "bar")?;
+        tag_url(&conn, &url, "baz")?;
+        let outgoing = store.do_apply_incoming(incoming(vec![]))?;
+        assert_eq!(outgoing_ids(&outgoing), vec!["bookmarkAAAA"]);
+        assert_eq!(outgoing.changes[0].data["tags"], json!(["baz", "foo"]));
+        store.do_sync_finished(
+            ServerTimestamp(Timestamp::now().0 as f64 / 1000.0),
+            &outgoing_ids(&outgoing),
+        )?;
+        assert!(sync_all(&store, vec![])?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// In-memory trees used by the merger. We build one from moz_bookmarks (the
+// "local" tree) and one from the mirror (the "remote" tree).
+
+use super::SyncedBookmarkKind;
+use crate::error::*;
+use crate::storage::bookmarks::{BookmarkRootGuid, USER_CONTENT_ROOTS};
+use crate::types::{BookmarkType, SyncGuid, Timestamp};
+use rusqlite::{Connection, Row};
+use std::collections::{HashMap, HashSet};
+
+/// The content of an item, used to find duplicates when a new local item
+/// matches a new remote item with a different guid.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    Bookmark { title: String, url: String },
+    Folder { title: String },
+    Separator { position: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub guid: SyncGuid,
+    pub kind: SyncedBookmarkKind,
+    /// When the item was last changed. For local items this is
+    /// `lastModified`, for remote items the server's modified time.
+    pub modified: Timestamp,
+    /// True if the item has changes which haven't been merged yet.
+    pub needs_merge: bool,
+    pub content: Option<Content>,
+}
+
+impl Item {
+    pub fn new(guid: SyncGuid, kind: SyncedBookmarkKind) -> Item {
+        Item {
+            guid,
+            kind,
+            modified: Timestamp(0),
+            needs_merge: false,
+            content: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Tree {
+    items: HashMap<SyncGuid, Item>,
+    parents: HashMap<SyncGuid, SyncGuid>,
+    children: HashMap<SyncGuid, Vec<SyncGuid>>,
+    deletions: HashSet<SyncGuid>,
+}
+
+impl Tree {
+    /// Creates a tree with just the root.
+    pub fn with_root(root: Item) -> Tree {
+        let mut items = HashMap::new();
+        items.insert(root.guid.clone(), root);
+        Tree {
+            items,
+            parents: HashMap::new(),
+            children: HashMap::new(),
+            deletions: HashSet::new(),
+        }
+    }
+
+    /// Adds an item without a parent. These are orphans unless they are
+    /// later attached with `set_parent`.
+    pub fn insert(&mut self, item: Item) {
+        self.items.insert(item.guid.clone(), item);
+    }
+
+    /// Appends `child` to the children of `parent`. Both must already exist
+    /// and `child` must not already have a parent.
+    pub fn set_parent(&mut self, child: &SyncGuid, parent: &SyncGuid) {
+        debug_assert!(self.items.contains_key(child) && self.items.contains_key(parent));
+        debug_assert!(!self.parents.contains_key(child));
+        self.parents.insert(child.clone(), parent.clone());
+        self.children
+            .entry(parent.clone())
+            .or_insert_with(Vec::new)
+            .push(child.clone());
+    }
+
+    pub fn note_deleted(&mut self, guid: SyncGuid) {
+        self.deletions.insert(guid);
+    }
+
+    #[inline]
+    pub fn item(&self, guid: &SyncGuid) -> Option<&Item> {
+        self.items.get(guid)
+    }
+
+    #[inline]
+    pub fn has_parent(&self, guid: &SyncGuid) -> bool {
+        self.parents.contains_key(guid)
+    }
+
+    #[inline]
+    pub fn parent_of(&self, guid: &SyncGuid) -> Option<&SyncGuid> {
+        self.parents.get(guid)
+    }
+
+    #[inline]
+    pub fn children_of(&self, guid: &SyncGuid) -> &[SyncGuid] {
+        self.children.get(guid).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    #[inline]
+    pub fn is_deleted(&self, guid: &SyncGuid) -> bool {
+        self.deletions.contains(guid)
+    }
+
+    /// All item guids, sorted so that anything iterating them (eg, to find
+    /// orphans) does so in a stable order.
+    pub fn guids(&self) -> Vec<&SyncGuid> {
+        let mut guids = self.items.keys().collect::<Vec<_>>();
+        guids.sort_by(|a, b| a.0.cmp(&b.0));
+        guids
+    }
+}
+
+fn local_item_from_row(row: &Row) -> Result<(Item, Option<SyncGuid>)> {
+    let guid: SyncGuid = row.get_checked("guid")?;
+    let url: Option<String> = row.get_checked("url")?;
+    let title = row
+        .get_checked::<_, Option<String>>("title")?
+        .unwrap_or_default();
+    let (kind, content) = match row.get_checked::<_, BookmarkType>("type")? {
+        BookmarkType::Bookmark => {
+            let url = url.unwrap_or_default();
+            let kind = if url.starts_with("place:") {
+                SyncedBookmarkKind::Query
+            } else {
+                SyncedBookmarkKind::Bookmark
+            };
+            (kind, Content::Bookmark { title, url })
+        }
+        BookmarkType::Folder => (SyncedBookmarkKind::Folder, Content::Folder { title }),
+        BookmarkType::Separator => (
+            SyncedBookmarkKind::Separator,
+            Content::Separator {
+                position: row.get_checked("position")?,
+            },
+        ),
+    };
+    let item = Item {
+        guid,
+        kind,
+        modified: row.get_checked("lastModified")?,
+        needs_merge: row.get_checked::<_, u32>("syncChangeCounter")? > 0,
+        content: Some(content),
+    };
+    Ok((item, row.get_checked("parentGuid")?))
+}
+
+/// Builds the local tree from moz_bookmarks and moz_bookmarks_deleted.
+pub fn fetch_local_tree(db: &Connection) -> Result<Tree> {
+    let root_guid = BookmarkRootGuid::Root.as_guid();
+    let mut tree = Tree::with_root(Item::new(root_guid.clone(), SyncedBookmarkKind::Folder));
+    let mut stmt = db.prepare(
+        "SELECT b.guid, p.guid AS parentGuid, b.type, b.position, b.title,
+                h.url, b.lastModified, b.syncChangeCounter
+         FROM moz_bookmarks b
+         LEFT JOIN moz_bookmarks p ON p.id = b.parent
+         LEFT JOIN moz_places h ON h.id = b.fk
+         WHERE b.guid <> :root_guid
+         ORDER BY b.parent, b.position",
+    )?;
+    let rows = stmt
+        .query_and_then_named(&[(":root_guid", &root_guid)], local_item_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    let mut parents = Vec::with_capacity(rows.len());
+    for (item, parent_guid) in rows {
+        if let Some(parent_guid) = parent_guid {
+            parents.push((item.guid.clone(), parent_guid));
+        }
+        tree.insert(item);
+    }
+    // The rows are ordered by position, so children are attached in order.
+    for (child, parent) in &parents {
+        tree.set_parent(child, parent);
+    }
+
+    let mut stmt = db.prepare("SELECT guid FROM moz_bookmarks_deleted")?;
+    let deleted = stmt
+        .query_map(&[], |row| row.get::<_, SyncGuid>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for guid in deleted {
+        tree.note_deleted(guid);
+    }
+    Ok(tree)
+}
+
+fn remote_item_from_row(row: &Row) -> Result<(Item, Option<SyncGuid>)> {
+    let guid: SyncGuid = row.get_checked("guid")?;
+    let kind = match SyncedBookmarkKind::from_u8(row.get_checked("kind")?) {
+        Some(kind) => kind,
+        // We validate the kind when staging, so this shouldn't happen.
+        None => return Err(InvalidPlaceInfo::IllegalChange(guid.0).into()),
+    };
+    let title = row
+        .get_checked::<_, Option<String>>("title")?
+        .unwrap_or_default();
+    let content = match kind {
+        SyncedBookmarkKind::Bookmark | SyncedBookmarkKind::Query => Some(Content::Bookmark {
+            title,
+            url: row
+                .get_checked::<_, Option<String>>("url")?
+                .unwrap_or_default(),
+        }),
+        SyncedBookmarkKind::Folder => Some(Content::Folder { title }),
+        // We only know a separator's position once it's in the tree.
+        SyncedBookmarkKind::Separator | SyncedBookmarkKind::Livemark => None,
+    };
+    let item = Item {
+        guid,
+        kind,
+        modified: row.get_checked("serverModified")?,
+        needs_merge: row.get_checked("needsMerge")?,
+        content,
+    };
+    Ok((item, row.get_checked("parentGuid")?))
+}
+
+/// Builds the remote tree from the mirror. The structure table is
+/// authoritative - an item listed in a folder's children is a child of that
+/// folder even if its `parentid` disagrees. Items which aren't in any folder
+/// are attached to their `parentid` if it's a folder we know about, otherwise
+/// they are left as orphans for the merger to deal with.
+pub fn fetch_remote_tree(db: &Connection) -> Result<Tree> {
+    let root_guid = BookmarkRootGuid::Root.as_guid();
+    let mut tree = Tree::with_root(Item::new(root_guid.clone(), SyncedBookmarkKind::Folder));
+
+    let mut stmt = db.prepare(
+        "SELECT guid, parentGuid, serverModified, needsMerge, kind, title, url
+         FROM moz_bookmarks_synced
+         WHERE NOT isDeleted AND guid <> :root_guid",
+    )?;
+    let rows = stmt
+        .query_and_then_named(&[(":root_guid", &root_guid)], remote_item_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    let mut parent_ids = HashMap::with_capacity(rows.len());
+    for (item, parent_guid) in rows {
+        if let Some(parent_guid) = parent_guid {
+            parent_ids.insert(item.guid.clone(), parent_guid);
+        }
+        tree.insert(item);
+    }
+
+    // The user content roots always exist, even if the server doesn't have
+    // them yet, and always live directly under the root, wherever the server
+    // says they are.
+    for root in USER_CONTENT_ROOTS {
+        let guid = root.as_guid();
+        if tree.item(&guid).is_none() {
+            tree.insert(Item::new(guid.clone(), SyncedBookmarkKind::Folder));
+        }
+        parent_ids.remove(&guid);
+        tree.set_parent(&guid, &root_guid);
+    }
+
+    let mut stmt = db.prepare(
+        "SELECT parentGuid, guid FROM moz_bookmarks_synced_structure
+         ORDER BY parentGuid, position",
+    )?;
+    let structure = stmt
+        .query_map(&[], |row| {
+            (row.get::<_, SyncGuid>(0), row.get::<_, SyncGuid>(1))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (parent, child) in &structure {
+        let is_folder = tree.item(parent).map_or(false, |p| p.kind.is_folder());
+        // Skip children we don't have records for, children listed in more
+        // than one folder, and roots, which can't be moved.
+        let is_root = BookmarkRootGuid::well_known(child.as_ref()).is_some();
+        if is_folder && !is_root && tree.item(child).is_some() && !tree.has_parent(child) {
+            tree.set_parent(child, parent);
+        }
+    }
+    // Now the items which no folder claims, in a stable order.
+    let mut unclaimed = parent_ids
+        .into_iter()
+        .filter(|(guid, _)| !tree.has_parent(guid))
+        .collect::<Vec<_>>();
+    unclaimed.sort_by(|a, b| (a.0).0.cmp(&(b.0).0));
+    for (guid, parent) in unclaimed {
+        let is_folder = tree.item(&parent).map_or(false, |p| p.kind.is_folder());
+        if is_folder && guid != parent {
+            tree.set_parent(&guid, &parent);
+        }
+    }
+
+    // Now we know where the separators are.
+    let separator_positions = tree
+        .items
+        .values()
+        .filter(|item| item.kind == SyncedBookmarkKind::Separator)
+        .filter_map(|item| {
+            let parent = tree.parent_of(&item.guid)?;
+            let position = tree
+                .children_of(parent)
+                .iter()
+                .position(|child| *child == item.guid)?;
+            Some((item.guid.clone(), position as u32))
+        })
+        .collect::<Vec<_>>();
+    for (guid, position) in separator_positions {
+        if let Some(item) = tree.items.get_mut(&guid) {
+            item.content = Some(Content::Separator { position });
+        }
+    }
+
+    let mut stmt = db.prepare(
+        "SELECT guid FROM moz_bookmarks_synced
+         WHERE isDeleted AND needsMerge",
+    )?;
+    let deleted = stmt
+        .query_map(&[], |row| row.get::<_, SyncGuid>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for guid in deleted {
+        tree.note_deleted(guid);
+    }
+    Ok(tree)
+}
@@ -8,19 +8,43 @@
 
 use super::schema;
 use crate::error::*;
+use crate::frecency::FrecencySettings;
 use crate::hash;
 use rusqlite::{self, Connection};
 use sql_support::{self, ConnExt};
+use std::cell::{Cell, RefCell};
 use std::ops::Deref;
 use std::path::Path;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
+use crate::api::history::{RecentEvents, UrlFilter};
 use crate::api::matcher::{split_after_host_and_port, split_after_prefix};
-use crate::match_impl::{AutocompleteMatch, MatchBehavior, SearchBehavior};
+use crate::match_impl::{
+    search_index_text, AutocompleteMatch, MatchBehavior, MatchFolding, SearchBehavior,
+};
+use crate::observer::{self, HistoryObserver, HistoryObservers};
 
 pub const MAX_VARIABLE_NUMBER: usize = 999;
 
 pub struct PlacesDb {
     pub db: Connection,
+    // The recently visited and recent event caches used by `visit_uri`.
+    pub(crate) recent_events: RecentEvents,
+    // The URL filter used by `can_add_url`, loaded the first time it's
+    // needed.
+    pub(crate) url_filter: RefCell<Option<UrlFilter>>,
+    // The settings used to calculate frecencies, loaded the first time
+    // they're needed.
+    pub(crate) frecency_settings: RefCell<Option<FrecencySettings>>,
+    // Whether the search index exists. See `storage::search_index`.
+    pub(crate) search_index_enabled: Cell<bool>,
+    // Bumped every time an interrupt handle interrupts the connection. See
+    // `InterruptScope`.
+    interrupt_counter: Arc<AtomicUsize>,
+    history_observers: HistoryObservers,
 }
 
 impl PlacesDb {
@@ -74,7 +98,15 @@ impl PlacesDb {
 
         db.execute_batch(&initial_pragmas)?;
         define_functions(&db)?;
-        let mut res = Self { db };
+        let mut res = Self {
+            db,
+            recent_events: RecentEvents::default(),
+            url_filter: RefCell::new(None),
+            frecency_settings: RefCell::new(None),
+            search_index_enabled: Cell::new(false),
+            interrupt_counter: Arc::new(AtomicUsize::new(0)),
+            history_observers: HistoryObservers::default(),
+        };
         schema::init(&mut res)?;
 
         Ok(res)
@@ -93,6 +125,109 @@ impl PlacesDb {
             encryption_key,
         )?)
     }
+
+    /// Returns a handle that can be used to interrupt operations on this
+    /// connection from another thread.
+    pub fn new_interrupt_handle(&self) -> PlacesInterruptHandle {
+        PlacesInterruptHandle {
+            db_handle: self.db.get_interrupt_handle(),
+            interrupt_counter: self.interrupt_counter.clone(),
+        }
+    }
+
+    /// Starts an interruptible operation. Interrupts that happened before
+    /// this is called don't affect the operation.
+    pub(crate) fn begin_interrupt_scope(&self) -> InterruptScope {
+        InterruptScope {
+            start_value: self.interrupt_counter.load(Ordering::SeqCst),
+            interrupt_counter: self.interrupt_counter.clone(),
+        }
+    }
+
+    /// Registers an observer that's called with the changes to history made
+    /// by each transaction, after it commits. Returns an id that can be
+    /// passed to `unregister_history_observer`.
+    pub fn register_history_observer(&mut self, observer: HistoryObserver) -> Result<u64> {
+        if self.history_observers.is_empty() {
+            observer::start_recording(&self.db)?;
+        }
+        Ok(self.history_observers.add(observer))
+    }
+
+    /// Unregisters an observer. Returns false if there's no observer with
+    /// this id.
+    pub fn unregister_history_observer(&mut self, id: u64) -> Result<bool> {
+        if !self.history_observers.remove(id) {
+            return Ok(false);
+        }
+        if self.history_observers.is_empty() {
+            observer::stop_recording(&self.db)?;
+        }
+        Ok(true)
+    }
+
+    /// Calls the history observers with the changes made since the last
+    /// call. `in_transaction` calls this after every transaction, so only
+    /// functions that manage their own transactions, or write without one,
+    /// need to call it, after they commit. This does nothing inside a
+    /// transaction, since its changes might still be rolled back; they're
+    /// reported once the transaction that contains them commits. The changes
+    /// are already committed, so errors are logged instead of returned.
+    pub(crate) fn notify_history_observers(&self) {
+        if self.history_observers.is_empty() || !self.db.is_autocommit() {
+            return;
+        }
+        match observer::take_events(&self.db) {
+            Ok(events) => {
+                if !events.is_empty() {
+                    self.history_observers.notify(&events);
+                }
+            }
+            Err(e) => log::error!("Failed to read history changes for observers: {}", e),
+        }
+    }
+}
+
+/// Interrupts long-running operations, like autocomplete searches, on a
+/// `PlacesDb`. Unlike the connection, this can be sent to and used from any
+/// thread.
+pub struct PlacesInterruptHandle {
+    db_handle: rusqlite::InterruptHandle,
+    interrupt_counter: Arc<AtomicUsize>,
+}
+
+impl PlacesInterruptHandle {
+    /// Interrupts the operation that's currently running on the connection,
+    /// which fails with `ErrorKind::InterruptedError`. Does nothing if the
+    /// connection is idle. Any statement can be interrupted, including
+    /// writes, which are rolled back along with their transaction.
+    pub fn interrupt(&self) {
+        self.interrupt_counter.fetch_add(1, Ordering::SeqCst);
+        self.db_handle.interrupt();
+    }
+}
+
+/// Tracks if an operation was interrupted. SQLite only interrupts statements
+/// that are running when `sqlite3_interrupt` is called, so operations that
+/// run several statements also need to check this between statements.
+pub(crate) struct InterruptScope {
+    start_value: usize,
+    interrupt_counter: Arc<AtomicUsize>,
+}
+
+impl InterruptScope {
+    #[inline]
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupt_counter.load(Ordering::SeqCst) != self.start_value
+    }
+
+    #[inline]
+    pub fn err_if_interrupted(&self) -> Result<()> {
+        if self.was_interrupted() {
+            return Err(ErrorKind::InterruptedError.into());
+        }
+        Ok(())
+    }
 }
 
 impl Drop for PlacesDb {
@@ -110,6 +245,19 @@ impl ConnExt for PlacesDb {
     fn conn(&self) -> &Connection {
         &self.db
     }
+
+    // Almost everything that changes history runs in a transaction, so we
+    // tell the history observers about the changes here, once they're
+    // committed.
+    fn in_transaction<T, E, F>(&self, f: F) -> std::result::Result<T, E>
+    where
+        E: From<rusqlite::Error>,
+        F: FnOnce() -> std::result::Result<T, E>,
+    {
+        let result = self.db.in_transaction(f);
+        self.notify_history_observers();
+        result
+    }
 }
 
 impl Deref for PlacesDb {
@@ -149,7 +297,7 @@ fn define_functions(c: &Connection) -> Result<()> {
             .map_err(|err| rusqlite::Error::UserFunctionError(err.into()))?;
         Ok(rev_host)
     })?;
-    c.create_scalar_function("autocomplete_match", 10, true, move |ctx| {
+    c.create_scalar_function("autocomplete_match", 11, true, move |ctx| {
         // Eventually we'll be able to borrow out of `ctx`, and avoid many of these copies.
         let search_string = ctx.get::<String>(0)?;
         let url = ctx.get::<String>(1)?;
@@ -161,6 +309,7 @@ fn define_functions(c: &Connection) -> Result<()> {
         let open_page_count = ctx.get::<Option<u32>>(7)?.unwrap_or(0);
         let match_behavior = ctx.get::<MatchBehavior>(8)?;
         let search_behavior = ctx.get::<SearchBehavior>(9)?;
+        let folding = ctx.get::<MatchFolding>(10)?;
 
         let matcher = AutocompleteMatch {
             search_str: &search_string,
@@ -173,9 +322,16 @@ fn define_functions(c: &Connection) -> Result<()> {
             open_page_count,
             match_behavior,
             search_behavior,
+            folding,
         };
         Ok(matcher.invoke())
     })?;
+    c.create_scalar_function("autocomplete_index_text", 3, true, move |ctx| {
+        let text = ctx.get::<Option<String>>(0)?;
+        let is_url = ctx.get::<bool>(1)?;
+        let trim = ctx.get::<bool>(2)?;
+        Ok(text.map(|text| search_index_text(&text, is_url, trim)))
+    })?;
     c.create_scalar_function("hash", -1, true, move |ctx| {
         Ok(match ctx.len() {
             1 => {
@@ -215,6 +371,35 @@ mod tests {
         PlacesDb::open_in_memory(None).expect("no memory db");
     }
 
+    #[test]
+    fn test_interrupt_scope() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let handle = conn.new_interrupt_handle();
+
+        // Interrupting before the scope starts doesn't affect it.
+        handle.interrupt();
+        let scope = conn.begin_interrupt_scope();
+        assert!(!scope.was_interrupted());
+        assert!(scope.err_if_interrupted().is_ok());
+
+        handle.interrupt();
+        assert!(scope.was_interrupted());
+        match scope.err_if_interrupted() {
+            Err(e) => match e.kind() {
+                ErrorKind::InterruptedError => {}
+                kind => panic!("Wrong error kind: {:?}", kind),
+            },
+            Ok(_) => panic!("Should be interrupted"),
+        }
+
+        // And the connection is still usable afterward.
+        let count: u32 = conn
+            .db
+            .query_row("SELECT COUNT(*) FROM moz_places", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
     #[test]
     fn test_reverse_host() {
         let conn = PlacesDb::open_in_memory(None).expect("no memory db");
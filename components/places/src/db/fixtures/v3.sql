@@ -0,0 +1,149 @@
+-- A places database at schema version 3, as `create` made it, with a
+-- page, a visit and, depending on the version, bookmarks and tags. Used
+-- to test upgrading from this version.
+
+CREATE TABLE moz_places (
+        id INTEGER PRIMARY KEY,
+        url LONGVARCHAR NOT NULL,
+        title LONGVARCHAR,
+        -- note - desktop has rev_host here - that's now in moz_origin.
+        visit_count_local INTEGER NOT NULL DEFAULT 0,
+        visit_count_remote INTEGER NOT NULL DEFAULT 0,
+        hidden INTEGER DEFAULT 0 NOT NULL,
+        typed INTEGER DEFAULT 0 NOT NULL, -- XXX - is 'typed' ok? Note also we want this as a *count*, not a bool.
+        frecency INTEGER DEFAULT -1 NOT NULL,
+        -- XXX - splitting last visit into local and remote correct?
+        last_visit_date_local INTEGER NOT NULL DEFAULT 0,
+        last_visit_date_remote INTEGER NOT NULL DEFAULT 0,
+        guid TEXT NOT NULL UNIQUE,
+        foreign_count INTEGER DEFAULT 0 NOT NULL,
+        url_hash INTEGER DEFAULT 0 NOT NULL,
+        description TEXT, -- XXXX - title above?
+        preview_image_url TEXT,
+        -- origin_id would ideally be NOT NULL, but we use a trigger to keep
+        -- it up to date, so do perform the initial insert with a null.
+        origin_id INTEGER,
+        -- a couple of sync-related fields.
+        sync_status TINYINT NOT NULL DEFAULT 1, -- 1 is SyncStatus::New
+        sync_change_counter INTEGER NOT NULL DEFAULT 0, -- adding visits will increment this
+
+        FOREIGN KEY(origin_id) REFERENCES moz_origins(id) ON DELETE CASCADE
+    );
+
+CREATE TABLE moz_places_tombstones (
+        guid TEXT PRIMARY KEY
+    ) WITHOUT ROWID;
+
+CREATE TABLE moz_historyvisits (
+        id INTEGER PRIMARY KEY,
+        is_local INTEGER NOT NULL, -- XXX - not in desktop - will always be true for visits added locally, always false visits added by sync.
+        from_visit INTEGER, -- XXX - self-reference?
+        place_id INTEGER NOT NULL,
+        visit_date INTEGER NOT NULL,
+        visit_type INTEGER NOT NULL,
+        -- session INTEGER, -- XXX - what is 'session'? Appears unused.
+
+        FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE,
+        FOREIGN KEY(from_visit) REFERENCES moz_historyvisits(id)
+    );
+
+CREATE TABLE moz_inputhistory (
+        place_id INTEGER NOT NULL,
+        input LONGVARCHAR NOT NULL,
+        use_count INTEGER,
+
+        PRIMARY KEY (place_id, input),
+        FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
+    );
+
+CREATE TABLE moz_bookmarks (
+        id INTEGER PRIMARY KEY,
+        fk INTEGER DEFAULT NULL, -- place_id
+        type INTEGER NOT NULL,
+        parent INTEGER,
+        position INTEGER NOT NULL,
+        title TEXT,
+        dateAdded INTEGER NOT NULL DEFAULT 0,
+        lastModified INTEGER NOT NULL DEFAULT 0,
+        guid TEXT NOT NULL UNIQUE CHECK(length(guid) == 12),
+
+        syncStatus INTEGER NOT NULL DEFAULT 1, -- 1 is SyncStatus::New
+        syncChangeCounter INTEGER NOT NULL DEFAULT 1,
+
+        -- bookmarks must have a fk to a URL, other types must not.
+        CHECK((type == 1 AND fk IS NOT NULL) OR (type > 1 AND fk IS NULL)),
+
+        FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT,
+        FOREIGN KEY(parent) REFERENCES moz_bookmarks(id) ON DELETE CASCADE
+    );
+
+CREATE TABLE moz_bookmarks_deleted (
+        guid TEXT PRIMARY KEY,
+        dateRemoved INTEGER NOT NULL
+    ) WITHOUT ROWID;
+
+CREATE TABLE moz_origins (
+        id INTEGER PRIMARY KEY,
+        prefix TEXT NOT NULL,
+        host TEXT NOT NULL,
+        rev_host TEXT NOT NULL,
+        frecency INTEGER NOT NULL, -- XXX - why not default of -1 like in moz_places?
+        UNIQUE (prefix, host)
+    );
+
+CREATE TABLE moz_meta (
+        key TEXT PRIMARY KEY,
+        value NOT NULL
+    ) WITHOUT ROWID;
+
+CREATE INDEX url_hashindex ON moz_places(url_hash);
+
+CREATE INDEX visitcountlocal ON moz_places(visit_count_local);
+
+CREATE INDEX visitcountremote ON moz_places(visit_count_remote);
+
+CREATE INDEX frecencyindex ON moz_places(frecency);
+
+CREATE INDEX lastvisitdatelocalindex ON moz_places(last_visit_date_local);
+
+CREATE INDEX lastvisitdateremoteindex ON moz_places(last_visit_date_remote);
+
+CREATE UNIQUE INDEX guid_uniqueindex ON moz_places(guid);
+
+CREATE INDEX originidindex ON moz_places(origin_id);
+
+CREATE INDEX placedateindex ON moz_historyvisits(place_id, visit_date);
+
+CREATE INDEX fromindex ON moz_historyvisits(from_visit);
+
+CREATE INDEX dateindex ON moz_historyvisits(visit_date);
+
+CREATE INDEX islocalindex ON moz_historyvisits(is_local);
+
+CREATE INDEX itemindex ON moz_bookmarks(fk, type);
+
+CREATE INDEX parentindex ON moz_bookmarks(parent, position);
+
+CREATE INDEX itemlastmodifiedindex ON moz_bookmarks(fk, lastModified);
+
+CREATE INDEX dateaddedindex ON moz_bookmarks(dateAdded);
+
+INSERT INTO moz_origins (id, prefix, host, rev_host, frecency)
+VALUES (1, 'http://', 'example.com', 'moc.elpmaxe.', 100);
+
+INSERT INTO moz_places (id, url, title, visit_count_local, frecency,
+                        last_visit_date_local, guid, foreign_count, origin_id)
+VALUES (1, 'http://example.com/', 'Example', 1, 100, 1000, 'placeAAAAAAA', 1, 1);
+
+INSERT INTO moz_historyvisits (id, is_local, from_visit, place_id, visit_date, visit_type)
+VALUES (1, 1, NULL, 1, 1000, 1);
+
+INSERT INTO moz_bookmarks (id, fk, type, parent, position, title, dateAdded, lastModified, guid) VALUES
+    (1, NULL, 2, NULL, 0, NULL, 1000, 1000, 'root________'),
+    (2, NULL, 2, 1, 0, NULL, 1000, 1000, 'menu________'),
+    (3, NULL, 2, 1, 1, NULL, 1000, 1000, 'toolbar_____'),
+    (4, NULL, 2, 1, 2, NULL, 1000, 1000, 'unfiled_____'),
+    (5, NULL, 2, 1, 3, NULL, 1000, 1000, 'mobile______'),
+    (6, 1, 1, 5, 0, 'Example', 1000, 1000, 'bookmarkAAAA');
+
+PRAGMA user_version = 3;
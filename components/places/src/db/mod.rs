@@ -4,6 +4,7 @@
 
 // We don't want 'db.rs' as a sub-module. We could move the contents here? Or something else?
 pub mod db;
-pub use crate::db::db::PlacesDb;
+pub(crate) use crate::db::db::InterruptScope;
+pub use crate::db::db::{PlacesDb, PlacesInterruptHandle};
 
-mod schema;
+pub(crate) mod schema;
@@ -9,10 +9,15 @@
 
 use crate::db::PlacesDb;
 use crate::error::*;
+use crate::observer;
+use crate::storage::bookmarks::create_bookmark_roots;
+use crate::storage::search_index;
+use crate::storage::{delete_meta, get_meta, put_meta, recalculate_origin_frecencies};
 use lazy_static::lazy_static;
-use sql_support::ConnExt;
+use rusqlite::Connection;
+use sql_support::{init_schema, ConnExt, DowngradePolicy, Schema};
 
-const VERSION: i64 = 2;
+const VERSION: i64 = 8;
 
 const CREATE_TABLE_PLACES_SQL: &str =
     "CREATE TABLE IF NOT EXISTS moz_places (
@@ -33,6 +38,10 @@ const CREATE_TABLE_PLACES_SQL: &str =
         url_hash INTEGER DEFAULT 0 NOT NULL,
         description TEXT, -- XXXX - title above?
         preview_image_url TEXT,
+        -- desktop keeps these in moz_places_metadata, for each visit. We only
+        -- need them for each page.
+        document_type INTEGER NOT NULL DEFAULT 0, -- a DocumentType
+        total_view_time INTEGER NOT NULL DEFAULT 0, -- milliseconds
         -- origin_id would ideally be NOT NULL, but we use a trigger to keep
         -- it up to date, so do perform the initial insert with a null.
         origin_id INTEGER,
@@ -74,22 +83,105 @@ const CREATE_TABLE_INPUTHISTORY_SQL: &str = "CREATE TABLE moz_inputhistory (
 // XXX - TODO - moz_annos
 // XXX - TODO - moz_anno_attributes
 // XXX - TODO - moz_items_annos
-// XXX - TODO - moz_bookmarks
-// XXX - TODO - moz_bookmarks_deleted
 
-// TODO: This isn't the complete `moz_bookmarks` definition, just enough to
-// test autocomplete.
 const CREATE_TABLE_BOOKMARKS_SQL: &str = "CREATE TABLE moz_bookmarks (
         id INTEGER PRIMARY KEY,
-        fk INTEGER,
+        fk INTEGER DEFAULT NULL, -- place_id
+        type INTEGER NOT NULL,
+        parent INTEGER,
+        position INTEGER NOT NULL,
         title TEXT,
+        dateAdded INTEGER NOT NULL DEFAULT 0,
         lastModified INTEGER NOT NULL DEFAULT 0,
+        guid TEXT NOT NULL UNIQUE CHECK(length(guid) == 12),
 
-        FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT
+        syncStatus INTEGER NOT NULL DEFAULT 1, -- 1 is SyncStatus::New
+        syncChangeCounter INTEGER NOT NULL DEFAULT 1,
+
+        -- bookmarks must have a fk to a URL, other types must not.
+        CHECK((type == 1 AND fk IS NOT NULL) OR (type > 1 AND fk IS NULL)),
+
+        FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT,
+        FOREIGN KEY(parent) REFERENCES moz_bookmarks(id) ON DELETE CASCADE
+    )";
+
+// Tombstones for bookmarks which have been synced and then deleted locally.
+const CREATE_TABLE_BOOKMARKS_DELETED_SQL: &str = "CREATE TABLE moz_bookmarks_deleted (
+        guid TEXT PRIMARY KEY,
+        dateRemoved INTEGER NOT NULL
+    ) WITHOUT ROWID";
+
+// The bookmarks "mirror" - the last known state of the bookmarks on the
+// server. Incoming records are staged here with `needsMerge` set, and the
+// merger compares this tree with moz_bookmarks. Unlike desktop, this lives
+// in the same database and stores the URL directly rather than referencing
+// moz_places, so mirrored-but-not-local URLs don't need a place.
+const CREATE_TABLE_BOOKMARKS_SYNCED_SQL: &str = "CREATE TABLE moz_bookmarks_synced (
+        id INTEGER PRIMARY KEY,
+        guid TEXT UNIQUE NOT NULL,
+        parentGuid TEXT,
+        serverModified INTEGER NOT NULL DEFAULT 0,
+        needsMerge BOOLEAN NOT NULL DEFAULT 0,
+        isDeleted BOOLEAN NOT NULL DEFAULT 0,
+        kind INTEGER NOT NULL DEFAULT -1, -- a SyncedBookmarkKind
+        dateAdded INTEGER NOT NULL DEFAULT 0,
+        title TEXT,
+        url TEXT,
+        feedURL TEXT,
+        siteURL TEXT
     )";
 
+// The children of each mirrored folder, in the order the server has them.
+const CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL: &str =
+    "CREATE TABLE moz_bookmarks_synced_structure (
+        guid TEXT,
+        parentGuid TEXT REFERENCES moz_bookmarks_synced(guid) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        PRIMARY KEY(parentGuid, guid)
+    ) WITHOUT ROWID";
+
+// Tags on the mirrored bookmarks, so that we can tell if they changed.
+const CREATE_TABLE_BOOKMARKS_SYNCED_TAG_RELATION_SQL: &str =
+    "CREATE TABLE moz_bookmarks_synced_tag_relation (
+        itemId INTEGER NOT NULL REFERENCES moz_bookmarks_synced(id) ON DELETE CASCADE,
+        tagId INTEGER NOT NULL REFERENCES moz_tags(id) ON DELETE CASCADE,
+        PRIMARY KEY(itemId, tagId)
+    ) WITHOUT ROWID";
+
+// Desktop stores tags as folders under a special root, with a bookmark for
+// each tagged URL. We don't - tags belong to URLs, not bookmarks, and live
+// in their own tables.
+const CREATE_TABLE_TAGS_SQL: &str = "CREATE TABLE moz_tags (
+        id INTEGER PRIMARY KEY,
+        tag TEXT UNIQUE NOT NULL,
+        lastModified INTEGER NOT NULL
+    )";
+
+const CREATE_TABLE_TAGS_RELATION_SQL: &str = "CREATE TABLE moz_tags_relation (
+        tag_id INTEGER NOT NULL REFERENCES moz_tags(id) ON DELETE CASCADE,
+        place_id INTEGER NOT NULL REFERENCES moz_places(id) ON DELETE CASCADE,
+        PRIMARY KEY(tag_id, place_id)
+    ) WITHOUT ROWID";
+
 // Note: desktop has/had a 'keywords' table, but we intentionally do not.
 
+// Places whose frecency needs to be recalculated. Rows are added by triggers
+// when visits change, and when visits age into a lower frecency bucket, and
+// are removed once the frecency has been updated.
+const CREATE_TABLE_PLACES_STALE_FRECENCIES_SQL: &str = "CREATE TABLE moz_places_stale_frecencies (
+        place_id INTEGER PRIMARY KEY NOT NULL,
+        stale_at INTEGER NOT NULL,
+        FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
+    )";
+
+// URLs the user removed from their top sites, so that `get_top_sites` never
+// suggests them again. This is keyed by URL rather than place, so that blocked
+// URLs stay blocked even if their history is removed and they're visited again.
+const CREATE_TABLE_TOPSITES_BLOCKED_SQL: &str = "CREATE TABLE moz_topsites_blocked (
+        url TEXT PRIMARY KEY,
+        dateAdded INTEGER NOT NULL
+    ) WITHOUT ROWID";
+
 const CREATE_TABLE_ORIGINS_SQL: &str = "CREATE TABLE moz_origins (
         id INTEGER PRIMARY KEY,
         prefix TEXT NOT NULL,
@@ -104,7 +196,7 @@ const CREATE_TRIGGER_AFTER_INSERT_ON_PLACES: &str = "
     AFTER INSERT ON moz_places FOR EACH ROW
     BEGIN
         INSERT OR IGNORE INTO moz_origins(prefix, host, rev_host, frecency)
-        VALUES(get_prefix(NEW.url), get_host_and_port(NEW.url), reverse_host(get_host_and_port(NEW.url)), NEW.frecency);
+        VALUES(get_prefix(NEW.url), get_host_and_port(NEW.url), reverse_host(get_host_and_port(NEW.url)), 0);
 
         -- This is temporary.
         UPDATE moz_places SET
@@ -113,6 +205,41 @@ const CREATE_TRIGGER_AFTER_INSERT_ON_PLACES: &str = "
                              host = get_host_and_port(NEW.url) AND
                              rev_host = reverse_host(get_host_and_port(NEW.url)))
         WHERE id = NEW.id;
+
+        UPDATE moz_origins SET frecency = frecency + MAX(NEW.frecency, 0)
+        WHERE prefix = get_prefix(NEW.url) AND host = get_host_and_port(NEW.url);
+    END
+";
+
+// An origin's frecency is the sum of the frecencies of its pages, like on
+// desktop. These keep it up to date as pages change, and remove origins once
+// they have no pages left.
+const CREATE_TRIGGER_PLACES_AFTERUPDATE_FRECENCY: &str = "
+    CREATE TEMP TRIGGER moz_places_afterupdate_frecency_trigger
+    AFTER UPDATE OF frecency ON moz_places FOR EACH ROW
+    WHEN NEW.frecency <> OLD.frecency
+    BEGIN
+        UPDATE moz_origins
+        SET frecency = frecency - MAX(OLD.frecency, 0) + MAX(NEW.frecency, 0)
+        WHERE id = NEW.origin_id;
+    END
+";
+
+const CREATE_TRIGGER_PLACES_AFTERDELETE: &str = "
+    CREATE TEMP TRIGGER moz_places_afterdelete_trigger
+    AFTER DELETE ON moz_places FOR EACH ROW
+    BEGIN
+        UPDATE moz_origins
+        SET frecency = frecency - MAX(OLD.frecency, 0)
+        WHERE id = OLD.origin_id;
+
+        DELETE FROM moz_origins
+        WHERE id = OLD.origin_id
+          AND NOT EXISTS(SELECT 1 FROM moz_places WHERE origin_id = OLD.origin_id);
+
+        DELETE FROM moz_places_stale_frecencies WHERE place_id = OLD.id;
+
+        DELETE FROM moz_inputhistory WHERE place_id = OLD.id;
     END
 ";
 
@@ -131,7 +258,55 @@ const CREATE_TRIGGER_MOZPLACES_AFTERINSERT_REMOVE_TOMBSTONES: &str = "
 // table changes.
 const EXCLUDED_VISIT_TYPES: &str = "0, 4, 7, 8, 9"; // stolen from desktop
 
+// The current time in milliseconds, like `Timestamp::now()`.
+const NOW_SQL: &str = "CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)";
+
+// The stats we keep in moz_meta for the origin frecency threshold only
+// count origins with a positive frecency. `old` and `new` are the SQL for the
+// old and new frecencies of the origin.
+fn update_origin_frecency_stats_sql(old: &str, new: &str) -> String {
+    format!(
+        "
+        REPLACE INTO moz_meta(key, value)
+        SELECT '{count}', IFNULL((SELECT value FROM moz_meta WHERE key = '{count}'), 0)
+                          + ({new} > 0) - ({old} > 0);
+        REPLACE INTO moz_meta(key, value)
+        SELECT '{sum}', IFNULL((SELECT value FROM moz_meta WHERE key = '{sum}'), 0)
+                        + MAX({new}, 0) - MAX({old}, 0);
+        REPLACE INTO moz_meta(key, value)
+        SELECT '{sum_of_squares}', IFNULL((SELECT value FROM moz_meta WHERE key = '{sum_of_squares}'), 0)
+                                   + MAX({new}, 0) * MAX({new}, 0) - MAX({old}, 0) * MAX({old}, 0);",
+        count = MOZ_META_KEY_ORIGIN_FRECENCY_COUNT,
+        sum = MOZ_META_KEY_ORIGIN_FRECENCY_SUM,
+        sum_of_squares = MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES,
+        old = old,
+        new = new,
+    )
+}
+
 lazy_static! {
+    static ref CREATE_TRIGGER_ORIGINS_AFTERINSERT: String = format!("
+        CREATE TEMP TRIGGER moz_origins_afterinsert_trigger
+        AFTER INSERT ON moz_origins FOR EACH ROW
+        BEGIN
+            {}
+        END", update_origin_frecency_stats_sql("0", "NEW.frecency"));
+
+    static ref CREATE_TRIGGER_ORIGINS_AFTERUPDATE: String = format!("
+        CREATE TEMP TRIGGER moz_origins_afterupdate_trigger
+        AFTER UPDATE OF frecency ON moz_origins FOR EACH ROW
+        WHEN NEW.frecency <> OLD.frecency
+        BEGIN
+            {}
+        END", update_origin_frecency_stats_sql("OLD.frecency", "NEW.frecency"));
+
+    static ref CREATE_TRIGGER_ORIGINS_AFTERDELETE: String = format!("
+        CREATE TEMP TRIGGER moz_origins_afterdelete_trigger
+        AFTER DELETE ON moz_origins FOR EACH ROW
+        BEGIN
+            {}
+        END", update_origin_frecency_stats_sql("OLD.frecency", "0"));
+
     static ref CREATE_TRIGGER_HISTORYVISITS_AFTERINSERT: String = format!("
         CREATE TEMP TRIGGER moz_historyvisits_afterinsert_trigger
         AFTER INSERT ON moz_historyvisits FOR EACH ROW
@@ -144,7 +319,10 @@ lazy_static! {
                 last_visit_date_remote = MAX(last_visit_date_remote,
                                              CASE WHEN NEW.is_local THEN 0 ELSE NEW.visit_date END)
             WHERE id = NEW.place_id;
-        END", excluded = EXCLUDED_VISIT_TYPES);
+
+            INSERT OR IGNORE INTO moz_places_stale_frecencies (place_id, stale_at)
+            VALUES (NEW.place_id, {now});
+        END", excluded = EXCLUDED_VISIT_TYPES, now = NOW_SQL);
 
     static ref CREATE_TRIGGER_HISTORYVISITS_AFTERDELETE: String = format!("
         CREATE TEMP TRIGGER moz_historyvisits_afterdelete_trigger
@@ -160,10 +338,82 @@ lazy_static! {
                                                  WHERE place_id = OLD.place_id AND NOT(is_local)
                                                  ORDER BY visit_date DESC LIMIT 1), 0)
             WHERE id = OLD.place_id;
-        END", excluded = EXCLUDED_VISIT_TYPES);
+
+            INSERT OR IGNORE INTO moz_places_stale_frecencies (place_id, stale_at)
+            VALUES (OLD.place_id, {now});
+
+            -- Unlink the visits that came from this one, so that they don't
+            -- point at a different visit if its id is reused.
+            UPDATE moz_historyvisits SET from_visit = NULL
+            WHERE from_visit = OLD.id;
+        END", excluded = EXCLUDED_VISIT_TYPES, now = NOW_SQL);
 }
 
-// XXX - TODO - lots of desktop temp tables - but it's not clear they make sense here yet?
+// Keep moz_places.foreign_count in sync with the bookmarks pointing at it.
+// Frecency and autocomplete both use foreign_count to decide if a page is
+// bookmarked.
+const CREATE_TRIGGER_BOOKMARKS_AFTERINSERT: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_foreign_count_afterinsert_trigger
+    AFTER INSERT ON moz_bookmarks FOR EACH ROW
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count + 1
+        WHERE id = NEW.fk;
+    END
+";
+
+const CREATE_TRIGGER_BOOKMARKS_AFTERDELETE: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_foreign_count_afterdelete_trigger
+    AFTER DELETE ON moz_bookmarks FOR EACH ROW
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count - 1
+        WHERE id = OLD.fk;
+    END
+";
+
+const CREATE_TRIGGER_BOOKMARKS_AFTERUPDATE: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_foreign_count_afterupdate_trigger
+    AFTER UPDATE OF fk ON moz_bookmarks FOR EACH ROW
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count + 1
+        WHERE id = NEW.fk;
+        UPDATE moz_places SET foreign_count = foreign_count - 1
+        WHERE id = OLD.fk;
+    END
+";
+
+// Tagged URLs count as foreign too, as they are on desktop, so that tagging a
+// page keeps it around.
+const CREATE_TRIGGER_TAGS_AFTERINSERT: &str = "
+    CREATE TEMP TRIGGER moz_tags_relation_foreign_count_afterinsert_trigger
+    AFTER INSERT ON moz_tags_relation FOR EACH ROW
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count + 1
+        WHERE id = NEW.place_id;
+    END
+";
+
+const CREATE_TRIGGER_TAGS_AFTERDELETE: &str = "
+    CREATE TEMP TRIGGER moz_tags_relation_foreign_count_afterdelete_trigger
+    AFTER DELETE ON moz_tags_relation FOR EACH ROW
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count - 1
+        WHERE id = OLD.place_id;
+    END
+";
+
+// Tracks the URLs open in tabs, so that autocomplete can suggest switching to
+// them. Like the triggers, this table only lives as long as the connection, so
+// apps need to register their open tabs each time they open Places.
+// See https://searchfox.org/mozilla-central/source/toolkit/components/places/nsPlacesTables.h
+const CREATE_TEMP_TABLE_OPENPAGES: &str = "
+    CREATE TEMP TABLE moz_openpages_temp (
+        url TEXT,
+        userContextId INTEGER,
+        open_count INTEGER,
+        PRIMARY KEY (url, userContextId)
+    )";
+
+// XXX - TODO - lots of other desktop temp tables - but it's not clear they make sense here yet?
 
 // XXX - TODO - lots of favicon related tables - but it's not clear they make sense here yet?
 
@@ -207,64 +457,182 @@ const CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE: &str =
 const CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL: &str =
     "CREATE INDEX islocalindex ON moz_historyvisits(is_local)";
 
-// const CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE: &str = "CREATE INDEX itemindex ON moz_bookmarks(fk, type)";
-// const CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION: &str = "CREATE INDEX parentindex ON moz_bookmarks(parent, position)";
+const CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE: &str =
+    "CREATE INDEX itemindex ON moz_bookmarks(fk, type)";
+const CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION: &str =
+    "CREATE INDEX parentindex ON moz_bookmarks(parent, position)";
 const CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED: &str =
     "CREATE INDEX itemlastmodifiedindex ON moz_bookmarks(fk, lastModified)";
-// const CREATE_IDX_MOZ_BOOKMARKS_DATEADDED: &str = "CREATE INDEX dateaddedindex ON moz_bookmarks(dateAdded)";
-// const CREATE_IDX_MOZ_BOOKMARKS_GUID: &str = "CREATE UNIQUE INDEX guid_uniqueindex ON moz_bookmarks(guid)";
+const CREATE_IDX_MOZ_BOOKMARKS_DATEADDED: &str =
+    "CREATE INDEX dateaddedindex ON moz_bookmarks(dateAdded)";
+// Note that the unique index on moz_bookmarks.guid is implied by the UNIQUE
+// constraint, so we don't create it explicitly like desktop does.
+
+// Used to find the tags for a URL.
+const CREATE_IDX_MOZ_TAGS_RELATION_PLACE_ID: &str =
+    "CREATE INDEX tagsrelationplaceindex ON moz_tags_relation(place_id)";
 
 // Keys in the moz_meta table.
-// pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_COUNT: &'static str = "origin_frecency_count";
-// pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM: &'static str = "origin_frecency_sum";
-// pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES: &'static str = "origin_frecency_sum_of_squares";
+pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_COUNT: &str = "origin_frecency_count";
+pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM: &str = "origin_frecency_sum";
+pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES: &str =
+    "origin_frecency_sum_of_squares";
+pub(crate) static MOZ_META_KEY_FRECENCIES_LAST_AGED: &str = "frecencies_last_aged";
+pub(crate) static MOZ_META_KEY_ADAPTIVE_HISTORY_LAST_DECAYED: &str =
+    "adaptive_history_last_decayed";
+// The source's kind is appended to this, so that each kind of source can
+// have a paused import.
+pub(crate) static MOZ_META_KEY_IMPORT_STATE: &str = "import_state";
+pub(crate) static MOZ_META_KEY_FRECENCY_SETTINGS: &str = "frecency_settings";
+// The newer schema version that we last rebuilt the database for.
+pub(crate) static MOZ_META_KEY_REBUILT_FOR_VERSION: &str = "rebuilt_for_version";
+
+// The oldest version we can upgrade. Nothing ever shipped an older one.
+const MIN_VERSION: i64 = 2;
+
+struct PlacesSchema;
+
+impl Schema for PlacesSchema {
+    type Error = Error;
+    const NAME: &'static str = "places";
+    const VERSION: i64 = VERSION;
+    const MIN_VERSION: i64 = MIN_VERSION;
+    const DOWNGRADE_POLICY: DowngradePolicy = DowngradePolicy::Rebuild;
+
+    fn create(&self, db: &Connection) -> Result<()> {
+        create(db)
+    }
 
-pub fn init(db: &PlacesDb) -> Result<()> {
-    let user_version = db.query_one::<i64>("PRAGMA user_version")?;
-    if user_version == 0 {
-        create(db)?;
-    } else if user_version != VERSION {
-        if user_version < VERSION {
-            upgrade(db, user_version)?;
-        } else {
-            log::warn!(
-                "Loaded future schema version {} (we only understand version {}). \
-                 Optimisitically ",
-                user_version,
-                VERSION
-            )
+    fn upgrade_from(&self, db: &Connection, version: i64) -> Result<()> {
+        upgrade_from(db, version)
+    }
+
+    fn rebuild(&self, db: &Connection, version: i64) -> Result<()> {
+        // This is called every time we open the database, but we only need
+        // to rebuild once, unless the newer version has used the database
+        // since. `init` forgets the version whenever the database is opened
+        // without rebuilding, which includes by the newer version.
+        if get_meta::<i64>(db, MOZ_META_KEY_REBUILT_FOR_VERSION)? == Some(version) {
+            return Ok(());
         }
+        // Everything else is either user data, or kept up to date by the
+        // triggers. A newer version may have calculated frecencies
+        // differently, so recalculate them all, along with the origin
+        // frecency stats, which `init` recalculates when they're missing.
+        db.execute_all(&[
+            "DELETE FROM moz_places_stale_frecencies",
+            &format!(
+                "INSERT INTO moz_places_stale_frecencies (place_id, stale_at)
+                 SELECT id, {now} FROM moz_places",
+                now = NOW_SQL
+            ),
+            &format!(
+                "DELETE FROM moz_meta WHERE key IN ('{}', '{}', '{}')",
+                MOZ_META_KEY_ORIGIN_FRECENCY_COUNT,
+                MOZ_META_KEY_ORIGIN_FRECENCY_SUM,
+                MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES
+            ),
+        ])?;
+        put_meta(db, MOZ_META_KEY_REBUILT_FOR_VERSION, &version)?;
+        Ok(())
+    }
+}
+
+pub fn init(db: &PlacesDb) -> Result<()> {
+    init_schema(db, &PlacesSchema)?;
+    if db.query_one::<i64>("PRAGMA user_version")? <= VERSION
+        && get_meta::<i64>(db, MOZ_META_KEY_REBUILT_FOR_VERSION)?.is_some()
+    {
+        delete_meta(db, MOZ_META_KEY_REBUILT_FOR_VERSION)?;
     }
     log::debug!("Creating temp tables and triggers");
     db.execute_all(&[
+        CREATE_TEMP_TABLE_OPENPAGES,
+        observer::CREATE_TEMP_TABLE_EVENTS,
         CREATE_TRIGGER_AFTER_INSERT_ON_PLACES,
         &CREATE_TRIGGER_HISTORYVISITS_AFTERINSERT,
         &CREATE_TRIGGER_HISTORYVISITS_AFTERDELETE,
         CREATE_TRIGGER_MOZPLACES_AFTERINSERT_REMOVE_TOMBSTONES,
+        CREATE_TRIGGER_BOOKMARKS_AFTERINSERT,
+        CREATE_TRIGGER_BOOKMARKS_AFTERDELETE,
+        CREATE_TRIGGER_BOOKMARKS_AFTERUPDATE,
+        CREATE_TRIGGER_TAGS_AFTERINSERT,
+        CREATE_TRIGGER_TAGS_AFTERDELETE,
+        CREATE_TRIGGER_PLACES_AFTERUPDATE_FRECENCY,
+        CREATE_TRIGGER_PLACES_AFTERDELETE,
+        &CREATE_TRIGGER_ORIGINS_AFTERINSERT,
+        &CREATE_TRIGGER_ORIGINS_AFTERUPDATE,
+        &CREATE_TRIGGER_ORIGINS_AFTERDELETE,
     ])?;
+    search_index::init(db)?;
+    // Databases created before we kept origin frecencies up to date, and new
+    // databases, don't have the stats yet.
+    if get_meta::<i64>(db, MOZ_META_KEY_ORIGIN_FRECENCY_COUNT)?.is_none() {
+        recalculate_origin_frecencies(db)?;
+    }
     Ok(())
 }
 
-// https://github.com/mozilla-mobile/firefox-ios/blob/master/Storage/SQL/LoginsSchema.swift#L100
-fn upgrade(_db: &PlacesDb, from: i64) -> Result<()> {
-    log::debug!("Upgrading schema from {} to {}", from, VERSION);
-    if from == VERSION {
-        return Ok(());
+// Upgrades the schema from `version` to `version + 1`. Each step only runs
+// once its earlier steps have, and in the same transaction as the version
+// bump, so it can assume the schema is exactly the one for `version`.
+//
+// Steps reuse the CREATE statements above for tables that haven't changed
+// since. If you change one of those statements, copy the old one into the
+// steps that use it.
+fn upgrade_from(db: &Connection, version: i64) -> Result<()> {
+    match version {
+        2 => {
+            // Version 2 only had enough of moz_bookmarks to test
+            // autocomplete, and nothing wrote to it.
+            db.execute_all(&[
+                "DROP TABLE moz_bookmarks",
+                CREATE_TABLE_BOOKMARKS_SQL,
+                CREATE_TABLE_BOOKMARKS_DELETED_SQL,
+                CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE,
+                CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
+                CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED,
+                CREATE_IDX_MOZ_BOOKMARKS_DATEADDED,
+            ])?;
+            create_bookmark_roots(db)?;
+        }
+        3 => db.execute_all(&[
+            CREATE_TABLE_BOOKMARKS_SYNCED_SQL,
+            CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL,
+        ])?,
+        4 => db.execute_all(&[
+            CREATE_TABLE_TAGS_SQL,
+            CREATE_TABLE_TAGS_RELATION_SQL,
+            CREATE_TABLE_BOOKMARKS_SYNCED_TAG_RELATION_SQL,
+            CREATE_IDX_MOZ_TAGS_RELATION_PLACE_ID,
+        ])?,
+        5 => db.execute_all(&[CREATE_TABLE_PLACES_STALE_FRECENCIES_SQL])?,
+        6 => db.execute_all(&[CREATE_TABLE_TOPSITES_BLOCKED_SQL])?,
+        7 => db.execute_all(&[
+            "ALTER TABLE moz_places ADD COLUMN document_type INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE moz_places ADD COLUMN total_view_time INTEGER NOT NULL DEFAULT 0",
+        ])?,
+        _ => unreachable!("no upgrade from version {}", version),
     }
-    // FIXME https://github.com/mozilla/application-services/issues/438
-    // NB: PlacesConnection.kt checks for this error message verbatim as a workaround.
-    panic!("sorry, no upgrades yet - delete your db!");
+    Ok(())
 }
 
-pub fn create(db: &PlacesDb) -> Result<()> {
-    log::debug!("Creating schema");
+fn create(db: &Connection) -> Result<()> {
     db.execute_all(&[
         CREATE_TABLE_PLACES_SQL,
         CREATE_TABLE_PLACES_TOMBSTONES_SQL,
+        CREATE_TABLE_PLACES_STALE_FRECENCIES_SQL,
         CREATE_TABLE_HISTORYVISITS_SQL,
         CREATE_TABLE_INPUTHISTORY_SQL,
         CREATE_TABLE_BOOKMARKS_SQL,
+        CREATE_TABLE_BOOKMARKS_DELETED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_TAG_RELATION_SQL,
+        CREATE_TABLE_TAGS_SQL,
+        CREATE_TABLE_TAGS_RELATION_SQL,
         CREATE_TABLE_ORIGINS_SQL,
+        CREATE_TABLE_TOPSITES_BLOCKED_SQL,
         CREATE_TABLE_META_SQL,
         CREATE_IDX_MOZ_PLACES_URL_HASH,
         CREATE_IDX_MOZ_PLACES_VISITCOUNT_LOCAL,
@@ -278,10 +646,15 @@ pub fn create(db: &PlacesDb) -> Result<()> {
         CREATE_IDX_MOZ_HISTORYVISITS_FROMVISIT,
         CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE,
         CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL,
+        CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE,
+        CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
         CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED,
-        &format!("PRAGMA user_version = {version}", version = VERSION),
+        CREATE_IDX_MOZ_BOOKMARKS_DATEADDED,
+        CREATE_IDX_MOZ_TAGS_RELATION_PLACE_ID,
     ])?;
 
+    create_bookmark_roots(db)?;
+
     Ok(())
 }
 
@@ -289,6 +662,7 @@ pub fn create(db: &PlacesDb) -> Result<()> {
 mod tests {
     use super::*;
     use crate::db::PlacesDb;
+    use crate::storage::bookmarks::{fetch_tree, BookmarkRootGuid};
     use crate::types::SyncStatus;
     use sync15::util::random_guid;
     use url::Url;
@@ -363,4 +737,203 @@ mod tests {
         .expect("should work");
         assert!(!has_tombstone(&conn, &guid));
     }
+
+    // Describes the tables, their columns, and the indexes in `conn`. Columns
+    // are sorted by name, since `ALTER TABLE` adds them at the end.
+    fn describe_schema(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT type, name, tbl_name FROM sqlite_master
+                 WHERE type IN ('table', 'index') AND name NOT LIKE 'sqlite_%'
+                 ORDER BY name",
+            )
+            .expect("should prepare");
+        let items = stmt
+            .query_map(&[], |row| -> (String, String, String) {
+                (row.get(0), row.get(1), row.get(2))
+            })
+            .expect("should query")
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .expect("should read schema");
+        let mut schema = Vec::new();
+        for (kind, name, table) in items {
+            schema.push(format!("{} {} ON {}", kind, name, table));
+            if kind != "table" {
+                continue;
+            }
+            let mut stmt = conn
+                .prepare(&format!("PRAGMA table_info({})", name))
+                .expect("should prepare");
+            let mut columns = stmt
+                .query_map(&[], |row| {
+                    format!(
+                        "    {} {} notnull={} default={:?} pk={}",
+                        row.get::<_, String>("name"),
+                        row.get::<_, String>("type"),
+                        row.get::<_, i64>("notnull"),
+                        row.get::<_, Option<String>>("dflt_value"),
+                        row.get::<_, i64>("pk"),
+                    )
+                })
+                .expect("should query")
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .expect("should read columns");
+            columns.sort();
+            schema.extend(columns);
+        }
+        schema
+    }
+
+    // Opens the database in `fixture`, and checks that upgrading it gives us
+    // the same schema as a new database, without losing anything.
+    fn check_upgrade_from(version: i64, fixture: &str) {
+        let conn = Connection::open_in_memory().expect("no memory db");
+        conn.execute_batch(fixture).expect("should load fixture");
+        assert_eq!(
+            conn.query_one::<i64>("PRAGMA user_version").unwrap(),
+            version
+        );
+
+        let db = PlacesDb::with_connection(conn, None).expect("should upgrade");
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), VERSION);
+        let new_db = PlacesDb::open_in_memory(None).expect("no memory db");
+        assert_eq!(describe_schema(&db), describe_schema(&new_db));
+
+        let (foreign_count, visit_count) = db
+            .query_row(
+                "SELECT foreign_count,
+                        (SELECT COUNT(*) FROM moz_historyvisits WHERE place_id = h.id)
+                 FROM moz_places h WHERE guid = 'placeAAAAAAA'",
+                &[],
+                |row| -> (i64, i64) { (row.get(0), row.get(1)) },
+            )
+            .expect("should have the page");
+        assert_eq!(visit_count, 1);
+        // The fixtures have a bookmark from version 3, and a tag from
+        // version 5.
+        let expected_foreign_count = match version {
+            2 => 0,
+            3 | 4 => 1,
+            _ => 2,
+        };
+        assert_eq!(foreign_count, expected_foreign_count);
+
+        let mobile = fetch_tree(&db, &BookmarkRootGuid::Mobile.as_guid())
+            .expect("should fetch the tree")
+            .expect("should have a mobile root");
+        assert_eq!(mobile.children.len(), if version >= 3 { 1 } else { 0 });
+    }
+
+    #[test]
+    fn test_upgrade_from_v2() {
+        check_upgrade_from(2, include_str!("fixtures/v2.sql"));
+    }
+
+    #[test]
+    fn test_upgrade_from_v3() {
+        check_upgrade_from(3, include_str!("fixtures/v3.sql"));
+    }
+
+    #[test]
+    fn test_upgrade_from_v4() {
+        check_upgrade_from(4, include_str!("fixtures/v4.sql"));
+    }
+
+    #[test]
+    fn test_upgrade_from_v5() {
+        check_upgrade_from(5, include_str!("fixtures/v5.sql"));
+    }
+
+    #[test]
+    fn test_upgrade_from_v6() {
+        check_upgrade_from(6, include_str!("fixtures/v6.sql"));
+    }
+
+    #[test]
+    fn test_upgrade_from_v7() {
+        check_upgrade_from(7, include_str!("fixtures/v7.sql"));
+    }
+
+    #[test]
+    fn test_failed_upgrade() {
+        let dir = tempfile::tempdir().expect("should create a temp dir");
+        let path = dir.path().join("places.sqlite");
+        {
+            let conn = Connection::open(&path).expect("should open");
+            conn.execute_batch(include_str!("fixtures/v5.sql"))
+                .expect("should load fixture");
+            // Upgrading from version 6 creates this table, so that step
+            // should fail.
+            conn.execute_batch(CREATE_TABLE_TOPSITES_BLOCKED_SQL)
+                .expect("should create table");
+        }
+        assert!(PlacesDb::open(&path, None).is_err());
+
+        // The upgrade from version 5 should have stuck.
+        let conn = Connection::open(&path).expect("should open");
+        assert_eq!(conn.query_one::<i64>("PRAGMA user_version").unwrap(), 6);
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places_stale_frecencies")
+                .unwrap(),
+            0
+        );
+        conn.execute_batch("DROP TABLE moz_topsites_blocked")
+            .expect("should drop table");
+        drop(conn);
+
+        // Opening it again picks up where we left off.
+        let db = PlacesDb::open(&path, None).expect("should upgrade");
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), VERSION);
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let dir = tempfile::tempdir().expect("should create a temp dir");
+        let path = dir.path().join("places.sqlite");
+        {
+            let db = PlacesDb::open(&path, None).expect("should open");
+            db.execute_batch(&format!(
+                "INSERT INTO moz_places (guid, url, url_hash)
+                 VALUES ('placeAAAAAAA', 'http://example.com/', hash('http://example.com/'));
+                 DELETE FROM moz_places_stale_frecencies;
+                 PRAGMA user_version = {}",
+                VERSION + 1
+            ))
+            .expect("should pretend to be a newer version");
+        }
+
+        let stale_count = |db: &PlacesDb| {
+            db.query_one::<i64>("SELECT COUNT(*) FROM moz_places_stale_frecencies")
+                .unwrap()
+        };
+        {
+            // The version is left alone, so that the newer version doesn't
+            // upgrade it again.
+            let db = PlacesDb::open(&path, None).expect("should open a newer database");
+            assert_eq!(
+                db.query_one::<i64>("PRAGMA user_version").unwrap(),
+                VERSION + 1
+            );
+            assert_eq!(stale_count(&db), 1);
+            db.execute_batch("DELETE FROM moz_places_stale_frecencies")
+                .expect("should update frecencies");
+        }
+        {
+            // We only need to rebuild once...
+            let db = PlacesDb::open(&path, None).expect("should open a newer database");
+            assert_eq!(stale_count(&db), 0);
+        }
+        {
+            // ...until the newer version uses the database again, which we
+            // pretend to be by opening it at our version.
+            let conn = Connection::open(&path).expect("should open");
+            conn.execute_batch(&format!("PRAGMA user_version = {}", VERSION))
+                .expect("should pretend to be the newer version");
+            let db = PlacesDb::with_connection(conn, None).expect("should open");
+            db.execute_batch(&format!("PRAGMA user_version = {}", VERSION + 1))
+                .expect("should pretend to be a newer version");
+        }
+        let db = PlacesDb::open(&path, None).expect("should open a newer database");
+        assert_eq!(stale_count(&db), 1);
+    }
 }
@@ -71,6 +71,18 @@ pub enum ErrorKind {
 
     #[fail(display = "Error parsing URL: {}", _0)]
     UrlParseError(#[fail(cause)] url::ParseError),
+
+    #[fail(display = "Error upgrading the database: {}", _0)]
+    MigrationError(#[fail(cause)] sql_support::MigrationError),
+
+    #[fail(display = "Operation interrupted")]
+    InterruptedError,
+
+    #[fail(display = "Invalid frecency settings: {}", _0)]
+    InvalidFrecencySettings(String),
+
+    #[fail(display = "I/O error: {}", _0)]
+    IoError(#[fail(cause)] std::io::Error),
 }
 
 macro_rules! impl_from_error {
@@ -96,7 +108,9 @@ impl_from_error! {
     (JsonError, serde_json::Error),
     (UrlParseError, url::ParseError),
     (SqlError, rusqlite::Error),
-    (InvalidPlaceInfo, InvalidPlaceInfo)
+    (MigrationError, sql_support::MigrationError),
+    (InvalidPlaceInfo, InvalidPlaceInfo),
+    (IoError, std::io::Error)
 }
 
 #[derive(Debug, Fail)]
@@ -105,4 +119,14 @@ pub enum InvalidPlaceInfo {
     NoUrl,
     #[fail(display = "Invalid guid")]
     InvalidGuid,
+    #[fail(display = "No such item: {}", _0)]
+    NoItem(String),
+    #[fail(display = "Invalid parent: {}", _0)]
+    InvalidParent(String),
+    #[fail(display = "Can't change a bookmark root: {}", _0)]
+    CannotUpdateRoot(String),
+    #[fail(display = "Illegal change: {}", _0)]
+    IllegalChange(String),
+    #[fail(display = "Invalid tag")]
+    InvalidTag,
 }
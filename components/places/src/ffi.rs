@@ -7,7 +7,7 @@
 // This module implement the traits that make the FFI code easier to manage.
 
 use crate::api::matcher::SearchResult;
-use crate::db::PlacesDb;
+use crate::db::{PlacesDb, PlacesInterruptHandle};
 use crate::error::{Error, ErrorKind};
 use ffi_support::{
     implement_into_ffi_by_json, implement_into_ffi_by_pointer, ErrorCode, ExternError,
@@ -30,6 +30,13 @@ pub mod error_codes {
     /// The requested operation failed because the database was busy
     /// performing operations on a separate connection to the same DB.
     pub const DATABASE_BUSY: i32 = 4;
+
+    /// The requested operation was interrupted by another thread, using a
+    /// `PlacesInterruptHandle`.
+    pub const DATABASE_INTERRUPTED: i32 = 5;
+
+    /// The frecency settings we were given are invalid.
+    pub const INVALID_FRECENCY_SETTINGS: i32 = 6;
 }
 
 fn get_code(err: &Error) -> ErrorCode {
@@ -38,6 +45,10 @@ fn get_code(err: &Error) -> ErrorCode {
             log::error!("Invalid place info: {}", info);
             ErrorCode::new(error_codes::INVALID_PLACE_INFO)
         }
+        ErrorKind::InvalidFrecencySettings(e) => {
+            log::error!("Invalid frecency settings: {}", e);
+            ErrorCode::new(error_codes::INVALID_FRECENCY_SETTINGS)
+        }
         ErrorKind::UrlParseError(e) => {
             log::error!("URL parse error: {}", e);
             ErrorCode::new(error_codes::URL_PARSE_ERROR)
@@ -50,6 +61,16 @@ fn get_code(err: &Error) -> ErrorCode {
             log::error!("Database busy: {:?} {:?}", err, msg);
             ErrorCode::new(error_codes::DATABASE_BUSY)
         }
+        ErrorKind::InterruptedError => {
+            log::info!("Operation interrupted");
+            ErrorCode::new(error_codes::DATABASE_INTERRUPTED)
+        }
+        ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, msg))
+            if err.code == rusqlite::ErrorCode::OperationInterrupted =>
+        {
+            log::info!("Operation interrupted: {:?} {:?}", err, msg);
+            ErrorCode::new(error_codes::DATABASE_INTERRUPTED)
+        }
         err => {
             log::error!("Unexpected error: {:?}", err);
             ErrorCode::new(error_codes::UNEXPECTED)
@@ -64,4 +85,5 @@ impl From<Error> for ExternError {
 }
 
 implement_into_ffi_by_pointer!(PlacesDb);
+implement_into_ffi_by_pointer!(PlacesInterruptHandle);
 implement_into_ffi_by_json!(SearchResult);
@@ -5,6 +5,7 @@
 use crate::error::*;
 use crate::types::VisitTransition;
 use rusqlite::Connection;
+use serde_derive::*;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum RedirectBonus {
@@ -13,7 +14,10 @@ enum RedirectBonus {
     Normal,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// The weights and bonuses used to calculate frecencies. Settings missing
+/// from JSON get their default values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct FrecencySettings {
     // TODO: These probably should not all be i32s...
     pub num_visits: i32,                     // from "places.frecency.numVisits"
@@ -103,6 +107,33 @@ impl FrecencySettings {
         }
     }
 
+    /// Returns an error if these settings can't be used to calculate
+    /// frecencies: at least one visit needs to be sampled, and each bucket
+    /// cutoff needs to be later than the one before it.
+    pub fn validate(&self) -> Result<()> {
+        if self.num_visits <= 0 {
+            return Err(ErrorKind::InvalidFrecencySettings(format!(
+                "num_visits must be positive, not {}",
+                self.num_visits
+            ))
+            .into());
+        }
+        let cutoffs = [
+            self.first_bucket_cutoff_days,
+            self.second_bucket_cutoff_days,
+            self.third_bucket_cutoff_days,
+            self.fourth_bucket_cutoff_days,
+        ];
+        if cutoffs.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(ErrorKind::InvalidFrecencySettings(format!(
+                "bucket cutoffs must be increasing, not {:?}",
+                cutoffs
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
     fn get_frecency_aged_weight(&self, age_in_days: i32) -> i32 {
         if age_in_days <= self.first_bucket_cutoff_days {
             self.first_bucket_weight
@@ -4,7 +4,7 @@
 
 use super::record::{HistoryRecord, HistoryRecordVisit, HistorySyncRecord};
 use super::{HISTORY_TTL, MAX_OUTGOING_PLACES, MAX_VISITS};
-use crate::api::history::can_add_url;
+use crate::api::history::{get_url_filter, UrlFilter};
 use crate::error::*;
 use crate::storage::history_sync::{
     apply_synced_deletion, apply_synced_reconciliation, apply_synced_visits, fetch_outgoing,
@@ -71,6 +71,7 @@ pub enum IncomingPlan {
 
 fn plan_incoming_record(
     conn: &Connection,
+    url_filter: &UrlFilter,
     record: HistoryRecord,
     max_visits: usize,
 ) -> IncomingPlan {
@@ -83,13 +84,8 @@ fn plan_incoming_record(
         return IncomingPlan::Invalid(InvalidPlaceInfo::InvalidGuid.into());
     }
 
-    match can_add_url(&url) {
-        Ok(can) => {
-            if !can {
-                return IncomingPlan::Skip;
-            }
-        }
-        Err(e) => return IncomingPlan::Failed(e.into()),
+    if !url_filter.can_add_url(&url) {
+        return IncomingPlan::Skip;
     }
     // Let's get what we know about it, if anything - last 20, like desktop?
     let visit_tuple = match fetch_visits(conn, &url, max_visits) {
@@ -177,6 +173,7 @@ fn plan_incoming_record(
 pub fn apply_plan(conn: &Connection, inbound: IncomingChangeset) -> Result<OutgoingChangeset> {
     // for a first-cut, let's do this in the most naive way possible...
     let mut plans: Vec<(SyncGuid, IncomingPlan)> = Vec::with_capacity(inbound.changes.len());
+    let url_filter = get_url_filter(conn)?;
     for incoming in inbound.changes {
         let item = match HistorySyncRecord::from_payload(incoming.0) {
             Ok(item) => item,
@@ -188,7 +185,7 @@ pub fn apply_plan(conn: &Connection, inbound: IncomingChangeset) -> Result<Outgo
             }
         };
         let plan = match item.record {
-            Some(record) => plan_incoming_record(conn, record, MAX_VISITS),
+            Some(record) => plan_incoming_record(conn, &url_filter, record, MAX_VISITS),
             None => IncomingPlan::Delete,
         };
         let guid = item.guid.clone();
@@ -289,12 +286,15 @@ pub fn finish_plan(conn: &Connection) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::matcher::{search_frecent, SearchParams};
+    use crate::api::history::set_url_filter;
+    use crate::api::matcher::{search_frecent, MatchFolding, SearchParams};
     use crate::db::PlacesDb;
     use crate::history_sync::ServerVisitTimestamp;
     use crate::observation::VisitObservation;
     use crate::storage::history_sync::fetch_visits;
-    use crate::storage::{apply_observation, delete_place_by_guid, url_to_guid};
+    use crate::storage::{
+        apply_observation, delete_place_by_guid, update_stale_frecencies, url_to_guid,
+    };
     use crate::types::{SyncStatus, Timestamp};
     use serde_json::json;
     use sql_support::ConnExt;
@@ -355,10 +355,12 @@ mod tests {
             visits: vec![],
         };
 
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Invalid(_) => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, &UrlFilter::default(), record, 10) {
+                IncomingPlan::Invalid(_) => true,
+                _ => false,
+            }
+        );
         Ok(())
     }
 
@@ -375,10 +377,12 @@ mod tests {
             visits: vec![],
         };
 
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Invalid(_) => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, &UrlFilter::default(), record, 10) {
+                IncomingPlan::Invalid(_) => true,
+                _ => false,
+            }
+        );
         Ok(())
     }
 
@@ -399,10 +403,12 @@ mod tests {
             visits,
         };
 
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Apply { old_guid: None, .. } => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, &UrlFilter::default(), record, 10) {
+                IncomingPlan::Apply { old_guid: None, .. } => true,
+                _ => false,
+            }
+        );
         Ok(())
     }
 
@@ -436,10 +442,12 @@ mod tests {
             visits,
         };
         // We should have reconciled it.
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Reconciled => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, &UrlFilter::default(), record, 10) {
+                IncomingPlan::Reconciled => true,
+                _ => false,
+            }
+        );
         Ok(())
     }
 
@@ -473,16 +481,18 @@ mod tests {
         };
         // Even though there are no visits we should record that it will be
         // applied with the guid change.
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Apply {
-                old_guid: Some(got_old_guid),
-                ..
-            } => {
-                assert_eq!(got_old_guid, old_guid);
-                true
+        assert!(
+            match plan_incoming_record(&conn, &UrlFilter::default(), record, 10) {
+                IncomingPlan::Apply {
+                    old_guid: Some(got_old_guid),
+                    ..
+                } => {
+                    assert_eq!(got_old_guid, old_guid);
+                    true
+                }
+                _ => false,
             }
-            _ => false,
-        });
+        );
     }
 
     #[test]
@@ -551,7 +561,7 @@ mod tests {
             ttl: 100,
             visits,
         };
-        let plan = plan_incoming_record(&db, record, 10);
+        let plan = plan_incoming_record(&db, &UrlFilter::default(), record, 10);
         // We expect "Reconciled" because after skipping the invalid visit
         // we found nothing to apply.
         assert!(match plan {
@@ -588,6 +598,10 @@ mod tests {
         let visit = visits.into_iter().next().unwrap();
         assert_eq!(visit.visit_date, now);
 
+        // The frecency is updated later, rather than while applying.
+        assert!(!update_stale_frecencies(&db, 0)?);
+        assert!(update_stale_frecencies(&db, 100)?);
+
         // page should have frecency (going through a public api to get this is a pain)
         // XXX - FIXME - searching for "title" here fails to find a result?
         // But above, we've checked title is in the record.
@@ -596,6 +610,8 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com".into(),
                 limit: 2,
+                user_context_id: None,
+                match_folding: MatchFolding::Case,
             },
         )?;
         assert_eq!(found.len(), 1);
@@ -625,6 +641,52 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_blocked_urls() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None)?;
+        let record = HistoryRecord {
+            id: SyncGuid("aaaaaaaaaaaa".to_string()),
+            title: "title".into(),
+            hist_uri: "javascript:alert('hi')".into(),
+            sortindex: 0,
+            ttl: 100,
+            visits: vec![],
+        };
+        assert!(
+            match plan_incoming_record(&db, &UrlFilter::default(), record, 10) {
+                IncomingPlan::Skip => true,
+                _ => false,
+            }
+        );
+
+        // Pages which were added before the filter changed aren't uploaded.
+        let url = Url::parse("https://example.com/a/long/url")?;
+        let obs = VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Some(SystemTime::now().into()));
+        apply_observation(&mut db, obs)?;
+        set_url_filter(
+            &db,
+            &UrlFilter {
+                max_url_length: 20,
+                ..Default::default()
+            },
+        )?;
+        let incoming = IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64));
+        let outgoing = apply_plan(&db, incoming)?;
+        assert_eq!(outgoing.changes.len(), 0);
+        // And they're marked as synced, so they aren't selected again.
+        let (status, counter): (u8, u32) = db.query_row(
+            "SELECT sync_status, sync_change_counter FROM moz_places",
+            &[],
+            |row| (row.get(0), row.get(1)),
+        )?;
+        assert_eq!(status, SyncStatus::Normal as u8);
+        assert_eq!(counter, 0);
+        Ok(())
+    }
+
     #[test]
     fn test_simple_visit_reconciliation() -> Result<()> {
         let _ = env_logger::try_init();
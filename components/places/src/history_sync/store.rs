@@ -2,8 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::db::PlacesDb;
 use crate::error::*;
 use crate::storage::history_sync::reset_storage;
+use crate::storage::{get_meta, put_meta};
 use rusqlite::types::{FromSql, ToSql};
 use rusqlite::Connection;
 use sql_support::ConnExt;
@@ -24,12 +26,12 @@ static GLOBAL_STATE_META_KEY: &'static str = "history_global_state";
 
 // Lifetime here seems wrong
 pub struct HistoryStore<'a> {
-    pub db: &'a Connection,
+    pub db: &'a PlacesDb,
     pub client_info: Cell<Option<ClientInfo>>,
 }
 
 impl<'a> HistoryStore<'a> {
-    pub fn new(db: &'a Connection) -> Self {
+    pub fn new(db: &'a PlacesDb) -> Self {
         Self {
             db,
             client_info: Cell::new(None),
@@ -37,25 +39,17 @@ impl<'a> HistoryStore<'a> {
     }
 
     fn put_meta(&self, key: &str, value: &ToSql) -> Result<()> {
-        self.execute_named_cached(
-            "REPLACE INTO moz_meta (key, value) VALUES (:key, :value)",
-            &[(":key", &key as &ToSql), (":value", value)],
-        )?;
-        Ok(())
+        put_meta(self, key, value)
     }
 
     fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
-        Ok(self.try_query_row(
-            "SELECT value FROM moz_meta WHERE key = :key",
-            &[(":key", &key as &ToSql)],
-            |row| Ok::<_, Error>(row.get_checked(0)?),
-            true,
-        )?)
+        get_meta(self, key)
     }
 
     fn do_apply_incoming(&self, inbound: IncomingChangeset) -> Result<OutgoingChangeset> {
         let timestamp = inbound.timestamp;
         let outgoing = apply_plan(&self, inbound)?;
+        self.db.notify_history_observers();
         // write the timestamp now, so if we are interrupted creating outgoing
         // changesets we don't need to re-reconcile what we just did.
         self.put_meta(LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
@@ -0,0 +1,427 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Reads desktop Firefox's `places.sqlite`. Desktop's schema is where ours
+// came from, so most of this maps directly, except that desktop stores times
+// in microseconds, and bookmarks reference pages by id instead of URL.
+
+use super::*;
+use rusqlite::OpenFlags;
+use std::path::Path;
+
+// The roots we import bookmarks from. These are the same as ours. We don't
+// import the tags root directly; tags are imported separately.
+const DESKTOP_ROOTS: &str = "'menu________', 'toolbar_____', 'unfiled_____', 'mobile______'";
+
+struct DesktopSource {
+    conn: Connection,
+}
+
+/// Imports history, bookmarks, tags and input history from the desktop
+/// `places.sqlite` at `path`, calling `progress` after each batch of records.
+///
+/// The source is opened read-only, but desktop may still be writing to it,
+/// so callers should import from a copy. If the import is interrupted, calling
+/// this again with the same source resumes it. Imported visits are marked as
+/// local: they're the user's own browsing, in the profile they're moving from,
+/// so they should count towards frecency like visits made here.
+pub fn import_desktop_places(
+    db: &PlacesDb,
+    path: impl AsRef<Path>,
+    mut progress: impl FnMut(&ImportProgress),
+) -> Result<ImportStats> {
+    let source = DesktopSource {
+        conn: Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?,
+    };
+    run_import(db, &source, &mut progress)
+}
+
+impl ImportSource for DesktopSource {
+    fn kind(&self) -> &'static str {
+        "desktop"
+    }
+
+    fn count(&self, phase: ImportPhase) -> Result<u64> {
+        let sql = match phase {
+            ImportPhase::History => "SELECT COUNT(*) FROM moz_places",
+            ImportPhase::VisitLinks => {
+                "SELECT COUNT(*) FROM moz_historyvisits WHERE from_visit > 0"
+            }
+            ImportPhase::InputHistory => "SELECT COUNT(*) FROM moz_inputhistory",
+            ImportPhase::Tags => {
+                "SELECT COUNT(*) FROM moz_bookmarks b
+                 JOIN moz_bookmarks t ON t.id = b.parent
+                 JOIN moz_bookmarks r ON r.id = t.parent
+                 WHERE r.guid = 'tags________' AND b.fk NOT NULL"
+            }
+            ImportPhase::Bookmarks | ImportPhase::Finished => return Ok(0),
+        };
+        let count = self.conn.query_row(sql, &[], |row| row.get::<_, i64>(0))?;
+        Ok(count.max(0) as u64)
+    }
+
+    fn fetch_pages(&self, after_id: i64, limit: u32) -> Result<Vec<SourcePage>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, guid, url, title, description, preview_image_url, hidden, typed
+             FROM moz_places
+             WHERE id > :after_id
+             ORDER BY id
+             LIMIT :limit",
+        )?;
+        let mut pages = stmt
+            .query_and_then_named(
+                &[(":after_id", &after_id), (":limit", &limit)],
+                |row| -> Result<_> {
+                    Ok(SourcePage {
+                        id: row.get_checked("id")?,
+                        guid: row
+                            .get_checked::<_, Option<String>>("guid")?
+                            .unwrap_or_default(),
+                        url: row.get_checked("url")?,
+                        title: row.get_checked("title")?,
+                        description: row.get_checked("description")?,
+                        preview_image_url: row.get_checked("preview_image_url")?,
+                        hidden: row.get_checked("hidden")?,
+                        typed: row.get_checked::<_, i64>("typed")?.max(0) as u32,
+                        visits: Vec::new(),
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        let mut visits_stmt = self.conn.prepare_cached(
+            "SELECT visit_date, visit_type FROM moz_historyvisits
+             WHERE place_id = :place_id",
+        )?;
+        for page in &mut pages {
+            page.visits = visits_stmt
+                .query_and_then_named(&[(":place_id", &page.id)], |row| -> Result<_> {
+                    Ok(SourceVisit {
+                        date: from_micros(row.get_checked("visit_date")?),
+                        visit_type: row.get_checked("visit_type")?,
+                        is_local: true,
+                    })
+                })?
+                .collect::<Result<Vec<_>>>()?;
+        }
+        Ok(pages)
+    }
+
+    fn fetch_visit_links(&self, after_id: i64, limit: u32) -> Result<Vec<SourceVisitLink>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT v.id, h.url, v.visit_date,
+                    fh.url AS from_url, fv.visit_date AS from_date
+             FROM moz_historyvisits v
+             JOIN moz_places h ON h.id = v.place_id
+             JOIN moz_historyvisits fv ON fv.id = v.from_visit
+             JOIN moz_places fh ON fh.id = fv.place_id
+             WHERE v.id > :after_id
+             ORDER BY v.id
+             LIMIT :limit",
+        )?;
+        let links = stmt
+            .query_and_then_named(
+                &[(":after_id", &after_id), (":limit", &limit)],
+                |row| -> Result<_> {
+                    Ok(SourceVisitLink {
+                        id: row.get_checked("id")?,
+                        url: row.get_checked("url")?,
+                        date: from_micros(row.get_checked("visit_date")?),
+                        from_url: row.get_checked("from_url")?,
+                        from_date: from_micros(row.get_checked("from_date")?),
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(links)
+    }
+
+    fn fetch_input_history(&self, after_id: i64, limit: u32) -> Result<Vec<SourceInputHistory>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT i.rowid AS id, h.url, i.input, i.use_count
+             FROM moz_inputhistory i
+             JOIN moz_places h ON h.id = i.place_id
+             WHERE i.rowid > :after_id
+             ORDER BY i.rowid
+             LIMIT :limit",
+        )?;
+        let entries = stmt
+            .query_and_then_named(
+                &[(":after_id", &after_id), (":limit", &limit)],
+                |row| -> Result<_> {
+                    Ok(SourceInputHistory {
+                        id: row.get_checked("id")?,
+                        url: row.get_checked("url")?,
+                        input: row.get_checked("input")?,
+                        use_count: row.get_checked("use_count")?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    fn fetch_bookmarks(&self) -> Result<Vec<SourceBookmark>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "WITH RECURSIVE
+             tree(id, level) AS (
+               SELECT id, 0 FROM moz_bookmarks WHERE guid IN ({roots})
+               UNION ALL
+               SELECT b.id, t.level + 1 FROM moz_bookmarks b
+               JOIN tree t ON b.parent = t.id
+             )
+             SELECT b.guid, p.guid AS parent_guid, b.type, h.url, b.title,
+                    b.dateAdded, b.lastModified
+             FROM tree t
+             JOIN moz_bookmarks b ON b.id = t.id
+             JOIN moz_bookmarks p ON p.id = b.parent
+             LEFT JOIN moz_places h ON h.id = b.fk
+             WHERE t.level > 0
+             ORDER BY t.level, b.parent, b.position",
+            roots = DESKTOP_ROOTS
+        ))?;
+        let bookmarks = stmt
+            .query_and_then(&[], |row| -> Result<_> {
+                let kind = row.get_checked::<_, i64>("type")?;
+                Ok(SourceBookmark {
+                    guid: row.get_checked("guid")?,
+                    parent_guid: row.get_checked("parent_guid")?,
+                    kind: if kind > 0 && kind <= i64::from(u8::max_value()) {
+                        BookmarkType::from_u8(kind as u8)
+                    } else {
+                        None
+                    },
+                    url: row.get_checked("url")?,
+                    title: row.get_checked("title")?,
+                    date_added: from_micros(row.get_checked("dateAdded")?),
+                    last_modified: from_micros(row.get_checked("lastModified")?),
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(bookmarks)
+    }
+
+    fn fetch_tags(&self, after_id: i64, limit: u32) -> Result<Vec<SourceTag>> {
+        // Desktop stores tags as folders in the tags root, containing a
+        // bookmark for each tagged URL.
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT b.id, h.url, t.title AS tag
+             FROM moz_bookmarks b
+             JOIN moz_bookmarks t ON t.id = b.parent
+             JOIN moz_bookmarks r ON r.id = t.parent
+             JOIN moz_places h ON h.id = b.fk
+             WHERE r.guid = 'tags________' AND b.id > :after_id
+             ORDER BY b.id
+             LIMIT :limit",
+        )?;
+        let tags = stmt
+            .query_and_then_named(
+                &[(":after_id", &after_id), (":limit", &limit)],
+                |row| -> Result<_> {
+                    Ok(SourceTag {
+                        id: row.get_checked("id")?,
+                        url: row.get_checked("url")?,
+                        tag: row
+                            .get_checked::<_, Option<String>>("tag")?
+                            .unwrap_or_default(),
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bookmarks::{fetch_tree, BookmarkRootGuid};
+    use crate::storage::tags::get_tags_for_url;
+
+    // Enough of desktop's schema for the importer.
+    fn create_desktop_places(path: &Path) -> Result<()> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE moz_places (
+                 id INTEGER PRIMARY KEY, url LONGVARCHAR, title LONGVARCHAR,
+                 hidden INTEGER DEFAULT 0 NOT NULL, typed INTEGER DEFAULT 0 NOT NULL,
+                 guid TEXT, description TEXT, preview_image_url TEXT
+             );
+             CREATE TABLE moz_historyvisits (
+                 id INTEGER PRIMARY KEY, from_visit INTEGER, place_id INTEGER,
+                 visit_date INTEGER, visit_type INTEGER
+             );
+             CREATE TABLE moz_inputhistory (
+                 place_id INTEGER NOT NULL, input LONGVARCHAR NOT NULL,
+                 use_count INTEGER, PRIMARY KEY (place_id, input)
+             );
+             CREATE TABLE moz_bookmarks (
+                 id INTEGER PRIMARY KEY, type INTEGER, fk INTEGER DEFAULT NULL,
+                 parent INTEGER, position INTEGER, title LONGVARCHAR,
+                 dateAdded INTEGER, lastModified INTEGER, guid TEXT
+             );
+
+             INSERT INTO moz_places (id, url, title, hidden, typed, guid) VALUES
+                 (1, 'http://example.com/', 'Example', 0, 1, 'placeAAAAAAA'),
+                 (2, 'http://example.com/redirect', NULL, 1, 0, 'placeBBBBBBB'),
+                 (3, 'http://mozilla.org/', 'Mozilla', 0, 0, 'placeCCCCCCC'),
+                 (4, 'http://bookmarked.com/', 'Bookmarked', 0, 0, 'placeDDDDDDD'),
+                 (5, 'not a url', NULL, 0, 0, 'placeEEEEEEE');
+             INSERT INTO moz_historyvisits (id, from_visit, place_id, visit_date, visit_type) VALUES
+                 (1, 0, 1, 1000000000, 2),
+                 (2, 1, 2, 2000000000, 1),
+                 (3, 2, 3, 2000001000, 5),
+                 (4, 0, 5, 3000000000, 1);
+             INSERT INTO moz_inputhistory (place_id, input, use_count) VALUES
+                 (3, 'moz', 2);
+             INSERT INTO moz_bookmarks (id, type, fk, parent, position, title, dateAdded, lastModified, guid) VALUES
+                 (1, 2, NULL, 0, 0, '', 0, 0, 'root________'),
+                 (2, 2, NULL, 1, 0, 'menu', 0, 0, 'menu________'),
+                 (3, 2, NULL, 1, 1, 'toolbar', 0, 0, 'toolbar_____'),
+                 (4, 2, NULL, 1, 2, 'tags', 0, 0, 'tags________'),
+                 (5, 2, NULL, 1, 3, 'unfiled', 0, 0, 'unfiled_____'),
+                 (6, 2, NULL, 1, 4, 'mobile', 0, 0, 'mobile______'),
+                 (7, 2, NULL, 3, 0, 'Folder', 1000000, 2000000, 'folderAAAAAA'),
+                 (8, 1, 4, 7, 0, 'Bookmarked', 1000000, 2000000, 'bookmarkAAAA'),
+                 (9, 3, NULL, 7, 1, NULL, 1000000, 2000000, 'separatorAAA'),
+                 (10, 1, 1, 2, 0, 'Example', 1000000, 2000000, 'bookmarkBBBB'),
+                 (11, 2, NULL, 4, 0, 'news', 0, 0, 'tagAAAAAAAAA'),
+                 (12, 1, 3, 11, 0, NULL, 0, 0, 'taggedAAAAAA');",
+        )?;
+        Ok(())
+    }
+
+    fn page_guid(conn: &PlacesDb, url: &str) -> Result<Option<String>> {
+        Ok(conn.try_query_row(
+            "SELECT guid FROM moz_places WHERE url = :url",
+            &[(":url", &url)],
+            |row| row.get_checked::<_, String>(0),
+            true,
+        )?)
+    }
+
+    fn count(conn: &PlacesDb, sql: &str) -> Result<i64> {
+        Ok(conn.query_row(sql, &[], |row| row.get::<_, i64>(0))?)
+    }
+
+    #[test]
+    fn test_import_desktop() -> Result<()> {
+        let _ = env_logger::try_init();
+        let dir = tempfile::tempdir().expect("should create a temp dir");
+        let path = dir.path().join("places.sqlite");
+        create_desktop_places(&path)?;
+
+        let conn = PlacesDb::open_in_memory(None)?;
+        let mut phases = Vec::new();
+        let stats = import_desktop_places(&conn, &path, |progress| {
+            phases.push(progress.phase);
+        })?;
+        assert_eq!(stats.pages, 3);
+        assert_eq!(stats.visits, 3);
+        assert_eq!(stats.visit_links, 2);
+        assert_eq!(stats.input_history, 1);
+        assert_eq!(stats.bookmarks, 4);
+        assert_eq!(stats.tags, 1);
+        assert_eq!(stats.errors.len(), 1);
+        assert_eq!(stats.errors[0].phase, ImportPhase::History);
+        assert_eq!(stats.errors[0].record, "not a url");
+        assert_eq!(phases.last(), Some(&ImportPhase::Finished));
+
+        // GUIDs are kept.
+        assert_eq!(
+            page_guid(&conn, "http://example.com/")?,
+            Some("placeAAAAAAA".to_string())
+        );
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM moz_historyvisits v
+                 JOIN moz_historyvisits f ON f.id = v.from_visit"
+            )?,
+            2
+        );
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT hidden FROM moz_places WHERE url = 'http://example.com/redirect'"
+            )?,
+            1
+        );
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM moz_historyvisits WHERE NOT is_local"
+            )?,
+            0
+        );
+
+        let toolbar = fetch_tree(&conn, &BookmarkRootGuid::Toolbar.as_guid())?
+            .expect("should have a toolbar");
+        let folder = &toolbar.children[0];
+        assert_eq!(folder.guid, SyncGuid("folderAAAAAA".into()));
+        assert_eq!(folder.title, Some("Folder".to_string()));
+        assert_eq!(folder.date_added, Timestamp(1000));
+        assert_eq!(
+            folder
+                .children
+                .iter()
+                .map(|c| c.guid.0.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bookmarkAAAA", "separatorAAA"]
+        );
+        assert_eq!(
+            get_tags_for_url(&conn, &Url::parse("http://mozilla.org/")?)?,
+            vec!["news".to_string()]
+        );
+        // Nothing to resume.
+        assert!(get_meta::<String>(&conn, "import_state_desktop")?.is_none());
+
+        // Importing again doesn't duplicate anything.
+        let stats = import_desktop_places(&conn, &path, |_| ())?;
+        assert_eq!(stats.pages, 0);
+        assert_eq!(stats.visits, 0);
+        assert_eq!(stats.bookmarks, 0);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_historyvisits")?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_import() -> Result<()> {
+        let dir = tempfile::tempdir().expect("should create a temp dir");
+        let path = dir.path().join("places.sqlite");
+        create_desktop_places(&path)?;
+
+        let conn = PlacesDb::open_in_memory(None)?;
+        let handle = conn.new_interrupt_handle();
+        let err = import_desktop_places(&conn, &path, |progress| {
+            if progress.phase == ImportPhase::VisitLinks {
+                handle.interrupt();
+            }
+        })
+        .expect_err("should be interrupted");
+        match err.kind() {
+            ErrorKind::InterruptedError => {}
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+        assert!(get_meta::<String>(&conn, "import_state_desktop")?.is_some());
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM moz_bookmarks WHERE guid = 'folderAAAAAA'"
+            )?,
+            0
+        );
+
+        // Importing from another source doesn't discard the paused import.
+        crate::backup::import_bookmarks_from_html(&conn, &b"<DL></DL>"[..])?;
+        assert!(get_meta::<String>(&conn, "import_state_desktop")?.is_some());
+
+        // History was already imported, so we only import the rest.
+        let stats = import_desktop_places(&conn, &path, |_| ())?;
+        assert_eq!(stats.pages, 0);
+        assert_eq!(stats.bookmarks, 4);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_historyvisits")?, 3);
+        assert!(get_meta::<String>(&conn, "import_state_desktop")?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Reads Fennec's `browser.db`. Fennec keeps history in `history`, with visits
+// in `visits` referencing pages by GUID, and bookmarks in `bookmarks`, which
+// store their own URLs. It doesn't have visit links, input history or tags,
+// and deletes records by setting `deleted`, so that it can sync them.
+
+use super::*;
+use crate::storage::bookmarks::BookmarkRootGuid;
+use rusqlite::OpenFlags;
+use std::path::Path;
+
+// Fennec's bookmark types.
+const FENNEC_TYPE_FOLDER: i64 = 0;
+const FENNEC_TYPE_BOOKMARK: i64 = 1;
+const FENNEC_TYPE_SEPARATOR: i64 = 2;
+
+struct FennecSource {
+    conn: Connection,
+}
+
+/// Imports history and bookmarks from the Fennec `browser.db` at `path`,
+/// calling `progress` after each batch of records.
+///
+/// The source is opened read-only, but callers should still import from a
+/// copy if Fennec might be running. If the import is interrupted, calling
+/// this again with the same source resumes it.
+pub fn import_fennec_browser_db(
+    db: &PlacesDb,
+    path: impl AsRef<Path>,
+    mut progress: impl FnMut(&ImportProgress),
+) -> Result<ImportStats> {
+    let source = FennecSource {
+        conn: Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?,
+    };
+    run_import(db, &source, &mut progress)
+}
+
+// Fennec's roots have short GUIDs; everything else has the same GUID here.
+fn map_fennec_guid(guid: String) -> String {
+    match guid.as_str() {
+        "menu" => BookmarkRootGuid::Menu.as_str().into(),
+        "toolbar" => BookmarkRootGuid::Toolbar.as_str().into(),
+        "unfiled" => BookmarkRootGuid::Unfiled.as_str().into(),
+        "mobile" => BookmarkRootGuid::Mobile.as_str().into(),
+        _ => guid,
+    }
+}
+
+impl ImportSource for FennecSource {
+    fn kind(&self) -> &'static str {
+        "fennec"
+    }
+
+    fn count(&self, phase: ImportPhase) -> Result<u64> {
+        if phase != ImportPhase::History {
+            return Ok(0);
+        }
+        let count = self.conn.query_row(
+            "SELECT COUNT(*) FROM history WHERE deleted = 0",
+            &[],
+            |row| row.get::<_, i64>(0),
+        )?;
+        Ok(count.max(0) as u64)
+    }
+
+    fn fetch_pages(&self, after_id: i64, limit: u32) -> Result<Vec<SourcePage>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT _id, guid, url, title FROM history
+             WHERE deleted = 0 AND _id > :after_id
+             ORDER BY _id
+             LIMIT :limit",
+        )?;
+        let mut pages = stmt
+            .query_and_then_named(
+                &[(":after_id", &after_id), (":limit", &limit)],
+                |row| -> Result<_> {
+                    Ok(SourcePage {
+                        id: row.get_checked("_id")?,
+                        guid: row.get_checked("guid")?,
+                        url: row.get_checked("url")?,
+                        title: row.get_checked("title")?,
+                        description: None,
+                        preview_image_url: None,
+                        hidden: false,
+                        typed: 0,
+                        visits: Vec::new(),
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        let mut visits_stmt = self.conn.prepare_cached(
+            "SELECT date, visit_type, is_local FROM visits
+             WHERE history_guid = :guid",
+        )?;
+        for page in &mut pages {
+            page.visits = visits_stmt
+                .query_and_then_named(&[(":guid", &page.guid)], |row| -> Result<_> {
+                    Ok(SourceVisit {
+                        date: from_micros(row.get_checked("date")?),
+                        visit_type: row.get_checked("visit_type")?,
+                        is_local: row.get_checked("is_local")?,
+                    })
+                })?
+                .collect::<Result<Vec<_>>>()?;
+            // Fennec doesn't track typed pages separately.
+            page.typed = page
+                .visits
+                .iter()
+                .filter(|v| v.visit_type == VisitTransition::Typed as i64)
+                .count() as u32;
+        }
+        Ok(pages)
+    }
+
+    fn fetch_visit_links(&self, _after_id: i64, _limit: u32) -> Result<Vec<SourceVisitLink>> {
+        Ok(Vec::new())
+    }
+
+    fn fetch_input_history(&self, _after_id: i64, _limit: u32) -> Result<Vec<SourceInputHistory>> {
+        Ok(Vec::new())
+    }
+
+    fn fetch_bookmarks(&self) -> Result<Vec<SourceBookmark>> {
+        let mut stmt = self.conn.prepare(
+            "WITH RECURSIVE
+             tree(id, level) AS (
+               SELECT _id, 0 FROM bookmarks
+               WHERE guid IN ('menu', 'toolbar', 'unfiled', 'mobile')
+               UNION ALL
+               SELECT b._id, t.level + 1 FROM bookmarks b
+               JOIN tree t ON b.parent = t.id
+               WHERE b.deleted = 0
+             )
+             SELECT b.guid, p.guid AS parent_guid, b.type, b.url, b.title,
+                    b.created, b.modified
+             FROM tree t
+             JOIN bookmarks b ON b._id = t.id
+             JOIN bookmarks p ON p._id = b.parent
+             WHERE t.level > 0
+             ORDER BY t.level, b.parent, b.position",
+        )?;
+        let now = Timestamp::now();
+        let bookmarks = stmt
+            .query_and_then(&[], |row| -> Result<_> {
+                let kind = match row.get_checked::<_, i64>("type")? {
+                    FENNEC_TYPE_FOLDER => Some(BookmarkType::Folder),
+                    FENNEC_TYPE_BOOKMARK => Some(BookmarkType::Bookmark),
+                    FENNEC_TYPE_SEPARATOR => Some(BookmarkType::Separator),
+                    _ => None,
+                };
+                // Fennec stores bookmark times in milliseconds. Either can be
+                // missing, in which case we use the other, or the current time
+                // if both are.
+                let created = row.get_checked::<_, Option<i64>>("created")?;
+                let modified = row.get_checked::<_, Option<i64>>("modified")?;
+                let date_added = created
+                    .or(modified)
+                    .map_or(now, |t| Timestamp(t.max(0) as u64));
+                let last_modified = modified.map_or(date_added, |t| Timestamp(t.max(0) as u64));
+                Ok(SourceBookmark {
+                    guid: row.get_checked("guid")?,
+                    parent_guid: map_fennec_guid(row.get_checked("parent_guid")?),
+                    kind,
+                    url: row.get_checked("url")?,
+                    title: row.get_checked("title")?,
+                    date_added,
+                    last_modified,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(bookmarks)
+    }
+
+    fn fetch_tags(&self, _after_id: i64, _limit: u32) -> Result<Vec<SourceTag>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bookmarks::fetch_tree;
+
+    #[test]
+    fn test_import_fennec() -> Result<()> {
+        let dir = tempfile::tempdir().expect("should create a temp dir");
+        let path = dir.path().join("browser.db");
+        Connection::open(&path)?.execute_batch(
+            "CREATE TABLE history (
+                 _id INTEGER PRIMARY KEY, title TEXT, url TEXT NOT NULL,
+                 guid TEXT NOT NULL, deleted INTEGER NOT NULL DEFAULT 0
+             );
+             CREATE TABLE visits (
+                 _id INTEGER PRIMARY KEY, history_guid TEXT NOT NULL,
+                 visit_type TINYINT NOT NULL DEFAULT 1, date INTEGER NOT NULL,
+                 is_local TINYINT NOT NULL DEFAULT 1
+             );
+             CREATE TABLE bookmarks (
+                 _id INTEGER PRIMARY KEY, title TEXT, url TEXT, type INTEGER NOT NULL,
+                 parent INTEGER, position INTEGER NOT NULL, created INTEGER,
+                 modified INTEGER, guid TEXT NOT NULL, deleted INTEGER NOT NULL DEFAULT 0
+             );
+
+             INSERT INTO history (_id, title, url, guid, deleted) VALUES
+                 (1, 'Example', 'http://example.com/', 'historyAAAAA', 0),
+                 (2, 'Deleted', 'http://deleted.com/', 'historyBBBBB', 1);
+             INSERT INTO visits (history_guid, visit_type, date, is_local) VALUES
+                 ('historyAAAAA', 2, 1000000000, 1),
+                 ('historyAAAAA', 1, 2000000000, 0),
+                 ('historyBBBBB', 1, 2000000000, 1);
+             INSERT INTO bookmarks (_id, title, url, type, parent, position, created, modified, guid, deleted) VALUES
+                 (0, '', NULL, 0, 0, 0, 0, 0, 'places', 0),
+                 (1, 'mobile', NULL, 0, 0, 0, 0, 0, 'mobile', 0),
+                 (2, 'Example', 'http://example.com/', 1, 1, 0, 1000, 2000, 'bookmarkAAAA', 0),
+                 (3, NULL, NULL, 2, 1, 1, 1000, 2000, 'separatorAAA', 0),
+                 (4, 'Gone', 'http://gone.com/', 1, 1, 2, 1000, 2000, 'bookmarkBBBB', 1),
+                 (5, 'Undated', 'http://undated.com/', 1, 1, 3, NULL, 3000, 'bookmarkCCCC', 0);",
+        )?;
+
+        let conn = PlacesDb::open_in_memory(None)?;
+        let stats = import_fennec_browser_db(&conn, &path, |_| ())?;
+        assert_eq!(stats.pages, 1);
+        assert_eq!(stats.visits, 2);
+        assert_eq!(stats.bookmarks, 3);
+        assert!(stats.errors.is_empty());
+
+        let (guid, typed, local_visits) = conn.query_row(
+            "SELECT guid, typed,
+                    (SELECT COUNT(*) FROM moz_historyvisits v
+                     WHERE v.place_id = h.id AND v.is_local)
+             FROM moz_places h WHERE url = 'http://example.com/'",
+            &[],
+            |row| -> (String, i64, i64) { (row.get(0), row.get(1), row.get(2)) },
+        )?;
+        assert_eq!(guid, "historyAAAAA");
+        assert_eq!(typed, 1);
+        assert_eq!(local_visits, 1);
+
+        let mobile = fetch_tree(&conn, &BookmarkRootGuid::Mobile.as_guid())?
+            .expect("should have a mobile root");
+        assert_eq!(
+            mobile
+                .children
+                .iter()
+                .map(|c| c.guid.0.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bookmarkAAAA", "separatorAAA", "bookmarkCCCC"]
+        );
+        assert_eq!(mobile.children[0].date_added, Timestamp(1000));
+        // Bookmarks without a creation time use their modification time.
+        assert_eq!(mobile.children[2].date_added, Timestamp(3000));
+        assert_eq!(mobile.children[2].last_modified, Timestamp(3000));
+        Ok(())
+    }
+}
@@ -0,0 +1,721 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Imports history, bookmarks, tags and input history from another browser's
+// places database into a `PlacesDb`. We support desktop Firefox's
+// `places.sqlite`, and Fennec's `browser.db`.
+//
+// An import runs in phases, and each phase reads the source in small batches.
+// Each batch is imported in its own transaction, along with a note in
+// `moz_meta` saying how far we got, so an import that's interrupted - because
+// the app was killed, or by a `PlacesInterruptHandle` - picks up where it left
+// off when it's run again. Importing a record twice is harmless: pages are
+// matched by URL, visits by their page and date, and bookmarks by GUID and
+// URL, so records that were already imported are skipped.
+//
+// We keep GUIDs, so that pages and bookmarks which were synced from the other
+// browser aren't duplicated when this one syncs. Origins aren't imported
+// directly; the triggers create them for imported pages, like they do for new
+// pages. Frecencies aren't calculated, either - imported pages are marked as
+// stale, and ranked properly once `update_stale_frecencies` runs.
+
+mod desktop;
+mod fennec;
+
+pub use self::desktop::import_desktop_places;
+pub use self::fennec::import_fennec_browser_db;
+
+use crate::api::history::{get_url_filter, UrlFilter};
+use crate::db::{schema, InterruptScope, PlacesDb};
+use crate::error::*;
+use crate::storage::bookmarks::{
+    insert_bookmark_in_tx, BookmarkPosition, InsertableBookmark, InsertableFolder, InsertableItem,
+    InsertableSeparator,
+};
+use crate::storage::tags::{tag_url_in_tx, validate_tag};
+use crate::storage::{delete_meta, get_meta, new_page_info, put_meta, RowId};
+use crate::types::{BookmarkType, SyncGuid, Timestamp, VisitTransition};
+use crate::valid_guid::is_valid_places_guid;
+use rusqlite::Connection;
+use serde_derive::*;
+use sql_support::ConnExt;
+use url::Url;
+
+// How many records we import in each transaction.
+const IMPORT_BATCH_SIZE: u32 = 500;
+
+/// The steps of an import, in the order they run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportPhase {
+    /// Pages, with their titles and visits.
+    History,
+    /// Which visits led to other visits.
+    VisitLinks,
+    /// The pages the user picked for past autocomplete searches.
+    InputHistory,
+    /// Bookmarks, folders and separators.
+    Bookmarks,
+    /// Tags on bookmarked URLs.
+    Tags,
+    Finished,
+}
+
+impl ImportPhase {
+    fn next(self) -> Self {
+        match self {
+            ImportPhase::History => ImportPhase::VisitLinks,
+            ImportPhase::VisitLinks => ImportPhase::InputHistory,
+            ImportPhase::InputHistory => ImportPhase::Bookmarks,
+            ImportPhase::Bookmarks => ImportPhase::Tags,
+            ImportPhase::Tags | ImportPhase::Finished => ImportPhase::Finished,
+        }
+    }
+}
+
+/// Reported after each batch of records is imported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportProgress {
+    pub phase: ImportPhase,
+    /// How many records in this phase have been read, including records that
+    /// were skipped or couldn't be imported.
+    pub processed: u64,
+    /// How many records there are in this phase. This is an estimate; some
+    /// sources don't say how many records they have until they're read.
+    pub total: u64,
+}
+
+/// A record that couldn't be imported. These don't stop the import.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportRecordError {
+    pub phase: ImportPhase,
+    /// The URL or GUID of the record.
+    pub record: String,
+    pub message: String,
+}
+
+/// What an import did. If the import was resumed, this only counts what was
+/// imported since it resumed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportStats {
+    pub pages: u64,
+    pub visits: u64,
+    pub visit_links: u64,
+    pub input_history: u64,
+    pub bookmarks: u64,
+    pub tags: u64,
+    pub errors: Vec<ImportRecordError>,
+}
+
+impl ImportStats {
+    fn add(&mut self, other: ImportStats) {
+        self.pages += other.pages;
+        self.visits += other.visits;
+        self.visit_links += other.visit_links;
+        self.input_history += other.input_history;
+        self.bookmarks += other.bookmarks;
+        self.tags += other.tags;
+        self.errors.extend(other.errors);
+    }
+}
+
+// A page and its visits, as read from the source. `id` is the page's id in
+// the source, and is only used to page through the source.
+#[derive(Clone)]
+pub(crate) struct SourcePage {
+    pub(crate) id: i64,
+    pub(crate) guid: String,
+    pub(crate) url: String,
+    pub(crate) title: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) preview_image_url: Option<String>,
+    pub(crate) hidden: bool,
+    pub(crate) typed: u32,
+    pub(crate) visits: Vec<SourceVisit>,
+}
+
+#[derive(Clone)]
+pub(crate) struct SourceVisit {
+    pub(crate) date: Timestamp,
+    // The source's visit type, which might not be one we know about.
+    pub(crate) visit_type: i64,
+    pub(crate) is_local: bool,
+}
+
+// A visit that came from another visit. Visit ids are different in the
+// source, so we identify both visits by their page's URL and date.
+#[derive(Clone)]
+pub(crate) struct SourceVisitLink {
+    pub(crate) id: i64,
+    pub(crate) url: String,
+    pub(crate) date: Timestamp,
+    pub(crate) from_url: String,
+    pub(crate) from_date: Timestamp,
+}
+
+#[derive(Clone)]
+pub(crate) struct SourceInputHistory {
+    pub(crate) id: i64,
+    pub(crate) url: String,
+    pub(crate) input: String,
+    pub(crate) use_count: f64,
+}
+
+#[derive(Clone)]
+pub(crate) struct SourceBookmark {
+    pub(crate) guid: String,
+    // Already mapped to our root GUIDs, if the parent is a root.
+    pub(crate) parent_guid: String,
+    // `None` if the source has a kind of item that we don't support.
+    pub(crate) kind: Option<BookmarkType>,
+    pub(crate) url: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) date_added: Timestamp,
+    pub(crate) last_modified: Timestamp,
+}
+
+#[derive(Clone)]
+pub(crate) struct SourceTag {
+    pub(crate) id: i64,
+    pub(crate) url: String,
+    pub(crate) tag: String,
+}
+
+// A database we can import from. The `fetch_*` methods, except for
+// `fetch_bookmarks`, return up to `limit` records with ids greater than
+// `after_id`, ordered by id.
+pub(crate) trait ImportSource {
+    // Identifies the kind of source, so that we don't resume an import from
+    // one kind of source with another.
+    fn kind(&self) -> &'static str;
+
+    // Returns how many records there are in `phase`. Bookmarks aren't
+    // counted, because we read them all at once.
+    fn count(&self, phase: ImportPhase) -> Result<u64>;
+
+    fn fetch_pages(&self, after_id: i64, limit: u32) -> Result<Vec<SourcePage>>;
+
+    fn fetch_visit_links(&self, after_id: i64, limit: u32) -> Result<Vec<SourceVisitLink>>;
+
+    fn fetch_input_history(&self, after_id: i64, limit: u32) -> Result<Vec<SourceInputHistory>>;
+
+    // Returns every bookmark, with parents before their children, and
+    // children in order, so that each one can be appended to its parent.
+    // Bookmark roots aren't included.
+    fn fetch_bookmarks(&self) -> Result<Vec<SourceBookmark>>;
+
+    fn fetch_tags(&self, after_id: i64, limit: u32) -> Result<Vec<SourceTag>>;
+
+    // Whether an interrupted import from this source can be resumed. Sources
+    // that are read into memory can't tell if they changed since the last
+    // import, so they always start again.
+    fn is_resumable(&self) -> bool {
+        true
+    }
+}
+
+// How far an import got, stored in `moz_meta` so that it can be resumed.
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportState {
+    fingerprint: String,
+    phase: ImportPhase,
+    // The id of the last record we read. For bookmarks, this is the number of
+    // bookmarks we read.
+    last_id: i64,
+    processed: u64,
+}
+
+impl ImportState {
+    fn new(fingerprint: String) -> Self {
+        ImportState {
+            fingerprint,
+            phase: ImportPhase::History,
+            last_id: 0,
+            processed: 0,
+        }
+    }
+}
+
+// Identifies the source, so that we only resume an import from the same one.
+// If the source changed since the import was interrupted - for example, if
+// it's a newer copy of the profile - we start again, which is safe, but slow.
+fn fingerprint(source: &dyn ImportSource) -> Result<String> {
+    let first_guid = source
+        .fetch_pages(0, 1)?
+        .into_iter()
+        .next()
+        .map(|page| page.guid)
+        .unwrap_or_default();
+    Ok(format!(
+        "{}:{}:{}",
+        source.kind(),
+        source.count(ImportPhase::History)?,
+        first_guid
+    ))
+}
+
+// The `moz_meta` key we save the state of an import from `source` under.
+// Each kind of source has its own key, so that importing from one source
+// doesn't discard a paused import from another.
+pub(crate) fn import_state_key(source: &dyn ImportSource) -> String {
+    format!("{}_{}", schema::MOZ_META_KEY_IMPORT_STATE, source.kind())
+}
+
+// Converts a time in microseconds, which both desktop and Fennec use for
+// visits, to a `Timestamp`.
+pub(crate) fn from_micros(micros: i64) -> Timestamp {
+    Timestamp((micros.max(0) / 1000) as u64)
+}
+
+pub(crate) fn run_import(
+    db: &PlacesDb,
+    source: &dyn ImportSource,
+    progress: &mut dyn FnMut(&ImportProgress),
+) -> Result<ImportStats> {
+    let scope = db.begin_interrupt_scope();
+    let fingerprint = fingerprint(source)?;
+    let state_key = import_state_key(source);
+    let saved_state = get_meta::<String>(db, &state_key)?
+        .and_then(|json| serde_json::from_str::<ImportState>(&json).ok());
+    let mut state = match saved_state {
+        Some(state) if source.is_resumable() && state.fingerprint == fingerprint => {
+            log::info!("Resuming import at {:?}", state.phase);
+            state
+        }
+        _ => ImportState::new(fingerprint),
+    };
+    let mut stats = ImportStats::default();
+    let url_filter = get_url_filter(db)?;
+    let mut bookmarks: Option<Vec<SourceBookmark>> = None;
+    let mut total: Option<u64> = None;
+
+    while state.phase != ImportPhase::Finished {
+        scope.err_if_interrupted()?;
+        let phase_total = match total {
+            Some(total) => total,
+            None => {
+                let phase_total = if state.phase == ImportPhase::Bookmarks {
+                    let all = source.fetch_bookmarks()?;
+                    let len = all.len() as u64;
+                    bookmarks = Some(all);
+                    len
+                } else {
+                    source.count(state.phase)?
+                };
+                total = Some(phase_total);
+                phase_total
+            }
+        };
+        let after_id = state.last_id;
+        let imported_any = match state.phase {
+            ImportPhase::History => {
+                let pages = source.fetch_pages(after_id, IMPORT_BATCH_SIZE)?;
+                import_batch(
+                    db,
+                    &scope,
+                    &mut state,
+                    &mut stats,
+                    &state_key,
+                    &pages,
+                    |page| page.id,
+                    |page| page.url.clone(),
+                    |conn, page, stats| import_page(conn, &url_filter, page, stats),
+                )?
+            }
+            ImportPhase::VisitLinks => {
+                let links = source.fetch_visit_links(after_id, IMPORT_BATCH_SIZE)?;
+                import_batch(
+                    db,
+                    &scope,
+                    &mut state,
+                    &mut stats,
+                    &state_key,
+                    &links,
+                    |link| link.id,
+                    |link| link.url.clone(),
+                    import_visit_link,
+                )?
+            }
+            ImportPhase::InputHistory => {
+                let entries = source.fetch_input_history(after_id, IMPORT_BATCH_SIZE)?;
+                import_batch(
+                    db,
+                    &scope,
+                    &mut state,
+                    &mut stats,
+                    &state_key,
+                    &entries,
+                    |entry| entry.id,
+                    |entry| entry.url.clone(),
+                    import_input_history,
+                )?
+            }
+            ImportPhase::Bookmarks => {
+                // Bookmarks are identified by their position in the list,
+                // starting at 1.
+                let batch = bookmarks
+                    .as_ref()
+                    .map(|all| {
+                        all.iter()
+                            .enumerate()
+                            .skip(after_id as usize)
+                            .take(IMPORT_BATCH_SIZE as usize)
+                            .map(|(index, bookmark)| (index as i64 + 1, bookmark))
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                import_batch(
+                    db,
+                    &scope,
+                    &mut state,
+                    &mut stats,
+                    &state_key,
+                    &batch,
+                    |(id, _)| *id,
+                    |(_, bookmark)| bookmark.guid.clone(),
+                    |conn, (_, bookmark), stats| import_bookmark(conn, bookmark, stats),
+                )?
+            }
+            ImportPhase::Tags => {
+                let tags = source.fetch_tags(after_id, IMPORT_BATCH_SIZE)?;
+                import_batch(
+                    db,
+                    &scope,
+                    &mut state,
+                    &mut stats,
+                    &state_key,
+                    &tags,
+                    |tag| tag.id,
+                    |tag| tag.url.clone(),
+                    import_tag,
+                )?
+            }
+            ImportPhase::Finished => false,
+        };
+        if imported_any {
+            progress(&ImportProgress {
+                phase: state.phase,
+                processed: state.processed,
+                total: phase_total.max(state.processed),
+            });
+            continue;
+        }
+        state.phase = state.phase.next();
+        state.last_id = 0;
+        state.processed = 0;
+        total = None;
+        if state.phase == ImportPhase::Finished {
+            delete_meta(db, &state_key)?;
+        } else {
+            put_meta(db, &state_key, &serde_json::to_string(&state)?)?;
+        }
+    }
+    progress(&ImportProgress {
+        phase: ImportPhase::Finished,
+        processed: 0,
+        total: 0,
+    });
+    Ok(stats)
+}
+
+// Imports a batch of records in a transaction, and saves how far we got in
+// the same transaction. Returns false if the batch was empty, which means the
+// phase is done.
+#[allow(clippy::too_many_arguments)]
+fn import_batch<T>(
+    db: &PlacesDb,
+    scope: &InterruptScope,
+    state: &mut ImportState,
+    stats: &mut ImportStats,
+    state_key: &str,
+    records: &[T],
+    id: impl Fn(&T) -> i64,
+    describe: impl Fn(&T) -> String,
+    import: impl Fn(&PlacesDb, &T, &mut ImportStats) -> Result<()>,
+) -> Result<bool> {
+    if records.is_empty() {
+        return Ok(false);
+    }
+    // The batch might still be rolled back, so we only add what it imported,
+    // and the records it couldn't, to `stats` once it's committed.
+    let mut batch_stats = ImportStats::default();
+    db.in_transaction(|| -> Result<()> {
+        for record in records {
+            // Each record gets its own savepoint, so that a record which
+            // fails part way through doesn't leave anything behind.
+            db.execute_batch("SAVEPOINT import_record")?;
+            match import(db, record, &mut batch_stats) {
+                Ok(()) => db.execute_batch("RELEASE import_record")?,
+                Err(e) => {
+                    db.execute_batch("ROLLBACK TO import_record; RELEASE import_record")?;
+                    // If we were interrupted, the record is fine; stop here
+                    // and import it next time.
+                    scope.err_if_interrupted()?;
+                    log::warn!("Failed to import {:?} record: {}", state.phase, e);
+                    batch_stats.errors.push(ImportRecordError {
+                        phase: state.phase,
+                        record: describe(record),
+                        message: e.to_string(),
+                    });
+                }
+            }
+            state.last_id = id(record);
+            state.processed += 1;
+        }
+        put_meta(db, state_key, &serde_json::to_string(state)?)?;
+        Ok(())
+    })?;
+    stats.add(batch_stats);
+    Ok(true)
+}
+
+// The `import_*` functions only update `stats` once everything else has
+// succeeded, so that records which fail aren't counted.
+
+fn import_page(
+    db: &PlacesDb,
+    url_filter: &UrlFilter,
+    page: &SourcePage,
+    stats: &mut ImportStats,
+) -> Result<()> {
+    let url = Url::parse(&page.url)?;
+    let visits = page
+        .visits
+        .iter()
+        .filter_map(|visit| {
+            if visit.visit_type <= 0 || visit.visit_type > i64::from(u8::max_value()) {
+                return None;
+            }
+            VisitTransition::from_primitive(visit.visit_type as u8).map(|t| (visit, t))
+        })
+        .collect::<Vec<_>>();
+    // Pages without visits are only interesting if they're bookmarked, and
+    // bookmarks bring in their own pages.
+    if visits.is_empty() || !url_filter.can_add_url(&url) {
+        return Ok(());
+    }
+    let existing = db.try_query_row(
+        "SELECT id, hidden FROM moz_places
+         WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str())],
+        |row| -> Result<_> {
+            Ok((
+                row.get_checked::<_, RowId>("id")?,
+                row.get_checked::<_, bool>("hidden")?,
+            ))
+        },
+        true,
+    )?;
+    let (place_id, hidden, is_new) = match existing {
+        // A page that's visible here, or in the source, stays visible.
+        Some((place_id, hidden)) => (place_id, hidden && page.hidden, false),
+        None => {
+            // Keep the page's GUID, unless it's invalid, or another page
+            // already has it.
+            let guid = if is_valid_places_guid(&page.guid) && !place_guid_exists(db, &page.guid)? {
+                Some(SyncGuid(page.guid.clone()))
+            } else {
+                None
+            };
+            (new_page_info(db, &url, guid)?.row_id, page.hidden, true)
+        }
+    };
+    let preview_image_url = page
+        .preview_image_url
+        .as_ref()
+        .and_then(|url| Url::parse(url).ok())
+        .map(String::from);
+    // We don't overwrite anything we already know about the page.
+    db.execute_named_cached(
+        "UPDATE moz_places SET
+             title = IFNULL(NULLIF(title, ''), :title),
+             description = IFNULL(description, :description),
+             preview_image_url = IFNULL(preview_image_url, :preview_image_url),
+             hidden = :hidden,
+             typed = MAX(typed, :typed)
+         WHERE id = :place_id",
+        &[
+            (":title", &page.title),
+            (":description", &page.description),
+            (":preview_image_url", &preview_image_url),
+            (":hidden", &hidden),
+            (":typed", &page.typed),
+            (":place_id", &place_id),
+        ],
+    )?;
+    let mut num_visits = 0;
+    for (visit, visit_type) in visits {
+        num_visits += db.execute_named_cached(
+            "INSERT INTO moz_historyvisits (place_id, visit_date, visit_type, is_local)
+             SELECT :place_id, :visit_date, :visit_type, :is_local
+             WHERE NOT EXISTS(SELECT 1 FROM moz_historyvisits
+                              WHERE place_id = :place_id AND visit_date = :visit_date)",
+            &[
+                (":place_id", &place_id),
+                (":visit_date", &visit.date),
+                (":visit_type", &visit_type),
+                (":is_local", &visit.is_local),
+            ],
+        )? as u64;
+    }
+    if is_new {
+        stats.pages += 1;
+    }
+    stats.visits += num_visits;
+    Ok(())
+}
+
+fn place_guid_exists(db: &Connection, guid: &str) -> Result<bool> {
+    Ok(db.query_row_named(
+        "SELECT EXISTS(SELECT 1 FROM moz_places WHERE guid = :guid)",
+        &[(":guid", &guid)],
+        |row| row.get::<_, bool>(0),
+    )?)
+}
+
+// Returns the id of the visit to `url` at `date`, if we have one.
+fn find_visit(db: &Connection, url: &Url, date: Timestamp) -> Result<Option<RowId>> {
+    Ok(db.try_query_row(
+        "SELECT v.id FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         WHERE h.url_hash = hash(:url) AND h.url = :url
+           AND v.visit_date = :date",
+        &[(":url", &url.as_str()), (":date", &date)],
+        |row| row.get_checked::<_, RowId>(0),
+        true,
+    )?)
+}
+
+fn import_visit_link(db: &PlacesDb, link: &SourceVisitLink, stats: &mut ImportStats) -> Result<()> {
+    let url = Url::parse(&link.url)?;
+    let from_url = Url::parse(&link.from_url)?;
+    // Either visit might not have been imported, if its page couldn't be.
+    let (visit_id, from_visit_id) = match (
+        find_visit(db, &url, link.date)?,
+        find_visit(db, &from_url, link.from_date)?,
+    ) {
+        (Some(visit_id), Some(from_visit_id)) => (visit_id, from_visit_id),
+        _ => return Ok(()),
+    };
+    let changes = db.execute_named_cached(
+        "UPDATE moz_historyvisits SET from_visit = :from_visit_id
+         WHERE id = :visit_id AND from_visit IS NULL",
+        &[(":from_visit_id", &from_visit_id), (":visit_id", &visit_id)],
+    )?;
+    stats.visit_links += changes as u64;
+    Ok(())
+}
+
+fn import_input_history(
+    db: &PlacesDb,
+    entry: &SourceInputHistory,
+    stats: &mut ImportStats,
+) -> Result<()> {
+    let url = Url::parse(&entry.url)?;
+    // If we already have this entry, keep whichever was used more.
+    let changes = db.execute_named_cached(
+        "INSERT OR REPLACE INTO moz_inputhistory (place_id, input, use_count)
+         SELECT h.id, :input, MAX(:use_count, IFNULL(i.use_count, 0))
+         FROM moz_places h
+         LEFT JOIN moz_inputhistory i ON i.place_id = h.id AND i.input = :input
+         WHERE h.url_hash = hash(:url) AND h.url = :url",
+        &[
+            (":input", &entry.input),
+            (":use_count", &entry.use_count),
+            (":url", &url.as_str()),
+        ],
+    )?;
+    stats.input_history += changes as u64;
+    Ok(())
+}
+
+fn import_bookmark(
+    db: &PlacesDb,
+    bookmark: &SourceBookmark,
+    stats: &mut ImportStats,
+) -> Result<()> {
+    if !is_valid_places_guid(&bookmark.guid) {
+        return Err(InvalidPlaceInfo::InvalidGuid.into());
+    }
+    let exists = db.query_row_named(
+        "SELECT EXISTS(SELECT 1 FROM moz_bookmarks WHERE guid = :guid)",
+        &[(":guid", &bookmark.guid)],
+        |row| row.get::<_, bool>(0),
+    )?;
+    if exists {
+        return Ok(());
+    }
+    // Sources that don't have GUIDs, like bookmarks HTML files, might have
+    // bookmarks we already have, so we also skip bookmarks for a URL that's
+    // already in the same folder.
+    if let (Some(BookmarkType::Bookmark), Some(url)) = (bookmark.kind, &bookmark.url) {
+        let url = Url::parse(url)?;
+        let exists = db.query_row_named(
+            "SELECT EXISTS(SELECT 1 FROM moz_bookmarks b
+                           JOIN moz_bookmarks p ON p.id = b.parent
+                           JOIN moz_places h ON h.id = b.fk
+                           WHERE p.guid = :parent_guid
+                             AND h.url_hash = hash(:url) AND h.url = :url)",
+            &[
+                (":parent_guid", &bookmark.parent_guid),
+                (":url", &url.as_str()),
+            ],
+            |row| row.get::<_, bool>(0),
+        )?;
+        if exists {
+            return Ok(());
+        }
+    }
+    let parent_guid = SyncGuid(bookmark.parent_guid.clone());
+    let position = BookmarkPosition::Append;
+    let date_added = Some(bookmark.date_added);
+    let last_modified = Some(bookmark.last_modified);
+    let guid = Some(SyncGuid(bookmark.guid.clone()));
+    let item = match bookmark.kind {
+        Some(BookmarkType::Bookmark) => {
+            let url = bookmark.url.as_ref().ok_or(InvalidPlaceInfo::NoUrl)?;
+            InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid,
+                position,
+                date_added,
+                last_modified,
+                guid,
+                url: Url::parse(url)?,
+                title: bookmark.title.clone(),
+            })
+        }
+        Some(BookmarkType::Folder) => InsertableItem::Folder(InsertableFolder {
+            parent_guid,
+            position,
+            date_added,
+            last_modified,
+            guid,
+            title: bookmark.title.clone(),
+        }),
+        Some(BookmarkType::Separator) => InsertableItem::Separator(InsertableSeparator {
+            parent_guid,
+            position,
+            date_added,
+            last_modified,
+            guid,
+        }),
+        None => {
+            return Err(InvalidPlaceInfo::IllegalChange(format!(
+                "Unsupported bookmark type for {}",
+                bookmark.guid
+            ))
+            .into());
+        }
+    };
+    insert_bookmark_in_tx(db, &item)?;
+    stats.bookmarks += 1;
+    Ok(())
+}
+
+fn import_tag(db: &PlacesDb, tag: &SourceTag, stats: &mut ImportStats) -> Result<()> {
+    let url = Url::parse(&tag.url)?;
+    tag_url_in_tx(db, &url, validate_tag(&tag.tag)?)?;
+    stats.tags += 1;
+    Ok(())
+}
@@ -3,24 +3,29 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 pub mod api;
+pub mod backup;
 pub mod error;
 pub mod types;
 // Making these all pub for now while we flesh out the API.
+pub mod bookmark_sync;
 pub mod db;
 #[cfg(feature = "ffi")]
 pub mod ffi;
 pub mod frecency;
 pub mod hash;
 pub mod history_sync;
+pub mod import;
 mod match_impl;
 pub mod observation;
+pub mod observer;
 pub mod storage;
 mod util;
 mod valid_guid;
 
 pub use crate::api::apply_observation;
-pub use crate::db::PlacesDb;
+pub use crate::db::{PlacesDb, PlacesInterruptHandle};
 pub use crate::error::*;
 pub use crate::observation::VisitObservation;
+pub use crate::observer::{HistoryEvent, HistoryObserver};
 pub use crate::storage::{PageInfo, RowId};
 pub use crate::types::*;
@@ -10,6 +10,7 @@ use rusqlite::{
     types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef},
 };
 use std::borrow::Cow;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use url::percent_encoding;
 
 const MAX_CHARS_TO_SEARCH_THROUGH: usize = 255;
@@ -56,6 +57,41 @@ impl ToSql for MatchBehavior {
     }
 }
 
+/// How characters in the search string are compared to the text we search.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u32)]
+pub enum MatchFolding {
+    /// Ignore case, like Desktop.
+    Case = 0,
+    /// Ignore case and diacritics, and compare the Unicode compatibility forms
+    /// of characters, so "cafe" matches "Café", and "file" matches "ﬁle".
+    CaseAndDiacritics = 1,
+}
+
+impl Default for MatchFolding {
+    fn default() -> MatchFolding {
+        MatchFolding::Case
+    }
+}
+
+impl FromSql for MatchFolding {
+    #[inline]
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        Ok(match value.as_i64()? {
+            0 => MatchFolding::Case,
+            1 => MatchFolding::CaseAndDiacritics,
+            _ => Err(FromSqlError::InvalidType)?,
+        })
+    }
+}
+
+impl ToSql for MatchFolding {
+    #[inline]
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u32))
+    }
+}
+
 bitflags! {
     pub struct SearchBehavior: u32 {
         /// Search through history.
@@ -79,7 +115,7 @@ bitflags! {
         /// Search for javascript: urls
         const JAVASCRIPT = 1 << 6;
 
-        /// Search for open pages (currently not meaningfully implemented)
+        /// Search for pages open in tabs. See `storage::open_tabs`.
         const OPENPAGE = 1 << 7;
 
         /// Use intersection between history, typed, bookmark, tag and openpage
@@ -303,6 +339,73 @@ pub fn find_in_string(token: &str, src: &str, only_boundary: bool) -> bool {
     return false;
 }
 
+/// Removes diacritics from `s`, and replaces characters with their
+/// compatibility forms, for `MatchFolding::CaseAndDiacritics`. Each character
+/// is decomposed, stripped of combining marks, and composed again on its own,
+/// so "Ｃafé" becomes "Cafe".
+fn strip_diacritics(s: &str) -> Cow<str> {
+    if s.is_ascii() {
+        return Cow::Borrowed(s);
+    }
+    let mut stripped = String::with_capacity(s.len());
+    for c in s.chars() {
+        push_stripped_char(&mut stripped, c);
+    }
+    Cow::Owned(stripped)
+}
+
+#[inline]
+fn push_stripped_char(stripped: &mut String, c: char) {
+    if c.is_ascii() {
+        stripped.push(c);
+    } else {
+        stripped.extend(c.nfkd().filter(|&c| !is_combining_mark(c)).nfc());
+    }
+}
+
+/// Text to match tokens against, with diacritics removed if we're ignoring
+/// them. Removing them changes where `is_on_boundary` finds word boundaries,
+/// like the one before the "t" in "Caféteria", so we remember the boundaries
+/// of the original text, and match on those instead.
+struct FoldedText<'a> {
+    text: Cow<'a, str>,
+    // The offsets in `text` of the boundaries in the original text, if it
+    // had any diacritics or compatibility characters.
+    boundaries: Option<Vec<usize>>,
+}
+
+impl<'a> FoldedText<'a> {
+    fn new(text: &'a str, folding: MatchFolding) -> Self {
+        if folding == MatchFolding::Case || text.is_ascii() {
+            return FoldedText {
+                text: Cow::Borrowed(text),
+                boundaries: None,
+            };
+        }
+        let mut folded = String::with_capacity(text.len());
+        let mut boundaries = Vec::new();
+        for (index, c) in text.char_indices() {
+            if is_on_boundary(text, index) {
+                boundaries.push(folded.len());
+            }
+            push_stripped_char(&mut folded, c);
+        }
+        FoldedText {
+            text: Cow::Owned(folded),
+            boundaries: Some(boundaries),
+        }
+    }
+
+    fn matches(&self, token: &str, search_fn: fn(&str, &str) -> bool, only_boundary: bool) -> bool {
+        match &self.boundaries {
+            Some(boundaries) if only_boundary => boundaries
+                .iter()
+                .any(|&index| string_match(token, &self.text[index..])),
+            _ => search_fn(token, &self.text),
+        }
+    }
+}
+
 // Places splits on ascii whitespace, so we do too. str::split_ascii_whitespace is
 // currently not stable, so we use this, which is the same thing and based on it's source.
 #[inline]
@@ -338,6 +441,116 @@ fn find_beginning_case_sensitive(token: &str, source: &str) -> bool {
     source.starts_with(token)
 }
 
+/// Percent-decodes `s`, after removing the http, https or ftp scheme if
+/// `strip_prefix` is true. We match against URLs in this form.
+fn fixup_url(mut s: &str, strip_prefix: bool) -> Cow<str> {
+    if strip_prefix {
+        if s.starts_with("http://") {
+            s = &s[7..];
+        } else if s.starts_with("https://") {
+            s = &s[8..];
+        } else if s.starts_with("ftp://") {
+            s = &s[6..];
+        }
+    }
+    // TODO: would be nice to decode punycode here too, but for now
+    // this is probably fine.
+    match percent_encoding::percent_decode(s.as_bytes()).decode_utf8() {
+        Err(_) => Cow::Borrowed(s),
+        Ok(decoded) => decoded,
+    }
+}
+
+/// The most characters of a word that we add to the search index for a
+/// boundary inside the word, and search for in each part of a token.
+const MAX_INDEXED_WORD_CHARS: usize = 16;
+
+/// Returns the text to store in the search index (see
+/// `storage::search_index`) for a URL, title or tag. `trim` limits it to the
+/// part of the text that `AutocompleteMatch::invoke` searches.
+///
+/// The index splits text into words, and finds tokens at the start of them.
+/// `find_on_boundary` also matches at boundaries inside words, like the "B" in
+/// "FooBar" or the "3" in "mp3", so we add the rest of the word after each of
+/// those as another word. If the text has diacritics, we add the words without
+/// them too, for `MatchFolding::CaseAndDiacritics`. This way, the query from
+/// `search_index_query` finds every page that `invoke` matches on boundaries,
+/// and maybe a few more.
+pub(crate) fn search_index_text(text: &str, is_url: bool, trim: bool) -> String {
+    let fixed = if is_url {
+        fixup_url(text, true)
+    } else {
+        Cow::Borrowed(text)
+    };
+    let text = if trim {
+        util::slice_up_to(&fixed, MAX_CHARS_TO_SEARCH_THROUGH)
+    } else {
+        &fixed
+    };
+    let mut indexed = String::new();
+    push_index_words(&mut indexed, text);
+    let stripped = strip_diacritics(text);
+    if stripped != text {
+        indexed.push(' ');
+        push_index_words(&mut indexed, &stripped);
+    }
+    indexed
+}
+
+fn push_index_words(indexed: &mut String, text: &str) {
+    indexed.extend(text.chars().default_case_fold());
+    let mut prev = None;
+    for (index, c) in text.char_indices() {
+        let starts_word = match prev {
+            Some(p) if p.is_alphanumeric() => c.is_alphanumeric() && is_on_boundary(text, index),
+            // The index's tokenizer might not split words on the same
+            // non-ASCII characters as we do, so add those words as well.
+            Some(p) => c.is_alphanumeric() && !p.is_ascii(),
+            None => false,
+        };
+        if starts_word {
+            indexed.push(' ');
+            indexed.extend(
+                text[index..]
+                    .chars()
+                    .take_while(|c| c.is_alphanumeric())
+                    .default_case_fold()
+                    .take(MAX_INDEXED_WORD_CHARS),
+            );
+        }
+        prev = Some(c);
+    }
+}
+
+/// Returns an FTS5 query for the search index that matches every page that
+/// `search_str` matches on boundaries, or `None` if there's nothing to search
+/// for. Each token is split into words, and we look for pages with words that
+/// start with each of them.
+pub(crate) fn search_index_query(search_str: &str, folding: MatchFolding) -> Option<String> {
+    let search_str = match folding {
+        MatchFolding::Case => Cow::Borrowed(search_str),
+        MatchFolding::CaseAndDiacritics => strip_diacritics(search_str),
+    };
+    let mut terms = Vec::new();
+    for token in ascii_words(&search_str) {
+        let folded: String = token.chars().default_case_fold().collect();
+        for word in folded.split(|c: char| !c.is_alphanumeric()) {
+            if word.is_empty() {
+                continue;
+            }
+            let prefix: String = word.chars().take(MAX_INDEXED_WORD_CHARS).collect();
+            terms.push(format!("\"{}\"*", prefix));
+        }
+    }
+    if terms.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{{url title tags description}} : ({})",
+        terms.join(" AND ")
+    ))
+}
+
 // I can't wait for Rust 2018 when lifetime annotations are automatic.
 pub struct AutocompleteMatch<'search, 'url, 'title, 'tags> {
     pub search_str: &'search str,
@@ -350,36 +563,36 @@ pub struct AutocompleteMatch<'search, 'url, 'title, 'tags> {
     pub open_page_count: u32,
     pub match_behavior: MatchBehavior,
     pub search_behavior: SearchBehavior,
+    pub folding: MatchFolding,
 }
 
 impl<'search, 'url, 'title, 'tags> AutocompleteMatch<'search, 'url, 'title, 'tags> {
     fn get_search_fn(&self) -> fn(&str, &str) -> bool {
+        if self.matches_on_boundaries() {
+            return find_on_boundary;
+        }
         match self.match_behavior {
-            MatchBehavior::Anywhere | MatchBehavior::AnywhereUnmodified => find_anywhere,
             MatchBehavior::Beginning => find_beginning,
             MatchBehavior::BeginningCaseSensitive => find_beginning_case_sensitive,
-            _ => find_on_boundary,
+            _ => find_anywhere,
         }
     }
 
-    fn fixup_url_str<'a>(&self, mut s: &'a str) -> Cow<'a, str> {
-        if self.match_behavior != MatchBehavior::AnywhereUnmodified {
-            if s.starts_with("http://") {
-                s = &s[7..];
-            } else if s.starts_with("https://") {
-                s = &s[8..];
-            } else if s.starts_with("ftp://") {
-                s = &s[6..];
-            }
-        }
-        // TODO: would be nice to decode punycode here too, but for now
-        // this is probably fine.
-        match percent_encoding::percent_decode(s.as_bytes()).decode_utf8() {
-            Err(_) => Cow::Borrowed(s),
-            Ok(decoded) => decoded,
+    #[inline]
+    fn matches_on_boundaries(&self) -> bool {
+        match self.match_behavior {
+            MatchBehavior::Anywhere
+            | MatchBehavior::AnywhereUnmodified
+            | MatchBehavior::Beginning
+            | MatchBehavior::BeginningCaseSensitive => false,
+            _ => true,
         }
     }
 
+    fn fixup_url_str<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        fixup_url(s, self.match_behavior != MatchBehavior::AnywhereUnmodified)
+    }
+
     #[inline]
     fn has_behavior(&self, behavior: SearchBehavior) -> bool {
         self.search_behavior.intersects(behavior)
@@ -416,21 +629,27 @@ impl<'search, 'url, 'title, 'tags> AutocompleteMatch<'search, 'url, 'title, 'tag
 
         let trimmed_url = util::slice_up_to(fixed_url.as_ref(), MAX_CHARS_TO_SEARCH_THROUGH);
         let trimmed_title = util::slice_up_to(self.title_str, MAX_CHARS_TO_SEARCH_THROUGH);
-        for token in ascii_words(self.search_str) {
+        let search_str = match self.folding {
+            MatchFolding::Case => Cow::Borrowed(self.search_str),
+            MatchFolding::CaseAndDiacritics => strip_diacritics(self.search_str),
+        };
+        let url = FoldedText::new(trimmed_url, self.folding);
+        let title = FoldedText::new(trimmed_title, self.folding);
+        let tags = FoldedText::new(self.tags, self.folding);
+        let only_boundary = self.matches_on_boundaries();
+        let search = |token: &str, text: &FoldedText| text.matches(token, search_fn, only_boundary);
+        for token in ascii_words(&search_str) {
             let matches = match (
                 self.has_behavior(SearchBehavior::TITLE),
                 self.has_behavior(SearchBehavior::URL),
             ) {
                 (true, true) => {
-                    (search_fn(token, trimmed_title) || search_fn(token, self.tags))
-                        && search_fn(token, trimmed_url)
+                    (search(token, &title) || search(token, &tags)) && search(token, &url)
                 }
-                (true, false) => search_fn(token, trimmed_title) || search_fn(token, self.tags),
-                (false, true) => search_fn(token, trimmed_url),
+                (true, false) => search(token, &title) || search(token, &tags),
+                (false, true) => search(token, &url),
                 (false, false) => {
-                    search_fn(token, trimmed_url)
-                        || search_fn(token, trimmed_title)
-                        || search_fn(token, self.tags)
+                    search(token, &url) || search(token, &title) || search(token, &tags)
                 }
             };
             if !matches {
@@ -530,4 +749,111 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_search_index_text() {
+        assert_eq!(
+            search_index_text("https://example.com/FooBar%20mp3", true, true),
+            "example.com/foobar mp3 bar 3"
+        );
+        assert_eq!(
+            search_index_text("Über Tab", false, true),
+            "über tab ber uber tab"
+        );
+        let long = "x".repeat(MAX_CHARS_TO_SEARCH_THROUGH + 10);
+        assert_eq!(
+            search_index_text(&long, false, true).len(),
+            MAX_CHARS_TO_SEARCH_THROUGH
+        );
+        assert_eq!(search_index_text(&long, false, false), long);
+    }
+
+    #[test]
+    fn test_search_index_query() {
+        assert_eq!(search_index_query(" \t", MatchFolding::Case), None);
+        assert_eq!(search_index_query("-- //", MatchFolding::Case), None);
+        assert_eq!(
+            search_index_query("Foo.Bar über", MatchFolding::Case),
+            Some(r#"{url title tags description} : ("foo"* AND "bar"* AND "über"*)"#.into())
+        );
+        assert_eq!(
+            search_index_query("über", MatchFolding::CaseAndDiacritics),
+            Some(r#"{url title tags description} : ("uber"*)"#.into())
+        );
+    }
+
+    #[test]
+    fn test_strip_diacritics() {
+        assert_eq!(strip_diacritics("plain ascii"), "plain ascii");
+        assert_eq!(strip_diacritics("Café Crème"), "Cafe Creme");
+        // Decomposed characters too.
+        assert_eq!(strip_diacritics("Cafe\u{301}"), "Cafe");
+        assert_eq!(strip_diacritics("ﬁle Ｃafé"), "file Cafe");
+        // Hangul decomposes into jamo, which aren't combining marks.
+        assert_eq!(strip_diacritics("한국어"), "한국어");
+    }
+
+    #[test]
+    fn test_match_folding() {
+        let matches = |search_str, title_str, match_behavior, folding| {
+            AutocompleteMatch {
+                search_str,
+                url_str: "http://example.com/",
+                title_str,
+                tags: "",
+                visit_count: 1,
+                typed: false,
+                bookmarked: false,
+                open_page_count: 0,
+                match_behavior,
+                search_behavior: SearchBehavior::default(),
+                folding,
+            }
+            .invoke()
+        };
+        let behavior = MatchBehavior::Boundary;
+        assert!(!matches("cafe", "Café Crème", behavior, MatchFolding::Case));
+        assert!(matches(
+            "cafe",
+            "Café Crème",
+            behavior,
+            MatchFolding::CaseAndDiacritics
+        ));
+        assert!(matches(
+            "CRÈME",
+            "Café Creme",
+            behavior,
+            MatchFolding::CaseAndDiacritics
+        ));
+        // Ignoring diacritics doesn't change the boundaries, so "teria" is
+        // still after one in "Caféteria", and "é" is still at one.
+        for &folding in &[MatchFolding::Case, MatchFolding::CaseAndDiacritics] {
+            assert!(matches("teria", "Caféteria", behavior, folding));
+            assert!(!matches("teria", "Cafeteria", behavior, folding));
+        }
+        assert!(!matches(
+            "eteria",
+            "Caféteria",
+            behavior,
+            MatchFolding::Case
+        ));
+        assert!(matches(
+            "eteria",
+            "Caféteria",
+            behavior,
+            MatchFolding::CaseAndDiacritics
+        ));
+        assert!(matches(
+            "cafeteria",
+            "Caféteria",
+            behavior,
+            MatchFolding::CaseAndDiacritics
+        ));
+        assert!(matches(
+            "fet",
+            "Caféteria",
+            MatchBehavior::Anywhere,
+            MatchFolding::CaseAndDiacritics
+        ));
+    }
 }
@@ -56,6 +56,27 @@ pub struct VisitObservation {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub is_remote: Option<bool>,
+
+    // Page metadata. Like the title, these update the page, and can be
+    // observed with or without a visit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub description: Option<String>,
+
+    #[serde(with = "url_serde")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub preview_image_url: Option<Url>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub document_type: Option<DocumentType>,
+
+    /// How long the user looked at the page, in milliseconds. This is added
+    /// to the page's total view time, so observe each period once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub view_time: Option<u64>,
 }
 
 impl VisitObservation {
@@ -70,6 +91,10 @@ impl VisitObservation {
             at: None,
             referrer: None,
             is_remote: None,
+            description: None,
+            preview_image_url: None,
+            document_type: None,
+            view_time: None,
         }
     }
 
@@ -116,6 +141,26 @@ impl VisitObservation {
         self
     }
 
+    pub fn with_description(mut self, v: impl Into<Option<String>>) -> Self {
+        self.description = v.into();
+        self
+    }
+
+    pub fn with_preview_image_url(mut self, v: impl Into<Option<Url>>) -> Self {
+        self.preview_image_url = v.into();
+        self
+    }
+
+    pub fn with_document_type(mut self, v: impl Into<Option<DocumentType>>) -> Self {
+        self.document_type = v.into();
+        self
+    }
+
+    pub fn with_view_time(mut self, v: impl Into<Option<u64>>) -> Self {
+        self.view_time = v.into();
+        self
+    }
+
     // Other helpers which can be derived.
     pub fn get_redirect_frecency_boost(&self) -> bool {
         self.is_redirect_source.is_some()
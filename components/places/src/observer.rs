@@ -0,0 +1,541 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Observers for changes to history, so that consumers don't need to
+// re-query to find out if a sync, deletion or expiration changed anything.
+//
+// While any observers are registered, temp triggers on `moz_places` and
+// `moz_historyvisits` record each change in `moz_places_events_temp`. That
+// table is written in the same transaction as the change, so changes that are
+// rolled back are never reported. After each transaction,
+// `PlacesDb::in_transaction` calls `PlacesDb::notify_history_observers`,
+// which reads and clears the table, and calls the observers with everything
+// the transaction changed. This includes changes made by writes that aren't
+// about history, like creating a page for a bookmark. Syncing manages its own
+// transactions, and a few functions write without one, so they notify the
+// observers themselves. Notifying does nothing inside a transaction, so a
+// write that's part of a caller's transaction is reported once that commits.
+//
+// When no observers are registered, the triggers don't exist, so recording
+// costs nothing.
+
+use crate::error::*;
+use crate::types::{SyncGuid, Timestamp, VisitTransition};
+use lazy_static::lazy_static;
+use rusqlite::{Connection, Row};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::HashSet;
+use std::panic::RefUnwindSafe;
+use url::Url;
+
+/// A change to history.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HistoryEvent {
+    /// A page was added, either for a new visit, or because it was synced
+    /// or imported.
+    PageAdded {
+        guid: SyncGuid,
+        #[serde(with = "url_serde")]
+        url: Url,
+        title: Option<String>,
+    },
+    VisitAdded {
+        guid: SyncGuid,
+        #[serde(with = "url_serde")]
+        url: Url,
+        visit_date: Timestamp,
+        visit_type: VisitTransition,
+        /// Indicates if the visit happened on another device, and was synced
+        /// to this one.
+        is_remote: bool,
+    },
+    TitleChanged {
+        guid: SyncGuid,
+        #[serde(with = "url_serde")]
+        url: Url,
+        title: Option<String>,
+    },
+    /// A page was removed, along with all of its visits.
+    PageRemoved {
+        guid: SyncGuid,
+        #[serde(with = "url_serde")]
+        url: Url,
+    },
+    /// All history was deleted. This replaces the events for the pages and
+    /// visits that were removed.
+    HistoryCleared,
+    FrecencyChanged {
+        guid: SyncGuid,
+        #[serde(with = "url_serde")]
+        url: Url,
+        frecency: i32,
+    },
+}
+
+const PAGE_ADDED: u8 = 1;
+const VISIT_ADDED: u8 = 2;
+const TITLE_CHANGED: u8 = 3;
+const PAGE_REMOVED: u8 = 4;
+const HISTORY_CLEARED: u8 = 5;
+const FRECENCY_CHANGED: u8 = 6;
+
+impl HistoryEvent {
+    fn from_row(row: &Row) -> Result<Option<Self>> {
+        let kind = row.get_checked::<_, u8>("kind")?;
+        if kind == HISTORY_CLEARED {
+            return Ok(Some(HistoryEvent::HistoryCleared));
+        }
+        let guid = row.get_checked("guid")?;
+        let url = Url::parse(&row.get_checked::<_, String>("url")?)?;
+        Ok(Some(match kind {
+            PAGE_ADDED => HistoryEvent::PageAdded {
+                guid,
+                url,
+                title: row.get_checked("title")?,
+            },
+            VISIT_ADDED => HistoryEvent::VisitAdded {
+                guid,
+                url,
+                visit_date: row.get_checked("visit_date")?,
+                visit_type: VisitTransition::from_primitive(row.get_checked("visit_type")?)
+                    .unwrap_or(VisitTransition::Link),
+                is_remote: !row.get_checked::<_, bool>("is_local")?,
+            },
+            TITLE_CHANGED => HistoryEvent::TitleChanged {
+                guid,
+                url,
+                title: row.get_checked("title")?,
+            },
+            PAGE_REMOVED => HistoryEvent::PageRemoved { guid, url },
+            FRECENCY_CHANGED => HistoryEvent::FrecencyChanged {
+                guid,
+                url,
+                frecency: row.get_checked("frecency")?,
+            },
+            _ => {
+                log::warn!("Unknown history event kind {}", kind);
+                return Ok(None);
+            }
+        }))
+    }
+}
+
+/// A function that's called with the changes made by each transaction that
+/// changes history, in the order they were made.
+///
+/// Observers are called on the thread that made the changes, while it's
+/// still using the connection, so they must not use the connection
+/// themselves.
+pub struct HistoryObserver {
+    callback_fn: Box<dyn Fn(&[HistoryEvent]) + Send + RefUnwindSafe>,
+}
+
+impl HistoryObserver {
+    pub fn new<F>(callback_fn: F) -> HistoryObserver
+    where
+        F: Fn(&[HistoryEvent]) + 'static + Send + RefUnwindSafe,
+    {
+        HistoryObserver {
+            callback_fn: Box::new(callback_fn),
+        }
+    }
+
+    pub fn call(&self, events: &[HistoryEvent]) {
+        (*self.callback_fn)(events);
+    }
+}
+
+// The observers registered on a `PlacesDb`, with the ids we returned for
+// them.
+#[derive(Default)]
+pub(crate) struct HistoryObservers {
+    next_id: u64,
+    observers: Vec<(u64, HistoryObserver)>,
+}
+
+impl HistoryObservers {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    pub fn add(&mut self, observer: HistoryObserver) -> u64 {
+        // Ids start at 1, so consumers can use 0 for "not registered".
+        self.next_id += 1;
+        self.observers.push((self.next_id, observer));
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: u64) -> bool {
+        let len = self.observers.len();
+        self.observers.retain(|(observer_id, _)| *observer_id != id);
+        self.observers.len() != len
+    }
+
+    pub fn notify(&self, events: &[HistoryEvent]) {
+        for (_, observer) in &self.observers {
+            observer.call(events);
+        }
+    }
+}
+
+pub(crate) const CREATE_TEMP_TABLE_EVENTS: &str = "
+    CREATE TEMP TABLE moz_places_events_temp (
+        id INTEGER PRIMARY KEY,
+        kind INTEGER NOT NULL,
+        guid TEXT,
+        url TEXT,
+        title TEXT,
+        frecency INTEGER,
+        visit_date INTEGER,
+        visit_type INTEGER,
+        is_local INTEGER
+    )";
+
+lazy_static! {
+    static ref CREATE_TRIGGERS: String = format!(
+        "CREATE TEMP TRIGGER moz_places_events_afterinsert_trigger
+         AFTER INSERT ON moz_places
+         BEGIN
+             INSERT INTO moz_places_events_temp (kind, guid, url, title)
+             VALUES ({page_added}, NEW.guid, NEW.url, NEW.title);
+         END;
+
+         CREATE TEMP TRIGGER moz_places_events_afterupdate_title_trigger
+         AFTER UPDATE OF title ON moz_places
+         WHEN NEW.title IS NOT OLD.title
+         BEGIN
+             INSERT INTO moz_places_events_temp (kind, guid, url, title)
+             VALUES ({title_changed}, NEW.guid, NEW.url, NEW.title);
+         END;
+
+         CREATE TEMP TRIGGER moz_places_events_afterupdate_frecency_trigger
+         AFTER UPDATE OF frecency ON moz_places
+         WHEN NEW.frecency <> OLD.frecency
+         BEGIN
+             INSERT INTO moz_places_events_temp (kind, guid, url, frecency)
+             VALUES ({frecency_changed}, NEW.guid, NEW.url, NEW.frecency);
+         END;
+
+         CREATE TEMP TRIGGER moz_places_events_afterdelete_trigger
+         AFTER DELETE ON moz_places
+         BEGIN
+             INSERT INTO moz_places_events_temp (kind, guid, url)
+             VALUES ({page_removed}, OLD.guid, OLD.url);
+         END;
+
+         CREATE TEMP TRIGGER moz_historyvisits_events_afterinsert_trigger
+         AFTER INSERT ON moz_historyvisits
+         BEGIN
+             INSERT INTO moz_places_events_temp
+                 (kind, guid, url, visit_date, visit_type, is_local)
+             SELECT {visit_added}, guid, url, NEW.visit_date, NEW.visit_type,
+                    NEW.is_local
+             FROM moz_places
+             WHERE id = NEW.place_id;
+         END;",
+        page_added = PAGE_ADDED,
+        title_changed = TITLE_CHANGED,
+        frecency_changed = FRECENCY_CHANGED,
+        page_removed = PAGE_REMOVED,
+        visit_added = VISIT_ADDED,
+    );
+}
+
+const DROP_TRIGGERS: &str = "
+    DROP TRIGGER IF EXISTS moz_places_events_afterinsert_trigger;
+    DROP TRIGGER IF EXISTS moz_places_events_afterupdate_title_trigger;
+    DROP TRIGGER IF EXISTS moz_places_events_afterupdate_frecency_trigger;
+    DROP TRIGGER IF EXISTS moz_places_events_afterdelete_trigger;
+    DROP TRIGGER IF EXISTS moz_historyvisits_events_afterinsert_trigger;";
+
+/// Starts recording changes, when the first observer is registered.
+pub(crate) fn start_recording(db: &Connection) -> Result<()> {
+    // `record_history_cleared` writes to the table even when we're not
+    // recording, so throw away anything left over.
+    db.execute_batch("DELETE FROM moz_places_events_temp")?;
+    db.execute_batch(&CREATE_TRIGGERS)?;
+    Ok(())
+}
+
+/// Stops recording changes, when the last observer is unregistered.
+pub(crate) fn stop_recording(db: &Connection) -> Result<()> {
+    db.execute_batch(DROP_TRIGGERS)?;
+    db.execute_batch("DELETE FROM moz_places_events_temp")?;
+    Ok(())
+}
+
+/// Replaces the changes recorded so far in this transaction with a
+/// `HistoryCleared` event. This is called after deleting all history, so
+/// observers get one event instead of one for every page.
+pub(crate) fn record_history_cleared(db: &Connection) -> Result<()> {
+    db.execute_all(&[
+        "DELETE FROM moz_places_events_temp",
+        &format!(
+            "INSERT INTO moz_places_events_temp (kind) VALUES ({})",
+            HISTORY_CLEARED
+        ),
+    ])?;
+    Ok(())
+}
+
+/// Returns the changes recorded since the last call, and clears them.
+pub(crate) fn take_events(db: &Connection) -> Result<Vec<HistoryEvent>> {
+    let events = {
+        let mut stmt = db.prepare_cached(
+            "SELECT kind, guid, url, title, frecency, visit_date, visit_type, is_local
+             FROM moz_places_events_temp
+             ORDER BY id",
+        )?;
+        let rows = stmt.query_and_then(&[], HistoryEvent::from_row)?;
+        let mut events = Vec::new();
+        for row in rows {
+            if let Some(event) = row? {
+                events.push(event);
+            }
+        }
+        events
+    };
+    db.execute_cached("DELETE FROM moz_places_events_temp", &[])?;
+    Ok(coalesce(events))
+}
+
+// A transaction can change the title or frecency of a page several times,
+// like when a visit is added to a new page, but only the last change matters.
+fn coalesce(events: Vec<HistoryEvent>) -> Vec<HistoryEvent> {
+    let mut titles = HashSet::new();
+    let mut frecencies = HashSet::new();
+    let mut events = events
+        .into_iter()
+        .rev()
+        .filter(|event| match event {
+            HistoryEvent::TitleChanged { guid, .. } => titles.insert(guid.clone()),
+            HistoryEvent::FrecencyChanged { guid, .. } => frecencies.insert(guid.clone()),
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+    events.reverse();
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::apply_observation;
+    use crate::db::PlacesDb;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        delete_bookmark, insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        InsertableItem,
+    };
+    use crate::storage::{
+        apply_observation_direct, delete_everything, delete_place_by_guid, url_to_guid,
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn observe(db: &mut PlacesDb) -> (u64, Arc<Mutex<Vec<Vec<HistoryEvent>>>>) {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let observed = batches.clone();
+        let id = db
+            .register_history_observer(HistoryObserver::new(move |events| {
+                observed.lock().unwrap().push(events.to_vec());
+            }))
+            .expect("should register observer");
+        (id, batches)
+    }
+
+    fn visit(db: &mut PlacesDb, url: &Url, title: &str) {
+        apply_observation(
+            db,
+            VisitObservation::new(url.clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp(1000))
+                .with_title(title.to_owned()),
+        )
+        .expect("should apply observation");
+    }
+
+    #[test]
+    fn test_events() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let (_, batches) = observe(&mut db);
+
+        visit(&mut db, &url, "Example");
+        let guid = url_to_guid(&db, &url).unwrap().expect("should have guid");
+        {
+            let batches = batches.lock().unwrap();
+            assert_eq!(batches.len(), 1);
+            let batch = &batches[0];
+            assert_eq!(
+                batch[0],
+                HistoryEvent::PageAdded {
+                    guid: guid.clone(),
+                    url: url.clone(),
+                    title: None,
+                }
+            );
+            assert!(batch.contains(&HistoryEvent::VisitAdded {
+                guid: guid.clone(),
+                url: url.clone(),
+                visit_date: Timestamp(1000),
+                visit_type: VisitTransition::Link,
+                is_remote: false,
+            }));
+            assert!(batch.contains(&HistoryEvent::TitleChanged {
+                guid: guid.clone(),
+                url: url.clone(),
+                title: Some("Example".to_owned()),
+            }));
+            // The frecency changes more than once, but we only report the
+            // last change.
+            let frecency_changes = batch
+                .iter()
+                .filter(|event| match event {
+                    HistoryEvent::FrecencyChanged { .. } => true,
+                    _ => false,
+                })
+                .count();
+            assert_eq!(frecency_changes, 1);
+        }
+
+        delete_place_by_guid(&db, &guid).expect("should delete");
+        assert_eq!(
+            batches.lock().unwrap()[1],
+            vec![HistoryEvent::PageRemoved {
+                guid: guid.clone(),
+                url: url.clone(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_history_cleared() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        for url in &["https://www.example.com/", "https://www.example.org/"] {
+            visit(&mut db, &Url::parse(url).unwrap(), "Example");
+        }
+        let (_, batches) = observe(&mut db);
+
+        delete_everything(&db).expect("should delete everything");
+        assert_eq!(
+            *batches.lock().unwrap(),
+            vec![vec![HistoryEvent::HistoryCleared]]
+        );
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let (_, batches) = observe(&mut db);
+
+        // Changes that are rolled back shouldn't be reported, even when a
+        // later transaction is.
+        let tx = db.unchecked_transaction().unwrap();
+        db.execute_batch(
+            "INSERT INTO moz_places (guid, url, url_hash)
+             VALUES ('placeAAAAAAA', 'https://www.example.com/', 1)",
+        )
+        .unwrap();
+        tx.rollback().unwrap();
+        let url = Url::parse("https://www.example.org/").unwrap();
+        visit(&mut db, &url, "Example");
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].iter().all(|event| match event {
+            HistoryEvent::PageAdded { url: added, .. } => *added == url,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn test_bookmark_events() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let (_, batches) = observe(&mut db);
+
+        // Bookmarking a new URL adds a page for it, and unbookmarking it
+        // changes its frecency.
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let bookmark_guid = insert_bookmark(
+            &db,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: None,
+            }),
+        )
+        .expect("should insert bookmark");
+        let guid = url_to_guid(&db, &url).unwrap().expect("should have guid");
+        delete_bookmark(&db, &bookmark_guid).expect("should delete bookmark");
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 2);
+        assert!(batches[0].iter().any(|event| match event {
+            HistoryEvent::PageAdded { guid: added, .. } => *added == guid,
+            _ => false,
+        }));
+        assert!(batches[1].iter().any(|event| match event {
+            HistoryEvent::FrecencyChanged { guid: changed, .. }
+            | HistoryEvent::PageRemoved { guid: changed, .. } => *changed == guid,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_autocommit_events() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let (_, batches) = observe(&mut db);
+
+        // Writes outside a transaction are reported right away...
+        let url = Url::parse("https://www.example.com/").unwrap();
+        apply_observation_direct(
+            &db,
+            VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Link),
+        )
+        .expect("should apply observation");
+        assert_eq!(batches.lock().unwrap().len(), 1);
+
+        // ...but writes inside one wait for it to commit.
+        let tx = db.unchecked_transaction().unwrap();
+        let other_url = Url::parse("https://www.example.org/").unwrap();
+        apply_observation_direct(
+            &db,
+            VisitObservation::new(other_url.clone()).with_visit_type(VisitTransition::Link),
+        )
+        .expect("should apply observation");
+        assert_eq!(batches.lock().unwrap().len(), 1);
+        tx.commit().unwrap();
+        db.notify_history_observers();
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 2);
+        assert!(batches[1].iter().any(|event| match event {
+            HistoryEvent::PageAdded { url, .. } => *url == other_url,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_unregister() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let (id, batches) = observe(&mut db);
+        assert!(db.unregister_history_observer(id).unwrap());
+        assert!(!db.unregister_history_observer(id).unwrap());
+
+        let url = Url::parse("https://www.example.com/").unwrap();
+        visit(&mut db, &url, "Example");
+        assert!(batches.lock().unwrap().is_empty());
+        let pending = db
+            .query_one::<i64>("SELECT COUNT(*) FROM moz_places_events_temp")
+            .unwrap();
+        assert_eq!(pending, 0);
+    }
+}
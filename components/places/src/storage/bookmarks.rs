@@ -0,0 +1,1068 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Storage for the bookmarks tree. This is roughly what desktop's
+// `PlacesUtils.bookmarks` does, although it's much simpler - we have no
+// keywords, annotations or livemarks, and the only "special" folders are
+// the 5 roots which are created with the schema.
+
+use super::{fetch_page_info, new_page_info, update_frecency, RowId};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
+use crate::valid_guid::is_valid_places_guid;
+use rusqlite::{Connection, Row};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use url::Url;
+
+/// The well-known roots of the bookmark tree. These have the same guids as
+/// desktop, so that they round-trip through sync.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BookmarkRootGuid {
+    Root,
+    Menu,
+    Toolbar,
+    Unfiled,
+    Mobile,
+}
+
+impl BookmarkRootGuid {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BookmarkRootGuid::Root => "root________",
+            BookmarkRootGuid::Menu => "menu________",
+            BookmarkRootGuid::Toolbar => "toolbar_____",
+            BookmarkRootGuid::Unfiled => "unfiled_____",
+            BookmarkRootGuid::Mobile => "mobile______",
+        }
+    }
+
+    pub fn as_guid(self) -> SyncGuid {
+        SyncGuid(self.as_str().into())
+    }
+
+    pub fn well_known(guid: &str) -> Option<Self> {
+        match guid {
+            "root________" => Some(BookmarkRootGuid::Root),
+            "menu________" => Some(BookmarkRootGuid::Menu),
+            "toolbar_____" => Some(BookmarkRootGuid::Toolbar),
+            "unfiled_____" => Some(BookmarkRootGuid::Unfiled),
+            "mobile______" => Some(BookmarkRootGuid::Mobile),
+            _ => None,
+        }
+    }
+}
+
+/// The folders which live directly under the root, in the order desktop
+/// creates them.
+pub const USER_CONTENT_ROOTS: &[BookmarkRootGuid] = &[
+    BookmarkRootGuid::Menu,
+    BookmarkRootGuid::Toolbar,
+    BookmarkRootGuid::Unfiled,
+    BookmarkRootGuid::Mobile,
+];
+
+/// Creates the root and the 4 user content roots. Only called when the
+/// schema is first created, or upgraded from a version without bookmarks.
+pub(crate) fn create_bookmark_roots(db: &Connection) -> Result<()> {
+    let now = Timestamp::now();
+    let sql = "INSERT INTO moz_bookmarks
+                   (type, parent, position, dateAdded, lastModified, guid)
+               VALUES (:type, :parent, :position, :now, :now, :guid)";
+    db.execute_named_cached(
+        sql,
+        &[
+            (":type", &BookmarkType::Folder),
+            (":parent", &rusqlite::types::Null),
+            (":position", &0),
+            (":now", &now),
+            (":guid", &BookmarkRootGuid::Root.as_guid()),
+        ],
+    )?;
+    let root_id = RowId(db.last_insert_rowid());
+    for (position, root) in USER_CONTENT_ROOTS.iter().enumerate() {
+        db.execute_named_cached(
+            sql,
+            &[
+                (":type", &BookmarkType::Folder),
+                (":parent", &root_id),
+                (":position", &(position as u32)),
+                (":now", &now),
+                (":guid", &root.as_guid()),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Where in a folder an item should be inserted or moved to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BookmarkPosition {
+    /// A specific index in the folder. Indexes past the end are treated as
+    /// `Append`.
+    Specific(u32),
+    /// After all the existing children.
+    Append,
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertableBookmark {
+    pub parent_guid: SyncGuid,
+    pub position: BookmarkPosition,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub guid: Option<SyncGuid>,
+    pub url: Url,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertableSeparator {
+    pub parent_guid: SyncGuid,
+    pub position: BookmarkPosition,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub guid: Option<SyncGuid>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertableFolder {
+    pub parent_guid: SyncGuid,
+    pub position: BookmarkPosition,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub guid: Option<SyncGuid>,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum InsertableItem {
+    Bookmark(InsertableBookmark),
+    Separator(InsertableSeparator),
+    Folder(InsertableFolder),
+}
+
+// We allow all "common" fields from the sub-types to be accessed directly
+// from the InsertableItem.
+macro_rules! impl_common_bookmark_getter {
+    ($getter_name:ident, $T:ty) => {
+        fn $getter_name(&self) -> &$T {
+            match self {
+                InsertableItem::Bookmark(b) => &b.$getter_name,
+                InsertableItem::Separator(s) => &s.$getter_name,
+                InsertableItem::Folder(f) => &f.$getter_name,
+            }
+        }
+    };
+}
+
+impl InsertableItem {
+    fn bookmark_type(&self) -> BookmarkType {
+        match self {
+            InsertableItem::Bookmark(_) => BookmarkType::Bookmark,
+            InsertableItem::Separator(_) => BookmarkType::Separator,
+            InsertableItem::Folder(_) => BookmarkType::Folder,
+        }
+    }
+
+    fn title(&self) -> Option<String> {
+        match self {
+            InsertableItem::Bookmark(b) => b.title.clone(),
+            InsertableItem::Separator(_) => None,
+            InsertableItem::Folder(f) => f.title.clone(),
+        }
+    }
+
+    impl_common_bookmark_getter!(parent_guid, SyncGuid);
+    impl_common_bookmark_getter!(position, BookmarkPosition);
+    impl_common_bookmark_getter!(date_added, Option<Timestamp>);
+    impl_common_bookmark_getter!(last_modified, Option<Timestamp>);
+    impl_common_bookmark_getter!(guid, Option<SyncGuid>);
+}
+
+/// A bookmark row, joined with the guid of its parent and its URL.
+#[derive(Debug, Clone)]
+struct RawBookmark {
+    row_id: RowId,
+    place_id: Option<RowId>,
+    bookmark_type: BookmarkType,
+    parent_id: Option<RowId>,
+    parent_guid: Option<SyncGuid>,
+    position: u32,
+    title: Option<String>,
+    url: Option<Url>,
+    date_added: Timestamp,
+    last_modified: Timestamp,
+    guid: SyncGuid,
+}
+
+const RAW_BOOKMARK_COLUMNS: &str = "
+    b.id AS _id, b.fk AS _placeId, b.type AS _type, b.parent AS _parentId,
+    p.guid AS _parentGuid, b.position AS _position, b.title AS _title,
+    h.url AS _url, b.dateAdded AS _dateAdded, b.lastModified AS _lastModified,
+    b.guid AS _guid";
+
+impl RawBookmark {
+    fn from_row(row: &Row) -> Result<Self> {
+        let url = match row.get_checked::<_, Option<String>>("_url")? {
+            Some(s) => Some(Url::parse(&s)?),
+            None => None,
+        };
+        Ok(Self {
+            row_id: row.get_checked("_id")?,
+            place_id: row.get_checked("_placeId")?,
+            bookmark_type: row.get_checked("_type")?,
+            parent_id: row.get_checked("_parentId")?,
+            parent_guid: row.get_checked("_parentGuid")?,
+            position: row.get_checked("_position")?,
+            title: row.get_checked("_title")?,
+            url,
+            date_added: row.get_checked("_dateAdded")?,
+            last_modified: row.get_checked("_lastModified")?,
+            guid: row.get_checked("_guid")?,
+        })
+    }
+}
+
+fn get_raw_bookmark(db: &Connection, guid: &SyncGuid) -> Result<Option<RawBookmark>> {
+    Ok(db.try_query_row(
+        &format!(
+            "SELECT {columns}
+             FROM moz_bookmarks b
+             LEFT JOIN moz_bookmarks p ON p.id = b.parent
+             LEFT JOIN moz_places h ON h.id = b.fk
+             WHERE b.guid = :guid",
+            columns = RAW_BOOKMARK_COLUMNS
+        ),
+        &[(":guid", guid)],
+        RawBookmark::from_row,
+        true,
+    )?)
+}
+
+fn get_folder(db: &Connection, guid: &SyncGuid) -> Result<RawBookmark> {
+    let folder = match get_raw_bookmark(db, guid)? {
+        Some(folder) => folder,
+        None => return Err(InvalidPlaceInfo::NoItem(guid.0.clone()).into()),
+    };
+    if folder.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(guid.0.clone()).into());
+    }
+    Ok(folder)
+}
+
+fn get_num_children(db: &Connection, parent_id: RowId) -> Result<u32> {
+    Ok(db.query_row_named(
+        "SELECT COUNT(*) FROM moz_bookmarks WHERE parent = :parent_id",
+        &[(":parent_id", &parent_id)],
+        |row| row.get::<_, u32>(0),
+    )?)
+}
+
+/// Returns the id of the moz_places row for `url`, creating it if necessary.
+pub(crate) fn get_or_create_place_id(db: &Connection, url: &Url) -> Result<RowId> {
+    Ok(match fetch_page_info(db, url)? {
+        Some(info) => info.page.row_id,
+        None => new_page_info(db, url, None)?.row_id,
+    })
+}
+
+/// Bumps the change counter and modified time of a folder whose children
+/// changed. Sync records a folder's children as part of the folder, so this
+/// is what causes the folder to be re-uploaded.
+fn note_children_changed(db: &Connection, folder_id: RowId, now: Timestamp) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks
+         SET syncChangeCounter = syncChangeCounter + 1,
+             lastModified = :now
+         WHERE id = :folder_id",
+        &[(":now", &now), (":folder_id", &folder_id)],
+    )?;
+    Ok(())
+}
+
+/// Returns true if `folder_id` is `item_id` or one of its descendants.
+fn is_same_or_descendant(db: &Connection, folder_id: RowId, item_id: RowId) -> Result<bool> {
+    Ok(db.query_row_named(
+        "WITH RECURSIVE ancestors(id) AS (
+             SELECT :folder_id
+             UNION ALL
+             SELECT b.parent FROM moz_bookmarks b
+             JOIN ancestors a ON b.id = a.id
+             WHERE b.parent NOT NULL
+         )
+         SELECT EXISTS(SELECT 1 FROM ancestors WHERE id = :item_id)",
+        &[(":folder_id", &folder_id), (":item_id", &item_id)],
+        |row| row.get::<_, bool>(0),
+    )?)
+}
+
+/// Makes room for a new child at `position` in `parent`, returning the index
+/// the child should be given.
+fn make_room_for_child(
+    db: &Connection,
+    parent: &RawBookmark,
+    position: BookmarkPosition,
+) -> Result<u32> {
+    let num_children = get_num_children(db, parent.row_id)?;
+    Ok(match position {
+        BookmarkPosition::Specific(specified) => {
+            let actual = min(specified, num_children);
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks SET position = position + 1
+                 WHERE parent = :parent_id AND position >= :position",
+                &[(":parent_id", &parent.row_id), (":position", &actual)],
+            )?;
+            actual
+        }
+        BookmarkPosition::Append => num_children,
+    })
+}
+
+/// Closes the gap left in `parent_id` by a child which was at `position`.
+fn close_gap_for_child(db: &Connection, parent_id: RowId, position: u32) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET position = position - 1
+         WHERE parent = :parent_id AND position > :position",
+        &[(":parent_id", &parent_id), (":position", &position)],
+    )?;
+    Ok(())
+}
+
+/// Inserts a new bookmark, folder or separator, returning its guid.
+pub fn insert_bookmark(db: &PlacesDb, item: &InsertableItem) -> Result<SyncGuid> {
+    db.in_transaction(|| insert_bookmark_in_tx(db, item))
+}
+
+pub(crate) fn insert_bookmark_in_tx(db: &PlacesDb, item: &InsertableItem) -> Result<SyncGuid> {
+    let guid = match item.guid() {
+        Some(guid) => {
+            if !is_valid_places_guid(guid.as_ref()) {
+                return Err(InvalidPlaceInfo::InvalidGuid.into());
+            }
+            guid.clone()
+        }
+        None => SyncGuid(
+            sync15::util::random_guid().expect("according to logins, this is fine :)"),
+        ),
+    };
+    let parent = get_folder(db, item.parent_guid())?;
+    // Only the user content roots live directly under the root.
+    if BookmarkRootGuid::well_known(&parent.guid.0) == Some(BookmarkRootGuid::Root) {
+        return Err(InvalidPlaceInfo::InvalidParent(parent.guid.0).into());
+    }
+    let position = make_room_for_child(db, &parent, *item.position())?;
+    let place_id = match item {
+        InsertableItem::Bookmark(b) => Some(get_or_create_place_id(db, &b.url)?),
+        _ => None,
+    };
+    let now = Timestamp::now();
+    let date_added = item.date_added().unwrap_or(now);
+    // last_modified can't be before date_added.
+    let last_modified = max(item.last_modified().unwrap_or(now), date_added);
+
+    db.execute_named_cached(
+        "INSERT INTO moz_bookmarks
+             (fk, type, parent, position, title, dateAdded, lastModified, guid)
+         VALUES
+             (:fk, :type, :parent, :position, :title, :dateAdded, :lastModified, :guid)",
+        &[
+            (":fk", &place_id),
+            (":type", &item.bookmark_type()),
+            (":parent", &parent.row_id),
+            (":position", &position),
+            (":title", &item.title()),
+            (":dateAdded", &date_added),
+            (":lastModified", &last_modified),
+            (":guid", &guid),
+        ],
+    )?;
+    note_children_changed(db, parent.row_id, now)?;
+    // Being bookmarked affects frecency.
+    if let Some(place_id) = place_id {
+        update_frecency(db, place_id, None)?;
+    }
+    Ok(guid)
+}
+
+/// Deletes a bookmark and, if it's a folder, all of its descendants, writing
+/// tombstones for anything which has already been synced. Returns false if
+/// the item didn't exist.
+pub fn delete_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
+    db.in_transaction(|| delete_bookmark_in_tx(db, guid))
+}
+
+fn delete_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
+    if BookmarkRootGuid::well_known(&guid.0).is_some() {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(guid.0.clone()).into());
+    }
+    let record = match get_raw_bookmark(db, guid)? {
+        Some(record) => record,
+        None => return Ok(false),
+    };
+    let parent_id = record
+        .parent_id
+        .expect("only the root has no parent, and we checked for roots above");
+    let now = Timestamp::now();
+
+    // Remember the pages which were bookmarked, so we can fix their frecency.
+    let mut stmt = db.prepare(
+        "WITH RECURSIVE descendants(id) AS (
+             SELECT :id
+             UNION ALL
+             SELECT b.id FROM moz_bookmarks b
+             JOIN descendants d ON b.parent = d.id
+         )
+         SELECT DISTINCT fk FROM moz_bookmarks
+         WHERE id IN (SELECT id FROM descendants) AND fk NOT NULL",
+    )?;
+    let place_ids = stmt
+        .query_map_named(&[(":id", &record.row_id)], |row| row.get::<_, RowId>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    db.execute_named_cached(
+        "WITH RECURSIVE descendants(id) AS (
+             SELECT :id
+             UNION ALL
+             SELECT b.id FROM moz_bookmarks b
+             JOIN descendants d ON b.parent = d.id
+         )
+         INSERT OR IGNORE INTO moz_bookmarks_deleted (guid, dateRemoved)
+         SELECT guid, :now FROM moz_bookmarks
+         WHERE id IN (SELECT id FROM descendants) AND syncStatus = :status",
+        &[
+            (":id", &record.row_id),
+            (":now", &now),
+            (":status", &SyncStatus::Normal),
+        ],
+    )?;
+    // We don't enable foreign keys, so can't rely on ON DELETE CASCADE.
+    db.execute_named_cached(
+        "WITH RECURSIVE descendants(id) AS (
+             SELECT :id
+             UNION ALL
+             SELECT b.id FROM moz_bookmarks b
+             JOIN descendants d ON b.parent = d.id
+         )
+         DELETE FROM moz_bookmarks WHERE id IN (SELECT id FROM descendants)",
+        &[(":id", &record.row_id)],
+    )?;
+    close_gap_for_child(db, parent_id, record.position)?;
+    note_children_changed(db, parent_id, now)?;
+    for place_id in place_ids {
+        update_frecency(db, place_id, None)?;
+    }
+    Ok(true)
+}
+
+/// Describes a change to an existing item. Fields which are `None` are left
+/// alone. Moving an item to a new parent without a position appends it.
+#[derive(Debug, Clone, Default)]
+pub struct BookmarkUpdateInfo {
+    pub guid: SyncGuid,
+    /// An empty title removes the existing one.
+    pub title: Option<String>,
+    /// Only valid for bookmarks.
+    pub url: Option<Url>,
+    pub parent_guid: Option<SyncGuid>,
+    pub position: Option<BookmarkPosition>,
+}
+
+/// Updates the title, url or location of an existing item.
+pub fn update_bookmark(db: &PlacesDb, info: &BookmarkUpdateInfo) -> Result<()> {
+    db.in_transaction(|| update_bookmark_in_tx(db, info))
+}
+
+fn update_bookmark_in_tx(db: &PlacesDb, info: &BookmarkUpdateInfo) -> Result<()> {
+    if BookmarkRootGuid::well_known(&info.guid.0).is_some() {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(info.guid.0.clone()).into());
+    }
+    let existing = match get_raw_bookmark(db, &info.guid)? {
+        Some(existing) => existing,
+        None => return Err(InvalidPlaceInfo::NoItem(info.guid.0.clone()).into()),
+    };
+    if info.url.is_some() && existing.bookmark_type != BookmarkType::Bookmark {
+        return Err(InvalidPlaceInfo::IllegalChange("only bookmarks have a url".into()).into());
+    }
+    if info.title.is_some() && existing.bookmark_type == BookmarkType::Separator {
+        return Err(
+            InvalidPlaceInfo::IllegalChange("separators don't have a title".into()).into(),
+        );
+    }
+    let now = Timestamp::now();
+
+    if info.parent_guid.is_some() || info.position.is_some() {
+        move_item(db, &existing, info, now)?;
+    }
+
+    let title = match info.title {
+        Some(ref title) if title.is_empty() => None,
+        Some(ref title) => Some(title.clone()),
+        None => existing.title.clone(),
+    };
+    let place_id = match info.url {
+        Some(ref url) => Some(get_or_create_place_id(db, url)?),
+        None => existing.place_id,
+    };
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks
+         SET fk = :fk,
+             title = :title,
+             lastModified = :now,
+             syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :id",
+        &[
+            (":fk", &place_id),
+            (":title", &title),
+            (":now", &now),
+            (":id", &existing.row_id),
+        ],
+    )?;
+    if place_id != existing.place_id {
+        for id in existing.place_id.iter().chain(place_id.iter()) {
+            update_frecency(db, *id, None)?;
+        }
+    }
+    Ok(())
+}
+
+fn move_item(
+    db: &Connection,
+    existing: &RawBookmark,
+    info: &BookmarkUpdateInfo,
+    now: Timestamp,
+) -> Result<()> {
+    let old_parent_id = existing
+        .parent_id
+        .expect("only the root has no parent, and roots can't be updated");
+    let new_parent = match info.parent_guid {
+        Some(ref parent_guid) => get_folder(db, parent_guid)?,
+        None => get_folder(
+            db,
+            existing
+                .parent_guid
+                .as_ref()
+                .expect("items with a parent id have a parent guid"),
+        )?,
+    };
+    if BookmarkRootGuid::well_known(&new_parent.guid.0) == Some(BookmarkRootGuid::Root) {
+        return Err(InvalidPlaceInfo::InvalidParent(new_parent.guid.0).into());
+    }
+    if is_same_or_descendant(db, new_parent.row_id, existing.row_id)? {
+        return Err(InvalidPlaceInfo::InvalidParent(new_parent.guid.0).into());
+    }
+    let position = match info.position {
+        Some(position) => position,
+        None if new_parent.row_id == old_parent_id => return Ok(()),
+        None => BookmarkPosition::Append,
+    };
+    // Take the item out of its old parent first, so moving within the same
+    // folder works the same way as moving between folders.
+    close_gap_for_child(db, old_parent_id, existing.position)?;
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET parent = NULL WHERE id = :id",
+        &[(":id", &existing.row_id)],
+    )?;
+    let new_position = make_room_for_child(db, &new_parent, position)?;
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET parent = :parent, position = :position WHERE id = :id",
+        &[
+            (":parent", &new_parent.row_id),
+            (":position", &new_position),
+            (":id", &existing.row_id),
+        ],
+    )?;
+    note_children_changed(db, old_parent_id, now)?;
+    if new_parent.row_id != old_parent_id {
+        note_children_changed(db, new_parent.row_id, now)?;
+    }
+    Ok(())
+}
+
+/// Reorders the children of a folder. Children are given the order they
+/// appear in `child_guids`; any children which aren't mentioned keep their
+/// relative order and are moved after the ones which are. Unknown guids are
+/// ignored.
+pub fn reorder_bookmarks(
+    db: &PlacesDb,
+    parent_guid: &SyncGuid,
+    child_guids: &[SyncGuid],
+) -> Result<()> {
+    db.in_transaction(|| reorder_bookmarks_in_tx(db, parent_guid, child_guids))
+}
+
+fn reorder_bookmarks_in_tx(
+    db: &Connection,
+    parent_guid: &SyncGuid,
+    child_guids: &[SyncGuid],
+) -> Result<()> {
+    let parent = get_folder(db, parent_guid)?;
+    let mut stmt = db.prepare(
+        "SELECT guid, id FROM moz_bookmarks
+         WHERE parent = :parent_id
+         ORDER BY position",
+    )?;
+    let existing = stmt
+        .query_map_named(&[(":parent_id", &parent.row_id)], |row| {
+            (row.get::<_, SyncGuid>(0), row.get::<_, RowId>(1))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut ids_by_guid: HashMap<&SyncGuid, RowId> =
+        existing.iter().map(|(guid, id)| (guid, *id)).collect();
+
+    let mut new_order = Vec::with_capacity(existing.len());
+    for guid in child_guids {
+        if let Some(id) = ids_by_guid.remove(guid) {
+            new_order.push(id);
+        }
+    }
+    for (guid, id) in &existing {
+        if ids_by_guid.contains_key(guid) {
+            new_order.push(*id);
+        }
+    }
+    for (position, id) in new_order.iter().enumerate() {
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET position = :position WHERE id = :id",
+            &[(":position", &(position as u32)), (":id", id)],
+        )?;
+    }
+    note_children_changed(db, parent.row_id, Timestamp::now())?;
+    Ok(())
+}
+
+/// A bookmark, folder or separator as returned by the fetch functions.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BookmarkNode {
+    pub guid: SyncGuid,
+    #[serde(rename = "type")]
+    pub node_type: BookmarkType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_guid: Option<SyncGuid>,
+    pub position: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(with = "url_serde")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+    pub date_added: Timestamp,
+    pub last_modified: Timestamp,
+    /// The children of a folder, in order. Only populated by `fetch_tree`,
+    /// and always empty for bookmarks and separators.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<BookmarkNode>,
+}
+
+impl From<RawBookmark> for BookmarkNode {
+    fn from(raw: RawBookmark) -> Self {
+        BookmarkNode {
+            guid: raw.guid,
+            node_type: raw.bookmark_type,
+            parent_guid: raw.parent_guid,
+            position: raw.position,
+            title: raw.title,
+            url: raw.url,
+            date_added: raw.date_added,
+            last_modified: raw.last_modified,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// Fetches a single item, without its children.
+pub fn fetch_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<BookmarkNode>> {
+    Ok(get_raw_bookmark(db, guid)?.map(BookmarkNode::from))
+}
+
+/// Fetches an item and, if it's a folder, all of its descendants.
+pub fn fetch_tree(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<BookmarkNode>> {
+    let mut stmt = db.prepare(&format!(
+        "WITH RECURSIVE descendants(id, level) AS (
+             SELECT id, 0 FROM moz_bookmarks WHERE guid = :guid
+             UNION ALL
+             SELECT b.id, d.level + 1 FROM moz_bookmarks b
+             JOIN descendants d ON b.parent = d.id
+         )
+         SELECT {columns}
+         FROM descendants d
+         JOIN moz_bookmarks b ON b.id = d.id
+         LEFT JOIN moz_bookmarks p ON p.id = b.parent
+         LEFT JOIN moz_places h ON h.id = b.fk
+         ORDER BY d.level, b.parent, b.position",
+        columns = RAW_BOOKMARK_COLUMNS
+    ))?;
+    let mut rows = stmt
+        .query_and_then_named(&[(":guid", guid)], RawBookmark::from_row)?
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+    let root = match rows.next() {
+        Some(root) => root,
+        None => return Ok(None),
+    };
+    // Rows are ordered by position, so each parent's children are pushed in
+    // the right order.
+    let mut children_by_parent: HashMap<RowId, Vec<RawBookmark>> = HashMap::new();
+    for row in rows {
+        let parent_id = row.parent_id.expect("only the root has no parent");
+        children_by_parent
+            .entry(parent_id)
+            .or_insert_with(Vec::new)
+            .push(row);
+    }
+    Ok(Some(build_tree(root, &mut children_by_parent)))
+}
+
+fn build_tree(
+    raw: RawBookmark,
+    children_by_parent: &mut HashMap<RowId, Vec<RawBookmark>>,
+) -> BookmarkNode {
+    let children = children_by_parent.remove(&raw.row_id).unwrap_or_default();
+    let mut node = BookmarkNode::from(raw);
+    node.children = children
+        .into_iter()
+        .map(|child| build_tree(child, children_by_parent))
+        .collect();
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert_bookmark_at(
+        conn: &PlacesDb,
+        parent: SyncGuid,
+        position: BookmarkPosition,
+        url: &str,
+    ) -> SyncGuid {
+        insert_bookmark(
+            conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: parent,
+                position,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse(url).expect("valid url"),
+                title: None,
+            }),
+        )
+        .expect("should insert")
+    }
+
+    fn insert_folder(conn: &PlacesDb, parent: SyncGuid, title: &str) -> SyncGuid {
+        insert_bookmark(
+            conn,
+            &InsertableItem::Folder(InsertableFolder {
+                parent_guid: parent,
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                title: Some(title.into()),
+            }),
+        )
+        .expect("should insert")
+    }
+
+    fn child_guids(conn: &PlacesDb, guid: SyncGuid) -> Vec<SyncGuid> {
+        fetch_tree(conn, &guid)
+            .expect("should fetch")
+            .expect("should exist")
+            .children
+            .into_iter()
+            .map(|child| child.guid)
+            .collect()
+    }
+
+    fn get_foreign_count(conn: &PlacesDb, url: &str) -> i64 {
+        conn.query_row_named(
+            "SELECT foreign_count FROM moz_places WHERE url = :url",
+            &[(":url", &Url::parse(url).unwrap().into_string())],
+            |row| row.get::<_, i64>(0),
+        )
+        .expect("should have the place")
+    }
+
+    #[test]
+    fn test_roots() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let root = fetch_tree(&conn, &BookmarkRootGuid::Root.as_guid())?.expect("has a root");
+        assert_eq!(root.node_type, BookmarkType::Folder);
+        assert_eq!(root.parent_guid, None);
+        let children = root
+            .children
+            .into_iter()
+            .map(|child| child.guid)
+            .collect::<Vec<_>>();
+        let expected = USER_CONTENT_ROOTS
+            .iter()
+            .map(|root| root.as_guid())
+            .collect::<Vec<_>>();
+        assert_eq!(children, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let guid = insert_bookmark_at(
+            &conn,
+            BookmarkRootGuid::Unfiled.as_guid(),
+            BookmarkPosition::Append,
+            "https://www.example.com/",
+        );
+        let bm = fetch_bookmark(&conn, &guid)?.expect("should exist");
+        assert_eq!(bm.node_type, BookmarkType::Bookmark);
+        assert_eq!(bm.parent_guid, Some(BookmarkRootGuid::Unfiled.as_guid()));
+        assert_eq!(bm.position, 0);
+        assert_eq!(
+            bm.url,
+            Some(Url::parse("https://www.example.com/").unwrap())
+        );
+        assert_eq!(get_foreign_count(&conn, "https://www.example.com/"), 1);
+
+        // Inserting at a specific position shifts the existing children.
+        let first = insert_bookmark_at(
+            &conn,
+            BookmarkRootGuid::Unfiled.as_guid(),
+            BookmarkPosition::Specific(0),
+            "https://www.example.com/first",
+        );
+        // And positions past the end are treated as "append".
+        let last = insert_bookmark_at(
+            &conn,
+            BookmarkRootGuid::Unfiled.as_guid(),
+            BookmarkPosition::Specific(100),
+            "https://www.example.com/last",
+        );
+        assert_eq!(
+            child_guids(&conn, BookmarkRootGuid::Unfiled.as_guid()),
+            vec![first, guid, last]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_invalid_parent() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let bm = insert_bookmark_at(
+            &conn,
+            BookmarkRootGuid::Unfiled.as_guid(),
+            BookmarkPosition::Append,
+            "https://www.example.com/",
+        );
+        for parent in &[
+            bm,
+            BookmarkRootGuid::Root.as_guid(),
+            SyncGuid::from("xxxxxxxxxxxx"),
+        ] {
+            let result = insert_bookmark(
+                &conn,
+                &InsertableItem::Separator(InsertableSeparator {
+                    parent_guid: parent.clone(),
+                    position: BookmarkPosition::Append,
+                    date_added: None,
+                    last_modified: None,
+                    guid: None,
+                }),
+            );
+            assert!(result.is_err(), "{:?} shouldn't be a valid parent", parent);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let folder = insert_folder(&conn, BookmarkRootGuid::Menu.as_guid(), "folder");
+        let child = insert_bookmark_at(
+            &conn,
+            folder.clone(),
+            BookmarkPosition::Append,
+            "https://www.example.com/child",
+        );
+        let sibling = insert_bookmark_at(
+            &conn,
+            BookmarkRootGuid::Menu.as_guid(),
+            BookmarkPosition::Append,
+            "https://www.example.com/sibling",
+        );
+        // Pretend the folder and its child have been synced.
+        conn.execute_cached(
+            &format!(
+                "UPDATE moz_bookmarks SET syncStatus = {}",
+                SyncStatus::Normal as u8
+            ),
+            &[],
+        )?;
+
+        assert!(delete_bookmark(&conn, &folder)?);
+        assert!(fetch_bookmark(&conn, &child)?.is_none());
+        assert_eq!(get_foreign_count(&conn, "https://www.example.com/child"), 0);
+        // The sibling should have moved up.
+        let sibling = fetch_bookmark(&conn, &sibling)?.expect("should exist");
+        assert_eq!(sibling.position, 0);
+
+        let num_tombstones: u32 = conn.query_row(
+            "SELECT COUNT(*) FROM moz_bookmarks_deleted",
+            &[],
+            |row| row.get(0),
+        )?;
+        assert_eq!(num_tombstones, 2);
+
+        // Deleting something which doesn't exist is fine, deleting a root isn't.
+        assert!(!delete_bookmark(&conn, &folder)?);
+        assert!(delete_bookmark(&conn, &BookmarkRootGuid::Mobile.as_guid()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_and_move() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        let folder = insert_folder(&conn, BookmarkRootGuid::Toolbar.as_guid(), "folder");
+        let a = insert_bookmark_at(
+            &conn,
+            BookmarkRootGuid::Toolbar.as_guid(),
+            BookmarkPosition::Append,
+            "https://www.example.com/a",
+        );
+        let b = insert_bookmark_at(
+            &conn,
+            BookmarkRootGuid::Toolbar.as_guid(),
+            BookmarkPosition::Append,
+            "https://www.example.com/b",
+        );
+
+        update_bookmark(
+            &conn,
+            &BookmarkUpdateInfo {
+                guid: a.clone(),
+                title: Some("new title".into()),
+                url: Some(Url::parse("https://www.example.com/new").unwrap()),
+                ..BookmarkUpdateInfo::default()
+            },
+        )?;
+        let updated = fetch_bookmark(&conn, &a)?.expect("should exist");
+        assert_eq!(updated.title, Some("new title".to_string()));
+        assert_eq!(
+            updated.url,
+            Some(Url::parse("https://www.example.com/new").unwrap())
+        );
+        assert_eq!(get_foreign_count(&conn, "https://www.example.com/a"), 0);
+        assert_eq!(get_foreign_count(&conn, "https://www.example.com/new"), 1);
+
+        // Move within the same folder.
+        update_bookmark(
+            &conn,
+            &BookmarkUpdateInfo {
+                guid: b.clone(),
+                position: Some(BookmarkPosition::Specific(0)),
+                ..BookmarkUpdateInfo::default()
+            },
+        )?;
+        assert_eq!(
+            child_guids(&conn, BookmarkRootGuid::Toolbar.as_guid()),
+            vec![b.clone(), folder.clone(), a.clone()]
+        );
+
+        // Move into the folder.
+        update_bookmark(
+            &conn,
+            &BookmarkUpdateInfo {
+                guid: a.clone(),
+                parent_guid: Some(folder.clone()),
+                ..BookmarkUpdateInfo::default()
+            },
+        )?;
+        assert_eq!(
+            child_guids(&conn, BookmarkRootGuid::Toolbar.as_guid()),
+            vec![b.clone(), folder.clone()]
+        );
+        assert_eq!(child_guids(&conn, folder.clone()), vec![a.clone()]);
+
+        // A folder can't be moved into itself.
+        assert!(update_bookmark(
+            &conn,
+            &BookmarkUpdateInfo {
+                guid: folder.clone(),
+                parent_guid: Some(folder.clone()),
+                ..BookmarkUpdateInfo::default()
+            },
+        )
+        .is_err());
+        // And only bookmarks have urls.
+        assert!(update_bookmark(
+            &conn,
+            &BookmarkUpdateInfo {
+                guid: folder.clone(),
+                url: Some(Url::parse("https://www.example.com/").unwrap()),
+                ..BookmarkUpdateInfo::default()
+            },
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorder() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let guids = (0..4)
+            .map(|i| {
+                insert_bookmark_at(
+                    &conn,
+                    BookmarkRootGuid::Mobile.as_guid(),
+                    BookmarkPosition::Append,
+                    &format!("https://www.example.com/{}", i),
+                )
+            })
+            .collect::<Vec<_>>();
+        reorder_bookmarks(
+            &conn,
+            &BookmarkRootGuid::Mobile.as_guid(),
+            &[
+                guids[2].clone(),
+                SyncGuid::from("xxxxxxxxxxxx"),
+                guids[0].clone(),
+            ],
+        )?;
+        assert_eq!(
+            child_guids(&conn, BookmarkRootGuid::Mobile.as_guid()),
+            vec![
+                guids[2].clone(),
+                guids[0].clone(),
+                guids[1].clone(),
+                guids[3].clone()
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_tree() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let folder = insert_folder(&conn, BookmarkRootGuid::Menu.as_guid(), "outer");
+        let inner = insert_folder(&conn, folder.clone(), "inner");
+        let bm = insert_bookmark_at(
+            &conn,
+            inner.clone(),
+            BookmarkPosition::Append,
+            "https://www.example.com/",
+        );
+        let tree = fetch_tree(&conn, &folder)?.expect("should exist");
+        assert_eq!(tree.title, Some("outer".to_string()));
+        assert_eq!(tree.children.len(), 1);
+        assert_eq!(tree.children[0].guid, inner);
+        assert_eq!(tree.children[0].children.len(), 1);
+        assert_eq!(tree.children[0].children[0].guid, bm);
+        assert!(fetch_tree(&conn, &SyncGuid::from("xxxxxxxxxxxx"))?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// History expiration. Nothing else ever removes old history, so without this
+// the database grows forever. This is loosely based on nsPlacesExpiration.js
+// on desktop - we remove the oldest visits once there are too many, and the
+// least frecent pages once there are too many, but never pages which are
+// bookmarked or tagged.
+//
+// Expiration is local only: it doesn't write tombstones, as we don't want
+// to remove history from other devices just because this device is short
+// on space.
+//
+// Expiration happens in small batches, each in its own transaction, so it
+// can be run while the device is idle without blocking other work for long.
+//
+// Input history is expired on its own, too: otherwise, inputs for pages
+// which are never expired, like bookmarks, would be kept forever.
+
+use super::{update_frecency, RowId};
+use crate::api::matcher::decay_adaptive_history_in_tx;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::types::Timestamp;
+use sql_support::ConnExt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpirationSettings {
+    /// The most pages we keep. Bookmarked and tagged pages don't count
+    /// towards this, and are never expired.
+    pub max_pages: u32,
+    /// The most visits we keep.
+    pub max_visits: u32,
+    /// The most pages and visits we remove in a single step.
+    pub batch_size: u32,
+}
+
+pub const DEFAULT_EXPIRATION_SETTINGS: ExpirationSettings = ExpirationSettings {
+    max_pages: 20_000,
+    max_visits: 100_000,
+    batch_size: 100,
+};
+
+impl Default for ExpirationSettings {
+    #[inline]
+    fn default() -> Self {
+        DEFAULT_EXPIRATION_SETTINGS
+    }
+}
+
+/// What `expire_history` did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExpirationStats {
+    pub visits_removed: usize,
+    pub pages_removed: usize,
+    pub inputs_removed: usize,
+    /// True if there's nothing left to expire, false if we ran out of time.
+    pub finished: bool,
+}
+
+/// Expires history until the database is within the limits in `settings`,
+/// or until `time_limit` has passed. At least one step is always run, so a
+/// zero time limit expires a single batch. Callers which run out of time
+/// should call this again later.
+pub fn expire_history(
+    db: &PlacesDb,
+    settings: &ExpirationSettings,
+    time_limit: Duration,
+) -> Result<ExpirationStats> {
+    let started = Instant::now();
+    let mut stats = ExpirationStats::default();
+    stats.inputs_removed = db.in_transaction(|| expire_input_history_in_tx(db))?;
+    loop {
+        if !db.in_transaction(|| expire_step_in_tx(db, settings, &mut stats))? {
+            stats.finished = true;
+            break;
+        }
+        if started.elapsed() >= time_limit {
+            break;
+        }
+    }
+    log::debug!("Expired history: {:?}", stats);
+    Ok(stats)
+}
+
+// Decays input history, which removes inputs that haven't been picked in a
+// long time, and removes inputs for pages that no longer exist. Returns the
+// number of inputs removed.
+fn expire_input_history_in_tx(db: &PlacesDb) -> Result<usize> {
+    let count_inputs =
+        || -> Result<u32> { Ok(db.query_one("SELECT COUNT(*) FROM moz_inputhistory")?) };
+    let num_before = count_inputs()?;
+    // This only decays once a day, so calling it on every expiration is fine.
+    decay_adaptive_history_in_tx(db, Timestamp::now())?;
+    db.execute_cached(
+        "DELETE FROM moz_inputhistory
+         WHERE place_id NOT IN (SELECT id FROM moz_places)",
+        &[],
+    )?;
+    Ok(num_before.saturating_sub(count_inputs()?) as usize)
+}
+
+// Runs a single step of expiration. Returns false if there was nothing to
+// expire.
+fn expire_step_in_tx(
+    db: &PlacesDb,
+    settings: &ExpirationSettings,
+    stats: &mut ExpirationStats,
+) -> Result<bool> {
+    db.execute_all(&[
+        "CREATE TEMP TABLE IF NOT EXISTS temp_expired_pages
+            (place_id INTEGER PRIMARY KEY)",
+        "CREATE TEMP TABLE IF NOT EXISTS temp_expired_visits
+            (id INTEGER PRIMARY KEY, place_id INTEGER NOT NULL)",
+        "DELETE FROM temp_expired_pages",
+        "DELETE FROM temp_expired_visits",
+    ])?;
+    let batch_size = settings.batch_size.max(1);
+
+    // Pages without visits which aren't bookmarked or tagged are useless.
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO temp_expired_pages (place_id)
+         SELECT id FROM moz_places h
+         WHERE foreign_count = 0
+           AND NOT EXISTS(SELECT 1 FROM moz_historyvisits WHERE place_id = h.id)
+         LIMIT :batch_size",
+        &[(":batch_size", &batch_size)],
+    )?;
+
+    // If there are too many pages, remove the least frecent ones, oldest
+    // first, by removing all their visits.
+    let num_pages: u32 = db.query_row(
+        "SELECT COUNT(*) FROM moz_places WHERE foreign_count = 0",
+        &[],
+        |row| row.get(0),
+    )?;
+    let excess_pages = num_pages.saturating_sub(settings.max_pages).min(batch_size);
+    if excess_pages > 0 {
+        db.execute_named_cached(
+            "INSERT OR IGNORE INTO temp_expired_pages (place_id)
+             SELECT id FROM moz_places
+             WHERE foreign_count = 0
+             ORDER BY frecency,
+                      MAX(last_visit_date_local, last_visit_date_remote),
+                      id
+             LIMIT :excess",
+            &[(":excess", &excess_pages)],
+        )?;
+        stats.visits_removed += db.execute_cached(
+            "DELETE FROM moz_historyvisits
+             WHERE place_id IN (SELECT place_id FROM temp_expired_pages)",
+            &[],
+        )?;
+    }
+
+    // If there are still too many visits, remove the oldest.
+    let num_visits: u32 = db.query_row("SELECT COUNT(*) FROM moz_historyvisits", &[], |row| {
+        row.get(0)
+    })?;
+    let excess_visits = num_visits
+        .saturating_sub(settings.max_visits)
+        .min(batch_size);
+    if excess_visits > 0 {
+        db.execute_named_cached(
+            "INSERT INTO temp_expired_visits (id, place_id)
+             SELECT id, place_id FROM moz_historyvisits
+             ORDER BY visit_date, id
+             LIMIT :excess",
+            &[(":excess", &excess_visits)],
+        )?;
+        db.execute_all(&[
+            "INSERT OR IGNORE INTO temp_expired_pages (place_id)
+             SELECT place_id FROM temp_expired_visits",
+            "DELETE FROM moz_historyvisits
+             WHERE id IN (SELECT id FROM temp_expired_visits)",
+        ])?;
+        stats.visits_removed += excess_visits as usize;
+    }
+
+    // Now remove the pages which were left without visits, and recalculate
+    // frecency for the others.
+    let orphans = "SELECT h.id FROM moz_places h
+                   JOIN temp_expired_pages t ON t.place_id = h.id
+                   WHERE h.foreign_count = 0
+                     AND NOT EXISTS(SELECT 1 FROM moz_historyvisits WHERE place_id = h.id)";
+    stats.pages_removed += db.execute_cached(
+        &format!("DELETE FROM moz_places WHERE id IN ({})", orphans),
+        &[],
+    )?;
+    let remaining = {
+        let mut stmt = db.prepare(
+            "SELECT h.id FROM moz_places h
+             JOIN temp_expired_pages t ON t.place_id = h.id",
+        )?;
+        let ids = stmt
+            .query_map(&[], |row| row.get::<_, RowId>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        ids
+    };
+    for place_id in remaining {
+        update_frecency(db, place_id, None)?;
+    }
+    let num_expired: u32 = db.query_row("SELECT COUNT(*) FROM temp_expired_pages", &[], |row| {
+        row.get(0)
+    })?;
+    db.execute_cached("DELETE FROM temp_expired_pages", &[])?;
+
+    // Input history and origins for the removed pages are cleaned up by
+    // triggers.
+    Ok(num_expired > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark, InsertableItem,
+    };
+    use crate::storage::{apply_observation, fetch_page_info};
+    use crate::types::VisitTransition;
+    use url::Url;
+
+    fn count(conn: &PlacesDb, table: &str) -> u32 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), &[], |row| {
+            row.get(0)
+        })
+        .expect("should be able to count")
+    }
+
+    fn add_visits(conn: &mut PlacesDb, url: &str, dates: &[u64]) -> Result<()> {
+        for date in dates {
+            apply_observation(
+                conn,
+                VisitObservation::new(Url::parse(url)?)
+                    .with_visit_type(VisitTransition::Link)
+                    .with_at(Some(Timestamp(*date))),
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_nothing_to_expire() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        add_visits(&mut conn, "http://example.com/", &[1000, 2000])?;
+        let stats = expire_history(&conn, &Default::default(), Duration::from_secs(10))?;
+        assert_eq!(
+            stats,
+            ExpirationStats {
+                visits_removed: 0,
+                pages_removed: 0,
+                inputs_removed: 0,
+                finished: true,
+            }
+        );
+        assert_eq!(count(&conn, "moz_historyvisits"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_visits() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        add_visits(&mut conn, "http://example.com/1", &[1000, 5000])?;
+        add_visits(&mut conn, "http://example.com/2", &[2000, 3000])?;
+        let settings = ExpirationSettings {
+            max_visits: 2,
+            ..Default::default()
+        };
+        let stats = expire_history(&conn, &settings, Duration::from_secs(10))?;
+        assert_eq!(stats.visits_removed, 2);
+        assert_eq!(stats.pages_removed, 0);
+        assert!(stats.finished);
+        let info = fetch_page_info(&conn, &Url::parse("http://example.com/1")?)?
+            .expect("should still exist");
+        assert_eq!(info.page.visit_count_local, 1);
+        assert_eq!(info.page.last_visit_date_local, Timestamp(5000));
+        let info = fetch_page_info(&conn, &Url::parse("http://example.com/2")?)?
+            .expect("should still exist");
+        assert_eq!(info.page.visit_count_local, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_pages() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        add_visits(&mut conn, "http://example.com/old", &[1000])?;
+        add_visits(&mut conn, "http://example.com/new", &[2000])?;
+        add_visits(&mut conn, "http://mozilla.org/bookmarked", &[500])?;
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse("http://mozilla.org/bookmarked")?,
+                title: None,
+            }),
+        )?;
+        // Make the frecencies the same, so the oldest goes first.
+        conn.execute_cached("UPDATE moz_places SET frecency = 100", &[])?;
+        let settings = ExpirationSettings {
+            max_pages: 1,
+            ..Default::default()
+        };
+        let stats = expire_history(&conn, &settings, Duration::from_secs(10))?;
+        assert_eq!(stats.pages_removed, 1);
+        assert!(stats.finished);
+        assert!(fetch_page_info(&conn, &Url::parse("http://example.com/old")?)?.is_none());
+        assert!(fetch_page_info(&conn, &Url::parse("http://example.com/new")?)?.is_some());
+        assert!(fetch_page_info(&conn, &Url::parse("http://mozilla.org/bookmarked")?)?.is_some());
+        // Expiration is local, so no tombstones.
+        assert_eq!(count(&conn, "moz_places_tombstones"), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_in_steps() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        for i in 0..10 {
+            add_visits(&mut conn, &format!("http://example{}.com/", i), &[1000 + i])?;
+        }
+        conn.execute_cached(
+            "INSERT INTO moz_inputhistory (place_id, input, use_count)
+             SELECT id, 'ex', 1 FROM moz_places",
+            &[],
+        )?;
+        let settings = ExpirationSettings {
+            max_pages: 0,
+            max_visits: 100,
+            batch_size: 3,
+        };
+        // A zero time limit only runs a single step.
+        let stats = expire_history(&conn, &settings, Duration::from_secs(0))?;
+        assert_eq!(stats.pages_removed, 3);
+        assert!(!stats.finished);
+        assert_eq!(count(&conn, "moz_places"), 7);
+        assert_eq!(count(&conn, "moz_origins"), 7);
+        assert_eq!(count(&conn, "moz_inputhistory"), 7);
+
+        let stats = expire_history(&conn, &settings, Duration::from_secs(10))?;
+        assert_eq!(stats.pages_removed, 7);
+        assert!(stats.finished);
+        for table in &[
+            "moz_places",
+            "moz_historyvisits",
+            "moz_origins",
+            "moz_inputhistory",
+        ] {
+            assert_eq!(count(&conn, table), 0, "{} should be empty", table);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_input_history() -> Result<()> {
+        use crate::db::schema::MOZ_META_KEY_ADAPTIVE_HISTORY_LAST_DECAYED;
+        use crate::storage::put_meta;
+
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        add_visits(&mut conn, "http://example.com/", &[1000])?;
+        conn.execute_cached(
+            "INSERT INTO moz_inputhistory (place_id, input, use_count)
+             SELECT id, 'old', 0.011 FROM moz_places UNION ALL
+             SELECT id, 'new', 5 FROM moz_places UNION ALL
+             SELECT 12345, 'orphan', 5",
+            &[],
+        )?;
+        // Pretend the input history was last decayed a week ago.
+        let week_ago = Timestamp(Timestamp::now().0 - 7 * 24 * 60 * 60 * 1000);
+        put_meta(&conn, MOZ_META_KEY_ADAPTIVE_HISTORY_LAST_DECAYED, &week_ago)?;
+
+        let stats = expire_history(&conn, &Default::default(), Duration::from_secs(10))?;
+        assert_eq!(stats.inputs_removed, 2);
+        assert_eq!(stats.pages_removed, 0);
+        let inputs = conn
+            .prepare("SELECT input FROM moz_inputhistory")?
+            .query_map(&[], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        assert_eq!(inputs, vec!["new".to_string()]);
+        Ok(())
+    }
+}
@@ -4,13 +4,22 @@
 
 // A "storage" module - this module is intended to be the layer between the
 // API and the database.
-// This should probably be a sub-directory
 
-use crate::db::PlacesDb;
+pub mod bookmarks;
+pub mod expiration;
+pub mod open_tabs;
+pub mod search_index;
+pub mod tags;
+pub mod top_sites;
+pub mod visits;
+
+use crate::api::history::{can_add_url, get_url_filter};
+use crate::db::{schema, PlacesDb};
 use crate::error::Result;
 use crate::frecency;
 use crate::hash;
 use crate::observation::VisitObservation;
+use crate::observer;
 use crate::types::{SyncGuid, SyncStatus, Timestamp, VisitTransition};
 use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::Result as RusqliteResult;
@@ -21,7 +30,9 @@ use std::fmt;
 use url::Url;
 
 // Typesafe way to manage RowIds. Does it make sense? A better way?
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize, Default)]
+#[derive(
+    Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Deserialize, Serialize, Default,
+)]
 pub struct RowId(pub i64);
 
 impl From<RowId> for i64 {
@@ -140,17 +151,30 @@ fn fetch_page_info(db: &impl ConnExt, url: &Url) -> Result<Option<FetchedPageInf
 
 /// Returns the RowId of a new visit in moz_historyvisits, or None if no new visit was added.
 pub fn apply_observation(db: &mut PlacesDb, visit_ob: VisitObservation) -> Result<Option<RowId>> {
-    let tx = db.db.transaction()?;
-    let result = apply_observation_direct(tx.conn(), visit_ob)?;
-    tx.commit()?;
-    Ok(result)
+    if !can_add_url(db, &visit_ob.url)? {
+        log::debug!("Ignoring observation for a URL we can't add");
+        return Ok(None);
+    }
+    db.in_transaction(|| apply_allowed_observation(db, visit_ob))
 }
 
 /// Returns the RowId of a new visit in moz_historyvisits, or None if no new visit was added.
 pub fn apply_observation_direct(
-    db: &Connection,
+    db: &PlacesDb,
     visit_ob: VisitObservation,
 ) -> Result<Option<RowId>> {
+    if !get_url_filter(db)?.can_add_url(&visit_ob.url) {
+        log::debug!("Ignoring observation for a URL we can't add");
+        return Ok(None);
+    }
+    let row_id = apply_allowed_observation(db, visit_ob)?;
+    db.notify_history_observers();
+    Ok(row_id)
+}
+
+// Applies an observation for a URL that's already been checked against the
+// URL filter.
+fn apply_allowed_observation(db: &PlacesDb, visit_ob: VisitObservation) -> Result<Option<RowId>> {
     let mut page_info = match fetch_page_info(db, &visit_ob.url)? {
         Some(info) => info.page,
         None => new_page_info(db, &visit_ob.url, None)?,
@@ -158,12 +182,23 @@ pub fn apply_observation_direct(
     let mut update_change_counter = false;
     let mut update_frec = false;
     let mut updates: Vec<(&str, &str, &ToSql)> = Vec::new();
+    let preview_image_url = visit_ob.preview_image_url.as_ref().map(Url::as_str);
 
     if let Some(ref title) = visit_ob.title {
         page_info.title = title.clone();
         updates.push(("title", ":title", &page_info.title));
         update_change_counter = true;
     }
+    // Page metadata isn't synced, so it doesn't bump the change counter.
+    if let Some(ref description) = visit_ob.description {
+        updates.push(("description", ":description", description));
+    }
+    if let Some(ref preview_image_url) = preview_image_url {
+        updates.push(("preview_image_url", ":preview_image_url", preview_image_url));
+    }
+    if let Some(ref document_type) = visit_ob.document_type {
+        updates.push(("document_type", ":document_type", document_type));
+    }
     // There's a new visit, so update everything that implies. To help with
     // testing we return the rowid of the visit we added.
     let visit_row_id = match visit_ob.visit_type {
@@ -179,7 +214,18 @@ pub fn apply_observation_direct(
 
             let at = visit_ob.at.unwrap_or_else(|| Timestamp::now());
             let is_remote = visit_ob.is_remote.unwrap_or(false);
-            let row_id = add_visit(db, &page_info.row_id, &None, &at, &visit_type, &!is_remote)?;
+            let from_visit = match visit_ob.referrer {
+                Some(ref referrer) => find_referrer_visit(db, referrer, at)?,
+                None => None,
+            };
+            let row_id = add_visit(
+                db,
+                &page_info.row_id,
+                &from_visit,
+                &at,
+                &visit_type,
+                &!is_remote,
+            )?;
             // a new visit implies new frecency except in error cases.
             if !visit_ob.is_error.unwrap_or(false) {
                 update_frec = true;
@@ -215,10 +261,21 @@ pub fn apply_observation_direct(
         );
         db.execute_named_cached(&sql, &params)?;
     }
+    if let Some(view_time) = visit_ob.view_time {
+        db.execute_named_cached(
+            "UPDATE moz_places
+             SET total_view_time = total_view_time + :view_time
+             WHERE id = :row_id",
+            &[
+                (":view_time", &(view_time as i64)),
+                (":row_id", &page_info.row_id),
+            ],
+        )?;
+    }
     // This needs to happen after the other updates.
     if update_frec {
         update_frecency(
-            &db,
+            db,
             page_info.row_id,
             Some(visit_ob.get_redirect_frecency_boost()),
         )?;
@@ -226,10 +283,10 @@ pub fn apply_observation_direct(
     Ok(visit_row_id)
 }
 
-pub fn update_frecency(db: &Connection, id: RowId, redirect_boost: Option<bool>) -> Result<()> {
+pub fn update_frecency(db: &PlacesDb, id: RowId, redirect_boost: Option<bool>) -> Result<()> {
     let score = frecency::calculate_frecency(
         db.conn(),
-        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        &cached_frecency_settings(db)?,
         id.0, // TODO: calculate_frecency should take a RowId here.
         redirect_boost,
     )?;
@@ -241,11 +298,203 @@ pub fn update_frecency(db: &Connection, id: RowId, redirect_boost: Option<bool>)
         WHERE id = :page_id",
         &[(":frecency", &score), (":page_id", &id.0)],
     )?;
+    db.execute_named_cached(
+        "DELETE FROM moz_places_stale_frecencies WHERE place_id = :page_id",
+        &[(":page_id", &id.0)],
+    )?;
+
+    Ok(())
+}
+
+/// Returns the settings used to calculate frecencies.
+pub fn get_frecency_settings(db: &impl ConnExt) -> Result<frecency::FrecencySettings> {
+    Ok(
+        match get_meta::<String>(db, schema::MOZ_META_KEY_FRECENCY_SETTINGS)? {
+            Some(json) => serde_json::from_str(&json)?,
+            None => frecency::FrecencySettings::default(),
+        },
+    )
+}
+
+// Returns the settings used to calculate frecencies. They're cached on the
+// connection, so this only reads them from the database the first time.
+fn cached_frecency_settings(db: &PlacesDb) -> Result<frecency::FrecencySettings> {
+    if let Some(settings) = &*db.frecency_settings.borrow() {
+        return Ok(settings.clone());
+    }
+    let settings = get_frecency_settings(db)?;
+    db.frecency_settings.replace(Some(settings.clone()));
+    Ok(settings)
+}
+
+// How many frecencies `set_frecency_settings` recalculates in each
+// transaction.
+const FRECENCY_SETTINGS_CHUNK_SIZE: u32 = 500;
+
+/// Changes the settings used to calculate frecencies, or fails without
+/// changing anything if they're invalid. If they're different, every
+/// frecency is recalculated with the new settings before this returns. The
+/// recalculation is done in chunks, each in its own transaction; if one of
+/// them fails, the frecencies which are left are stale, and
+/// `update_stale_frecencies` recalculates them later. Connections cache the
+/// settings, so other connections which are already open keep using the old
+/// settings until they're reopened.
+pub fn set_frecency_settings(db: &PlacesDb, settings: &frecency::FrecencySettings) -> Result<()> {
+    settings.validate()?;
+    let changed = db.in_transaction(|| set_frecency_settings_in_tx(db, settings))?;
+    db.frecency_settings.replace(Some(settings.clone()));
+    if changed {
+        while !update_stale_frecencies(db, FRECENCY_SETTINGS_CHUNK_SIZE)? {}
+    }
+    Ok(())
+}
+
+// Stores the new settings and marks every frecency as stale, returning false
+// if the settings didn't change.
+fn set_frecency_settings_in_tx(
+    db: &PlacesDb,
+    settings: &frecency::FrecencySettings,
+) -> Result<bool> {
+    if get_frecency_settings(db)? == *settings {
+        return Ok(false);
+    }
+    put_meta(
+        db,
+        schema::MOZ_META_KEY_FRECENCY_SETTINGS,
+        &serde_json::to_string(settings)?,
+    )?;
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_places_stale_frecencies (place_id, stale_at)
+         SELECT id, :now FROM moz_places",
+        &[(":now", &Timestamp::now())],
+    )?;
+    Ok(true)
+}
 
+// Marks places with visits which have aged into a lower frecency bucket since
+// we last checked as stale.
+fn mark_aged_frecencies_stale(
+    db: &Connection,
+    settings: &frecency::FrecencySettings,
+    now: Timestamp,
+) -> Result<()> {
+    const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+    if let Some(last_aged) = get_meta::<Timestamp>(db, schema::MOZ_META_KEY_FRECENCIES_LAST_AGED)? {
+        for &days in &[
+            settings.first_bucket_cutoff_days,
+            settings.second_bucket_cutoff_days,
+            settings.third_bucket_cutoff_days,
+            settings.fourth_bucket_cutoff_days,
+        ] {
+            let cutoff = days.max(0) as u64 * MS_PER_DAY;
+            db.execute_named_cached(
+                "INSERT OR IGNORE INTO moz_places_stale_frecencies (place_id, stale_at)
+                 SELECT place_id, :now FROM moz_historyvisits
+                 WHERE visit_date > :start AND visit_date <= :end",
+                &[
+                    (":now", &now),
+                    (":start", &Timestamp(last_aged.0.saturating_sub(cutoff))),
+                    (":end", &Timestamp(now.0.saturating_sub(cutoff))),
+                ],
+            )?;
+        }
+    }
+    put_meta(db, schema::MOZ_META_KEY_FRECENCIES_LAST_AGED, &now)?;
     Ok(())
 }
 
-fn new_page_info(db: &impl ConnExt, url: &Url, new_guid: Option<SyncGuid>) -> Result<PageInfo> {
+/// Recalculates the frecency of up to `max_places` places whose frecency is
+/// stale, oldest first. Frecencies become stale when visits are added or
+/// removed by sync, and as visits age. Nothing else recalculates stale
+/// frecencies, so the app needs to call this periodically, and after syncing.
+/// Returns true if there are no stale frecencies left, or false if this
+/// should be called again.
+pub fn update_stale_frecencies(db: &PlacesDb, max_places: u32) -> Result<bool> {
+    db.in_transaction(|| update_stale_frecencies_in_tx(db, max_places))
+}
+
+fn update_stale_frecencies_in_tx(db: &PlacesDb, max_places: u32) -> Result<bool> {
+    mark_aged_frecencies_stale(db, &cached_frecency_settings(db)?, Timestamp::now())?;
+    let stale = {
+        let mut stmt = db.prepare_cached(
+            "SELECT place_id FROM moz_places_stale_frecencies
+             ORDER BY stale_at, place_id
+             LIMIT :max_places",
+        )?;
+        let ids = stmt
+            .query_map_named(&[(":max_places", &max_places)], |row| {
+                row.get::<_, RowId>(0)
+            })?
+            .collect::<RusqliteResult<Vec<_>>>()?;
+        ids
+    };
+    for place_id in stale {
+        update_frecency(db, place_id, None)?;
+    }
+    let remaining: u32 = db.query_row(
+        "SELECT COUNT(*) FROM moz_places_stale_frecencies",
+        &[],
+        |row| row.get(0),
+    )?;
+    Ok(remaining == 0)
+}
+
+/// Recalculates the frecency of every origin, and the stats used for the
+/// origin frecency threshold, from scratch. Normally these are kept up to
+/// date by triggers as page frecencies change.
+pub fn recalculate_origin_frecencies(db: &impl ConnExt) -> Result<()> {
+    db.execute_all(&[
+        "DELETE FROM moz_origins
+         WHERE id NOT IN (SELECT origin_id FROM moz_places WHERE origin_id NOT NULL)",
+        "UPDATE moz_origins
+         SET frecency = IFNULL((SELECT SUM(MAX(frecency, 0)) FROM moz_places
+                                WHERE origin_id = moz_origins.id), 0)",
+        &format!(
+            "REPLACE INTO moz_meta(key, value)
+             SELECT '{}', COUNT(*) FROM moz_origins WHERE frecency > 0",
+            schema::MOZ_META_KEY_ORIGIN_FRECENCY_COUNT
+        ),
+        &format!(
+            "REPLACE INTO moz_meta(key, value)
+             SELECT '{}', IFNULL(SUM(frecency), 0) FROM moz_origins WHERE frecency > 0",
+            schema::MOZ_META_KEY_ORIGIN_FRECENCY_SUM
+        ),
+        &format!(
+            "REPLACE INTO moz_meta(key, value)
+             SELECT '{}', IFNULL(SUM(frecency * frecency), 0) FROM moz_origins WHERE frecency > 0",
+            schema::MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES
+        ),
+    ])?;
+    Ok(())
+}
+
+/// Returns the frecency an origin needs to be autofilled. Like desktop, this
+/// is one standard deviation above the mean frecency of all origins.
+pub fn origin_frecency_threshold(db: &impl ConnExt) -> Result<f64> {
+    let count = get_meta::<i64>(db, schema::MOZ_META_KEY_ORIGIN_FRECENCY_COUNT)?.unwrap_or(0);
+    if count <= 0 {
+        return Ok(0.0);
+    }
+    let count = count as f64;
+    let sum = get_meta::<i64>(db, schema::MOZ_META_KEY_ORIGIN_FRECENCY_SUM)?.unwrap_or(0) as f64;
+    let sum_of_squares = get_meta::<i64>(db, schema::MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES)?
+        .unwrap_or(0) as f64;
+    let mean = sum / count;
+    let stddev = if count > 1.0 {
+        ((count * sum_of_squares - sum * sum) / (count * (count - 1.0)))
+            .max(0.0)
+            .sqrt()
+    } else {
+        0.0
+    };
+    Ok(mean + stddev)
+}
+
+pub(crate) fn new_page_info(
+    db: &impl ConnExt,
+    url: &Url,
+    new_guid: Option<SyncGuid>,
+) -> Result<PageInfo> {
     let guid = match new_guid {
         Some(guid) => guid,
         None => sync15::util::random_guid()
@@ -275,6 +524,24 @@ fn new_page_info(db: &impl ConnExt, url: &Url, new_guid: Option<SyncGuid>) -> Re
     })
 }
 
+// Finds the visit that a visit at `at` came from, which is the most recent
+// visit to the referrer at or before that time. For redirects, the referrer
+// is the redirect source, so this also links redirect sources to their
+// targets.
+fn find_referrer_visit(db: &impl ConnExt, referrer: &Url, at: Timestamp) -> Result<Option<RowId>> {
+    Ok(db.try_query_row(
+        "SELECT v.id FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         WHERE h.url_hash = hash(:url) AND h.url = :url
+           AND v.visit_date <= :at
+         ORDER BY v.visit_date DESC, v.id DESC
+         LIMIT 1",
+        &[(":url", &referrer.as_str()), (":at", &at)],
+        |row| row.get_checked::<_, RowId>(0),
+        true,
+    )?)
+}
+
 // Add a single visit - you must know the page rowid. Does not update the
 // page info - if you are calling this, you will also need to update the
 // parent page with an updated change counter etc.
@@ -326,6 +593,12 @@ fn do_delete_place_by_guid(db: &impl ConnExt, guid: &SyncGuid) -> Result<()> {
                SELECT guid FROM moz_places
                WHERE guid = :guid AND sync_status = :status";
     db.execute_named_cached(sql, &[(":guid", guid), (":status", &SyncStatus::Normal)])?;
+    // Foreign keys aren't enforced, so we need to remove the visits ourselves.
+    db.execute_named_cached(
+        "DELETE FROM moz_historyvisits
+         WHERE place_id = (SELECT id FROM moz_places WHERE guid = :guid)",
+        &[(":guid", guid)],
+    )?;
     // and try the delete - it might not exist, but that's ok.
     let delete_sql = "DELETE FROM moz_places WHERE guid = :guid";
     db.execute_named_cached(delete_sql, &[(":guid", guid)])?;
@@ -333,11 +606,191 @@ fn do_delete_place_by_guid(db: &impl ConnExt, guid: &SyncGuid) -> Result<()> {
 }
 
 /// Delete a place given its guid, creating a tombstone if necessary.
-pub fn delete_place_by_guid(db: &impl ConnExt, guid: &SyncGuid) -> Result<()> {
-    let tx = db.unchecked_transaction()?;
-    let result = do_delete_place_by_guid(db, guid);
-    tx.commit()?;
-    result
+pub fn delete_place_by_guid(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
+    db.in_transaction(|| do_delete_place_by_guid(db, guid))
+}
+
+// The pages in `temp_pages_with_removed_visits` which have no visits left,
+// and aren't bookmarked or tagged.
+const ORPHANED_PAGES_SQL: &str = "
+    SELECT h.id FROM moz_places h
+    JOIN temp_pages_with_removed_visits t ON t.place_id = h.id
+    WHERE h.foreign_count = 0
+      AND NOT EXISTS(SELECT 1 FROM moz_historyvisits WHERE place_id = h.id)";
+
+/// Deletes the visits matching `visit_filter`, and then cleans up the pages
+/// they belonged to: pages with no visits left which aren't bookmarked or
+/// tagged are removed (with tombstones if they have been synced), and the
+/// others are marked as changed so the remaining visits are uploaded.
+///
+/// Note that history sync has no way to delete individual visits on the
+/// server - other clients only find out about a page once all its visits
+/// have gone.
+fn delete_visits_where(
+    db: &PlacesDb,
+    visit_filter: &str,
+    params: &[(&str, &dyn ToSql)],
+) -> Result<()> {
+    db.execute_all(&[
+        "CREATE TEMP TABLE IF NOT EXISTS temp_pages_with_removed_visits
+            (place_id INTEGER PRIMARY KEY)",
+        "DELETE FROM temp_pages_with_removed_visits",
+    ])?;
+    db.execute_named(
+        &format!(
+            "INSERT OR IGNORE INTO temp_pages_with_removed_visits (place_id)
+             SELECT place_id FROM moz_historyvisits WHERE {}",
+            visit_filter
+        ),
+        params,
+    )?;
+    db.execute_named(
+        &format!("DELETE FROM moz_historyvisits WHERE {}", visit_filter),
+        params,
+    )?;
+    cleanup_pages_with_removed_visits(db)
+}
+
+/// Does the cleanup described in `delete_visits_where` for the pages in
+/// `temp_pages_with_removed_visits`.
+fn cleanup_pages_with_removed_visits(db: &PlacesDb) -> Result<()> {
+    db.execute_all(&[
+        &format!(
+            "INSERT OR IGNORE INTO moz_places_tombstones (guid)
+             SELECT guid FROM moz_places
+             WHERE id IN ({orphans}) AND sync_status = {status}",
+            orphans = ORPHANED_PAGES_SQL,
+            status = SyncStatus::Normal as u8
+        ),
+        &format!(
+            "DELETE FROM moz_places WHERE id IN ({})",
+            ORPHANED_PAGES_SQL
+        ),
+        "UPDATE moz_places
+         SET sync_change_counter = sync_change_counter + 1
+         WHERE id IN (SELECT place_id FROM temp_pages_with_removed_visits)",
+    ])?;
+    let remaining = {
+        let mut stmt = db.prepare(
+            "SELECT h.id FROM moz_places h
+             JOIN temp_pages_with_removed_visits t ON t.place_id = h.id",
+        )?;
+        let ids = stmt
+            .query_map(&[], |row| row.get::<_, RowId>(0))?
+            .collect::<RusqliteResult<Vec<_>>>()?;
+        ids
+    };
+    for place_id in remaining {
+        update_frecency(db, place_id, None)?;
+    }
+    db.execute_cached("DELETE FROM temp_pages_with_removed_visits", &[])?;
+    Ok(())
+}
+
+/// Deletes all visits between `start` and `end`, inclusive. This is what
+/// "clear the last hour" and friends use.
+pub fn delete_visits_between(db: &PlacesDb, start: Timestamp, end: Timestamp) -> Result<()> {
+    db.in_transaction(|| {
+        delete_visits_where(
+            db,
+            "visit_date BETWEEN :start AND :end",
+            &[(":start", &start), (":end", &end)],
+        )
+    })
+}
+
+/// Deletes a single visit. Deleting a visit which doesn't exist isn't an
+/// error.
+pub fn delete_visit(db: &PlacesDb, visit_id: RowId) -> Result<()> {
+    db.in_transaction(|| delete_visits_where(db, "id = :visit_id", &[(":visit_id", &visit_id)]))
+}
+
+/// Deletes the history for every page on `host`, with any scheme or port.
+/// Pages which are bookmarked or tagged lose their visits, but are kept.
+pub fn delete_host(db: &PlacesDb, host: &str) -> Result<()> {
+    db.in_transaction(|| {
+        delete_pages_where(
+            db,
+            "SELECT id FROM moz_places
+             WHERE origin_id IN (SELECT id FROM moz_origins
+                                 WHERE rev_host = reverse_host(:host))",
+            &[(":host", &host)],
+        )
+    })
+}
+
+/// Deletes the history for every page with the same origin (scheme, host and
+/// port) as `url`.
+pub fn delete_origin(db: &PlacesDb, url: &Url) -> Result<()> {
+    db.in_transaction(|| {
+        delete_pages_where(
+            db,
+            "SELECT id FROM moz_places
+             WHERE origin_id IN (SELECT id FROM moz_origins
+                                 WHERE prefix = get_prefix(:url)
+                                   AND host = get_host_and_port(:url))",
+            &[(":url", &url.as_str())],
+        )
+    })
+}
+
+// Deletes all the visits for the pages selected by `pages_sql`, and then
+// cleans them up in the same way as `delete_visits_where`. Unlike deleting
+// by visit, this also finds pages which don't have any visits.
+fn delete_pages_where(db: &PlacesDb, pages_sql: &str, params: &[(&str, &dyn ToSql)]) -> Result<()> {
+    db.execute_all(&[
+        "CREATE TEMP TABLE IF NOT EXISTS temp_pages_with_removed_visits
+            (place_id INTEGER PRIMARY KEY)",
+        "DELETE FROM temp_pages_with_removed_visits",
+    ])?;
+    db.execute_named(
+        &format!(
+            "INSERT OR IGNORE INTO temp_pages_with_removed_visits (place_id) {}",
+            pages_sql
+        ),
+        params,
+    )?;
+    db.execute_cached(
+        "DELETE FROM moz_historyvisits
+         WHERE place_id IN (SELECT place_id FROM temp_pages_with_removed_visits)",
+        &[],
+    )?;
+    cleanup_pages_with_removed_visits(db)
+}
+
+/// Deletes all history. Bookmarked and tagged pages are kept, without their
+/// visits. Synced pages get tombstones, so this clears history on other
+/// devices too.
+pub fn delete_everything(db: &PlacesDb) -> Result<()> {
+    db.in_transaction(|| {
+        delete_pages_where(db, "SELECT id FROM moz_places", &[])?;
+        // Anything left is bookmarked or tagged, so no longer has any history.
+        db.execute_cached("DELETE FROM moz_inputhistory", &[])?;
+        observer::record_history_cleared(db)
+    })
+}
+
+pub(crate) fn put_meta(db: &impl ConnExt, key: &str, value: &dyn ToSql) -> Result<()> {
+    db.execute_named_cached(
+        "REPLACE INTO moz_meta (key, value) VALUES (:key, :value)",
+        &[(":key", &key), (":value", value)],
+    )?;
+    Ok(())
+}
+
+pub(crate) fn delete_meta(db: &impl ConnExt, key: &str) -> Result<()> {
+    db.execute_named_cached("DELETE FROM moz_meta WHERE key = :key", &[(":key", &key)])?;
+    Ok(())
+}
+
+pub(crate) fn get_meta<T: FromSql>(db: &impl ConnExt, key: &str) -> Result<Option<T>> {
+    let res = db.try_query_row(
+        "SELECT value FROM moz_meta WHERE key = :key",
+        &[(":key", &key)],
+        |row| Ok::<_, crate::error::Error>(row.get_checked(0)?),
+        true,
+    )?;
+    Ok(res)
 }
 
 // Support for Sync - in its own module to try and keep a delineation
@@ -463,9 +916,9 @@ pub mod history_sync {
                 &false,
             )?;
         }
-        // XXX - we really need a better story for frecency-boost than
-        // Option<bool> - None vs Some(false) is confusing. We should use an enum.
-        update_frecency(&db, page_info.row_id, None)?;
+        // We don't update the frecency here, as doing so for every incoming
+        // record makes syncing slow. Adding the visits marked it as stale, so
+        // it will be updated by `update_stale_frecencies`.
 
         // and the place itself if necessary.
         let new_title = title.as_ref().unwrap_or(&page_info.title);
@@ -590,14 +1043,21 @@ pub mod history_sync {
 
         let insert_meta_sql = "
             INSERT INTO temp_sync_updated_meta VALUES (:row_id, :change_delta)";
+        let url_filter = get_url_filter(db)?;
 
         let rows = stmt.query_and_then_named(
             &[(":max_places", &(max_places_left as u32))],
             PageInfo::from_row,
         )?;
         let mut ids_to_update = Vec::new();
+        let mut ids_to_ignore = Vec::new();
         for t in rows {
             let page = t?;
+            if !url_filter.can_add_url(&page.url) {
+                log::debug!("Page {:?} can't be added to history - skipping", &page.guid);
+                ids_to_ignore.push(page.row_id);
+                continue;
+            }
             let visit_rows = visits.query_map_named(
                 &[
                     (":max_visits", &(max_visits as u32)),
@@ -661,6 +1121,23 @@ pub mod history_sync {
             Ok(())
         })?;
 
+        // Pages which the URL filter doesn't allow are never uploaded, so we
+        // mark them as synced now instead of waiting for `finish_outgoing`.
+        // Otherwise, if the sync fails, they'd be selected again, and count
+        // towards `max_places`, on every sync.
+        sql_support::each_chunk(&ids_to_ignore, |chunk, _| -> Result<()> {
+            db.conn().execute(
+                &format!(
+                    "UPDATE moz_places SET sync_status = {status}, sync_change_counter = 0
+                     WHERE id IN ({vars})",
+                    vars = sql_support::repeat_sql_vars(chunk.len()),
+                    status = SyncStatus::Normal as u8
+                ),
+                chunk,
+            )?;
+            Ok(())
+        })?;
+
         Ok(result)
     }
 
@@ -799,6 +1276,7 @@ pub fn get_visited_urls(
 mod tests {
     use super::history_sync::*;
     use super::*;
+    use crate::error::ErrorKind;
     use crate::history_sync::record::HistoryRecord;
     use std::time::{Duration, SystemTime};
 
@@ -836,6 +1314,218 @@ mod tests {
         assert_eq!(o.rev_host(), "moc.oof");
     }
 
+    fn get_origin_frecency(conn: &PlacesDb, host: &str) -> Option<i64> {
+        conn.try_query_row(
+            "SELECT frecency FROM moz_origins WHERE host = :host",
+            &[(":host", &host)],
+            |row| Ok::<_, crate::error::Error>(row.get_checked(0)?),
+            true,
+        )
+        .expect("should have worked")
+    }
+
+    #[test]
+    fn test_origin_frecency() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let pi1 = get_observed_page(&mut conn, "http://example.com/1")?;
+        let pi2 = get_observed_page(&mut conn, "http://example.com/2")?;
+        let pi3 = get_observed_page(&mut conn, "http://mozilla.org/")?;
+        assert!(pi1.frecency > 0 && pi2.frecency > 0);
+        assert_eq!(
+            get_origin_frecency(&conn, "example.com"),
+            Some(i64::from(pi1.frecency) + i64::from(pi2.frecency))
+        );
+
+        // Frecency changes are reflected in the origin.
+        conn.execute_named_cached(
+            "UPDATE moz_places SET frecency = 1000 WHERE id = :id",
+            &[(":id", &pi1.row_id)],
+        )?;
+        assert_eq!(
+            get_origin_frecency(&conn, "example.com"),
+            Some(1000 + i64::from(pi2.frecency))
+        );
+
+        // The threshold is the mean plus one standard deviation.
+        let origins = [1000.0 + f64::from(pi2.frecency), f64::from(pi3.frecency)];
+        let mean = (origins[0] + origins[1]) / 2.0;
+        let variance =
+            origins.iter().map(|f| (f - mean).powi(2)).sum::<f64>() / (origins.len() - 1) as f64;
+        let stddev = variance.sqrt();
+        assert!((origin_frecency_threshold(&conn)? - (mean + stddev)).abs() < 0.001);
+
+        // As are deletions, and origins without pages are removed.
+        delete_place_by_guid(&conn, &pi1.guid)?;
+        assert_eq!(
+            get_origin_frecency(&conn, "example.com"),
+            Some(i64::from(pi2.frecency))
+        );
+        delete_place_by_guid(&conn, &pi2.guid)?;
+        assert_eq!(get_origin_frecency(&conn, "example.com"), None);
+        assert_eq!(origin_frecency_threshold(&conn)?, f64::from(pi3.frecency));
+
+        // And recalculating from scratch gives the same answer.
+        recalculate_origin_frecencies(&conn)?;
+        assert_eq!(
+            get_origin_frecency(&conn, "mozilla.org"),
+            Some(i64::from(pi3.frecency))
+        );
+        assert_eq!(origin_frecency_threshold(&conn)?, f64::from(pi3.frecency));
+        Ok(())
+    }
+
+    fn get_stale_count(conn: &PlacesDb) -> u32 {
+        conn.query_row(
+            "SELECT COUNT(*) FROM moz_places_stale_frecencies",
+            &[],
+            |row| row.get(0),
+        )
+        .expect("should have worked")
+    }
+
+    #[test]
+    fn test_stale_frecencies() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        // Observations update the frecency immediately.
+        let pi = get_observed_page(&mut conn, "http://example.com/1")?;
+        assert_eq!(get_stale_count(&conn), 0);
+
+        // But other changes to visits leave it stale.
+        conn.execute_named_cached(
+            "INSERT INTO moz_historyvisits (place_id, visit_date, visit_type, is_local)
+             VALUES (:place_id, :date, :visit_type, 1)",
+            &[
+                (":place_id", &pi.row_id),
+                (":date", &Timestamp::now()),
+                (":visit_type", &VisitTransition::Typed),
+            ],
+        )?;
+        assert_eq!(get_stale_count(&conn), 1);
+        assert!(update_stale_frecencies(&conn, 10)?);
+        assert_eq!(get_stale_count(&conn), 0);
+        let updated = fetch_page_info(&conn, &pi.url)?.expect("should exist").page;
+        assert!(updated.frecency > pi.frecency);
+
+        // Deleting a page removes it from the queue.
+        conn.execute_named_cached(
+            "DELETE FROM moz_historyvisits WHERE place_id = :place_id",
+            &[(":place_id", &pi.row_id)],
+        )?;
+        assert_eq!(get_stale_count(&conn), 1);
+        delete_place_by_guid(&conn, &pi.guid)?;
+        assert_eq!(get_stale_count(&conn), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aged_frecencies() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let day = 24 * 60 * 60 * 1000;
+        let now = Timestamp::now();
+        let cutoff = frecency::DEFAULT_FRECENCY_SETTINGS.first_bucket_cutoff_days as u64 * day;
+        // A visit which has just moved out of the first bucket, and one which
+        // is still well inside it.
+        let aged = Timestamp(now.0 - cutoff - 1000);
+        let recent = Timestamp(now.0 - day);
+        get_custom_observed_page(&mut conn, "http://example.com/aged", |o| {
+            o.with_at(Some(aged))
+        })?;
+        get_custom_observed_page(&mut conn, "http://example.com/recent", |o| {
+            o.with_at(Some(recent))
+        })?;
+
+        // The first time there's nothing to compare with.
+        mark_aged_frecencies_stale(&conn, &frecency::DEFAULT_FRECENCY_SETTINGS, now)?;
+        assert_eq!(get_stale_count(&conn), 0);
+
+        put_meta(
+            &conn,
+            schema::MOZ_META_KEY_FRECENCIES_LAST_AGED,
+            &Timestamp(now.0 - 60 * 60 * 1000),
+        )?;
+        mark_aged_frecencies_stale(&conn, &frecency::DEFAULT_FRECENCY_SETTINGS, now)?;
+        let stale: RowId = conn.query_row(
+            "SELECT place_id FROM moz_places_stale_frecencies",
+            &[],
+            |row| row.get(0),
+        )?;
+        let aged_page = fetch_page_info(&conn, &Url::parse("http://example.com/aged")?)?
+            .expect("should exist")
+            .page;
+        assert_eq!(stale, aged_page.row_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frecency_settings() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        assert_eq!(
+            get_frecency_settings(&conn)?,
+            frecency::DEFAULT_FRECENCY_SETTINGS
+        );
+        let pi = get_observed_page(&mut conn, "http://example.com/1")?;
+
+        // Setting the same settings again doesn't do anything.
+        set_frecency_settings(&conn, &frecency::DEFAULT_FRECENCY_SETTINGS)?;
+        assert_eq!(get_stale_count(&conn), 0);
+
+        let settings = frecency::FrecencySettings {
+            first_bucket_weight: 1000,
+            ..frecency::FrecencySettings::default()
+        };
+        set_frecency_settings(&conn, &settings)?;
+        assert_eq!(get_frecency_settings(&conn)?, settings);
+        // Every frecency is recalculated right away.
+        assert_eq!(get_stale_count(&conn), 0);
+        let updated = fetch_page_info(&conn, &pi.url)?.expect("should exist").page;
+        assert!(updated.frecency > pi.frecency);
+
+        // Invalid settings are rejected, and the current settings are kept.
+        for invalid in &[
+            frecency::FrecencySettings {
+                num_visits: 0,
+                ..settings.clone()
+            },
+            frecency::FrecencySettings {
+                num_visits: -1,
+                ..settings.clone()
+            },
+            frecency::FrecencySettings {
+                second_bucket_cutoff_days: settings.first_bucket_cutoff_days,
+                ..settings.clone()
+            },
+            frecency::FrecencySettings {
+                fourth_bucket_cutoff_days: settings.third_bucket_cutoff_days - 1,
+                ..settings.clone()
+            },
+        ] {
+            match set_frecency_settings(&conn, invalid)
+                .expect_err("should reject invalid settings")
+                .kind()
+            {
+                ErrorKind::InvalidFrecencySettings(_) => {}
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+        assert_eq!(get_frecency_settings(&conn)?, settings);
+        assert_eq!(cached_frecency_settings(&conn)?, settings);
+        assert_eq!(get_stale_count(&conn), 0);
+
+        // Settings missing from JSON get their defaults.
+        let partial: frecency::FrecencySettings =
+            serde_json::from_str(r#"{"typed_visit_bonus": 100}"#)?;
+        assert_eq!(partial.typed_visit_bonus, 100);
+        assert_eq!(
+            partial.link_visit_bonus,
+            frecency::DEFAULT_FRECENCY_SETTINGS.link_visit_bonus
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_get_visited_urls() {
         use std::collections::HashSet;
@@ -1122,6 +1812,58 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_page_metadata() -> Result<()> {
+        use crate::storage::top_sites::get_top_sites;
+        use crate::storage::visits::{get_visit_page, VisitQuery};
+        use crate::types::DocumentType;
+
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/video")?;
+        apply_observation(
+            &mut conn,
+            VisitObservation::new(url.clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_view_time(1000),
+        )?;
+        let pi = fetch_page_info(&conn, &url)?
+            .expect("page should exist")
+            .page;
+        // Metadata without a visit updates the page, but doesn't change
+        // anything that's synced.
+        apply_observation(
+            &mut conn,
+            VisitObservation::new(url.clone())
+                .with_description("A video".to_string())
+                .with_preview_image_url(Url::parse("http://example.com/preview.png")?)
+                .with_document_type(DocumentType::Media)
+                .with_view_time(500),
+        )?;
+        let new_pi = fetch_page_info(&conn, &url)?
+            .expect("page should exist")
+            .page;
+        assert_eq!(new_pi.sync_change_counter, pi.sync_change_counter);
+
+        let page = get_visit_page(&conn, &VisitQuery::new(10))?;
+        assert_eq!(page.visits.len(), 1);
+        let visit = &page.visits[0];
+        assert_eq!(visit.description, Some("A video".to_string()));
+        assert_eq!(
+            visit.preview_image_url,
+            Some(Url::parse("http://example.com/preview.png")?)
+        );
+        assert_eq!(visit.document_type, DocumentType::Media);
+        assert_eq!(visit.total_view_time, 1500);
+
+        let top_sites = get_top_sites(&conn, 10, false)?;
+        assert_eq!(top_sites.len(), 1);
+        assert_eq!(top_sites[0].description, Some("A video".to_string()));
+        assert_eq!(top_sites[0].document_type, DocumentType::Media);
+        assert_eq!(top_sites[0].total_view_time, 1500);
+        Ok(())
+    }
+
     #[test]
     fn test_status_columns() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1325,4 +2067,161 @@ mod tests {
         assert_eq!(get_tombstone_count(&conn), 0, "should be no tombstones");
         Ok(())
     }
+
+    fn observe_at(conn: &mut PlacesDb, url: &str, at: Timestamp) -> Result<RowId> {
+        Ok(apply_observation(
+            conn,
+            VisitObservation::new(Url::parse(url)?)
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Some(at)),
+        )?
+        .expect("should get a rowid"))
+    }
+
+    fn mark_all_synced(conn: &PlacesDb) -> Result<()> {
+        conn.execute_cached(
+            &format!(
+                "UPDATE moz_places set sync_status = {}",
+                (SyncStatus::Normal as u8)
+            ),
+            &[],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_visits_between() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let early = Timestamp(1_000_000);
+        let late = Timestamp(2_000_000);
+        observe_at(&mut conn, "http://example.com/1", early)?;
+        observe_at(&mut conn, "http://example.com/2", early)?;
+        observe_at(&mut conn, "http://example.com/2", late)?;
+        mark_all_synced(&conn)?;
+        let url2 = Url::parse("http://example.com/2")?;
+        let counter_before = fetch_page_info(&conn, &url2)?
+            .expect("should have the page")
+            .page
+            .sync_change_counter;
+
+        delete_visits_between(&conn, Timestamp(1_500_000), Timestamp(2_500_000))?;
+        let pi = fetch_page_info(&conn, &url2)?
+            .expect("should still have the page")
+            .page;
+        assert_eq!(pi.visit_count_local, 1);
+        assert!(pi.sync_change_counter > counter_before);
+        assert_eq!(get_tombstone_count(&conn), 0);
+
+        delete_visits_between(&conn, Timestamp(0), Timestamp(1_500_000))?;
+        for url in &["http://example.com/1", "http://example.com/2"] {
+            assert!(fetch_page_info(&conn, &Url::parse(url)?)?.is_none());
+        }
+        assert_eq!(get_tombstone_count(&conn), 2);
+        let num_origins: u32 =
+            conn.query_row("SELECT COUNT(*) FROM moz_origins", &[], |row| row.get(0))?;
+        assert_eq!(num_origins, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_visit() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/")?;
+        let rid1 = observe_at(&mut conn, url.as_str(), Timestamp(1_000_000))?;
+        let rid2 = observe_at(&mut conn, url.as_str(), Timestamp(2_000_000))?;
+
+        delete_visit(&conn, rid1)?;
+        let pi = fetch_page_info(&conn, &url)?.expect("should have the page");
+        assert_eq!(pi.page.visit_count_local, 1);
+        assert_eq!(pi.last_visit_id, Some(rid2));
+
+        delete_visit(&conn, rid2)?;
+        assert!(fetch_page_info(&conn, &url)?.is_none());
+        // New pages don't need tombstones.
+        assert_eq!(get_tombstone_count(&conn), 0);
+        // And deleting a visit that doesn't exist is fine.
+        delete_visit(&conn, rid2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_keeps_bookmarked_pages() -> Result<()> {
+        use crate::storage::bookmarks::{
+            insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+            InsertableItem,
+        };
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/")?;
+        observe_at(&mut conn, url.as_str(), Timestamp(1_000_000))?;
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: None,
+            }),
+        )?;
+
+        delete_everything(&conn)?;
+        let pi = fetch_page_info(&conn, &url)?.expect("should keep the page");
+        assert_eq!(pi.page.visit_count_local, 0);
+        assert_eq!(pi.last_visit_id, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_host_and_origin() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let now = Timestamp::now();
+        observe_at(&mut conn, "http://example.com/1", now)?;
+        observe_at(&mut conn, "https://example.com/2", now)?;
+        observe_at(&mut conn, "https://www.example.com/", now)?;
+        observe_at(&mut conn, "https://mozilla.org/1", now)?;
+        observe_at(&mut conn, "https://mozilla.org:8080/2", now)?;
+        mark_all_synced(&conn)?;
+
+        delete_host(&conn, "example.com")?;
+        for url in &["http://example.com/1", "https://example.com/2"] {
+            assert!(fetch_page_info(&conn, &Url::parse(url)?)?.is_none());
+        }
+        assert!(fetch_page_info(&conn, &Url::parse("https://www.example.com/")?)?.is_some());
+        assert_eq!(get_tombstone_count(&conn), 2);
+
+        delete_origin(&conn, &Url::parse("https://mozilla.org/something")?)?;
+        assert!(fetch_page_info(&conn, &Url::parse("https://mozilla.org/1")?)?.is_none());
+        assert!(fetch_page_info(&conn, &Url::parse("https://mozilla.org:8080/2")?)?.is_some());
+        assert_eq!(get_tombstone_count(&conn), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_everything() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        let now = Timestamp::now();
+        observe_at(&mut conn, "http://example.com/1", now)?;
+        mark_all_synced(&conn)?;
+        observe_at(&mut conn, "http://example.com/2", now)?;
+
+        delete_everything(&conn)?;
+        let num_places: u32 =
+            conn.query_row("SELECT COUNT(*) FROM moz_places", &[], |row| row.get(0))?;
+        assert_eq!(num_places, 0);
+        let num_visits: u32 =
+            conn.query_row("SELECT COUNT(*) FROM moz_historyvisits", &[], |row| {
+                row.get(0)
+            })?;
+        assert_eq!(num_visits, 0);
+        // Only the synced page needs a tombstone.
+        assert_eq!(get_tombstone_count(&conn), 1);
+        Ok(())
+    }
 }
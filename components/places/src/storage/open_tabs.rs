@@ -0,0 +1,105 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// The app tells us which URLs are open in tabs, so that autocomplete can
+// suggest switching to an open tab instead of opening the URL again. Open
+// tabs are kept in a temp table, so they're forgotten when the connection is
+// closed. Tabs are grouped by user context (container), and a URL can be
+// open in more than one tab, so we keep a count for each context.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use sql_support::ConnExt;
+use url::Url;
+
+/// Notes that `url` was opened in a tab in the given user context. Call this
+/// once for each tab; a URL that's open in two tabs should be registered
+/// twice, and unregistered twice.
+pub fn register_open_page(db: &PlacesDb, url: &Url, user_context_id: u32) -> Result<()> {
+    db.execute_named_cached(
+        "INSERT OR REPLACE INTO moz_openpages_temp (url, userContextId, open_count)
+         VALUES (:url, :user_context_id,
+                 IFNULL((SELECT open_count FROM moz_openpages_temp
+                         WHERE url = :url AND userContextId = :user_context_id), 0) + 1)",
+        &[
+            (":url", &url.as_str()),
+            (":user_context_id", &user_context_id),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Notes that a tab with `url` in the given user context was closed, or
+/// navigated to another URL. Unregistering a URL that isn't open is a no-op.
+pub fn unregister_open_page(db: &PlacesDb, url: &Url, user_context_id: u32) -> Result<()> {
+    db.in_transaction(|| unregister_open_page_in_tx(db, url, user_context_id))
+}
+
+fn unregister_open_page_in_tx(db: &PlacesDb, url: &Url, user_context_id: u32) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_openpages_temp
+         SET open_count = open_count - 1
+         WHERE url = :url AND userContextId = :user_context_id",
+        &[
+            (":url", &url.as_str()),
+            (":user_context_id", &user_context_id),
+        ],
+    )?;
+    db.execute_cached("DELETE FROM moz_openpages_temp WHERE open_count <= 0", &[])?;
+    Ok(())
+}
+
+/// Forgets every open tab, in all user contexts. This is useful if the app
+/// loses track of its tabs, and wants to register them all again.
+pub fn unregister_all_open_pages(db: &PlacesDb) -> Result<()> {
+    db.execute_cached("DELETE FROM moz_openpages_temp", &[])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_open_count(db: &PlacesDb, url: &Url, user_context_id: u32) -> u32 {
+        db.try_query_row(
+            "SELECT open_count FROM moz_openpages_temp
+             WHERE url = :url AND userContextId = :user_context_id",
+            &[
+                (":url", &url.as_str()),
+                (":user_context_id", &user_context_id),
+            ],
+            |row| row.get_checked::<_, u32>(0),
+            false,
+        )
+        .expect("should query open pages")
+        .unwrap_or(0)
+    }
+
+    #[test]
+    fn test_open_pages() -> Result<()> {
+        let db = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/")?;
+
+        register_open_page(&db, &url, 0)?;
+        register_open_page(&db, &url, 0)?;
+        register_open_page(&db, &url, 1)?;
+        assert_eq!(get_open_count(&db, &url, 0), 2);
+        assert_eq!(get_open_count(&db, &url, 1), 1);
+
+        unregister_open_page(&db, &url, 0)?;
+        assert_eq!(get_open_count(&db, &url, 0), 1);
+        unregister_open_page(&db, &url, 1)?;
+        unregister_open_page(&db, &url, 1)?;
+        assert_eq!(get_open_count(&db, &url, 1), 0);
+        let num_rows: u32 =
+            db.query_row("SELECT COUNT(*) FROM moz_openpages_temp", &[], |row| {
+                row.get(0)
+            })?;
+        assert_eq!(num_rows, 1);
+
+        unregister_all_open_pages(&db)?;
+        assert_eq!(get_open_count(&db, &url, 0), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// An optional full-text index over the URL, title, tags and description of
+// each page, in the FTS5 table `moz_places_fts`. The autocomplete matchers
+// otherwise run `AUTOCOMPLETE_MATCH` on every page, which gets slow with
+// hundreds of thousands of them. With the index, they first look up the pages
+// with words starting with each part of the search string, and only run
+// `AUTOCOMPLETE_MATCH` on those.
+//
+// The index can't do everything `AUTOCOMPLETE_MATCH` does, so we store text
+// from `match_impl::search_index_text`, which adds words for the boundaries
+// inside each word. It still can't match anywhere in a word, so we don't use
+// it for `MatchBehavior::Anywhere` or `MatchBehavior::AnywhereUnmodified`.
+//
+// Triggers stored in the database note the pages whose URL, title,
+// description, bookmark titles or tags change in `moz_places_fts_stale`.
+// They only use built-in SQL, so they see writes from every connection,
+// including ones opened before the index was enabled, and ones that don't
+// define our functions, like the SQLite shell. Before each search,
+// `search_index_match` refreshes the index entries for those pages with
+// `autocomplete_index_text`, which we define for each of our connections in
+// `db::define_functions`. If it can't, the matchers check every page instead
+// of trusting an out of date index.
+//
+// The index needs SQLite to be built with FTS5, so it's off until
+// `enable_search_index` is called. `init` remembers whether it's enabled when
+// a connection is opened, so connections which are already open don't start
+// using it until they're reopened. They do notice it being disabled.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::match_impl::{search_index_query, MatchBehavior, MatchFolding};
+use sql_support::ConnExt;
+
+// Inserts the index entries for the pages matching `condition`.
+fn insert_entries_sql(condition: &str) -> String {
+    format!(
+        "INSERT INTO moz_places_fts(rowid, url, title, tags, description)
+         SELECT h.id,
+                autocomplete_index_text(h.url, 1, 1),
+                IFNULL(autocomplete_index_text(h.title, 0, 1), '') || ' ' ||
+                IFNULL((SELECT GROUP_CONCAT(autocomplete_index_text(b.title, 0, 1), ' ')
+                        FROM moz_bookmarks b
+                        WHERE b.fk = h.id AND b.title NOT NULL), ''),
+                (SELECT GROUP_CONCAT(autocomplete_index_text(t.tag, 0, 0), ' ')
+                 FROM moz_tags t
+                 JOIN moz_tags_relation r ON r.tag_id = t.id
+                 WHERE r.place_id = h.id),
+                h.description
+         FROM moz_places h
+         WHERE {}",
+        condition
+    )
+}
+
+// Notes that the index entry for the page with the id `place_id`, which is
+// an expression in a trigger, needs to be refreshed.
+fn note_stale_sql(place_id: &str) -> String {
+    format!(
+        "INSERT OR IGNORE INTO moz_places_fts_stale(place_id)
+         SELECT {place_id} WHERE {place_id} NOT NULL;",
+        place_id = place_id,
+    )
+}
+
+fn create_triggers_sql() -> String {
+    format!(
+        "CREATE TRIGGER moz_places_fts_afterinsert_trigger
+         AFTER INSERT ON moz_places
+         BEGIN
+             {new_place}
+         END;
+
+         CREATE TRIGGER moz_places_fts_afterupdate_trigger
+         AFTER UPDATE OF url, title, description ON moz_places
+         BEGIN
+             {new_place}
+         END;
+
+         CREATE TRIGGER moz_places_fts_afterdelete_trigger
+         AFTER DELETE ON moz_places
+         BEGIN
+             {old_place}
+         END;
+
+         CREATE TRIGGER moz_bookmarks_fts_afterinsert_trigger
+         AFTER INSERT ON moz_bookmarks
+         BEGIN
+             {new_bookmark}
+         END;
+
+         CREATE TRIGGER moz_bookmarks_fts_afterupdate_trigger
+         AFTER UPDATE OF fk, title ON moz_bookmarks
+         BEGIN
+             {old_bookmark}
+             {new_bookmark}
+         END;
+
+         CREATE TRIGGER moz_bookmarks_fts_afterdelete_trigger
+         AFTER DELETE ON moz_bookmarks
+         BEGIN
+             {old_bookmark}
+         END;
+
+         CREATE TRIGGER moz_tags_relation_fts_afterinsert_trigger
+         AFTER INSERT ON moz_tags_relation
+         BEGIN
+             {new_tagged}
+         END;
+
+         CREATE TRIGGER moz_tags_relation_fts_afterdelete_trigger
+         AFTER DELETE ON moz_tags_relation
+         BEGIN
+             {old_tagged}
+         END;",
+        new_place = note_stale_sql("NEW.id"),
+        old_place = note_stale_sql("OLD.id"),
+        new_bookmark = note_stale_sql("NEW.fk"),
+        old_bookmark = note_stale_sql("OLD.fk"),
+        new_tagged = note_stale_sql("NEW.place_id"),
+        old_tagged = note_stale_sql("OLD.place_id"),
+    )
+}
+
+const DROP_TRIGGERS_SQL: &str = "
+    DROP TRIGGER IF EXISTS moz_places_fts_afterinsert_trigger;
+    DROP TRIGGER IF EXISTS moz_places_fts_afterupdate_trigger;
+    DROP TRIGGER IF EXISTS moz_places_fts_afterdelete_trigger;
+    DROP TRIGGER IF EXISTS moz_bookmarks_fts_afterinsert_trigger;
+    DROP TRIGGER IF EXISTS moz_bookmarks_fts_afterupdate_trigger;
+    DROP TRIGGER IF EXISTS moz_bookmarks_fts_afterdelete_trigger;
+    DROP TRIGGER IF EXISTS moz_tags_relation_fts_afterinsert_trigger;
+    DROP TRIGGER IF EXISTS moz_tags_relation_fts_afterdelete_trigger;
+";
+
+/// Returns true if the search index has been enabled for this database.
+pub fn is_search_index_enabled(db: &impl ConnExt) -> Result<bool> {
+    Ok(db.query_row_and_then_named(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master
+                       WHERE type = 'table' AND name = 'moz_places_fts')",
+        &[],
+        |row| row.get_checked(0),
+        true,
+    )?)
+}
+
+// Remembers whether the index exists for a new connection. Called by
+// `db::schema::init`.
+pub(crate) fn init(db: &PlacesDb) -> Result<()> {
+    db.search_index_enabled.set(is_search_index_enabled(db)?);
+    Ok(())
+}
+
+/// Creates the search index, and fills it with every page. This does nothing
+/// if the index is already enabled, and fails if SQLite doesn't have FTS5.
+pub fn enable_search_index(db: &PlacesDb) -> Result<()> {
+    db.in_transaction(|| enable_search_index_in_tx(db))?;
+    db.search_index_enabled.set(true);
+    Ok(())
+}
+
+fn enable_search_index_in_tx(db: &PlacesDb) -> Result<()> {
+    if is_search_index_enabled(db)? {
+        // Another connection enabled it since we opened this one.
+        return Ok(());
+    }
+    db.execute_batch(
+        "CREATE VIRTUAL TABLE moz_places_fts USING fts5(
+             url, title, tags, description,
+             tokenize = 'unicode61'
+         );
+         CREATE TABLE moz_places_fts_stale(
+             place_id INTEGER PRIMARY KEY
+         );",
+    )?;
+    db.execute_batch(&create_triggers_sql())?;
+    db.execute_batch(&insert_entries_sql("1"))?;
+    Ok(())
+}
+
+/// Removes the search index, if it's enabled.
+pub fn disable_search_index(db: &PlacesDb) -> Result<()> {
+    db.in_transaction(|| {
+        db.execute_batch(DROP_TRIGGERS_SQL)?;
+        db.execute_batch(
+            "DROP TABLE IF EXISTS moz_places_fts;
+             DROP TABLE IF EXISTS moz_places_fts_stale;",
+        )?;
+        Ok(())
+    })?;
+    db.search_index_enabled.set(false);
+    Ok(())
+}
+
+/// Throws away the search index and fills it again, in case it's out of date
+/// or corrupt. It's an error to call this if the index isn't enabled.
+pub fn rebuild_search_index(db: &PlacesDb) -> Result<()> {
+    db.in_transaction(|| {
+        db.execute_all(&[
+            "DELETE FROM moz_places_fts",
+            "DELETE FROM moz_places_fts_stale",
+            &insert_entries_sql("1"),
+            "INSERT INTO moz_places_fts(moz_places_fts) VALUES('optimize')",
+        ])?;
+        Ok(())
+    })
+}
+
+// Refreshes the index entries for the pages which changed since the last
+// search. Returns false if the index has been disabled since this connection
+// was opened.
+fn refresh_stale_entries(db: &PlacesDb) -> Result<bool> {
+    if !is_search_index_enabled(db)? {
+        return Ok(false);
+    }
+    let stale: u32 = db.query_one("SELECT COUNT(*) FROM moz_places_fts_stale")?;
+    if stale > 0 {
+        db.in_transaction(|| {
+            db.execute_all(&[
+                "DELETE FROM moz_places_fts
+                 WHERE rowid IN (SELECT place_id FROM moz_places_fts_stale)",
+                &insert_entries_sql("h.id IN (SELECT place_id FROM moz_places_fts_stale)"),
+                "DELETE FROM moz_places_fts_stale",
+            ])?;
+            Ok(())
+        })?;
+    }
+    Ok(true)
+}
+
+/// Returns the query to narrow down the pages to search for `search_str`, or
+/// `None` if we need to search them all. The query is for
+/// `moz_places_fts MATCH`.
+pub(crate) fn search_index_match(
+    db: &PlacesDb,
+    search_str: &str,
+    match_behavior: MatchBehavior,
+    folding: MatchFolding,
+) -> Result<Option<String>> {
+    match match_behavior {
+        MatchBehavior::Anywhere | MatchBehavior::AnywhereUnmodified => return Ok(None),
+        _ => {}
+    }
+    if !db.search_index_enabled.get() {
+        return Ok(None);
+    }
+    let query = match search_index_query(search_str, folding) {
+        Some(query) => query,
+        None => return Ok(None),
+    };
+    match refresh_stale_entries(db) {
+        Ok(true) => Ok(Some(query)),
+        Ok(false) => {
+            db.search_index_enabled.set(false);
+            Ok(None)
+        }
+        // Another connection might be writing, so we can't bring the index
+        // up to date. Searching every page is slower, but finds everything.
+        Err(e) => {
+            log::warn!("Not using the out of date search index: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::apply_observation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark, InsertableItem,
+    };
+    use crate::storage::tags::{tag_url, untag_url};
+    use crate::types::VisitTransition;
+    use url::Url;
+
+    fn add_page(conn: &PlacesDb, url: &str, title: &str) -> Result<()> {
+        apply_observation(
+            conn,
+            VisitObservation::new(Url::parse(url)?)
+                .with_title(title.to_string())
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        Ok(())
+    }
+
+    // Returns the URLs of the pages that the index finds for `search_str`.
+    fn search(conn: &PlacesDb, search_str: &str) -> Result<Vec<String>> {
+        let query = search_index_match(
+            conn,
+            search_str,
+            MatchBehavior::Boundary,
+            MatchFolding::Case,
+        )?
+        .expect("should have a query");
+        let mut stmt = conn.prepare(
+            "SELECT h.url FROM moz_places h
+             WHERE h.id IN (SELECT rowid FROM moz_places_fts
+                            WHERE moz_places_fts MATCH :query)
+             ORDER BY h.url",
+        )?;
+        let urls = stmt
+            .query_and_then_named(&[(":query", &query)], |row| row.get_checked(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(urls)
+    }
+
+    #[test]
+    fn test_search_index() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(None)?;
+        add_page(&conn, "https://www.example.com/", "Example Domain")?;
+        add_page(&conn, "https://mozilla.org/firefox", "Get FireFox")?;
+
+        assert!(!is_search_index_enabled(&conn)?);
+        assert_eq!(
+            search_index_match(&conn, "fox", MatchBehavior::Boundary, MatchFolding::Case)?,
+            None
+        );
+        enable_search_index(&conn)?;
+        assert!(is_search_index_enabled(&conn)?);
+        // Enabling it again does nothing.
+        enable_search_index(&conn)?;
+
+        assert_eq!(search(&conn, "exam dom")?, vec!["https://www.example.com/"]);
+        // Boundaries inside words.
+        assert_eq!(search(&conn, "fox")?, vec!["https://mozilla.org/firefox"]);
+        assert_eq!(
+            search(&conn, "org/fire")?,
+            vec!["https://mozilla.org/firefox"]
+        );
+        assert!(search(&conn, "ample")?.is_empty());
+        assert_eq!(
+            search_index_match(&conn, "fox", MatchBehavior::Anywhere, MatchFolding::Case)?,
+            None
+        );
+
+        // New pages, titles, bookmarks and tags are indexed before searching.
+        add_page(&conn, "https://example.org/", "Another Example")?;
+        assert_eq!(search(&conn, "another")?, vec!["https://example.org/"]);
+        add_page(&conn, "https://example.org/", "Changed")?;
+        assert!(search(&conn, "another")?.is_empty());
+
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse("https://example.org/")?,
+                title: Some("Bookmarked Page".into()),
+            }),
+        )?;
+        assert_eq!(search(&conn, "marked")?, vec!["https://example.org/"]);
+
+        let url = Url::parse("https://www.example.com/")?;
+        tag_url(&conn, &url, "sometag")?;
+        assert_eq!(search(&conn, "somet")?, vec!["https://www.example.com/"]);
+        untag_url(&conn, &url, "sometag")?;
+        assert!(search(&conn, "somet")?.is_empty());
+
+        // Rebuilding adds back missing entries.
+        conn.execute_batch("DELETE FROM moz_places_fts")?;
+        assert!(search(&conn, "exam")?.is_empty());
+        rebuild_search_index(&conn)?;
+        assert_eq!(
+            search(&conn, "exam")?,
+            vec!["https://example.org/", "https://www.example.com/"]
+        );
+
+        disable_search_index(&conn)?;
+        assert!(!is_search_index_enabled(&conn)?);
+        assert_eq!(
+            search_index_match(&conn, "fox", MatchBehavior::Boundary, MatchFolding::Case)?,
+            None
+        );
+        // The triggers are gone too, so this doesn't fail.
+        add_page(&conn, "https://example.net/", "Still works")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_index_other_connections() -> Result<()> {
+        let _ = env_logger::try_init();
+        let dir = tempfile::tempdir().expect("should create a temp dir");
+        let path = dir.path().join("places.sqlite");
+        let conn = PlacesDb::open(&path, None)?;
+        add_page(&conn, "https://example.com/", "Example Domain")?;
+        let opened_before = PlacesDb::open(&path, None)?;
+        enable_search_index(&conn)?;
+
+        // Connections opened before the index was enabled don't use it, but
+        // it still sees their writes...
+        add_page(&opened_before, "https://example.org/", "Written before")?;
+        assert_eq!(search(&conn, "before")?, vec!["https://example.org/"]);
+        assert_eq!(
+            search_index_match(
+                &opened_before,
+                "before",
+                MatchBehavior::Boundary,
+                MatchFolding::Case
+            )?,
+            None
+        );
+
+        // ...and writes from connections that don't define our functions.
+        {
+            let other = rusqlite::Connection::open(&path)?;
+            other.execute_batch(
+                "UPDATE moz_places SET title = 'Written elsewhere'
+                 WHERE url = 'https://example.com/'",
+            )?;
+        }
+        assert_eq!(search(&conn, "elsewhere")?, vec!["https://example.com/"]);
+        assert!(search(&conn, "domain")?.is_empty());
+
+        // Reopening remembers the index.
+        let reopened = PlacesDb::open(&path, None)?;
+        add_page(&reopened, "https://example.com/", "Different Title")?;
+        assert_eq!(search(&reopened, "diff")?, vec!["https://example.com/"]);
+
+        // Connections notice it being disabled.
+        disable_search_index(&conn)?;
+        assert_eq!(
+            search_index_match(
+                &reopened,
+                "diff",
+                MatchBehavior::Boundary,
+                MatchFolding::Case
+            )?,
+            None
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Tags are attached to URLs rather than to bookmarks, so a URL can be tagged
+// without being bookmarked. They are synced as part of the bookmark records
+// for the URL, so changing the tags for a URL bumps the change counter of
+// every bookmark for it.
+
+use super::bookmarks::get_or_create_place_id;
+use super::{fetch_page_info, RowId};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::types::Timestamp;
+use rusqlite::Connection;
+use sql_support::ConnExt;
+use url::Url;
+
+/// The longest tag we allow. This matches desktop.
+pub const TAG_MAX_LENGTH: usize = 100;
+
+/// Checks that `tag` is valid, returning it without leading or trailing
+/// whitespace.
+pub fn validate_tag(tag: &str) -> Result<&str> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.len() > TAG_MAX_LENGTH || tag.contains(|c: char| c.is_control()) {
+        return Err(InvalidPlaceInfo::InvalidTag.into());
+    }
+    Ok(tag)
+}
+
+/// Returns the id of `tag`, creating it if necessary. The tag must already
+/// have been validated.
+pub(crate) fn get_or_create_tag_id(db: &Connection, tag: &str, now: Timestamp) -> Result<RowId> {
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_tags (tag, lastModified) VALUES (:tag, :now)",
+        &[(":tag", &tag), (":now", &now)],
+    )?;
+    Ok(db.query_row_named(
+        "SELECT id FROM moz_tags WHERE tag = :tag",
+        &[(":tag", &tag)],
+        |row| row.get::<_, RowId>(0),
+    )?)
+}
+
+/// Returns the tags for a place, sorted.
+pub(crate) fn get_tags_for_place(db: &Connection, place_id: RowId) -> Result<Vec<String>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT t.tag
+         FROM moz_tags t
+         JOIN moz_tags_relation r ON r.tag_id = t.id
+         WHERE r.place_id = :place_id
+         ORDER BY t.tag",
+    )?;
+    let tags = stmt
+        .query_map_named(&[(":place_id", &place_id)], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(tags)
+}
+
+// Bumps the change counter for every bookmark of the place, so that the new
+// tags are uploaded.
+fn note_tags_changed(db: &Connection, place_id: RowId) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks
+         SET syncChangeCounter = syncChangeCounter + 1
+         WHERE fk = :place_id",
+        &[(":place_id", &place_id)],
+    )?;
+    Ok(())
+}
+
+// Removes tags which aren't used by any URL, or by anything in the mirror.
+fn remove_unused_tags(db: &Connection) -> Result<()> {
+    db.execute_cached(
+        "DELETE FROM moz_tags
+         WHERE id NOT IN (SELECT tag_id FROM moz_tags_relation)
+           AND id NOT IN (SELECT tagId FROM moz_bookmarks_synced_tag_relation)",
+        &[],
+    )?;
+    Ok(())
+}
+
+/// Tags a URL. The URL doesn't need to be bookmarked or in history. Tagging
+/// a URL with a tag it already has is a no-op.
+pub fn tag_url(db: &PlacesDb, url: &Url, tag: &str) -> Result<()> {
+    let tag = validate_tag(tag)?;
+    db.in_transaction(|| tag_url_in_tx(db, url, tag))
+}
+
+pub(crate) fn tag_url_in_tx(db: &Connection, url: &Url, tag: &str) -> Result<()> {
+    let place_id = get_or_create_place_id(db, url)?;
+    let tag_id = get_or_create_tag_id(db, tag, Timestamp::now())?;
+    let changes = db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_tags_relation (tag_id, place_id)
+         VALUES (:tag_id, :place_id)",
+        &[(":tag_id", &tag_id), (":place_id", &place_id)],
+    )?;
+    if changes > 0 {
+        note_tags_changed(db, place_id)?;
+    }
+    Ok(())
+}
+
+/// Removes a tag from a URL. Removing a tag the URL doesn't have is a no-op.
+pub fn untag_url(db: &PlacesDb, url: &Url, tag: &str) -> Result<()> {
+    let tag = validate_tag(tag)?;
+    db.in_transaction(|| untag_url_in_tx(db, url, Some(tag)))
+}
+
+/// Removes all the tags from a URL.
+pub fn remove_all_tags_from_url(db: &PlacesDb, url: &Url) -> Result<()> {
+    db.in_transaction(|| untag_url_in_tx(db, url, None))
+}
+
+fn untag_url_in_tx(db: &Connection, url: &Url, tag: Option<&str>) -> Result<()> {
+    let place_id = match fetch_page_info(db, url)? {
+        Some(info) => info.page.row_id,
+        None => return Ok(()),
+    };
+    let changes = db.execute_named_cached(
+        "DELETE FROM moz_tags_relation
+         WHERE place_id = :place_id
+           AND (:tag IS NULL OR tag_id = (SELECT id FROM moz_tags WHERE tag = :tag))",
+        &[(":place_id", &place_id), (":tag", &tag)],
+    )?;
+    if changes > 0 {
+        note_tags_changed(db, place_id)?;
+        remove_unused_tags(db)?;
+    }
+    Ok(())
+}
+
+/// Removes a tag from every URL which has it.
+pub fn remove_tag(db: &PlacesDb, tag: &str) -> Result<()> {
+    let tag = validate_tag(tag)?;
+    db.in_transaction(|| remove_tag_in_tx(db, tag))
+}
+
+fn remove_tag_in_tx(db: &Connection, tag: &str) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks
+         SET syncChangeCounter = syncChangeCounter + 1
+         WHERE fk IN (SELECT r.place_id FROM moz_tags_relation r
+                      JOIN moz_tags t ON t.id = r.tag_id
+                      WHERE t.tag = :tag)",
+        &[(":tag", &tag)],
+    )?;
+    db.execute_named_cached(
+        "DELETE FROM moz_tags_relation
+         WHERE tag_id = (SELECT id FROM moz_tags WHERE tag = :tag)",
+        &[(":tag", &tag)],
+    )?;
+    remove_unused_tags(db)?;
+    Ok(())
+}
+
+/// Returns the tags for a URL, sorted.
+pub fn get_tags_for_url(db: &PlacesDb, url: &Url) -> Result<Vec<String>> {
+    match fetch_page_info(db, url)? {
+        Some(info) => get_tags_for_place(db, info.page.row_id),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Returns the URLs with a tag, most frecent first.
+pub fn get_urls_with_tag(db: &PlacesDb, tag: &str) -> Result<Vec<Url>> {
+    let tag = validate_tag(tag)?;
+    let mut stmt = db.prepare(
+        "SELECT h.url
+         FROM moz_places h
+         JOIN moz_tags_relation r ON r.place_id = h.id
+         JOIN moz_tags t ON t.id = r.tag_id
+         WHERE t.tag = :tag
+         ORDER BY h.frecency DESC, h.id",
+    )?;
+    let urls = stmt
+        .query_and_then_named(&[(":tag", &tag)], |row| -> Result<Url> {
+            Ok(Url::parse(&row.get_checked::<_, String>(0)?)?)
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(urls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark, InsertableItem,
+    };
+    use crate::types::SyncGuid;
+
+    fn get_change_counter(conn: &PlacesDb, guid: &SyncGuid) -> u32 {
+        conn.query_row_named(
+            "SELECT syncChangeCounter FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", guid)],
+            |row| row.get::<_, u32>(0),
+        )
+        .expect("should have the bookmark")
+    }
+
+    #[test]
+    fn test_validate_tag() {
+        assert_eq!(validate_tag("foo").unwrap(), "foo");
+        assert_eq!(validate_tag("  foo bar\n").unwrap(), "foo bar");
+        assert!(validate_tag("").is_err());
+        assert!(validate_tag("   ").is_err());
+        assert!(validate_tag("foo\u{0}bar").is_err());
+        assert!(validate_tag(&"x".repeat(TAG_MAX_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_tags() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let url1 = Url::parse("http://example.com/1")?;
+        let url2 = Url::parse("http://example.com/2")?;
+
+        tag_url(&conn, &url1, "common")?;
+        tag_url(&conn, &url1, "one")?;
+        tag_url(&conn, &url1, " one ")?;
+        tag_url(&conn, &url2, "common")?;
+        assert_eq!(get_tags_for_url(&conn, &url1)?, vec!["common", "one"]);
+        assert_eq!(get_tags_for_url(&conn, &url2)?, vec!["common"]);
+        let mut urls = get_urls_with_tag(&conn, "common")?;
+        urls.sort();
+        assert_eq!(urls, vec![url1.clone(), url2.clone()]);
+        assert_eq!(get_urls_with_tag(&conn, "one")?, vec![url1.clone()]);
+        assert!(get_urls_with_tag(&conn, "two")?.is_empty());
+
+        untag_url(&conn, &url1, "one")?;
+        assert_eq!(get_tags_for_url(&conn, &url1)?, vec!["common"]);
+        let num_tags: u32 =
+            conn.query_row("SELECT COUNT(*) FROM moz_tags", &[], |row| row.get(0))?;
+        assert_eq!(num_tags, 1);
+
+        remove_tag(&conn, "common")?;
+        assert!(get_tags_for_url(&conn, &url1)?.is_empty());
+        assert!(get_tags_for_url(&conn, &url2)?.is_empty());
+
+        tag_url(&conn, &url1, "a")?;
+        tag_url(&conn, &url1, "b")?;
+        remove_all_tags_from_url(&conn, &url1)?;
+        assert!(get_tags_for_url(&conn, &url1)?.is_empty());
+
+        // Untagging a URL we don't know about is fine.
+        untag_url(&conn, &Url::parse("http://example.com/unknown")?, "a")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_tags_bump_change_counter() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(None)?;
+        let url = Url::parse("http://example.com/")?;
+        let guid = insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: None,
+            }),
+        )?;
+        let counter = get_change_counter(&conn, &guid);
+        tag_url(&conn, &url, "foo")?;
+        assert_eq!(get_change_counter(&conn, &guid), counter + 1);
+        // Not a change.
+        tag_url(&conn, &url, "foo")?;
+        assert_eq!(get_change_counter(&conn, &guid), counter + 1);
+        untag_url(&conn, &url, "foo")?;
+        assert_eq!(get_change_counter(&conn, &guid), counter + 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// "Top sites" are the most frecent pages the user has visited, shown on the
+// new tab page. This is loosely based on the top sites query in Activity
+// Stream on desktop.
+//
+// We only want pages the user actually looked at, so we skip hidden pages
+// (which were only ever embedded, framed, or redirected from), pages which
+// redirected to another page, and pages which only failed to load - error
+// visits don't give a page frecency. The user can also remove pages from
+// their top sites, and we remember those in `moz_topsites_blocked`.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::types::{DocumentType, Timestamp, VisitTransition};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::HashSet;
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopSite {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub title: Option<String>,
+    #[serde(with = "url_serde")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_image_url: Option<Url>,
+    pub description: Option<String>,
+    pub document_type: DocumentType,
+    /// How long the user has looked at the page, in milliseconds.
+    pub total_view_time: u64,
+    pub frecency: i64,
+}
+
+/// Returns the `limit` most frecent pages, excluding blocked pages. If
+/// `dedupe_by_origin` is true, only the most frecent page for each origin is
+/// returned.
+pub fn get_top_sites(db: &PlacesDb, limit: u32, dedupe_by_origin: bool) -> Result<Vec<TopSite>> {
+    let mut stmt = db.prepare_cached(&format!(
+        "SELECT h.url, h.title, h.preview_image_url, h.description,
+                h.document_type, h.total_view_time, h.frecency, h.origin_id
+         FROM moz_places h
+         WHERE NOT h.hidden
+           AND h.frecency > 0
+           AND MAX(h.last_visit_date_local, h.last_visit_date_remote) > 0
+           AND NOT EXISTS(SELECT 1 FROM moz_topsites_blocked b WHERE b.url = h.url)
+           AND NOT EXISTS(SELECT 1 FROM moz_historyvisits v
+                          JOIN moz_historyvisits r ON r.from_visit = v.id
+                          WHERE v.place_id = h.id AND r.visit_type IN ({}, {}))
+         ORDER BY h.frecency DESC,
+                  MAX(h.last_visit_date_local, h.last_visit_date_remote) DESC,
+                  h.id",
+        VisitTransition::RedirectPermanent as u8,
+        VisitTransition::RedirectTemporary as u8
+    ))?;
+    let mut rows = stmt.query(&[])?;
+    let mut seen_origins = HashSet::new();
+    let mut top_sites = Vec::new();
+    // We can't dedupe in the query, so read rows until we have enough.
+    while let Some(row) = rows.next() {
+        if top_sites.len() >= limit as usize {
+            break;
+        }
+        let row = row?;
+        if dedupe_by_origin {
+            let origin_id = row.get_checked::<_, Option<i64>>("origin_id")?;
+            if !seen_origins.insert(origin_id) {
+                continue;
+            }
+        }
+        top_sites.push(TopSite {
+            url: Url::parse(&row.get_checked::<_, String>("url")?)?,
+            title: row.get_checked("title")?,
+            preview_image_url: match row.get_checked::<_, Option<String>>("preview_image_url")? {
+                Some(url) => Url::parse(&url).ok(),
+                None => None,
+            },
+            description: row.get_checked("description")?,
+            document_type: row.get_checked("document_type")?,
+            total_view_time: row.get_checked::<_, i64>("total_view_time")?.max(0) as u64,
+            frecency: row.get_checked("frecency")?,
+        });
+    }
+    Ok(top_sites)
+}
+
+/// Removes `url` from the top sites. It stays blocked until it's unblocked,
+/// even if its history is removed.
+pub fn block_top_site(db: &PlacesDb, url: &Url) -> Result<()> {
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_topsites_blocked (url, dateAdded)
+         VALUES (:url, :now)",
+        &[(":url", &url.as_str()), (":now", &Timestamp::now())],
+    )?;
+    Ok(())
+}
+
+/// Lets a blocked URL show up in the top sites again.
+pub fn unblock_top_site(db: &PlacesDb, url: &Url) -> Result<()> {
+    db.execute_named_cached(
+        "DELETE FROM moz_topsites_blocked WHERE url = :url",
+        &[(":url", &url.as_str())],
+    )?;
+    Ok(())
+}
+
+/// Lets every blocked URL show up in the top sites again.
+pub fn unblock_all_top_sites(db: &PlacesDb) -> Result<()> {
+    db.execute_cached("DELETE FROM moz_topsites_blocked", &[])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::apply_observation;
+
+    fn urls(top_sites: &[TopSite]) -> Vec<&str> {
+        top_sites.iter().map(|s| s.url.as_str()).collect()
+    }
+
+    #[test]
+    fn test_top_sites() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        for url in &[
+            "http://example.com/1",
+            "http://example.com/2",
+            "http://mozilla.org/",
+            "http://example.org/",
+        ] {
+            apply_observation(
+                &mut conn,
+                VisitObservation::new(Url::parse(url)?)
+                    .with_title(url.to_string())
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        // A page that only redirected is hidden.
+        apply_observation(
+            &mut conn,
+            VisitObservation::new(Url::parse("http://redirect.com/")?)
+                .with_visit_type(VisitTransition::Link)
+                .with_is_redirect_source(true),
+        )?;
+        // A page that only failed to load has no frecency.
+        apply_observation(
+            &mut conn,
+            VisitObservation::new(Url::parse("http://error.com/")?)
+                .with_visit_type(VisitTransition::Link)
+                .with_is_error(true),
+        )?;
+        conn.execute_all(&[
+            "UPDATE moz_places SET frecency = 400 WHERE url = 'http://example.com/1'",
+            "UPDATE moz_places SET frecency = 300 WHERE url = 'http://mozilla.org/'",
+            "UPDATE moz_places SET frecency = 200 WHERE url = 'http://example.com/2'",
+            "UPDATE moz_places SET frecency = 100 WHERE url = 'http://example.org/'",
+            "UPDATE moz_places SET frecency = 1000
+             WHERE url IN ('http://redirect.com/', 'http://error.com/')
+               AND frecency > 0",
+        ])?;
+
+        let top_sites = get_top_sites(&conn, 10, false)?;
+        assert_eq!(
+            urls(&top_sites),
+            vec![
+                "http://example.com/1",
+                "http://mozilla.org/",
+                "http://example.com/2",
+                "http://example.org/",
+            ]
+        );
+        assert_eq!(top_sites[0].title, Some("http://example.com/1".to_string()));
+        assert_eq!(top_sites[0].frecency, 400);
+
+        assert_eq!(
+            urls(&get_top_sites(&conn, 2, false)?),
+            vec!["http://example.com/1", "http://mozilla.org/"]
+        );
+        assert_eq!(
+            urls(&get_top_sites(&conn, 10, true)?),
+            vec![
+                "http://example.com/1",
+                "http://mozilla.org/",
+                "http://example.org/",
+            ]
+        );
+
+        block_top_site(&conn, &Url::parse("http://example.com/1")?)?;
+        block_top_site(&conn, &Url::parse("http://mozilla.org/")?)?;
+        assert_eq!(
+            urls(&get_top_sites(&conn, 2, true)?),
+            vec!["http://example.com/2", "http://example.org/"]
+        );
+        unblock_top_site(&conn, &Url::parse("http://mozilla.org/")?)?;
+        assert_eq!(
+            urls(&get_top_sites(&conn, 1, true)?),
+            vec!["http://mozilla.org/"]
+        );
+        unblock_all_top_sites(&conn)?;
+        assert_eq!(
+            urls(&get_top_sites(&conn, 1, true)?),
+            vec!["http://example.com/1"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,611 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Lists visits along with the page they're for, most recent first, for
+// showing history to the user. Unlike `get_visited_urls`, this returns one
+// entry per visit, and supports paging through history and filtering it.
+//
+// Visits are linked to the visit they came from, using `from_visit`, so we
+// can also walk back through the chain of visits that led to a visit.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::RowId;
+use crate::types::{DocumentType, Timestamp, VisitTransition};
+use rusqlite::types::ToSql;
+use rusqlite::Row;
+use serde_derive::*;
+use url::Url;
+
+/// A visit, along with the page it's for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VisitInfo {
+    pub visit_id: RowId,
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    pub title: Option<String>,
+    pub visit_date: Timestamp,
+    pub visit_type: VisitTransition,
+    /// Indicates if the visit happened on another device, and was synced to
+    /// this one.
+    pub is_remote: bool,
+    #[serde(with = "url_serde")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_image_url: Option<Url>,
+    pub description: Option<String>,
+    pub document_type: DocumentType,
+    /// How long the user has looked at the page, in milliseconds.
+    pub total_view_time: u64,
+}
+
+const VISIT_INFO_COLUMNS: &str = "v.id AS visit_id, h.url, h.title, h.preview_image_url,
+     h.description, h.document_type, h.total_view_time,
+     v.visit_date, v.visit_type, v.is_local";
+
+impl VisitInfo {
+    fn from_row(row: &Row) -> Result<Self> {
+        let visit_type = row.get_checked::<_, u8>("visit_type")?;
+        Ok(VisitInfo {
+            visit_id: row.get_checked("visit_id")?,
+            url: Url::parse(&row.get_checked::<_, String>("url")?)?,
+            title: row.get_checked("title")?,
+            visit_date: row.get_checked("visit_date")?,
+            // Visits with unknown transitions can't be written, so treat any
+            // we find as links.
+            visit_type: VisitTransition::from_primitive(visit_type)
+                .unwrap_or(VisitTransition::Link),
+            is_remote: !row.get_checked::<_, bool>("is_local")?,
+            preview_image_url: match row.get_checked::<_, Option<String>>("preview_image_url")? {
+                Some(url) => Url::parse(&url).ok(),
+                None => None,
+            },
+            description: row.get_checked("description")?,
+            document_type: row.get_checked("document_type")?,
+            total_view_time: row.get_checked::<_, i64>("total_view_time")?.max(0) as u64,
+        })
+    }
+}
+
+/// Where a page of visits starts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VisitCursor {
+    /// Start with the visits after this one, in the order they're listed.
+    /// Visits are paged by date, and by id for visits that happened in the
+    /// same millisecond, so a page won't repeat or skip visits from the last
+    /// page, even if history changes in between.
+    Before {
+        visit_date: Timestamp,
+        visit_id: RowId,
+    },
+    /// Skip this many visits. Unlike `Before`, this never skips visits, but
+    /// a page can repeat or skip visits if history changes in between.
+    Offset(u32),
+}
+
+/// Which visits to list, and how many.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct VisitQuery {
+    /// Where to start, or `None` to start with the most recent visit.
+    #[serde(default)]
+    pub cursor: Option<VisitCursor>,
+
+    /// The most visits to return.
+    pub limit: u32,
+
+    /// Only list visits with these transitions, or every visit if `None`.
+    #[serde(default)]
+    pub visit_types: Option<Vec<VisitTransition>>,
+
+    /// Only list visits to pages whose URL or title contains this text,
+    /// ignoring case.
+    #[serde(default)]
+    pub search: Option<String>,
+
+    /// Only list visits to pages on this host. Subdomains of the host
+    /// aren't included.
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Also list visits that happened on other devices.
+    #[serde(default = "default_include_remote")]
+    pub include_remote: bool,
+
+    /// Hide visits that redirected to another page, so that each redirect
+    /// chain is listed once, as a visit to the page it ended on.
+    #[serde(default = "default_collapse_redirects")]
+    pub collapse_redirects: bool,
+}
+
+fn default_include_remote() -> bool {
+    true
+}
+
+fn default_collapse_redirects() -> bool {
+    true
+}
+
+impl VisitQuery {
+    /// Returns a query for the most recent `limit` visits, with the default
+    /// filters.
+    pub fn new(limit: u32) -> Self {
+        VisitQuery {
+            cursor: None,
+            limit,
+            visit_types: None,
+            search: None,
+            host: None,
+            include_remote: default_include_remote(),
+            collapse_redirects: default_collapse_redirects(),
+        }
+    }
+}
+
+/// A page of visits returned by `get_visit_page`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VisitPage {
+    pub visits: Vec<VisitInfo>,
+
+    /// The cursor for the next page, of the same kind as the query's cursor,
+    /// or `None` if this is the last page.
+    pub next_cursor: Option<VisitCursor>,
+}
+
+// Escapes `%`, `_` and `\` in `text`, so that it's matched literally by
+// `LIKE ... ESCAPE '\'`.
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Returns a page of visits matching `query`, most recent first.
+pub fn get_visit_page(db: &PlacesDb, query: &VisitQuery) -> Result<VisitPage> {
+    let mut filters = Vec::new();
+    if let Some(VisitCursor::Before { .. }) = query.cursor {
+        filters.push(
+            "(v.visit_date < :before OR (v.visit_date = :before AND v.id < :before_id))"
+                .to_string(),
+        );
+    }
+    if let Some(visit_types) = &query.visit_types {
+        // These are integers, so they're safe to put in the SQL.
+        let types = visit_types
+            .iter()
+            .map(|t| (*t as u8).to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        filters.push(format!("v.visit_type IN ({})", types));
+    }
+    if query.search.is_some() {
+        filters.push(
+            "(h.url LIKE :search ESCAPE '\\' OR h.title LIKE :search ESCAPE '\\')".to_string(),
+        );
+    }
+    if query.host.is_some() {
+        filters.push("o.rev_host = reverse_host(:host)".to_string());
+    }
+    if !query.include_remote {
+        filters.push("v.is_local".to_string());
+    }
+    if query.collapse_redirects {
+        filters.push(format!(
+            "NOT EXISTS(SELECT 1 FROM moz_historyvisits r
+                        WHERE r.from_visit = v.id AND r.visit_type IN ({}, {}))",
+            VisitTransition::RedirectPermanent as u8,
+            VisitTransition::RedirectTemporary as u8
+        ));
+    }
+    let sql = format!(
+        "SELECT {columns}
+         FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         LEFT JOIN moz_origins o ON o.id = h.origin_id
+         {where_clause}
+         ORDER BY v.visit_date DESC, v.id DESC
+         LIMIT :limit OFFSET :offset",
+        columns = VISIT_INFO_COLUMNS,
+        where_clause = if filters.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", filters.join(" AND "))
+        }
+    );
+
+    let (before, before_id) = match query.cursor {
+        Some(VisitCursor::Before {
+            visit_date,
+            visit_id,
+        }) => (visit_date, visit_id),
+        _ => (Timestamp(0), RowId(0)),
+    };
+    let offset = match query.cursor {
+        Some(VisitCursor::Offset(offset)) => offset,
+        _ => 0,
+    };
+    let search = query
+        .search
+        .as_ref()
+        .map(|s| format!("%{}%", escape_like(s)));
+    // Fetch one more visit than we need, so we know if there's another page.
+    let limit = query.limit.saturating_add(1);
+    let mut params: Vec<(&str, &dyn ToSql)> = vec![(":limit", &limit), (":offset", &offset)];
+    if let Some(VisitCursor::Before { .. }) = query.cursor {
+        params.push((":before", &before));
+        params.push((":before_id", &before_id));
+    }
+    if let Some(search) = &search {
+        params.push((":search", search));
+    }
+    if let Some(host) = &query.host {
+        params.push((":host", host));
+    }
+
+    let mut stmt = db.prepare(&sql)?;
+    let mut visits = stmt
+        .query_and_then_named(&params, VisitInfo::from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    let has_more = visits.len() > query.limit as usize;
+    visits.truncate(query.limit as usize);
+    let next_cursor = if has_more {
+        match query.cursor {
+            Some(VisitCursor::Offset(offset)) => Some(VisitCursor::Offset(
+                offset.saturating_add(visits.len() as u32),
+            )),
+            _ => visits.last().map(|visit| VisitCursor::Before {
+                visit_date: visit.visit_date,
+                visit_id: visit.visit_id,
+            }),
+        }
+    } else {
+        None
+    };
+    Ok(VisitPage {
+        visits,
+        next_cursor,
+    })
+}
+
+// Chains longer than this are almost certainly redirect loops, or corrupt.
+const MAX_VISIT_CHAIN_LENGTH: u32 = 100;
+
+/// Returns the chain of visits that led to `visit_id`: the visit it came
+/// from, the visit that one came from, and so on. The chain is returned in
+/// the order the visits happened, so it starts with the first visit in the
+/// chain and ends with `visit_id` itself. Returns an empty chain if the visit
+/// doesn't exist.
+pub fn get_visit_chain(db: &PlacesDb, visit_id: RowId) -> Result<Vec<VisitInfo>> {
+    let mut stmt = db.prepare_cached(&format!(
+        "WITH RECURSIVE chain(id, depth) AS (
+             SELECT :visit_id, 0
+             UNION ALL
+             SELECT v.from_visit, c.depth + 1
+             FROM moz_historyvisits v
+             JOIN chain c ON c.id = v.id
+             WHERE v.from_visit IS NOT NULL AND c.depth < :max_depth
+         )
+         SELECT {columns}
+         FROM chain c
+         JOIN moz_historyvisits v ON v.id = c.id
+         JOIN moz_places h ON h.id = v.place_id
+         ORDER BY c.depth DESC",
+        columns = VISIT_INFO_COLUMNS
+    ))?;
+    let chain = stmt
+        .query_and_then_named(
+            &[
+                (":visit_id", &visit_id),
+                (":max_depth", &MAX_VISIT_CHAIN_LENGTH),
+            ],
+            VisitInfo::from_row,
+        )?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::apply_observation;
+    use sql_support::ConnExt;
+
+    fn observe(conn: &mut PlacesDb, url: &str, title: &str, t: VisitTransition, at: u64) {
+        let visit = VisitObservation::new(Url::parse(url).unwrap())
+            .with_title(title.to_string())
+            .with_visit_type(t)
+            .with_at(Timestamp(at));
+        apply_observation(conn, visit).expect("should apply visit");
+    }
+
+    fn urls(page: &VisitPage) -> Vec<&str> {
+        page.visits.iter().map(|v| v.url.as_str()).collect()
+    }
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!(escape_like("abc"), "abc");
+        assert_eq!(escape_like("100%_a\\b"), "100\\%\\_a\\\\b");
+    }
+
+    #[test]
+    fn test_visit_pages() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        observe(
+            &mut conn,
+            "http://example.com/1",
+            "One",
+            VisitTransition::Typed,
+            1000,
+        );
+        observe(
+            &mut conn,
+            "http://example.com/2",
+            "Two",
+            VisitTransition::Link,
+            2000,
+        );
+        observe(
+            &mut conn,
+            "http://mozilla.org/",
+            "Mozilla",
+            VisitTransition::Link,
+            3000,
+        );
+        observe(
+            &mut conn,
+            "http://example.com/1",
+            "One",
+            VisitTransition::Link,
+            4000,
+        );
+
+        let page = get_visit_page(&conn, &VisitQuery::new(10))?;
+        assert_eq!(
+            urls(&page),
+            vec![
+                "http://example.com/1",
+                "http://mozilla.org/",
+                "http://example.com/2",
+                "http://example.com/1",
+            ]
+        );
+        assert_eq!(page.visits[0].title, Some("One".to_string()));
+        assert_eq!(page.visits[0].visit_date, Timestamp(4000));
+        assert_eq!(page.visits[3].visit_type, VisitTransition::Typed);
+        assert!(!page.visits[0].is_remote);
+        assert_eq!(page.next_cursor, None);
+
+        // Page by date.
+        let page = get_visit_page(&conn, &VisitQuery::new(3))?;
+        assert_eq!(page.visits.len(), 3);
+        assert_eq!(
+            page.next_cursor,
+            Some(VisitCursor::Before {
+                visit_date: Timestamp(2000),
+                visit_id: page.visits[2].visit_id,
+            })
+        );
+        let page = get_visit_page(
+            &conn,
+            &VisitQuery {
+                cursor: page.next_cursor,
+                ..VisitQuery::new(3)
+            },
+        )?;
+        assert_eq!(urls(&page), vec!["http://example.com/1"]);
+        assert_eq!(page.next_cursor, None);
+
+        // Page by offset.
+        let page = get_visit_page(
+            &conn,
+            &VisitQuery {
+                cursor: Some(VisitCursor::Offset(1)),
+                ..VisitQuery::new(2)
+            },
+        )?;
+        assert_eq!(
+            urls(&page),
+            vec!["http://mozilla.org/", "http://example.com/2"]
+        );
+        assert_eq!(page.next_cursor, Some(VisitCursor::Offset(3)));
+
+        // Filters.
+        let page = get_visit_page(
+            &conn,
+            &VisitQuery {
+                visit_types: Some(vec![VisitTransition::Typed]),
+                ..VisitQuery::new(10)
+            },
+        )?;
+        assert_eq!(urls(&page), vec!["http://example.com/1"]);
+        assert_eq!(page.visits[0].visit_date, Timestamp(1000));
+
+        let page = get_visit_page(
+            &conn,
+            &VisitQuery {
+                search: Some("TWO".to_string()),
+                ..VisitQuery::new(10)
+            },
+        )?;
+        assert_eq!(urls(&page), vec!["http://example.com/2"]);
+
+        let page = get_visit_page(
+            &conn,
+            &VisitQuery {
+                host: Some("mozilla.org".to_string()),
+                ..VisitQuery::new(10)
+            },
+        )?;
+        assert_eq!(urls(&page), vec!["http://mozilla.org/"]);
+
+        let page = get_visit_page(
+            &conn,
+            &VisitQuery {
+                search: Some("%".to_string()),
+                ..VisitQuery::new(10)
+            },
+        )?;
+        assert!(page.visits.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_visit_pages_same_date() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        for i in 0..5 {
+            observe(
+                &mut conn,
+                &format!("http://example.com/{}", i),
+                "",
+                VisitTransition::Link,
+                1000,
+            );
+        }
+        observe(
+            &mut conn,
+            "http://example.com/old",
+            "",
+            VisitTransition::Link,
+            500,
+        );
+
+        // Paging through visits that happened in the same millisecond
+        // shouldn't skip or repeat any.
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = get_visit_page(
+                &conn,
+                &VisitQuery {
+                    cursor,
+                    ..VisitQuery::new(2)
+                },
+            )?;
+            seen.extend(page.visits.iter().map(|v| v.url.to_string()));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            seen,
+            vec![
+                "http://example.com/4",
+                "http://example.com/3",
+                "http://example.com/2",
+                "http://example.com/1",
+                "http://example.com/0",
+                "http://example.com/old",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_chains() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(None)?;
+        observe(
+            &mut conn,
+            "http://mozilla.org/",
+            "Referrer",
+            VisitTransition::Link,
+            500,
+        );
+        apply_observation(
+            &mut conn,
+            VisitObservation::new(Url::parse("http://example.com/")?)
+                .with_visit_type(VisitTransition::Link)
+                .with_is_redirect_source(true)
+                .with_referrer(Url::parse("http://mozilla.org/")?)
+                .with_at(Timestamp(1000)),
+        )?;
+        let target_id = apply_observation(
+            &mut conn,
+            VisitObservation::new(Url::parse("https://example.com/")?)
+                .with_visit_type(VisitTransition::RedirectPermanent)
+                .with_referrer(Url::parse("http://example.com/")?)
+                .with_at(Timestamp(1001)),
+        )?
+        .expect("should add a visit");
+
+        // The redirect source is hidden, but the referrer isn't.
+        let page = get_visit_page(&conn, &VisitQuery::new(10))?;
+        assert_eq!(
+            urls(&page),
+            vec!["https://example.com/", "http://mozilla.org/"]
+        );
+        let page = get_visit_page(
+            &conn,
+            &VisitQuery {
+                collapse_redirects: false,
+                ..VisitQuery::new(10)
+            },
+        )?;
+        assert_eq!(
+            urls(&page),
+            vec![
+                "https://example.com/",
+                "http://example.com/",
+                "http://mozilla.org/",
+            ]
+        );
+
+        let chain = get_visit_chain(&conn, target_id)?;
+        assert_eq!(
+            chain.iter().map(|v| v.url.as_str()).collect::<Vec<_>>(),
+            vec![
+                "http://mozilla.org/",
+                "http://example.com/",
+                "https://example.com/",
+            ]
+        );
+        assert_eq!(chain[2].visit_id, target_id);
+
+        // Removing a visit in the middle of the chain breaks it.
+        conn.execute_cached("DELETE FROM moz_historyvisits WHERE visit_date = 1000", &[])?;
+        let chain = get_visit_chain(&conn, target_id)?;
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].visit_id, target_id);
+
+        assert!(get_visit_chain(&conn, RowId(12345))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_query() {
+        let query: VisitQuery = serde_json::from_str(
+            r#"{"limit": 5, "cursor": {"before": {"visit_date": 1234, "visit_id": 5}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            VisitQuery {
+                cursor: Some(VisitCursor::Before {
+                    visit_date: Timestamp(1234),
+                    visit_id: RowId(5),
+                }),
+                ..VisitQuery::new(5)
+            }
+        );
+        let query: VisitQuery = serde_json::from_str(
+            r#"{"limit": 5, "cursor": {"offset": 10}, "visit_types": [1, 2],
+                "include_remote": false}"#,
+        )
+        .unwrap();
+        assert_eq!(query.cursor, Some(VisitCursor::Offset(10)));
+        assert_eq!(
+            query.visit_types,
+            Some(vec![VisitTransition::Link, VisitTransition::Typed])
+        );
+        assert!(!query.include_remote);
+        assert!(query.collapse_redirects);
+    }
+}
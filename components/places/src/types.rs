@@ -2,14 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::Result as RusqliteResult;
 use serde_derive::*;
 use std::fmt;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // XXX - copied from logins - surprised it's not in `sync`
-#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SyncGuid(pub String);
 
 impl AsRef<str> for SyncGuid {
@@ -180,6 +180,118 @@ impl<'de> serde::Deserialize<'de> for VisitTransition {
     }
 }
 
+// NOTE: These are the same values desktop uses in `moz_bookmarks.type`, so
+// don't change them.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BookmarkType {
+    Bookmark = 1,
+    Folder = 2,
+    Separator = 3,
+}
+
+impl BookmarkType {
+    #[inline]
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(BookmarkType::Bookmark),
+            2 => Some(BookmarkType::Folder),
+            3 => Some(BookmarkType::Separator),
+            _ => None,
+        }
+    }
+}
+
+impl ToSql for BookmarkType {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for BookmarkType {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        if v < 0 || v > i64::from(u8::max_value()) {
+            return Err(FromSqlError::OutOfRange(v));
+        }
+        BookmarkType::from_u8(v as u8).ok_or_else(|| FromSqlError::OutOfRange(v))
+    }
+}
+
+impl serde::Serialize for BookmarkType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BookmarkType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let v = u8::deserialize(deserializer)?;
+        BookmarkType::from_u8(v)
+            .ok_or_else(|| D::Error::custom(format!("unknown BookmarkType value: {}", v)))
+    }
+}
+
+// The kind of document a page is, so that apps can treat media differently
+// from regular pages. NOTE: These are the same values desktop uses in
+// `moz_places_metadata.document_type`, so don't change them.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DocumentType {
+    Regular = 0,
+    Media = 1,
+}
+
+impl DocumentType {
+    #[inline]
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(DocumentType::Regular),
+            1 => Some(DocumentType::Media),
+            _ => None,
+        }
+    }
+}
+
+impl Default for DocumentType {
+    #[inline]
+    fn default() -> Self {
+        DocumentType::Regular
+    }
+}
+
+impl ToSql for DocumentType {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for DocumentType {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        if v < 0 || v > i64::from(u8::max_value()) {
+            return Err(FromSqlError::OutOfRange(v));
+        }
+        DocumentType::from_u8(v as u8).ok_or_else(|| FromSqlError::OutOfRange(v))
+    }
+}
+
+impl serde::Serialize for DocumentType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for DocumentType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let v = u8::deserialize(deserializer)?;
+        DocumentType::from_u8(v)
+            .ok_or_else(|| D::Error::custom(format!("unknown DocumentType value: {}", v)))
+    }
+}
+
 /// Re SyncStatus - note that:
 /// * logins has synced=0, changed=1, new=2
 /// * desktop bookmarks has unknown=0, new=1, normal=2
@@ -230,5 +342,9 @@ mod tests {
             VisitTransition::from_primitive(1)
         );
         assert_eq!(None, VisitTransition::from_primitive(99));
+        assert_eq!(Some(BookmarkType::Folder), BookmarkType::from_u8(2));
+        assert_eq!(None, BookmarkType::from_u8(0));
+        assert_eq!(Some(DocumentType::Media), DocumentType::from_u8(1));
+        assert_eq!(None, DocumentType::from_u8(2));
     }
 }
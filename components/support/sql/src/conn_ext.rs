@@ -106,6 +106,22 @@ pub trait ConnExt {
     fn unchecked_transaction(&self) -> SqlResult<UncheckedTransaction> {
         UncheckedTransaction::new(self.conn(), TransactionBehavior::Deferred)
     }
+
+    /// Runs `f` in an unchecked transaction, which is committed if `f`
+    /// succeeds, and rolled back if it fails.
+    fn in_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        E: From<rusqlite::Error>,
+        F: FnOnce() -> Result<T, E>,
+    {
+        let tx = self.unchecked_transaction()?;
+        let result = f();
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    }
 }
 
 impl ConnExt for Connection {
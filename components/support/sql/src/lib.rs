@@ -5,11 +5,13 @@
 mod conn_ext;
 mod each_chunk;
 mod maybe_cached;
+mod migration;
 mod repeat;
 
 pub use crate::conn_ext::*;
 pub use crate::each_chunk::*;
 pub use crate::maybe_cached::*;
+pub use crate::migration::*;
 pub use crate::repeat::*;
 
 /// In PRAGMA foo='bar', `'bar'` must be a constant string (it cannot be a
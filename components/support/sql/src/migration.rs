@@ -0,0 +1,388 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A small framework for creating and upgrading database schemas.
+//!
+//! A component describes its schema by implementing `Schema`, and calls
+//! `init_schema` when it opens a connection. We track the version with
+//! `PRAGMA user_version`, where 0 means the database is empty.
+//!
+//! Each upgrade step runs in its own transaction, and bumps the version in the
+//! same transaction. If a step fails, it's rolled back, and the database is
+//! left at the last version that upgraded successfully, so that opening it
+//! again retries from there.
+
+use crate::conn_ext::ConnExt;
+use rusqlite::Connection;
+use std::{error, fmt};
+
+/// What to do with a database that was last used by a newer version of the
+/// code, with a schema version we don't know about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DowngradePolicy {
+    /// Fail with `MigrationError::TooNew`, and leave the database alone.
+    Refuse,
+    /// Use the database as it is. This is for schemas where everything is
+    /// user data, which newer versions only add to.
+    Ignore,
+    /// Call `Schema::rebuild` to rebuild the tables that we can recreate
+    /// without losing data. The version is left alone, so the newer version
+    /// doesn't run its upgrade steps again when it next opens the database.
+    Rebuild,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationError {
+    /// The database is older than the oldest version we can upgrade.
+    TooOld { version: i64, min_version: i64 },
+    /// The database is newer than we know about, and the schema's
+    /// `DowngradePolicy` is `Refuse`.
+    TooNew { version: i64, max_version: i64 },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::TooOld {
+                version,
+                min_version,
+            } => write!(
+                f,
+                "Can't upgrade schema version {} (the oldest we support is {})",
+                version, min_version
+            ),
+            MigrationError::TooNew {
+                version,
+                max_version,
+            } => write!(
+                f,
+                "Can't use schema version {} (we only understand up to {})",
+                version, max_version
+            ),
+        }
+    }
+}
+
+impl error::Error for MigrationError {}
+
+/// Describes how to create a database schema, and how to upgrade older
+/// versions of it.
+pub trait Schema {
+    type Error: From<rusqlite::Error> + From<MigrationError>;
+
+    /// The name of the schema, for logging.
+    const NAME: &'static str;
+
+    /// The current version of the schema.
+    const VERSION: i64;
+
+    /// The oldest version that `upgrade_from` can upgrade.
+    const MIN_VERSION: i64 = 1;
+
+    const DOWNGRADE_POLICY: DowngradePolicy = DowngradePolicy::Refuse;
+
+    /// Creates the current version of the schema in an empty database.
+    fn create(&self, db: &Connection) -> Result<(), Self::Error>;
+
+    /// Upgrades the schema from `version` to `version + 1`. This is called
+    /// for each version from the database's version up to `VERSION - 1`, so
+    /// each step only needs to handle the changes made in the next version.
+    fn upgrade_from(&self, db: &Connection, version: i64) -> Result<(), Self::Error>;
+
+    /// Rebuilds the tables that don't hold user data, like caches and tables
+    /// derived from other tables, for a database last used by a newer
+    /// version. Only called if `DOWNGRADE_POLICY` is `Rebuild`. Since the
+    /// version doesn't change, this is called every time we open the
+    /// database, so it should skip work that it's already done.
+    fn rebuild(&self, _db: &Connection, _version: i64) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Creates or upgrades the schema for `db`, following the schema's
+/// `DowngradePolicy` if the database is newer.
+pub fn init_schema<S: Schema>(db: &Connection, schema: &S) -> Result<(), S::Error> {
+    let version = db.query_one::<i64>("PRAGMA user_version")?;
+    if version == 0 {
+        log::debug!("Creating {} schema", S::NAME);
+        return run_step(db, S::VERSION, || schema.create(db));
+    }
+    if version > S::VERSION {
+        return match S::DOWNGRADE_POLICY {
+            DowngradePolicy::Refuse => Err(MigrationError::TooNew {
+                version,
+                max_version: S::VERSION,
+            }
+            .into()),
+            DowngradePolicy::Ignore => {
+                log::warn!(
+                    "Loaded future {} schema version {} (we only understand version {}); \
+                     using it as is",
+                    S::NAME,
+                    version,
+                    S::VERSION
+                );
+                Ok(())
+            }
+            DowngradePolicy::Rebuild => {
+                log::warn!(
+                    "Loaded future {} schema version {} (we only understand version {}); \
+                     rebuilding",
+                    S::NAME,
+                    version,
+                    S::VERSION
+                );
+                db.in_transaction(|| schema.rebuild(db, version))
+            }
+        };
+    }
+    if version < S::MIN_VERSION {
+        return Err(MigrationError::TooOld {
+            version,
+            min_version: S::MIN_VERSION,
+        }
+        .into());
+    }
+    for from in version..S::VERSION {
+        log::debug!("Upgrading {} schema from {} to {}", S::NAME, from, from + 1);
+        run_step(db, from + 1, || schema.upgrade_from(db, from)).map_err(|e| {
+            log::warn!("Failed to upgrade {} schema from {}", S::NAME, from);
+            e
+        })?;
+    }
+    Ok(())
+}
+
+// Runs `step` in a transaction, and sets the version to `version` if it
+// succeeds.
+fn run_step<E, F>(db: &Connection, version: i64, step: F) -> Result<(), E>
+where
+    E: From<rusqlite::Error>,
+    F: FnOnce() -> Result<(), E>,
+{
+    db.in_transaction(|| {
+        step()?;
+        db.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    enum TestError {
+        Sql(rusqlite::Error),
+        Migration(MigrationError),
+    }
+
+    impl From<rusqlite::Error> for TestError {
+        fn from(e: rusqlite::Error) -> Self {
+            TestError::Sql(e)
+        }
+    }
+
+    impl From<MigrationError> for TestError {
+        fn from(e: MigrationError) -> Self {
+            TestError::Migration(e)
+        }
+    }
+
+    // Version 1 has `a`, version 2 adds `b`, and version 3 adds `c`, which
+    // is a cache of `a`. Upgrading from the version passed in fails.
+    struct TestSchema(Option<i64>);
+
+    impl Schema for TestSchema {
+        type Error = TestError;
+        const NAME: &'static str = "test";
+        const VERSION: i64 = 3;
+        const DOWNGRADE_POLICY: DowngradePolicy = DowngradePolicy::Rebuild;
+
+        fn create(&self, db: &Connection) -> Result<(), TestError> {
+            db.execute_all(&[
+                "CREATE TABLE a (x INTEGER)",
+                "CREATE TABLE b (y INTEGER)",
+                "CREATE TABLE c AS SELECT x FROM a",
+            ])?;
+            Ok(())
+        }
+
+        fn upgrade_from(&self, db: &Connection, version: i64) -> Result<(), TestError> {
+            match version {
+                1 => db.execute_batch("CREATE TABLE b (y INTEGER)")?,
+                2 => db.execute_batch("CREATE TABLE c AS SELECT x FROM a")?,
+                _ => unreachable!("no upgrade from version {}", version),
+            }
+            if self.0 == Some(version) {
+                db.execute_batch("SELECT * FROM missing")?;
+            }
+            Ok(())
+        }
+
+        fn rebuild(&self, db: &Connection, _version: i64) -> Result<(), TestError> {
+            db.execute_all(&["DELETE FROM c", "INSERT INTO c SELECT x FROM a"])?;
+            Ok(())
+        }
+    }
+
+    // The next version of `TestSchema`, which adds a column to `a`.
+    struct NewerSchema;
+
+    impl Schema for NewerSchema {
+        type Error = TestError;
+        const NAME: &'static str = "test";
+        const VERSION: i64 = 4;
+        const DOWNGRADE_POLICY: DowngradePolicy = DowngradePolicy::Rebuild;
+
+        fn create(&self, db: &Connection) -> Result<(), TestError> {
+            TestSchema(None).create(db)?;
+            self.upgrade_from(db, 3)
+        }
+
+        fn upgrade_from(&self, db: &Connection, version: i64) -> Result<(), TestError> {
+            match version {
+                3 => db.execute_batch("ALTER TABLE a ADD COLUMN z INTEGER")?,
+                _ => TestSchema(None).upgrade_from(db, version)?,
+            }
+            Ok(())
+        }
+    }
+
+    struct IgnoringSchema;
+
+    impl Schema for IgnoringSchema {
+        type Error = TestError;
+        const NAME: &'static str = "ignoring";
+        const VERSION: i64 = 1;
+        const DOWNGRADE_POLICY: DowngradePolicy = DowngradePolicy::Ignore;
+
+        fn create(&self, _db: &Connection) -> Result<(), TestError> {
+            Ok(())
+        }
+
+        fn upgrade_from(&self, _db: &Connection, _version: i64) -> Result<(), TestError> {
+            Ok(())
+        }
+    }
+
+    struct RefusingSchema;
+
+    impl Schema for RefusingSchema {
+        type Error = TestError;
+        const NAME: &'static str = "refusing";
+        const VERSION: i64 = 1;
+
+        fn create(&self, _db: &Connection) -> Result<(), TestError> {
+            Ok(())
+        }
+
+        fn upgrade_from(&self, _db: &Connection, _version: i64) -> Result<(), TestError> {
+            Ok(())
+        }
+    }
+
+    fn user_version(db: &Connection) -> i64 {
+        db.query_one("PRAGMA user_version").unwrap()
+    }
+
+    fn has_table(db: &Connection, name: &str) -> bool {
+        db.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+            &[&name],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+            == 1
+    }
+
+    fn v1_db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE a (x INTEGER);
+             INSERT INTO a VALUES (1);
+             PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn test_create() {
+        let db = Connection::open_in_memory().unwrap();
+        init_schema(&db, &TestSchema(None)).unwrap();
+        assert_eq!(user_version(&db), 3);
+        assert!(has_table(&db, "c"));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let db = v1_db();
+        init_schema(&db, &TestSchema(None)).unwrap();
+        assert_eq!(user_version(&db), 3);
+        assert!(has_table(&db, "b"));
+        assert_eq!(db.query_one::<i64>("SELECT x FROM c").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_failed_upgrade() {
+        let db = v1_db();
+        match init_schema(&db, &TestSchema(Some(2))) {
+            Err(TestError::Sql(_)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        // The first step should stick, but not the second.
+        assert_eq!(user_version(&db), 2);
+        assert!(has_table(&db, "b"));
+        assert!(!has_table(&db, "c"));
+
+        // Opening it again picks up where we left off.
+        init_schema(&db, &TestSchema(None)).unwrap();
+        assert_eq!(user_version(&db), 3);
+        assert!(has_table(&db, "c"));
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let db = Connection::open_in_memory().unwrap();
+        init_schema(&db, &TestSchema(None)).unwrap();
+        db.execute_all(&["INSERT INTO a VALUES (5)", "PRAGMA user_version = 4"])
+            .unwrap();
+        init_schema(&db, &TestSchema(None)).unwrap();
+        assert_eq!(user_version(&db), 4);
+        assert_eq!(db.query_one::<i64>("SELECT x FROM c").unwrap(), 5);
+
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch("PRAGMA user_version = 2").unwrap();
+        init_schema(&db, &IgnoringSchema).unwrap();
+        assert_eq!(user_version(&db), 2);
+
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch("PRAGMA user_version = 2").unwrap();
+        match init_schema(&db, &RefusingSchema) {
+            Err(TestError::Migration(MigrationError::TooNew {
+                version: 2,
+                max_version: 1,
+            })) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(user_version(&db), 2);
+    }
+
+    #[test]
+    fn test_reopen_after_downgrade() {
+        // Opening a newer database with an older version, then with the
+        // newer version again, shouldn't replay the newer version's upgrade.
+        let db = v1_db();
+        init_schema(&db, &NewerSchema).unwrap();
+        assert_eq!(user_version(&db), 4);
+
+        init_schema(&db, &TestSchema(None)).unwrap();
+        assert_eq!(user_version(&db), 4);
+
+        init_schema(&db, &NewerSchema).unwrap();
+        assert_eq!(user_version(&db), 4);
+        assert_eq!(db.query_one::<i64>("SELECT COUNT(z) FROM a").unwrap(), 0);
+    }
+}